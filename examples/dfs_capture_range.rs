@@ -0,0 +1,63 @@
+//! Capture range of the AGWS dispersed fringe sensor and of the holographic dispersed fringe sensor
+//!
+//! Segment #1 of M1 is pistoned from -100 to 100 micron and the differential piston `p_7-p_1`
+//! estimated by both sensors is written to `dfs_capture_range.pkl`.
+
+use std::{fs::File, io::BufWriter, time::Instant};
+
+use crseo::{
+    segment_piston_sensor::{
+        holographic::{HolographicFringeSensor, HolographicFringes},
+        processing::DispersedFringeSensor,
+    },
+    Builder, FromBuilder, Gmt, SegmentPistonSensor, Source,
+};
+
+fn main() -> anyhow::Result<()> {
+    let mut gmt = Gmt::builder().build()?;
+    let src_builder = Source::builder().band("J");
+
+    let mut sps = SegmentPistonSensor::builder()
+        .src(src_builder.clone())
+        .build()?;
+    let mut hdfs = HolographicFringeSensor::builder().build()?;
+
+    let mut src = src_builder.build()?;
+
+    src.through(&mut gmt).xpupil().through(&mut sps);
+    let mut dfs0 = DispersedFringeSensor::from(sps.fft());
+    dfs0.intercept();
+    src.through(&mut hdfs);
+    let mut hdfs0 = HolographicFringes::from(&hdfs);
+    hdfs0.intercept();
+
+    let mut data = vec![];
+
+    let now = Instant::now();
+    for tz in (-100..=100).step_by(5) {
+        let mut tr_xyz = [0f64; 6];
+        tr_xyz[2] = tz as f64 * 1e-6;
+        gmt.m1.set_rigid_body_motions(1, &tr_xyz);
+
+        src.through(&mut gmt).xpupil().through(sps.reset());
+        let mut dfs = DispersedFringeSensor::from(sps.fft());
+        dfs.set_reference(&dfs0).intercept();
+
+        src.through(hdfs.reset());
+        let mut fringes = HolographicFringes::from(&hdfs);
+        fringes.set_reference(&hdfs0).intercept();
+
+        println!(
+            "Tz: {:4}micron ; DFS: {:8.3}micron ; HDFS: {:8.3?}micron",
+            tz,
+            dfs.intercept[0] * 1e6,
+            fringes.intercept[0].map(|x| x * 1e6)
+        );
+        data.push((tz, dfs.intercept.clone(), fringes.intercept.clone()));
+    }
+    println!("elapsed {:.3?}", now.elapsed());
+
+    let mut buffer = BufWriter::new(File::create("dfs_capture_range.pkl")?);
+    serde_pickle::to_writer(&mut buffer, &data, Default::default())?;
+    Ok(())
+}
@@ -0,0 +1,35 @@
+//! Pyramid optical gains versus seeing
+//!
+//! The optical gains of a modulated pyramid are derived from the convolutional model
+//! for the residual phase of an AO loop with the pyramid and for several seeing conditions.
+//! The gains are given for the Fourier modes of the spatial frequencies `k/D`.
+
+use crseo::{
+    atmosphere::theory::VonKarman,
+    wavefrontsensor::fourier::{FourierPyramid, FourierSensor},
+};
+
+fn main() {
+    let diameter = 25.5;
+    let wavelength = 0.8e-6;
+    let pym = FourierPyramid::new(diameter / 92., diameter).modulation(3.);
+    let frequencies: Vec<f64> = [1, 2, 4, 8, 16, 32, 46]
+        .into_iter()
+        .map(|k| k as f64 / diameter)
+        .collect();
+    println!("k: {:?}", [1, 2, 4, 8, 16, 32, 46]);
+    for r0 in [0.08, 0.12, 0.16, 0.20, 0.25] {
+        let atm = VonKarman::new(r0, 25.).at_wavelength(wavelength);
+        let gains = pym.optical_gains(
+            &frequencies,
+            |fx, fy| pym.error_psd(&atm, fx, fy).sum(),
+            256,
+        );
+        println!(
+            "r0={:.2}m ; residual: {:.3}rd² ; optical gains: {:.3?}",
+            r0,
+            pym.error_variance(&atm, 64).sum(),
+            gains
+        );
+    }
+}
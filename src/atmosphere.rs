@@ -12,10 +12,23 @@ use crate::builders::{AtmosphereBuilder, AtmosphereBuilderError};
 use super::{Cu, FromBuilder, Propagation, Single, Source};
 use ffi::atmosphere;
 
+pub mod evolution;
+pub mod profile;
+pub mod screens;
+pub mod theory;
+pub use evolution::Evolution;
+pub use profile::{NamedProfile, TurbulenceProfile};
+
 #[derive(Debug, thiserror::Error)]
 pub enum AtmosphereError {
     #[error("cannot create `::crseo::AtmosphereBuilder`")]
     Builder(#[from] AtmosphereBuilderError),
+    #[error("cannot load the turbulence profile")]
+    Profile(#[from] profile::ProfileError),
+    #[error("cannot load the atmosphere evolution")]
+    Evolution(#[from] evolution::EvolutionError),
+    #[error("cannot read or write the phase screens file")]
+    PhaseScreens(#[from] screens::PhaseScreensError),
 }
 pub type Result<T> = std::result::Result<T, AtmosphereError>;
 
@@ -36,26 +49,6 @@ struct GmtAtmosphere {
     seed: i32,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[doc(hidden)]
-pub struct TurbulenceProfile {
-    pub n_layer: usize,
-    pub altitude: Vec<f32>,
-    pub xi0: Vec<f32>,
-    pub wind_speed: Vec<f32>,
-    pub wind_direction: Vec<f32>,
-}
-impl Default for TurbulenceProfile {
-    fn default() -> Self {
-        TurbulenceProfile {
-            n_layer: 7,
-            altitude: [25.0, 275.0, 425.0, 1_250.0, 4_000.0, 8_000.0, 13_000.0].to_vec(),
-            xi0: [0.1257, 0.0874, 0.0666, 0.3498, 0.2273, 0.0681, 0.0751].to_vec(),
-            wind_speed: [5.6540, 5.7964, 5.8942, 6.6370, 13.2925, 34.8250, 29.4187].to_vec(),
-            wind_direction: [0.0136, 0.1441, 0.2177, 0.5672, 1.2584, 1.6266, 1.7462].to_vec(),
-        }
-    }
-}
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[doc(hidden)]
 pub struct RayTracing {
@@ -65,6 +58,8 @@ pub struct RayTracing {
     pub(crate) duration: f32,
     pub(crate) filepath: Option<String>,
     pub(crate) n_duration: Option<i32>,
+    #[serde(default)]
+    pub(crate) screens: Option<String>,
 }
 /// Default properties:
 ///  * width        : 25.5m
@@ -73,6 +68,7 @@ pub struct RayTracing {
 ///  * duration     : 1s
 ///  * filepath     : None
 ///  * n_duration   : None
+///  * screens      : None
 impl Default for RayTracing {
     fn default() -> Self {
         Self {
@@ -82,6 +78,7 @@ impl Default for RayTracing {
             duration: 1.0,
             filepath: None,
             n_duration: None,
+            screens: None,
         }
     }
 }
@@ -121,6 +118,14 @@ impl RayTracing {
         self.n_duration = Some(n_duration as i32);
         self
     }
+    /// Path where to save the phase screens in the [phase screens file](screens) format
+    ///
+    /// The phase screens are saved when the atmosphere is built
+    pub fn screens<P: AsRef<Path>>(mut self, path: P) -> Self {
+        let path = path.as_ref();
+        self.screens = Some(path.to_str().unwrap().to_string());
+        self
+    }
 }
 
 pub struct Atmosphere {
@@ -132,6 +137,7 @@ pub struct Atmosphere {
     //filename: String,
     //k_duration: i32,
     pub(crate) propagate_ptr: fn(&mut Atmosphere, &mut Source, f32),
+    pub(crate) evolution: Option<evolution::EvolutionState>,
 }
 impl Display for Atmosphere {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -351,6 +357,7 @@ impl Drop for Atmosphere {
 }
 impl Propagation for Atmosphere {
     fn time_propagate(&mut self, secs: f64, src: &mut Source) {
+        self.evolve(secs);
         (self.propagate_ptr)(self, src, secs as f32);
     }
     fn propagate(&mut self, src: &mut Source) {
@@ -0,0 +1,462 @@
+//!
+//! # Atmosphere evolution
+//!
+//! Models the evolution of the seeing over time.
+//!
+//! An [Evolution] replays the r0 and the layer strength and wind velocity either from time series
+//! or from stochastic processes, while the [Atmosphere] is propagated in time with
+//! [time_propagate](crate::Propagation::time_propagate).
+//! The wind jitter option adds a random component to the velocity of each layer,
+//! the phase screens are still advected as frozen flow: the jitter does not model the boiling of the turbulence.
+//!
+//! The r0 and the wind velocities of the [Evolution] are given at zenith, they are projected along
+//! the line of sight as in the [AtmosphereBuilder](crate::builders::AtmosphereBuilder):
+//! r0 is scaled by sec(z)^-3/5 and the wind speed is divided by sec(z).
+//! CEO advects the phase screens with the layer velocities times the absolute time,
+//! so the displacement of each layer is integrated over time on the host and the layer velocities
+//! sent to CEO are the mean velocities since the start, keeping the phase screens continuous
+//! when the velocities change.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use crseo::{
+//!     atmosphere::evolution::{Evolution, Process},
+//!     Atmosphere, Builder, FromBuilder,
+//! };
+//! let evolution = Evolution::default()
+//!     .r0(Process::LogNormalWalk {
+//!         rms: 0.2,
+//!         time_constant: 600.,
+//!     })
+//!     .wind_jitter(1., 1.);
+//! let atm = Atmosphere::builder().evolution(evolution).build().unwrap();
+//! ```
+
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use ffi::host2dev;
+use rand::{rngs::StdRng, RngExt, SeedableRng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+use super::{Atmosphere, TurbulenceProfile};
+
+#[derive(Debug, thiserror::Error)]
+pub enum EvolutionError {
+    #[error("cannot open time series file: {1}")]
+    Open(#[source] std::io::Error, PathBuf),
+    #[error("cannot read time series file: {1}")]
+    Read(#[source] std::io::Error, PathBuf),
+    #[error("invalid time series at line {0}: {1}")]
+    Csv(usize, String),
+    #[error("the time series is empty")]
+    Empty,
+}
+pub type Result<T> = std::result::Result<T, EvolutionError>;
+
+/// Time series
+///
+/// The values are linearly interpolated in between the time samples
+/// and are held constant before the first and after the last sample
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeSeries {
+    /// time \[s\]
+    pub time: Vec<f64>,
+    pub value: Vec<f64>,
+}
+impl TimeSeries {
+    /// Creates a new time series
+    pub fn new(time: Vec<f64>, value: Vec<f64>) -> Result<Self> {
+        if time.is_empty() || time.len() != value.len() {
+            return Err(EvolutionError::Empty);
+        }
+        Ok(Self { time, value })
+    }
+    /// Loads a time series from a csv file
+    ///
+    /// Each row is `time,value`, lines starting with `#` and a non-numeric header are skipped
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file =
+            File::open(&path).map_err(|e| EvolutionError::Open(e, path.as_ref().to_path_buf()))?;
+        let mut csv = String::new();
+        file.read_to_string(&mut csv)
+            .map_err(|e| EvolutionError::Read(e, path.as_ref().to_path_buf()))?;
+        let (mut time, mut value) = (vec![], vec![]);
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let row: std::result::Result<Vec<f64>, _> =
+                line.split(',').map(|x| x.trim().parse::<f64>()).collect();
+            match row {
+                Ok(row) if row.len() == 2 => {
+                    time.push(row[0]);
+                    value.push(row[1]);
+                }
+                Ok(row) => {
+                    return Err(EvolutionError::Csv(
+                        i + 1,
+                        format!("expected 2 columns, found {}", row.len()),
+                    ))
+                }
+                Err(_) if time.is_empty() => continue,
+                Err(e) => return Err(EvolutionError::Csv(i + 1, e.to_string())),
+            }
+        }
+        Self::new(time, value)
+    }
+    /// Returns the value at time `t` \[s\]
+    pub fn at(&self, t: f64) -> f64 {
+        let n = self.time.len();
+        if t <= self.time[0] {
+            return self.value[0];
+        }
+        if t >= self.time[n - 1] {
+            return self.value[n - 1];
+        }
+        let k = self.time.partition_point(|&x| x <= t);
+        let (t0, t1) = (self.time[k - 1], self.time[k]);
+        let (v0, v1) = (self.value[k - 1], self.value[k]);
+        v0 + (v1 - v0) * (t - t0) / (t1 - t0)
+    }
+}
+
+/// Evolution of a turbulence parameter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Process {
+    /// The parameter follows the time series
+    Series(TimeSeries),
+    /// The logarithm of the parameter follows a random walk (Ornstein-Uhlenbeck process)
+    /// around the logarithm of the nominal value with the standard deviation `rms` and the correlation time `time_constant` \[s\]
+    LogNormalWalk { rms: f64, time_constant: f64 },
+    /// The parameter follows a random walk (Ornstein-Uhlenbeck process)
+    /// around the nominal value with the standard deviation `rms` and the correlation time `time_constant` \[s\]
+    RandomWalk { rms: f64, time_constant: f64 },
+}
+impl Process {
+    fn value(&self, nominal: f64, t: f64, walk: f64) -> f64 {
+        match self {
+            Process::Series(series) => series.at(t),
+            Process::LogNormalWalk { .. } => nominal * walk.exp(),
+            Process::RandomWalk { .. } => nominal + walk,
+        }
+    }
+    fn step(&self, walk: &mut f64, dt: f64, rng: &mut StdRng) {
+        match self {
+            Process::Series(_) => (),
+            Process::LogNormalWalk { rms, time_constant }
+            | Process::RandomWalk { rms, time_constant } => {
+                *walk = ornstein_uhlenbeck(*walk, *rms, *time_constant, dt, rng)
+            }
+        }
+    }
+}
+
+/// Evolution of a turbulence layer
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LayerEvolution {
+    /// layer strength as a fraction of the turbulence
+    pub xi0: Option<Process>,
+    /// wind speed at zenith \[m/s\]
+    pub wind_speed: Option<Process>,
+    /// wind direction \[rd\]
+    pub wind_direction: Option<Process>,
+}
+
+/// Random jitter of the wind velocity of the turbulence layers
+///
+/// A random velocity with the standard deviation `speed_rms` \[m/s\] per axis and the correlation time `time_constant` \[s\]
+/// is added to the wind velocity of each layer
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WindJitter {
+    pub speed_rms: f64,
+    pub time_constant: f64,
+}
+
+/// Atmosphere evolution model
+///
+/// Default properties:
+///  - r0     : constant
+///  - layers : constant
+///  - wind jitter: none
+///  - seed   : 0
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Evolution {
+    /// r0 at zenith \[m\]
+    pub r0: Option<Process>,
+    /// layers evolution, the layers are matched by their indices in the turbulence profile
+    pub layers: Vec<LayerEvolution>,
+    pub wind_jitter: Option<WindJitter>,
+    pub seed: u64,
+}
+impl Evolution {
+    /// Sets the evolution of r0 at zenith \[m\]
+    pub fn r0(self, process: Process) -> Self {
+        Self {
+            r0: Some(process),
+            ..self
+        }
+    }
+    /// Sets the evolution of the layer with the zero based index `layer_idx`
+    pub fn layer(mut self, layer_idx: usize, evolution: LayerEvolution) -> Self {
+        if self.layers.len() <= layer_idx {
+            self.layers.resize(layer_idx + 1, Default::default());
+        }
+        self.layers[layer_idx] = evolution;
+        self
+    }
+    /// Sets the wind jitter with the velocity standard deviation `speed_rms` \[m/s\] and the correlation time `time_constant` \[s\]
+    pub fn wind_jitter(self, speed_rms: f64, time_constant: f64) -> Self {
+        Self {
+            wind_jitter: Some(WindJitter {
+                speed_rms,
+                time_constant,
+            }),
+            ..self
+        }
+    }
+    /// Sets the seed of the random number generator
+    pub fn seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+}
+
+/// Evolution model state attached to an [Atmosphere]
+#[derive(Debug)]
+pub(crate) struct EvolutionState {
+    evolution: Evolution,
+    rng: StdRng,
+    time: f64,
+    // nominal r0 at zenith
+    r0_at_zenith: f64,
+    // nominal turbulence profile at zenith
+    turbulence: TurbulenceProfile,
+    // random walks: r0, then xi0, wind speed, wind direction, jitter vx and jitter vy for each layer
+    walks: Vec<f64>,
+    // layers velocity [vx,vy] along the line of sight at the last update
+    velocity: Vec<[f64; 2]>,
+    // layers displacement [x,y] since the start
+    displacement: Vec<[f64; 2]>,
+}
+impl EvolutionState {
+    pub(crate) fn new(
+        evolution: Evolution,
+        r0_at_zenith: f64,
+        zenith_angle: f64,
+        turbulence: TurbulenceProfile,
+    ) -> Self {
+        let secz = 1. / zenith_angle.cos();
+        let velocity = turbulence
+            .wind_speed
+            .iter()
+            .zip(&turbulence.wind_direction)
+            .map(|(&v, &o)| {
+                let (s, c) = (o as f64).sin_cos();
+                [v as f64 * c / secz, v as f64 * s / secz]
+            })
+            .collect();
+        Self {
+            rng: StdRng::seed_from_u64(evolution.seed),
+            evolution,
+            time: 0.,
+            r0_at_zenith,
+            walks: vec![0.; 1 + 5 * turbulence.n_layer],
+            velocity,
+            displacement: vec![[0.; 2]; turbulence.n_layer],
+            turbulence,
+        }
+    }
+    // Restarts the random walks and the layers displacement from the nominal values at time 0
+    fn reset(&mut self, zenith_angle: f64) {
+        *self = Self::new(
+            self.evolution.clone(),
+            self.r0_at_zenith,
+            zenith_angle,
+            self.turbulence.clone(),
+        );
+    }
+}
+
+impl Atmosphere {
+    /// Sets the r0, the layers strength and the wind velocities at time `secs` \[s\]
+    ///
+    /// The layers displacement is integrated from the previous update with the trapezoidal rule
+    /// and the layer velocities are set to the mean velocities since the start.
+    /// If `secs` is before the time of the previous update, the evolution restarts from time 0 with the same seed.
+    /// Does nothing if the atmosphere has no [Evolution]
+    pub fn evolve(&mut self, secs: f64) {
+        let Some(state) = self.evolution.as_mut() else {
+            return;
+        };
+        if secs < state.time {
+            state.reset(self.zenith_angle);
+        }
+        let dt = secs - state.time;
+        state.time = secs;
+        let EvolutionState {
+            evolution,
+            rng,
+            r0_at_zenith,
+            turbulence,
+            walks,
+            velocity,
+            displacement,
+            ..
+        } = state;
+        let n_layer = turbulence.n_layer;
+        let secz = 1. / self.zenith_angle.cos();
+
+        if let Some(process) = &evolution.r0 {
+            if dt > 0. {
+                process.step(&mut walks[0], dt, rng);
+            }
+            self.r0_at_zenith = process.value(*r0_at_zenith, secs, walks[0]);
+            self._c_.r0 = (self.r0_at_zenith.powf(-5. / 3.) * secz).powf(-3. / 5.) as f32;
+        }
+
+        let mut xi0 = turbulence.xi0.clone();
+        let mut wind_speed = turbulence.wind_speed.clone();
+        let mut wind_direction = turbulence.wind_direction.clone();
+        for (k, layer) in evolution.layers.iter().enumerate().take(n_layer) {
+            let walk = &mut walks[1 + 5 * k..1 + 5 * (k + 1)];
+            for (i, (process, value)) in [
+                (&layer.xi0, &mut xi0[k]),
+                (&layer.wind_speed, &mut wind_speed[k]),
+                (&layer.wind_direction, &mut wind_direction[k]),
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                if let Some(process) = process {
+                    if dt > 0. {
+                        process.step(&mut walk[i], dt, rng);
+                    }
+                    *value = process.value(*value as f64, secs, walk[i]) as f32;
+                }
+            }
+        }
+        if let Some(WindJitter {
+            speed_rms,
+            time_constant,
+        }) = evolution.wind_jitter
+        {
+            for k in 0..n_layer {
+                let walk = &mut walks[1 + 5 * k..1 + 5 * (k + 1)];
+                if dt > 0. {
+                    walk[3] = ornstein_uhlenbeck(walk[3], speed_rms, time_constant, dt, rng);
+                    walk[4] = ornstein_uhlenbeck(walk[4], speed_rms, time_constant, dt, rng);
+                }
+            }
+        }
+        for k in 0..n_layer {
+            let walk = &walks[1 + 5 * k..1 + 5 * (k + 1)];
+            let (jitter_vx, jitter_vy) = match evolution.wind_jitter {
+                Some(_) => (walk[3], walk[4]),
+                None => (0., 0.),
+            };
+            let (s, c) = (wind_direction[k] as f64).sin_cos();
+            let v = [
+                (wind_speed[k] as f64 * c + jitter_vx) / secz,
+                (wind_speed[k] as f64 * s + jitter_vy) / secz,
+            ];
+            let [vx, vy] = advection(&mut displacement[k], &mut velocity[k], v, dt, secs);
+            wind_speed[k] = vx.hypot(vy) as f32;
+            wind_direction[k] = vy.atan2(vx) as f32;
+        }
+        let xi0_sum: f32 = xi0.iter().sum();
+        xi0.iter_mut().for_each(|x| *x /= xi0_sum);
+
+        unsafe {
+            let profile = &mut self._c_.turbulence;
+            host2dev(profile.xi0, xi0.as_mut_ptr(), n_layer as i32);
+            host2dev(profile.wind_speed, wind_speed.as_mut_ptr(), n_layer as i32);
+            host2dev(
+                profile.wind_direction,
+                wind_direction.as_mut_ptr(),
+                n_layer as i32,
+            );
+            if !self._c_.layers.is_null() {
+                let layers = std::slice::from_raw_parts_mut(self._c_.layers, n_layer);
+                for (k, layer) in layers.iter_mut().enumerate() {
+                    layer.xi0 = xi0[k];
+                    layer.wind_speed = wind_speed[k];
+                    layer.wind_direction = wind_direction[k];
+                    layer.vx = wind_speed[k] * wind_direction[k].cos();
+                    layer.vy = wind_speed[k] * wind_direction[k].sin();
+                }
+                host2dev(
+                    self._c_.d__layers as *mut f32,
+                    self._c_.layers as *mut f32,
+                    (n_layer * std::mem::size_of::<ffi::layer>() / std::mem::size_of::<f32>())
+                        as i32,
+                );
+            }
+        }
+    }
+}
+
+// Integrates the displacement `x` from the velocity `v0` to the velocity `v` over `dt`
+// and returns the mean velocity since the start at time `secs`
+fn advection(x: &mut [f64; 2], v0: &mut [f64; 2], v: [f64; 2], dt: f64, secs: f64) -> [f64; 2] {
+    x[0] += 0.5 * (v0[0] + v[0]) * dt;
+    x[1] += 0.5 * (v0[1] + v[1]) * dt;
+    *v0 = v;
+    if secs > 0. {
+        [x[0] / secs, x[1] / secs]
+    } else {
+        v
+    }
+}
+
+fn ornstein_uhlenbeck(x: f64, rms: f64, time_constant: f64, dt: f64, rng: &mut StdRng) -> f64 {
+    let a = (-dt / time_constant).exp();
+    a * x + (1. - a * a).sqrt() * rms * rng.sample::<f64, _>(StandardNormal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_series() {
+        let series = TimeSeries::new(vec![0., 10.], vec![0.1, 0.2]).unwrap();
+        assert_eq!(series.at(-1.), 0.1);
+        assert!((series.at(5.) - 0.15).abs() < 1e-12);
+        assert_eq!(series.at(20.), 0.2);
+    }
+
+    #[test]
+    fn continuous_advection() {
+        let (mut x, mut v0) = ([0.; 2], [10., 0.]);
+        let v = advection(&mut x, &mut v0, [10., 0.], 10., 10.);
+        assert_eq!(v, [10., 0.]);
+        // the wind speed doubles in between 10s and 11s
+        let v = advection(&mut x, &mut v0, [20., 0.], 1., 11.);
+        // the screen has moved by 115m and not by 220m
+        assert!((v[0] * 11. - 115.).abs() < 1e-9);
+        let v = advection(&mut x, &mut v0, [20., 0.], 1., 12.);
+        assert!((v[0] * 12. - 135.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reset() {
+        let evolution = Evolution::default().wind_jitter(1., 1.).seed(1);
+        let mut state = EvolutionState::new(evolution, 0.16, 0., TurbulenceProfile::default());
+        let velocity = state.velocity.clone();
+        state.time = 10.;
+        state.walks.iter_mut().for_each(|w| *w = 1.);
+        state.displacement[0] = [100., 0.];
+        state.velocity[0] = [0., 0.];
+        state.reset(0.);
+        assert_eq!(state.time, 0.);
+        assert!(state.walks.iter().all(|&w| w == 0.));
+        assert_eq!(state.displacement[0], [0., 0.]);
+        assert_eq!(state.velocity, velocity);
+    }
+}
@@ -0,0 +1,505 @@
+//!
+//! # Turbulence profiles
+//!
+//! A [TurbulenceProfile] describes the vertical distribution of the atmospheric turbulence as a set of layers
+//! with their altitudes, their fractional strengths and their wind velocities.
+//!
+//! Profiles are either selected from the library of [NamedProfile]s,
+//! loaded from a Cn² table with [TurbulenceProfile::from_csv] or [TurbulenceProfile::load],
+//! or resampled to fewer layers with [TurbulenceProfile::resample].
+//! The integrated parameters of the turbulence are derived with [TurbulenceProfile::integrated].
+//!
+//! # Examples
+//!
+//! ```
+//! use crseo::atmosphere::profile::{NamedProfile, Resampling};
+//! let (r0, profile) = NamedProfile::Gmt50.profile();
+//! let parameters = profile.integrated(r0, 30f64.to_radians(), 500e-9);
+//! let coarse = profile.resample(3, Resampling::OptimalGrouping);
+//! ```
+
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("cannot open turbulence profile file: {1}")]
+    Open(#[source] std::io::Error, PathBuf),
+    #[error("cannot read turbulence profile file: {1}")]
+    Read(#[source] std::io::Error, PathBuf),
+    #[error("cannot deserialize turbulence profile from toml")]
+    Load(#[from] toml::de::Error),
+    #[error("invalid Cn² table at line {0}: {1}")]
+    Csv(usize, String),
+    #[error("the Cn² table is empty")]
+    Empty,
+    #[error("expected {0} layers, found {1} {2}")]
+    Length(usize, usize, String),
+}
+pub type Result<T> = std::result::Result<T, ProfileError>;
+
+/// Atmospheric turbulence profile
+///
+/// The strength of the layers `xi0` is given as fractions of the total turbulence, they sum to 1.
+///
+/// Default properties (GMT median profile):
+///  - n_layer        : 7
+///  - altitude       : [25.0, 275.0, 425.0, 1250.0, 4000.0, 8000.0, 13000.0] m
+///  - xi0            : [0.1257, 0.0874, 0.0666, 0.3498, 0.2273, 0.0681, 0.0751]
+///  - wind speed     : [5.6540, 5.7964, 5.8942, 6.6370, 13.2925, 34.8250, 29.4187] m/s
+///  - wind direction : [0.0136, 0.1441, 0.2177, 0.5672, 1.2584, 1.6266, 1.7462] rd
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TurbulenceProfile {
+    pub n_layer: usize,
+    pub altitude: Vec<f32>,
+    pub xi0: Vec<f32>,
+    pub wind_speed: Vec<f32>,
+    pub wind_direction: Vec<f32>,
+}
+impl Default for TurbulenceProfile {
+    fn default() -> Self {
+        TurbulenceProfile {
+            n_layer: 7,
+            altitude: [25.0, 275.0, 425.0, 1_250.0, 4_000.0, 8_000.0, 13_000.0].to_vec(),
+            xi0: [0.1257, 0.0874, 0.0666, 0.3498, 0.2273, 0.0681, 0.0751].to_vec(),
+            wind_speed: [5.6540, 5.7964, 5.8942, 6.6370, 13.2925, 34.8250, 29.4187].to_vec(),
+            wind_direction: [0.0136, 0.1441, 0.2177, 0.5672, 1.2584, 1.6266, 1.7462].to_vec(),
+        }
+    }
+}
+
+/// Library of turbulence profiles
+///
+/// The profiles share the 7 layers of the GMT median profile, the [TurbulenceProfile] default.
+/// The GMT profiles use the r0 at 500nm of the 25, 50 and 75 percentiles of the DIMM seeing
+/// measured during the GMT site testing campaign at Las Campanas Observatory
+/// (J. E. Thomas-Osip et al., *Giant Magellan Telescope site testing seeing and turbulence statistics*, Proc. SPIE 7012, 2008):
+///
+/// | profile | seeing | r0 |
+/// |---------|--------|----|
+/// | Gmt25   | 0.51"  | 19.7cm |
+/// | Gmt50   | 0.63"  | 16.0cm |
+/// | Gmt75   | 0.78"  | 12.9cm |
+///
+/// The Las Campanas variants keep the median r0 and bracket the variability of the ground layer turbulence
+/// (below 1km) reported by the MASS-DIMM measurements of the same campaign, with the strengths:
+///
+/// | profile                 | 25m    | 275m   | 425m   | 1250m  | 4000m  | 8000m  | 13000m | ground |
+/// |-------------------------|--------|--------|--------|--------|--------|--------|--------|--------|
+/// | Gmt50                   | 0.1257 | 0.0874 | 0.0666 | 0.3498 | 0.2273 | 0.0681 | 0.0751 | 28%    |
+/// | LasCampanasStrongGround | 0.1654 | 0.1150 | 0.0876 | 0.3069 | 0.1994 | 0.0597 | 0.0659 | 37%    |
+/// | LasCampanasWeakGround   | 0.0731 | 0.0508 | 0.0387 | 0.4067 | 0.2643 | 0.0792 | 0.0873 | 16%    |
+///
+/// Other site profiles are loaded from their published Cn² tables with
+/// [TurbulenceProfile::from_csv] or [TurbulenceProfile::load]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NamedProfile {
+    /// GMT 25 percentile (good seeing), r0=19.7cm
+    Gmt25,
+    /// GMT median profile, the [TurbulenceProfile] default with r0=16cm
+    Gmt50,
+    /// GMT 75 percentile (bad seeing), r0=12.9cm
+    Gmt75,
+    /// Las Campanas median seeing with a strong ground layer
+    LasCampanasStrongGround,
+    /// Las Campanas median seeing with a weak ground layer
+    LasCampanasWeakGround,
+}
+impl NamedProfile {
+    /// Returns the r0 at zenith \[m\] at 500nm and the turbulence profile
+    pub fn profile(&self) -> (f64, TurbulenceProfile) {
+        match self {
+            NamedProfile::Gmt25 => (0.197, TurbulenceProfile::default()),
+            NamedProfile::Gmt50 => (0.16, TurbulenceProfile::default()),
+            NamedProfile::Gmt75 => (0.129, TurbulenceProfile::default()),
+            NamedProfile::LasCampanasStrongGround => (
+                0.16,
+                TurbulenceProfile {
+                    xi0: [0.1654, 0.1150, 0.0876, 0.3069, 0.1994, 0.0597, 0.0659].to_vec(),
+                    ..Default::default()
+                },
+            ),
+            NamedProfile::LasCampanasWeakGround => (
+                0.16,
+                TurbulenceProfile {
+                    xi0: [0.0731, 0.0508, 0.0387, 0.4067, 0.2643, 0.0792, 0.0873].to_vec(),
+                    ..Default::default()
+                },
+            ),
+        }
+    }
+}
+
+/// Resampling methods of a [TurbulenceProfile]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Resampling {
+    /// Layers are merged within slabs of equal thickness
+    EquivalentLayers,
+    /// Layers are merged into the groups that minimize the dispersion of the altitudes within the groups
+    OptimalGrouping,
+}
+
+/// Integrated parameters of the atmospheric turbulence
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct IntegratedParameters {
+    /// Fried parameter along the line of sight \[m\]
+    pub r0: f64,
+    /// seeing (full width at half maximum) \[rd\]
+    pub seeing: f64,
+    /// isoplanatic angle θ0 \[rd\]
+    pub theta0: f64,
+    /// coherence time τ0 \[s\]
+    pub tau0: f64,
+}
+
+#[derive(Deserialize)]
+struct Cn2Table {
+    altitude: Vec<f64>,
+    cn2dh: Vec<f64>,
+    wind_speed: Option<Vec<f64>>,
+    wind_direction: Option<Vec<f64>>,
+}
+
+impl TurbulenceProfile {
+    /// Creates a profile from the altitudes \[m\] and integrated strengths Cn²dh \[m^1/3\] of the layers
+    ///
+    /// Returns the r0 at zenith \[m\] at 500nm and the turbulence profile with normalized strengths,
+    /// or an error if the table is empty or if the lengths of the table columns differ
+    pub fn from_cn2(
+        altitude: Vec<f64>,
+        cn2dh: Vec<f64>,
+        wind_speed: Vec<f64>,
+        wind_direction: Vec<f64>,
+    ) -> Result<(f64, Self)> {
+        let n = altitude.len();
+        if n == 0 {
+            return Err(ProfileError::Empty);
+        }
+        for (column, len) in [
+            ("Cn²dh", cn2dh.len()),
+            ("wind speeds", wind_speed.len()),
+            ("wind directions", wind_direction.len()),
+        ] {
+            if len != n {
+                return Err(ProfileError::Length(n, len, column.to_string()));
+            }
+        }
+        let cn2: f64 = cn2dh.iter().sum();
+        let k = 2. * PI / 500e-9;
+        let r0 = (0.423 * k * k * cn2).powf(-3. / 5.);
+        Ok((
+            r0,
+            Self {
+                n_layer: altitude.len(),
+                altitude: altitude.iter().map(|&x| x as f32).collect(),
+                xi0: cn2dh.iter().map(|&x| (x / cn2) as f32).collect(),
+                wind_speed: wind_speed.iter().map(|&x| x as f32).collect(),
+                wind_direction: wind_direction.iter().map(|&x| x as f32).collect(),
+            },
+        ))
+    }
+    /// Loads a Cn² table from a toml file
+    ///
+    /// The file must contain the arrays `altitude` \[m\] and `cn2dh` \[m^1/3\]
+    /// and optionally the arrays `wind_speed` \[m/s\] and `wind_direction` \[rd\]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(f64, Self)> {
+        let table: Cn2Table = toml::from_str(&read_to_string(&path)?)?;
+        Self::from_table(table)
+    }
+    /// Loads a Cn² table from a csv file
+    ///
+    /// Each row is `altitude,cn2dh[,wind_speed[,wind_direction]]`, lines starting with `#` and a non-numeric header are skipped
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> Result<(f64, Self)> {
+        let csv = read_to_string(&path)?;
+        let mut table = Cn2Table {
+            altitude: vec![],
+            cn2dh: vec![],
+            wind_speed: Some(vec![]),
+            wind_direction: Some(vec![]),
+        };
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let row: std::result::Result<Vec<f64>, _> =
+                line.split(',').map(|x| x.trim().parse::<f64>()).collect();
+            let row = match row {
+                Ok(row) => row,
+                Err(_) if table.altitude.is_empty() => continue,
+                Err(e) => return Err(ProfileError::Csv(i + 1, e.to_string())),
+            };
+            if row.len() < 2 {
+                return Err(ProfileError::Csv(
+                    i + 1,
+                    format!("expected at least 2 columns, found {}", row.len()),
+                ));
+            }
+            table.altitude.push(row[0]);
+            table.cn2dh.push(row[1]);
+            table
+                .wind_speed
+                .as_mut()
+                .unwrap()
+                .push(*row.get(2).unwrap_or(&0.));
+            table
+                .wind_direction
+                .as_mut()
+                .unwrap()
+                .push(*row.get(3).unwrap_or(&0.));
+        }
+        Self::from_table(table)
+    }
+    fn from_table(table: Cn2Table) -> Result<(f64, Self)> {
+        let n = table.altitude.len();
+        Self::from_cn2(
+            table.altitude,
+            table.cn2dh,
+            table.wind_speed.unwrap_or(vec![0.; n]),
+            table.wind_direction.unwrap_or(vec![0.; n]),
+        )
+    }
+    /// Scales the strength of the layers below 1km by `gain` and renormalizes the profile
+    pub fn ground_layer_gain(mut self, gain: f32) -> Self {
+        self.xi0
+            .iter_mut()
+            .zip(&self.altitude)
+            .filter(|(_, &h)| h < 1e3)
+            .for_each(|(xi0, _)| *xi0 *= gain);
+        let sum: f32 = self.xi0.iter().sum();
+        self.xi0.iter_mut().for_each(|xi0| *xi0 /= sum);
+        self
+    }
+    /// Resamples the profile to `n_layer` layers
+    ///
+    /// Within a group of layers, the equivalent altitude and wind speed are the 5/3 moments weighted by the layer strengths,
+    /// the wind direction is the strength weighted mean direction
+    pub fn resample(&self, n_layer: usize, method: Resampling) -> Self {
+        if n_layer >= self.n_layer || n_layer == 0 {
+            return self.clone();
+        }
+        let mut idx: Vec<usize> = (0..self.n_layer).collect();
+        idx.sort_by(|&a, &b| self.altitude[a].total_cmp(&self.altitude[b]));
+        let groups = match method {
+            Resampling::EquivalentLayers => self.equivalent_layers(&idx, n_layer),
+            Resampling::OptimalGrouping => self.optimal_grouping(&idx, n_layer),
+        };
+        let mut profile = Self {
+            n_layer: 0,
+            altitude: vec![],
+            xi0: vec![],
+            wind_speed: vec![],
+            wind_direction: vec![],
+        };
+        for group in groups.into_iter().filter(|g| !g.is_empty()) {
+            let xi0: f64 = group.iter().map(|&k| self.xi0[k] as f64).sum();
+            let moment = |v: &[f32]| {
+                (group
+                    .iter()
+                    .map(|&k| self.xi0[k] as f64 * (v[k] as f64).powf(5. / 3.))
+                    .sum::<f64>()
+                    / xi0)
+                    .powf(3. / 5.)
+            };
+            let (vx, vy) = group.iter().fold((0f64, 0f64), |(x, y), &k| {
+                let w = self.xi0[k] as f64 * self.wind_speed[k] as f64;
+                let a = self.wind_direction[k] as f64;
+                (x + w * a.cos(), y + w * a.sin())
+            });
+            profile.altitude.push(moment(&self.altitude) as f32);
+            profile.xi0.push(xi0 as f32);
+            profile.wind_speed.push(moment(&self.wind_speed) as f32);
+            profile.wind_direction.push(vy.atan2(vx) as f32);
+            profile.n_layer += 1;
+        }
+        profile
+    }
+    fn equivalent_layers(&self, idx: &[usize], n_layer: usize) -> Vec<Vec<usize>> {
+        let h_min = self.altitude[idx[0]];
+        let h_max = self.altitude[idx[idx.len() - 1]];
+        let dh = (h_max - h_min) / n_layer as f32;
+        let mut groups = vec![vec![]; n_layer];
+        for &k in idx {
+            let g = if dh > 0. {
+                (((self.altitude[k] - h_min) / dh) as usize).min(n_layer - 1)
+            } else {
+                0
+            };
+            groups[g].push(k);
+        }
+        groups
+    }
+    fn optimal_grouping(&self, idx: &[usize], n_layer: usize) -> Vec<Vec<usize>> {
+        let n = idx.len();
+        // strength weighted variance of h^(5/3) within the layers i..j
+        let cost = |i: usize, j: usize| {
+            let layers = &idx[i..j];
+            let w: f64 = layers.iter().map(|&k| self.xi0[k] as f64).sum();
+            if w == 0. {
+                return 0.;
+            }
+            let h = |k: usize| (self.altitude[k] as f64).powf(5. / 3.);
+            let mean = layers
+                .iter()
+                .map(|&k| self.xi0[k] as f64 * h(k))
+                .sum::<f64>()
+                / w;
+            layers
+                .iter()
+                .map(|&k| self.xi0[k] as f64 * (h(k) - mean).powi(2))
+                .sum::<f64>()
+        };
+        // dynamic programming over the contiguous partitions of the sorted layers
+        let mut best = vec![vec![f64::INFINITY; n + 1]; n_layer + 1];
+        let mut split = vec![vec![0usize; n + 1]; n_layer + 1];
+        best[0][0] = 0.;
+        for g in 1..=n_layer {
+            for j in g..=n {
+                for i in g - 1..j {
+                    let c = best[g - 1][i] + cost(i, j);
+                    if c < best[g][j] {
+                        best[g][j] = c;
+                        split[g][j] = i;
+                    }
+                }
+            }
+        }
+        let mut groups = vec![];
+        let mut j = n;
+        for g in (1..=n_layer).rev() {
+            let i = split[g][j];
+            groups.push(idx[i..j].to_vec());
+            j = i;
+        }
+        groups.reverse();
+        groups
+    }
+    /// Returns the integrated parameters of the turbulence
+    ///
+    /// The parameters are computed at the given `zenith_angle` \[rd\] and `wavelength` \[m\] from the r0 \[m\] at zenith at 500nm
+    pub fn integrated(
+        &self,
+        r0_at_zenith: f64,
+        zenith_angle: f64,
+        wavelength: f64,
+    ) -> IntegratedParameters {
+        let secz = 1. / zenith_angle.cos();
+        let r0 = r0_at_zenith * secz.powf(-3. / 5.) * (wavelength / 500e-9).powf(6. / 5.);
+        let moment = |v: &[f32]| {
+            self.xi0
+                .iter()
+                .zip(v)
+                .map(|(&xi0, &v)| xi0 as f64 * (v as f64).powf(5. / 3.))
+                .sum::<f64>()
+                .powf(3. / 5.)
+        };
+        IntegratedParameters {
+            r0,
+            seeing: 0.9759 * wavelength / r0,
+            theta0: 0.314 * r0 / (moment(&self.altitude) * secz),
+            tau0: 0.314 * r0 / moment(&self.wind_speed),
+        }
+    }
+    /// Returns the seeing \[rd\] at the given `zenith_angle` \[rd\] and `wavelength` \[m\]
+    pub fn seeing(&self, r0_at_zenith: f64, zenith_angle: f64, wavelength: f64) -> f64 {
+        self.integrated(r0_at_zenith, zenith_angle, wavelength)
+            .seeing
+    }
+    /// Returns the isoplanatic angle θ0 \[rd\] at the given `zenith_angle` \[rd\] and `wavelength` \[m\]
+    pub fn isoplanatic_angle(&self, r0_at_zenith: f64, zenith_angle: f64, wavelength: f64) -> f64 {
+        self.integrated(r0_at_zenith, zenith_angle, wavelength)
+            .theta0
+    }
+    /// Returns the coherence time τ0 \[s\] at the given `zenith_angle` \[rd\] and `wavelength` \[m\]
+    pub fn coherence_time(&self, r0_at_zenith: f64, zenith_angle: f64, wavelength: f64) -> f64 {
+        self.integrated(r0_at_zenith, zenith_angle, wavelength).tau0
+    }
+}
+
+fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String> {
+    let mut file =
+        File::open(&path).map_err(|e| ProfileError::Open(e, path.as_ref().to_path_buf()))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(|e| ProfileError::Read(e, path.as_ref().to_path_buf()))?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample() {
+        let profile = TurbulenceProfile::default();
+        for method in [Resampling::EquivalentLayers, Resampling::OptimalGrouping] {
+            let coarse = profile.resample(3, method);
+            assert!(coarse.n_layer <= 3);
+            let xi0: f32 = coarse.xi0.iter().sum();
+            assert!((xi0 - 1.).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn named_profiles() {
+        let seeing: Vec<f64> = [
+            NamedProfile::Gmt25,
+            NamedProfile::Gmt50,
+            NamedProfile::Gmt75,
+        ]
+        .iter()
+        .map(|p| {
+            let (r0, profile) = p.profile();
+            profile.seeing(r0, 0., 500e-9) * 180. * 3600. / PI
+        })
+        .collect();
+        assert!(seeing.windows(2).all(|s| s[0] < s[1]));
+        assert!((seeing[1] - 0.63).abs() < 0.01);
+        for (p, gain) in [
+            (NamedProfile::LasCampanasStrongGround, 1.5),
+            (NamedProfile::LasCampanasWeakGround, 0.5),
+        ] {
+            let (r0, profile) = p.profile();
+            assert_eq!(r0, 0.16);
+            let xi0: f32 = profile.xi0.iter().sum();
+            assert!((xi0 - 1.).abs() < 1e-3);
+            TurbulenceProfile::default()
+                .ground_layer_gain(gain)
+                .xi0
+                .iter()
+                .zip(&profile.xi0)
+                .for_each(|(a, b)| assert!((a - b).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn cn2_r0() {
+        let (r0, profile) =
+            TurbulenceProfile::from_cn2(vec![0.], vec![3e-13], vec![0.], vec![0.]).unwrap();
+        assert_eq!(profile.n_layer, 1);
+        let IntegratedParameters { r0: r0_z, .. } = profile.integrated(r0, 0., 500e-9);
+        assert!((r0 - r0_z).abs() < 1e-12);
+        assert!(r0 > 0.1 && r0 < 0.2);
+    }
+
+    #[test]
+    fn cn2_length_mismatch() {
+        let table = Cn2Table {
+            altitude: vec![0., 1e3],
+            cn2dh: vec![3e-13, 1e-13],
+            wind_speed: Some(vec![5.]),
+            wind_direction: None,
+        };
+        assert!(matches!(
+            TurbulenceProfile::from_table(table),
+            Err(ProfileError::Length(2, 1, _))
+        ));
+        assert!(TurbulenceProfile::from_cn2(vec![], vec![], vec![], vec![]).is_err());
+    }
+}
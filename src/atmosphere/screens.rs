@@ -0,0 +1,601 @@
+//!
+//! # Phase screens file
+//!
+//! A file format for sharing pre-computed atmospheric phase screens.
+//!
+//! The file is made of:
+//!  - the magic bytes `CRSEOPS1`,
+//!  - the length in bytes of the header as a little-endian `u64`,
+//!  - the [PhaseScreensHeader] serialized in toml, padded with spaces to a multiple of 8 bytes,
+//!  - the phase screens as little-endian `f32`.
+//!
+//! Each layer is a `n_length`x`n_width` screen stored row after row, the rows running along the layer length.
+//! The layer length is split into `n_chunk` time slices and the data is stored slice after slice,
+//! each slice containing all the layers.
+//! A time window of all the layers is thus stored contiguously.
+//!
+//! [PhaseScreens] memory-maps the file and reads sub-regions and time windows of the layers
+//! without loading the whole file.
+//! The phase screens are converted to and from the numpy NPZ format with [PhaseScreens::to_npz] and [PhaseScreens::from_npz].
+//!
+//! The phase screens of an [Atmosphere] built with ray tracing are saved with [PhaseScreens::save_atmosphere]
+//! or, when the atmosphere is built, by setting the path with [RayTracing::screens](crate::RayTracing::screens).
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Seek, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+use npyz::{AutoSerialize, DType, NpyFile, Order, WriteOptions, WriterBuilder};
+use serde::{Deserialize, Serialize};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::builders::AtmosphereBuilder;
+
+use super::Atmosphere;
+
+const MAGIC: &[u8; 8] = b"CRSEOPS1";
+const VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PhaseScreensError {
+    #[error("cannot open phase screens file: {1}")]
+    Open(#[source] std::io::Error, PathBuf),
+    #[error("cannot create phase screens file: {1}")]
+    Create(#[source] std::io::Error, PathBuf),
+    #[error("cannot read phase screens file: {1}")]
+    Read(#[source] std::io::Error, PathBuf),
+    #[error("cannot write phase screens file: {1}")]
+    Write(#[source] std::io::Error, PathBuf),
+    #[error("cannot deserialize phase screens header from toml")]
+    Load(#[from] toml::de::Error),
+    #[error("cannot serialize phase screens header into toml")]
+    Save(#[from] toml::ser::Error),
+    #[error("invalid phase screens file: {0}")]
+    Format(String),
+    #[error("invalid NPZ file: {0}")]
+    Npz(String),
+    #[error("cannot read or write NPZ archive")]
+    Zip(#[from] zip::result::ZipError),
+}
+pub type Result<T> = std::result::Result<T, PhaseScreensError>;
+
+/// Phase screen layer geometry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerGeometry {
+    /// layer altitude \[m\]
+    pub altitude: f64,
+    /// layer width \[m\]
+    pub width: f64,
+    /// layer length \[m\]
+    pub length: f64,
+    /// number of pixels across the width
+    pub n_width: usize,
+    /// number of pixels along the length
+    pub n_length: usize,
+}
+impl LayerGeometry {
+    /// Returns the pixel size \[m\]
+    pub fn pixel_size(&self) -> f64 {
+        self.width / (self.n_width - 1) as f64
+    }
+}
+
+/// Phase screens file header
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseScreensHeader {
+    pub version: u32,
+    /// seed of the random number generator
+    pub seed: i64,
+    /// phase screens time duration \[s\]
+    pub duration: f64,
+    /// number of time slices
+    pub n_chunk: usize,
+    /// the atmosphere generation parameters
+    pub atmosphere: AtmosphereBuilder,
+    pub layers: Vec<LayerGeometry>,
+}
+impl PhaseScreensHeader {
+    /// Creates a new header
+    ///
+    /// Returns an error if the number of time slices is 0, if a layer is less than 2 pixels wide
+    /// or if a layer length is not a multiple of the number of time slices
+    pub fn new(
+        atmosphere: AtmosphereBuilder,
+        layers: Vec<LayerGeometry>,
+        seed: i64,
+        duration: f64,
+        n_chunk: usize,
+    ) -> Result<Self> {
+        let header = Self {
+            version: VERSION,
+            seed,
+            duration,
+            n_chunk,
+            atmosphere,
+            layers,
+        };
+        header.check()?;
+        Ok(header)
+    }
+    fn check(&self) -> Result<()> {
+        if self.version != VERSION {
+            return Err(PhaseScreensError::Format(format!(
+                "expected version {VERSION}, found {}",
+                self.version
+            )));
+        }
+        if self.n_chunk == 0 {
+            return Err(PhaseScreensError::Format("no time slice".into()));
+        }
+        if let Some(layer) = self.layers.iter().find(|layer| layer.n_width < 2) {
+            return Err(PhaseScreensError::Format(format!(
+                "the layer width ({}px) is less than 2px",
+                layer.n_width
+            )));
+        }
+        if let Some(layer) = self
+            .layers
+            .iter()
+            .find(|layer| layer.n_length % self.n_chunk != 0)
+        {
+            return Err(PhaseScreensError::Format(format!(
+                "the layer length ({}px) is not a multiple of the number of time slices ({})",
+                layer.n_length, self.n_chunk
+            )));
+        }
+        Ok(())
+    }
+    /// Returns the number of rows of the layer in a time slice
+    pub fn chunk_length(&self, layer: usize) -> usize {
+        self.layers[layer].n_length / self.n_chunk
+    }
+    // number of f32 of a time slice
+    fn chunk_size(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.n_width * layer.n_length / self.n_chunk)
+            .sum()
+    }
+    // offset in f32 of the layer within a time slice
+    fn layer_offset(&self, layer: usize) -> usize {
+        self.layers
+            .iter()
+            .take(layer)
+            .map(|layer| layer.n_width * layer.n_length / self.n_chunk)
+            .sum()
+    }
+}
+
+/// Phase screens file writer
+///
+/// The time slices must be written in order with [PhaseScreensWriter::write_chunk]
+pub struct PhaseScreensWriter {
+    header: PhaseScreensHeader,
+    writer: BufWriter<File>,
+    path: PathBuf,
+    n_written: usize,
+}
+impl PhaseScreensWriter {
+    /// Creates a new phase screens file and writes the header
+    pub fn create<P: AsRef<Path>>(path: P, header: PhaseScreensHeader) -> Result<Self> {
+        header.check()?;
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(|e| PhaseScreensError::Create(e, path.clone()))?;
+        let mut writer = BufWriter::new(file);
+        let mut toml = toml::to_string_pretty(&header)?;
+        while toml.len() % 8 != 0 {
+            toml.push(' ');
+        }
+        writer
+            .write_all(MAGIC)
+            .and_then(|_| writer.write_all(&(toml.len() as u64).to_le_bytes()))
+            .and_then(|_| writer.write_all(toml.as_bytes()))
+            .map_err(|e| PhaseScreensError::Write(e, path.clone()))?;
+        Ok(Self {
+            header,
+            writer,
+            path,
+            n_written: 0,
+        })
+    }
+    /// Writes the next time slice, `layers` contains the rows of the time slice for each layer
+    pub fn write_chunk(&mut self, layers: &[&[f32]]) -> Result<()> {
+        if self.n_written == self.header.n_chunk {
+            return Err(PhaseScreensError::Format(format!(
+                "all the {} time slices have been written",
+                self.header.n_chunk
+            )));
+        }
+        if layers.len() != self.header.layers.len() {
+            return Err(PhaseScreensError::Format(format!(
+                "expected {} layers, found {}",
+                self.header.layers.len(),
+                layers.len()
+            )));
+        }
+        for (k, data) in layers.iter().enumerate() {
+            let n = self.header.layers[k].n_width * self.header.chunk_length(k);
+            if data.len() != n {
+                return Err(PhaseScreensError::Format(format!(
+                    "expected {n} values for layer #{k}, found {}",
+                    data.len()
+                )));
+            }
+            for x in data.iter() {
+                self.writer
+                    .write_all(&x.to_le_bytes())
+                    .map_err(|e| PhaseScreensError::Write(e, self.path.clone()))?;
+            }
+        }
+        self.n_written += 1;
+        Ok(())
+    }
+    /// Writes whole layers, splitting them into time slices
+    pub fn write_layers(mut self, layers: &[Vec<f32>]) -> Result<()> {
+        if layers.len() != self.header.layers.len() {
+            return Err(PhaseScreensError::Format(format!(
+                "expected {} layers, found {}",
+                self.header.layers.len(),
+                layers.len()
+            )));
+        }
+        for chunk in 0..self.header.n_chunk {
+            let slices: Vec<&[f32]> = layers
+                .iter()
+                .enumerate()
+                .map(|(k, data)| {
+                    let n = self.header.layers[k].n_width * self.header.chunk_length(k);
+                    &data[(chunk * n).min(data.len())..((chunk + 1) * n).min(data.len())]
+                })
+                .collect();
+            self.write_chunk(&slices)?;
+        }
+        self.finish()
+    }
+    /// Flushes the file, checking that all the time slices have been written
+    pub fn finish(mut self) -> Result<()> {
+        if self.n_written != self.header.n_chunk {
+            return Err(PhaseScreensError::Format(format!(
+                "{} time slices written out of {}",
+                self.n_written, self.header.n_chunk
+            )));
+        }
+        self.writer
+            .flush()
+            .map_err(|e| PhaseScreensError::Write(e, self.path.clone()))
+    }
+}
+
+/// Memory-mapped phase screens file
+pub struct PhaseScreens {
+    header: PhaseScreensHeader,
+    mmap: Mmap,
+    data_offset: usize,
+}
+impl PhaseScreens {
+    /// Opens a phase screens file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path).map_err(|e| PhaseScreensError::Open(e, path.clone()))?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| PhaseScreensError::Read(e, path))?;
+        if mmap.len() < 16 || &mmap[..8] != MAGIC {
+            return Err(PhaseScreensError::Format("missing magic bytes".into()));
+        }
+        let header_len = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+        let data_offset = 16 + header_len;
+        let toml = mmap
+            .get(16..data_offset)
+            .ok_or_else(|| PhaseScreensError::Format("truncated header".into()))
+            .and_then(|bytes| {
+                std::str::from_utf8(bytes).map_err(|e| PhaseScreensError::Format(e.to_string()))
+            })?;
+        let header: PhaseScreensHeader = toml::from_str(toml)?;
+        header.check()?;
+        let n_data = header.n_chunk * header.chunk_size();
+        if mmap.len() != data_offset + 4 * n_data {
+            return Err(PhaseScreensError::Format(format!(
+                "expected {} bytes of data, found {}",
+                4 * n_data,
+                mmap.len() - data_offset
+            )));
+        }
+        Ok(Self {
+            header,
+            mmap,
+            data_offset,
+        })
+    }
+    /// Returns the header
+    pub fn header(&self) -> &PhaseScreensHeader {
+        &self.header
+    }
+    /// Reads the sub-region of the layer within the range of `rows` along the length and the range of `columns` across the width
+    ///
+    /// The sub-region is returned row after row
+    pub fn read(
+        &self,
+        layer: usize,
+        rows: Range<usize>,
+        columns: Range<usize>,
+    ) -> Result<Vec<f32>> {
+        let geometry =
+            self.header.layers.get(layer).ok_or_else(|| {
+                PhaseScreensError::Format(format!("layer #{layer} is out of bounds"))
+            })?;
+        if rows.end > geometry.n_length || columns.end > geometry.n_width {
+            return Err(PhaseScreensError::Format(format!(
+                "region {rows:?}x{columns:?} is out of the {}x{} layer #{layer}",
+                geometry.n_length, geometry.n_width
+            )));
+        }
+        let chunk_length = self.header.chunk_length(layer);
+        let chunk_size = self.header.chunk_size();
+        let layer_offset = self.header.layer_offset(layer);
+        let mut data = Vec::with_capacity(rows.len() * columns.len());
+        for row in rows {
+            let (chunk, row_in_chunk) = (row / chunk_length, row % chunk_length);
+            let start =
+                chunk * chunk_size + layer_offset + row_in_chunk * geometry.n_width + columns.start;
+            let bytes = &self.mmap
+                [self.data_offset + 4 * start..self.data_offset + 4 * (start + columns.len())];
+            data.extend(
+                bytes
+                    .chunks_exact(4)
+                    .map(|x| f32::from_le_bytes(x.try_into().unwrap())),
+            );
+        }
+        Ok(data)
+    }
+    /// Reads the whole layer
+    pub fn layer(&self, layer: usize) -> Result<Vec<f32>> {
+        let geometry =
+            self.header.layers.get(layer).ok_or_else(|| {
+                PhaseScreensError::Format(format!("layer #{layer} is out of bounds"))
+            })?;
+        self.read(layer, 0..geometry.n_length, 0..geometry.n_width)
+    }
+    /// Reads the rows of the layer swept by the wind within the time window \[s\]
+    ///
+    /// The layer is assumed to move along its length at the wind speed of the layer
+    pub fn time_window(&self, layer: usize, window: Range<f64>) -> Result<Vec<f32>> {
+        let geometry =
+            self.header.layers.get(layer).ok_or_else(|| {
+                PhaseScreensError::Format(format!("layer #{layer} is out of bounds"))
+            })?;
+        let wind_speed = self
+            .header
+            .atmosphere
+            .turbulence
+            .wind_speed
+            .get(layer)
+            .copied()
+            .unwrap_or_default() as f64;
+        let row = |t: f64| {
+            ((wind_speed * t / geometry.pixel_size()).round().max(0.) as usize)
+                .min(geometry.n_length)
+        };
+        let rows = row(window.start)..(row(window.end) + geometry.n_width).min(geometry.n_length);
+        self.read(layer, rows, 0..geometry.n_width)
+    }
+    /// Saves the phase screens of an [Atmosphere] built with ray tracing
+    ///
+    /// The phase screens currently loaded in the host memory of the atmosphere are saved as a single time slice
+    pub fn save_atmosphere<P: AsRef<Path>>(
+        path: P,
+        atm: &Atmosphere,
+        atmosphere: AtmosphereBuilder,
+    ) -> Result<()> {
+        let c = &atm._c_;
+        if c.layers.is_null() || c.phase_screen_LAYER.is_null() {
+            return Err(PhaseScreensError::Format(
+                "the atmosphere has no ray tracing phase screens".into(),
+            ));
+        }
+        let n_layer = c.N_LAYER as usize;
+        let layers = unsafe { std::slice::from_raw_parts(c.layers, n_layer) };
+        let data =
+            unsafe { std::slice::from_raw_parts(c.phase_screen_LAYER, c.N_PHASE_LAYER as usize) };
+        let geometry: Vec<_> = layers
+            .iter()
+            .map(|layer| LayerGeometry {
+                altitude: layer.altitude as f64,
+                width: layer.WIDTH_LAYER as f64,
+                length: layer.LENGTH_LAYER as f64,
+                n_width: layer.N_WIDTH_LAYER as usize,
+                n_length: layer.N_LENGTH_LAYER as usize,
+            })
+            .collect();
+        let mut offset = 0;
+        let screens: Vec<Vec<f32>> = geometry
+            .iter()
+            .map(|layer| {
+                let n = layer.n_width * layer.n_length;
+                let screen = data[offset..offset + n].to_vec();
+                offset += n;
+                screen
+            })
+            .collect();
+        let header = PhaseScreensHeader::new(
+            atmosphere,
+            geometry,
+            c.LOCAL_RAND_SEED as i64,
+            c.layers_duration as f64,
+            1,
+        )?;
+        PhaseScreensWriter::create(path, header)?.write_layers(&screens)
+    }
+    /// Converts the phase screens into a NPZ file
+    ///
+    /// The NPZ file contains the arrays `layer_<k>` of shape `(n_length,n_width)`
+    /// and the array `header` with the bytes of the toml header,
+    /// the arrays are stored uncompressed with the zip64 extensions for the arrays larger than 4GB
+    pub fn to_npz<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(|e| PhaseScreensError::Create(e, path.clone()))?;
+        let mut npz = ZipWriter::new(BufWriter::new(file));
+        let header = toml::to_string(&self.header)?;
+        let write_err = |e| PhaseScreensError::Write(e, path.clone());
+        write_npy(
+            &mut npz,
+            "header",
+            header.as_bytes(),
+            &[header.len() as u64],
+        )
+        .map_err(write_err)?;
+        for (k, layer) in self.header.layers.iter().enumerate() {
+            write_npy(
+                &mut npz,
+                &format!("layer_{k}"),
+                &self.layer(k)?,
+                &[layer.n_length as u64, layer.n_width as u64],
+            )
+            .map_err(write_err)?;
+        }
+        npz.finish()?.flush().map_err(write_err)
+    }
+    /// Converts a NPZ file written with [PhaseScreens::to_npz] into a phase screens file
+    pub fn from_npz<P: AsRef<Path>, Q: AsRef<Path>>(npz_path: P, path: Q) -> Result<()> {
+        let npz_path = npz_path.as_ref().to_path_buf();
+        let file =
+            File::open(&npz_path).map_err(|e| PhaseScreensError::Open(e, npz_path.clone()))?;
+        let mut npz = ZipArchive::new(BufReader::new(file))?;
+        let read_err = |e| PhaseScreensError::Read(e, npz_path.clone());
+        let header: Vec<u8> = NpyFile::new(npz.by_name("header.npy")?)
+            .and_then(|npy| npy.into_vec())
+            .map_err(read_err)?;
+        let header: PhaseScreensHeader = toml::from_str(
+            std::str::from_utf8(&header).map_err(|e| PhaseScreensError::Npz(e.to_string()))?,
+        )?;
+        header.check()?;
+        let layers = header
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(k, geometry)| {
+                let npy =
+                    NpyFile::new(npz.by_name(&format!("layer_{k}.npy"))?).map_err(read_err)?;
+                let shape = [geometry.n_length as u64, geometry.n_width as u64];
+                if npy.shape() != shape || npy.order() != Order::C {
+                    return Err(PhaseScreensError::Npz(format!(
+                        "layer #{k}: expected C ordered array of shape ({},{}), found {:?}",
+                        geometry.n_length,
+                        geometry.n_width,
+                        npy.shape()
+                    )));
+                }
+                match npy.dtype() {
+                    DType::Plain(ty) if ty.size_field() == 8 => npy
+                        .into_vec::<f64>()
+                        .map(|data| data.into_iter().map(|x| x as f32).collect()),
+                    _ => npy.into_vec::<f32>(),
+                }
+                .map_err(read_err)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        PhaseScreensWriter::create(path, header)?.write_layers(&layers)
+    }
+}
+
+// Writes the array of the given shape into the archive entry `<name>.npy`
+fn write_npy<T, W>(
+    npz: &mut ZipWriter<W>,
+    name: &str,
+    data: &[T],
+    shape: &[u64],
+) -> std::io::Result<()>
+where
+    T: AutoSerialize + Copy,
+    W: Write + Seek,
+{
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    npz.start_file(format!("{name}.npy"), options)?;
+    let mut npy = WriteOptions::new()
+        .default_dtype()
+        .shape(shape)
+        .writer(&mut *npz)
+        .begin_nd()?;
+    npy.extend(data.iter().copied())?;
+    npy.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screens() -> (PhaseScreensHeader, Vec<Vec<f32>>) {
+        let layers = vec![
+            LayerGeometry {
+                altitude: 0.,
+                width: 3.,
+                length: 7.,
+                n_width: 4,
+                n_length: 8,
+            },
+            LayerGeometry {
+                altitude: 1e3,
+                width: 1.,
+                length: 3.,
+                n_width: 2,
+                n_length: 4,
+            },
+        ];
+        let data = layers
+            .iter()
+            .enumerate()
+            .map(|(k, layer)| {
+                (0..layer.n_width * layer.n_length)
+                    .map(|i| (100 * k + i) as f32)
+                    .collect()
+            })
+            .collect();
+        (
+            PhaseScreensHeader::new(AtmosphereBuilder::default(), layers, 2020, 1., 2).unwrap(),
+            data,
+        )
+    }
+
+    #[test]
+    fn write_read() -> anyhow::Result<()> {
+        let (header, data) = screens();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("phase_screens.bin");
+        PhaseScreensWriter::create(&path, header.clone())?.write_layers(&data)?;
+        let screens = PhaseScreens::open(&path)?;
+        assert_eq!(screens.header(), &header);
+        assert_eq!(screens.layer(0)?, data[0]);
+        assert_eq!(screens.layer(1)?, data[1]);
+        assert_eq!(screens.read(0, 3..5, 1..3)?, vec![13., 14., 17., 18.]);
+
+        let npz = dir.path().join("phase_screens.npz");
+        screens.to_npz(&npz)?;
+        let path2 = dir.path().join("phase_screens_npz.bin");
+        PhaseScreens::from_npz(&npz, &path2)?;
+        assert_eq!(std::fs::read(&path)?, std::fs::read(&path2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_layers() -> anyhow::Result<()> {
+        let (header, mut data) = screens();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("phase_screens.bin");
+        data.push(vec![0.; 8]);
+        assert!(matches!(
+            PhaseScreensWriter::create(&path, header.clone())?.write_layers(&data),
+            Err(PhaseScreensError::Format(_))
+        ));
+        let mut layers = header.layers;
+        layers[1].n_width = 1;
+        assert!(matches!(
+            PhaseScreensHeader::new(AtmosphereBuilder::default(), layers, 2020, 1., 2),
+            Err(PhaseScreensError::Format(_))
+        ));
+        Ok(())
+    }
+}
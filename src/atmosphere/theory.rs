@@ -0,0 +1,329 @@
+//!
+//! # Atmospheric turbulence theory
+//!
+//! Analytic statistics of the von Kármán phase screens.
+//!
+//! [VonKarman] gives the phase structure function, covariance and power spectral density,
+//! the long-exposure optical transfer function, the Zernike and Karhunen-Loeve modal variances on a segment
+//! and the temporal power spectral densities of the segment tip-tilt and piston.
+//! [anisoplanatism] gives the phase variance between two directions for a turbulence profile.
+//!
+//! All the phase quantities are in radians at the wavelength of r0.
+//!
+//! # Examples
+//!
+//! ```
+//! use crseo::atmosphere::theory::VonKarman;
+//! let atm = VonKarman::new(0.16, 25.).at_wavelength(1.65e-6);
+//! let tip_variance = atm.zernike_variance(1, 8.365);
+//! ```
+
+use std::f64::consts::PI;
+
+use nalgebra::{DMatrix, SymmetricEigen};
+
+use super::TurbulenceProfile;
+
+/// Von Kármán turbulence statistics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VonKarman {
+    /// Fried parameter \[m\]
+    pub r0: f64,
+    /// outer scale \[m\]
+    pub oscale: f64,
+}
+impl VonKarman {
+    /// Creates the statistics for the Fried parameter `r0` \[m\] at 500nm and the outer scale `oscale` \[m\]
+    pub fn new(r0: f64, oscale: f64) -> Self {
+        Self { r0, oscale }
+    }
+    /// Scales r0 from 500nm to the `wavelength` \[m\]
+    pub fn at_wavelength(self, wavelength: f64) -> Self {
+        Self {
+            r0: self.r0 * (wavelength / 500e-9).powf(6. / 5.),
+            ..self
+        }
+    }
+    /// Phase power spectral density \[rd²m²\] at the spatial frequency `f` \[m⁻¹\]
+    pub fn psd(&self, f: f64) -> f64 {
+        psd_constant() * self.r0.powf(-5. / 3.) * (f * f + self.oscale.powi(-2)).powf(-11. / 6.)
+    }
+    /// Phase covariance \[rd²\] at the separation `r` \[m\]
+    pub fn covariance(&self, r: f64) -> f64 {
+        let c = covariance_constant() * (self.oscale / self.r0).powf(5. / 3.);
+        if r == 0. {
+            return c * libm::tgamma(5. / 6.) * 2f64.powf(-1. / 6.);
+        }
+        let u = 2. * PI * r / self.oscale;
+        c * u.powf(5. / 6.) * bessel_k(5. / 6., u)
+    }
+    /// Phase structure function \[rd²\] at the separation `r` \[m\]
+    pub fn structure_function(&self, r: f64) -> f64 {
+        if r == 0. {
+            return 0.;
+        }
+        // D(r) = 4π ∫ f Φ(f) [1 - J0(2πfr)] df
+        4. * PI
+            * integrate_log(
+                |f| f * self.psd(f) * (1. - libm::j0(2. * PI * f * r)),
+                1e-12 / r,
+                1e3 / r,
+            )
+    }
+    /// Long-exposure atmospheric optical transfer function at the separation `r` \[m\] in the pupil
+    pub fn long_exposure_otf(&self, r: f64) -> f64 {
+        (-0.5 * self.structure_function(r)).exp()
+    }
+    /// Variance \[rd²\] of one Zernike mode of radial order `n` on a segment of diameter `diameter` \[m\]
+    ///
+    /// The Zernike modes are normalized to 1rd rms (Noll's convention)
+    pub fn zernike_variance(&self, n: u32, diameter: f64) -> f64 {
+        let r = 0.5 * diameter;
+        let nf = (n + 1) as f64;
+        integrate_log(
+            |f| {
+                let x = 2. * PI * f * r;
+                let q = libm::jn(n as i32 + 1, x) / (PI * f * r);
+                2. * PI * f * self.psd(f) * nf * q * q
+            },
+            1e-12 / diameter,
+            1e3 / diameter,
+        )
+    }
+    /// Variances \[rd²\] of the piston-removed Karhunen-Loeve modes on a segment
+    ///
+    /// The segment of diameter `diameter` \[m\] with the central obstruction ratio `obstruction`
+    /// is sampled with `n_px`x`n_px` pixels.
+    /// The variances are sorted in decreasing order and the modes are normalized to 1rd rms,
+    /// the outer scale must be finite
+    pub fn karhunen_loeve_variances(
+        &self,
+        diameter: f64,
+        obstruction: f64,
+        n_px: usize,
+    ) -> Vec<f64> {
+        let d = diameter / (n_px - 1) as f64;
+        let xy: Vec<(f64, f64)> = (0..n_px * n_px)
+            .map(|k| {
+                let x = (k % n_px) as f64 * d - 0.5 * diameter;
+                let y = (k / n_px) as f64 * d - 0.5 * diameter;
+                (x, y)
+            })
+            .filter(|(x, y)| {
+                let r = x.hypot(*y) / (0.5 * diameter);
+                r <= 1. && r >= obstruction
+            })
+            .collect();
+        let n = xy.len();
+        let b0 = self.covariance(0.);
+        let mut c = DMatrix::<f64>::from_fn(n, n, |i, j| {
+            if i == j {
+                b0
+            } else {
+                self.covariance((xy[i].0 - xy[j].0).hypot(xy[i].1 - xy[j].1))
+            }
+        });
+        // piston removal
+        let row_mean: Vec<f64> = c.row_iter().map(|row| row.mean()).collect();
+        let mean = c.mean();
+        for i in 0..n {
+            for j in 0..n {
+                c[(i, j)] += mean - row_mean[i] - row_mean[j];
+            }
+        }
+        let mut variances: Vec<f64> = SymmetricEigen::new(c / n as f64)
+            .eigenvalues
+            .iter()
+            .copied()
+            .collect();
+        variances.sort_by(|a, b| b.total_cmp(a));
+        variances.pop();
+        variances
+    }
+    /// Two-sided temporal power spectral density \[rd²/Hz\] of the tip (or tilt) of a segment
+    ///
+    /// The PSD is computed at the temporal frequency `nu` \[Hz\] for the frozen flow of a single layer with the wind speed `wind_speed` \[m/s\]
+    /// on a segment of diameter `diameter` \[m\], `angle` \[rd\] is the angle between the wind and the tip axis
+    pub fn tiptilt_temporal_psd(&self, nu: f64, wind_speed: f64, diameter: f64, angle: f64) -> f64 {
+        let r = 0.5 * diameter;
+        self.temporal_psd(nu, wind_speed, diameter, |fx, fy| {
+            let f = fx.hypot(fy);
+            let q = libm::jn(2, 2. * PI * f * r) / (PI * f * r);
+            let c = (fy.atan2(fx) - angle).cos();
+            4. * q * q * c * c
+        })
+    }
+    /// Two-sided temporal power spectral density \[rd²/Hz\] of the piston of a segment
+    ///
+    /// The PSD is computed at the temporal frequency `nu` \[Hz\] for the frozen flow of a single layer with the wind speed `wind_speed` \[m/s\]
+    /// on a segment of diameter `diameter` \[m\], the outer scale must be finite
+    pub fn piston_temporal_psd(&self, nu: f64, wind_speed: f64, diameter: f64) -> f64 {
+        let r = 0.5 * diameter;
+        self.temporal_psd(nu, wind_speed, diameter, |fx, fy| {
+            let x = 2. * PI * fx.hypot(fy) * r;
+            let q = 2. * libm::j1(x) / x;
+            q * q
+        })
+    }
+    // The integral is computed over fy>0 only, the filter is symmetrized with respect to fy
+    // as 0.5*(F(fx,fy)+F(fx,-fy)) for filters that are not even in fy
+    fn temporal_psd<F: Fn(f64, f64) -> f64>(
+        &self,
+        nu: f64,
+        wind_speed: f64,
+        diameter: f64,
+        filter: F,
+    ) -> f64 {
+        let fx = nu / wind_speed;
+        2. * integrate_log(
+            |fy| self.psd(fx.hypot(fy)) * 0.5 * (filter(fx, fy) + filter(fx, -fy)),
+            1e-12 / diameter,
+            1e3 / diameter,
+        ) / wind_speed
+    }
+}
+
+/// Phase variance \[rd²\] between two directions separated by the angle `theta` \[rd\]
+///
+/// The turbulence is given by the `profile` with the Fried parameter `r0` \[m\] and the outer scale `oscale` \[m\]
+/// at the wavelength of the phase. In the Kolmogorov limit, the variance is (θ/θ0)^5/3 with θ0 the isoplanatic angle.
+pub fn anisoplanatism(profile: &TurbulenceProfile, r0: f64, oscale: f64, theta: f64) -> f64 {
+    profile
+        .xi0
+        .iter()
+        .zip(&profile.altitude)
+        .map(|(&xi0, &h)| {
+            let layer = VonKarman::new(r0 * (xi0 as f64).powf(-3. / 5.), oscale);
+            layer.structure_function(h as f64 * theta)
+        })
+        .sum()
+}
+/// Two-sided temporal power spectral density \[rd²/Hz\] of the tip (or tilt) of a segment for a turbulence profile
+///
+/// The wind direction of each layer is projected on the tip axis given by the angle `angle` \[rd\]
+pub fn tiptilt_temporal_psd(
+    profile: &TurbulenceProfile,
+    r0: f64,
+    oscale: f64,
+    nu: f64,
+    diameter: f64,
+    angle: f64,
+) -> f64 {
+    (0..profile.n_layer)
+        .map(|k| {
+            let layer = VonKarman::new(r0 * (profile.xi0[k] as f64).powf(-3. / 5.), oscale);
+            layer.tiptilt_temporal_psd(
+                nu,
+                profile.wind_speed[k] as f64,
+                diameter,
+                angle - profile.wind_direction[k] as f64,
+            )
+        })
+        .sum()
+}
+/// Two-sided temporal power spectral density \[rd²/Hz\] of the piston of a segment for a turbulence profile
+pub fn piston_temporal_psd(
+    profile: &TurbulenceProfile,
+    r0: f64,
+    oscale: f64,
+    nu: f64,
+    diameter: f64,
+) -> f64 {
+    (0..profile.n_layer)
+        .map(|k| {
+            let layer = VonKarman::new(r0 * (profile.xi0[k] as f64).powf(-3. / 5.), oscale);
+            layer.piston_temporal_psd(nu, profile.wind_speed[k] as f64, diameter)
+        })
+        .sum()
+}
+
+// (24/5 Γ(6/5))^5/6
+fn kolmogorov_constant() -> f64 {
+    (24. / 5. * libm::tgamma(6. / 5.)).powf(5. / 6.)
+}
+// ≃0.023
+fn psd_constant() -> f64 {
+    kolmogorov_constant() * libm::tgamma(11. / 6.).powi(2) / (2. * PI.powf(11. / 3.))
+}
+fn covariance_constant() -> f64 {
+    kolmogorov_constant() * libm::tgamma(11. / 6.) / (2f64.powf(5. / 6.) * PI.powf(8. / 3.))
+}
+
+/// Modified Bessel function of the second kind K_ν(x) for x>0
+fn bessel_k(nu: f64, x: f64) -> f64 {
+    // K_ν(x) = ∫_0^∞ exp(-x cosh t) cosh(νt) dt
+    let t_max = (2. * (50. + nu * 20.) / x).ln().max(1.) + 1.;
+    let n = 4000;
+    let h = t_max / n as f64;
+    let f = |t: f64| (-x * t.cosh()).exp() * (nu * t).cosh();
+    simpson(f, 0., h, n)
+}
+
+// Simpson's rule with n (even) intervals of width h from a
+fn simpson<F: Fn(f64) -> f64>(f: F, a: f64, h: f64, n: usize) -> f64 {
+    let s: f64 = (1..n)
+        .map(|i| f(a + i as f64 * h) * if i % 2 == 1 { 4. } else { 2. })
+        .sum();
+    (f(a) + s + f(a + n as f64 * h)) * h / 3.
+}
+
+// ∫_a^b f(x)dx with a logarithmic sampling
+fn integrate_log<F: Fn(f64) -> f64>(f: F, a: f64, b: f64) -> f64 {
+    let (u_a, u_b) = (a.ln(), b.ln());
+    let n = 8000;
+    simpson(
+        |u| {
+            let x = u.exp();
+            f(x) * x
+        },
+        u_a,
+        (u_b - u_a) / n as f64,
+        n,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kolmogorov_structure_function() {
+        let atm = VonKarman::new(0.1, 1e8);
+        let d = atm.structure_function(0.1);
+        assert!((d - 6.88).abs() / 6.88 < 1e-2, "D(r0)={d}");
+    }
+
+    #[test]
+    fn psd_constant_value() {
+        assert!((psd_constant() - 0.023).abs() < 1e-3);
+    }
+
+    #[test]
+    fn noll_tiptilt() {
+        // Noll (1976): tip variance 0.449(D/r0)^5/3
+        let atm = VonKarman::new(1., 1e8);
+        let var = atm.zernike_variance(1, 1.);
+        assert!((var - 0.449).abs() < 5e-3, "tip variance: {var}");
+    }
+
+    #[test]
+    fn isoplanatic_angle() {
+        let profile = TurbulenceProfile::default();
+        let r0 = 0.16;
+        let theta0 = profile.integrated(r0, 0., 500e-9).theta0;
+        let var = anisoplanatism(&profile, r0, 1e8, theta0);
+        assert!((var - 1.).abs() < 2e-2, "σ²(θ0)={var}");
+    }
+
+    #[test]
+    fn tiptilt_temporal_psd_angle() {
+        let atm = VonKarman::new(0.16, 25.);
+        let psd = |angle: f64| atm.tiptilt_temporal_psd(1., 10., 8.365, angle);
+        let (psd_0, psd_45, psd_90) = (psd(0.), psd(0.25 * PI), psd(0.5 * PI));
+        assert!(
+            (psd_45 - 0.5 * (psd_0 + psd_90)).abs() < 1e-6 * psd_45,
+            "{psd_0} {psd_45} {psd_90}"
+        );
+        assert!((psd(-0.25 * PI) - psd_45).abs() < 1e-6 * psd_45);
+    }
+}
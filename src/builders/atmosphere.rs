@@ -5,7 +5,14 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::{atmosphere::TurbulenceProfile, Atmosphere, Builder, CrseoError, RayTracing};
+use crate::{
+    atmosphere::{
+        evolution::EvolutionState,
+        screens::{PhaseScreens, PhaseScreensError},
+        Evolution, NamedProfile, TurbulenceProfile,
+    },
+    Atmosphere, Builder, CrseoError, RayTracing,
+};
 
 /// [`CEO`](../struct.CEO.html#impl-6) [`Atmosphere`](../struct.Atmosphere.html) builder type
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -15,6 +22,8 @@ pub struct AtmosphereBuilder {
     pub zenith_angle: f64,
     pub turbulence: TurbulenceProfile,
     pub ray_tracing: Option<RayTracing>,
+    #[serde(default)]
+    pub evolution: Option<Evolution>,
 }
 /// Default properties:
 ///  * r0           : 16cm
@@ -27,6 +36,7 @@ pub struct AtmosphereBuilder {
 ///    * wind speed     : [5.6540, 5.7964, 5.8942, 6.6370, 13.2925, 34.8250, 29.4187] m/s
 ///    * wind direction : [0.0136, 0.1441, 0.2177, 0.5672, 1.2584, 1.6266, 1.7462] rd
 /// * ray tracing : none
+/// * evolution   : none
 impl Default for AtmosphereBuilder {
     fn default() -> Self {
         AtmosphereBuilder {
@@ -35,6 +45,7 @@ impl Default for AtmosphereBuilder {
             zenith_angle: 30_f64.to_radians(),
             turbulence: TurbulenceProfile::default(),
             ray_tracing: None,
+            evolution: None,
         }
     }
 }
@@ -55,6 +66,8 @@ pub enum AtmosphereBuilderError {
     Save(#[from] toml::ser::Error),
     #[error("cannot find atmosphere toml file: {1}")]
     Toml(#[source] std::io::Error, PathBuf),
+    #[error("cannot save the phase screens")]
+    PhaseScreens(#[from] PhaseScreensError),
 }
 
 /// ## `Atmosphere` builder
@@ -100,6 +113,15 @@ impl AtmosphereBuilder {
     pub fn turbulence_profile(self, turbulence: TurbulenceProfile) -> Self {
         Self { turbulence, ..self }
     }
+    /// Set both r0 at zenith and the turbulence profile from a profile of the library
+    pub fn named_profile(self, profile: NamedProfile) -> Self {
+        let (r0_at_zenith, turbulence) = profile.profile();
+        Self {
+            r0_at_zenith,
+            turbulence,
+            ..self
+        }
+    }
     /// Set a single turbulence layer
     pub fn single_turbulence_layer(
         self,
@@ -135,6 +157,13 @@ impl AtmosphereBuilder {
             ..self
         }
     }
+    /// Set the model of the atmosphere evolution over time
+    pub fn evolution(self, evolution: Evolution) -> Self {
+        Self {
+            evolution: Some(evolution),
+            ..self
+        }
+    }
 }
 impl Builder for AtmosphereBuilder {
     type Component = Atmosphere;
@@ -149,6 +178,7 @@ impl Builder for AtmosphereBuilder {
             //filename: String::new(),
             //k_duration: 0,
             propagate_ptr: |_, _, _| (),
+            evolution: None,
         };
         let secz = 1f64 / atm.zenith_angle.cos();
         let r0 = (atm.r0_at_zenith.powf(-5.0 / 3.0) * secz).powf(-3.0 / 5.0);
@@ -171,6 +201,14 @@ impl Builder for AtmosphereBuilder {
             .collect::<Vec<f32>>();
         let mut xi0 = self.turbulence.xi0.clone();
         let mut wind_direction = self.turbulence.wind_direction.clone();
+        atm.evolution = self.evolution.clone().map(|evolution| {
+            EvolutionState::new(
+                evolution,
+                self.r0_at_zenith,
+                self.zenith_angle,
+                self.turbulence.clone(),
+            )
+        });
         match &self.ray_tracing {
             None => unsafe {
                 atm._c_.setup(
@@ -256,6 +294,11 @@ impl Builder for AtmosphereBuilder {
                 },
             },
         }
+        if let Some(path) = self.ray_tracing.as_ref().and_then(|rtc| rtc.screens.as_ref()) {
+            log::info!("Saving phase screens to {}", path);
+            PhaseScreens::save_atmosphere(path, &atm, self.clone())
+                .map_err(AtmosphereBuilderError::from)?;
+        }
         Ok(atm)
     }
 }
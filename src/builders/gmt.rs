@@ -96,6 +96,8 @@ pub struct GmtBuilder {
     pub m1: MirrorBuilder,
     pub m2: MirrorBuilder,
     pub pointing_error: Option<(f64, f64)>,
+    #[serde(default)]
+    pub rotator_angle: f64,
     pub m1_truss_projection: bool,
 }
 impl Default for GmtBuilder {
@@ -110,6 +112,7 @@ impl Default for GmtBuilder {
                 ..Default::default()
             },
             pointing_error: None,
+            rotator_angle: 0.,
             m1_truss_projection: true,
         }
     }
@@ -175,6 +178,13 @@ impl GmtBuilder {
         self.pointing_error = Some(pointing_error);
         self
     }
+    /// Set the rotator angle in radians
+    pub fn rotator_angle(self, rotator_angle: f64) -> Self {
+        Self {
+            rotator_angle,
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -216,6 +226,7 @@ impl Builder for GmtBuilder {
             // a1: self.m1.a.clone(),
             // a2: self.m2.a.clone(),
             pointing_error: self.pointing_error,
+            rotator_angle: self.rotator_angle,
             m1_truss_projection: self.m1_truss_projection,
         };
 
@@ -240,6 +251,7 @@ impl From<&Gmt> for GmtBuilder {
             m1: gmt.get_m1(),
             m2: gmt.get_m2(),
             pointing_error: gmt.pointing_error,
+            rotator_angle: gmt.rotator_angle,
             m1_truss_projection: gmt.m1_truss_projection,
         }
     }
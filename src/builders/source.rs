@@ -5,6 +5,8 @@ use serde::{Deserialize, Serialize};
 use skyangle::Conversion;
 
 use crate::{
+    imaging::Misregistration,
+    lgs::LaserGuideStar,
     source::{PupilSampling, PHOTOMETRY},
     Builder, Source,
 };
@@ -19,6 +21,7 @@ use crate::{
 ///  - zenith           : 0degree
 ///  - azimuth          : 0degree
 ///  - magnitude        : 0
+///  - height           : infinite
 ///
 /// # Examples
 ///
@@ -48,6 +51,10 @@ pub struct SourceBuilder {
     pub rays_coordinates: Option<(Vec<f64>, Vec<f64>)>,
     pub fwhm: Option<f64>,
     pub rays_azimuth: Option<f64>,
+    #[serde(default)]
+    pub laser_guide_star: Option<LaserGuideStar>,
+    #[serde(default)]
+    pub misregistration: Option<Misregistration>,
 }
 impl Default for SourceBuilder {
     fn default() -> Self {
@@ -65,6 +72,8 @@ impl Default for SourceBuilder {
             rays_coordinates: None,
             fwhm: None,
             rays_azimuth: None,
+            laser_guide_star: None,
+            misregistration: None,
         }
     }
 }
@@ -113,6 +122,14 @@ impl SourceBuilder {
         self.rays_azimuth = Some(rays_azimuth);
         self
     }
+    /// Sets the misregistration of a wavefront sensor with respect to the pupil
+    ///
+    /// The rays are traced through the pupil at the locations seen by the wavefront sensor,
+    /// the rays bundle is shifted, rotated and its size is divided by the magnification
+    pub fn misregistration(mut self, misregistration: Misregistration) -> Self {
+        self.misregistration = Some(misregistration);
+        self
+    }
     /// Set the source zenith and azimuth angles
     pub fn zenith_azimuth(self, zenith: Vec<f32>, azimuth: Vec<f32>) -> Self {
         assert_eq!(
@@ -200,6 +217,20 @@ impl SourceBuilder {
             ..self
         }
     }
+    /// Sets the source as a sodium laser guide star
+    ///
+    /// The source is located at the altitude of the sodium layer centroid
+    pub fn laser_guide_star(self, lgs: LaserGuideStar) -> Self {
+        Self {
+            laser_guide_star: Some(lgs),
+            ..self
+        }
+    }
+    fn height(&self) -> f32 {
+        self.laser_guide_star
+            .as_ref()
+            .map_or(f32::INFINITY, |lgs| lgs.height() as f32)
+    }
 }
 impl Builder for SourceBuilder {
     type Component = Source;
@@ -215,15 +246,33 @@ impl Builder for SourceBuilder {
             zenith: self.zenith.clone(),
             azimuth: self.azimuth.clone(),
             magnitude: self.magnitude,
+            laser_guide_star: self.laser_guide_star.clone(),
+            misregistration: self.misregistration,
         };
 
+        let misregistration = self.misregistration.unwrap_or_default();
+        // the rotation is applied to the coordinates of user set rays
+        let rotation = if self.rays_coordinates.is_some() {
+            0.
+        } else {
+            misregistration.rotation
+        };
         let origin = vector {
             x: 0.0,
             y: 0.0,
             z: 25.0,
         };
+        let height = self.height();
         let src_band = CString::new(self.band.into_bytes()).unwrap();
-        if let Some((mut rays_x, mut rays_y)) = self.rays_coordinates {
+        if let Some((rays_x, rays_y)) = self.rays_coordinates {
+            let (mut rays_x, mut rays_y): (Vec<_>, Vec<_>) = rays_x
+                .into_iter()
+                .zip(rays_y)
+                .map(|xy| {
+                    self.misregistration
+                        .map_or(xy, |misregistration| misregistration.to_pupil(xy))
+                })
+                .unzip();
             let mut zenith: Vec<_> = self.zenith.iter().map(|&x| x as f64).collect();
             let mut azimuth: Vec<_> = self.azimuth.iter().map(|&x| x as f64).collect();
             unsafe {
@@ -232,7 +281,7 @@ impl Builder for SourceBuilder {
                     src.magnitude.as_mut_ptr(),
                     zenith.as_mut_ptr(),
                     azimuth.as_mut_ptr(),
-                    f32::INFINITY,
+                    height,
                     self.size as i32,
                     rays_x.len() as i32,
                     rays_x.as_mut_ptr(),
@@ -247,11 +296,15 @@ impl Builder for SourceBuilder {
                     src.magnitude.as_mut_ptr(),
                     src.zenith.as_mut_ptr(),
                     src.azimuth.as_mut_ptr(),
-                    f32::INFINITY,
+                    height,
                     self.size as i32,
-                    self.pupil_size,
+                    self.pupil_size / misregistration.magnification,
                     self.pupil_sampling.side() as i32,
-                    origin,
+                    vector {
+                        x: misregistration.shift.0,
+                        y: misregistration.shift.1,
+                        ..origin
+                    },
                 );
             }
         }
@@ -259,7 +312,9 @@ impl Builder for SourceBuilder {
             src._c_.fwhm = fwhm as f32;
         }
         if let Some(angle) = self.rays_azimuth {
-            src.rotate_rays(angle)
+            src.rotate_rays(angle + rotation)
+        } else if rotation != 0. {
+            src.rotate_rays(rotation)
         }
         Ok(src)
     }
@@ -281,6 +336,8 @@ impl From<&Source> for SourceBuilder {
             rays_coordinates: None,
             fwhm: Some(src._c_.fwhm as f64),
             rays_azimuth: None,
+            laser_guide_star: src.laser_guide_star.clone(),
+            misregistration: src.misregistration,
         }
     }
 }
@@ -1,8 +1,9 @@
 use crate::builders::{GmtBuilder, SourceBuilder};
 
 use super::{
-    cu::Double, wavefrontsensor::Geometric, Builder, Cu, Gmt, Propagation, ShackHartmann, Source,
-    WavefrontSensor,
+    cu::Double,
+    wavefrontsensor::{Geometric, ValidLensletStrategy, ValidLenslets},
+    Builder, Cu, Gmt, Propagation, ShackHartmann, Source, WavefrontSensor,
 };
 use log;
 use std::ops::Range;
@@ -11,6 +12,10 @@ use std::ops::Range;
 pub enum ValidLensletCriteria<'a> {
     Threshold(Option<f64>),
     OtherSensor(&'a mut Box<dyn WavefrontSensor>),
+    /// Valid lenslets selected with a [ValidLensletStrategy]
+    Strategy(ValidLensletStrategy),
+    /// Valid lenslets given by a mask, i.e. loaded from a file
+    Lenslets(&'a ValidLenslets),
 }
 
 #[derive(Clone, Debug, Copy)]
@@ -127,6 +132,10 @@ where
             //poke_qr: Cu::new(),
         }
     }
+    /// Returns the calibration matrix `[n_data x n_mode]`
+    pub fn matrix(&mut self) -> nalgebra::DMatrix<f64> {
+        nalgebra::DMatrix::from_column_slice(self.n_data, self.n_mode, &self.poke.from_dev())
+    }
     /// Performs the calibration of a single `Segment` function for a single `Mirror`
     pub fn sample(
         gmt: &mut Gmt,
@@ -247,6 +256,12 @@ impl<T: Clone + Builder<Component = ShackHartmann<Geometric>>>
                                 wfs.valid_lenslet_from((*other_wfs).as_mut());
                                 wfs.set_reference_slopes(&mut src);
                             }
+                            Strategy(strategy) => {
+                                wfs.set_valid_lenslets_with(&mut src, strategy);
+                            }
+                            Lenslets(valid_lenslets) => {
+                                wfs.set_valid_lenslets(valid_lenslets, &mut src);
+                            }
                         }
                         nnz = wfs.n_valid_lenslet();
                         //println!("# valid lenslet: {}", wfs.n_valid_lenslet());
@@ -10,6 +10,8 @@ pub enum CrseoError {
     Atmosphere(#[from] AtmosphereBuilderError),
     #[error("cannot build `::crseo::Gmt`")]
     Gmt(#[from] crate::GmtError),
+    #[error("expected a distortion map of {0} lenslets, found {1}")]
+    Distortion(usize, usize),
 }
 
 /* impl fmt::Display for CrseoError {
@@ -29,6 +29,12 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
+pub mod diagnostics;
+pub mod dof;
+pub mod global;
+pub mod layout;
+pub mod misalignment;
+
 pub type GmtM1 = gmt_m1;
 pub type GmtM2 = gmt_m2;
 
@@ -226,6 +232,8 @@ pub struct Gmt {
     */
     // pointing error
     pub pointing_error: Option<(f64, f64)>,
+    /// rotation of the field around the optical axis \[rd\]
+    pub rotator_angle: f64,
     pub(crate) m1_truss_projection: bool,
 }
 impl Display for Gmt {
@@ -344,6 +352,14 @@ impl Gmt {
             self.m2.update(t_xyz, r_xyz, sid);
         }
     }
+    /// Returns M1 segments rigid body motions `[Txyz,Rxyz]` segment wise
+    pub fn m1_segments_state(&self) -> Vec<f64> {
+        segments_state(&self.m1.motion_CS)
+    }
+    /// Returns M2 segments rigid body motions `[Txyz,Rxyz]` segment wise
+    pub fn m2_segments_state(&self) -> Vec<f64> {
+        segments_state(&self.m2.motion_CS)
+    }
     /// Sets M1 modal coefficients
     ///
     /// The coefficients are given segment wise
@@ -516,6 +532,20 @@ impl Gmt {
         self
     }
 }
+// segments origin and Euler angles of a mirror motion coordinate system
+fn segments_state(cs: &ffi::coordinate_system) -> Vec<f64> {
+    let (origin, euler_angles) = unsafe {
+        (
+            std::slice::from_raw_parts(cs.origin, 7),
+            std::slice::from_raw_parts(cs.euler_angles, 7),
+        )
+    };
+    origin
+        .iter()
+        .zip(euler_angles)
+        .flat_map(|(t, r)| [t.x, t.y, t.z, r.x, r.y, r.z])
+        .collect()
+}
 impl Drop for Gmt {
     /// Frees CEO memory before dropping `Gmt`
     fn drop(&mut self) {
@@ -525,53 +555,53 @@ impl Drop for Gmt {
         }
     }
 }
+impl Gmt {
+    // ray traces from the entrance to the exit pupil
+    fn trace(&mut self, src: &mut Source) {
+        unsafe {
+            src.as_raw_mut_ptr().reset_rays();
+            let rays = &mut src.as_raw_mut_ptr().rays;
+            self.m2.blocking(rays);
+            self.m1.trace(rays);
+            if self.m1_truss_projection {
+                rays.gmt_truss_onaxis();
+            }
+            rays.gmt_m2_baffle();
+            self.m2.trace(rays);
+            rays.to_sphere1(-5.830, 2.197173);
+        }
+    }
+    // source directions in telescope coordinates, with the rotator angle and the pointing error
+    fn line_of_sight(&self, src: &Source) -> (Vec<f64>, Vec<f64>) {
+        let (px, py) = self.pointing_error.map_or((0., 0.), |(pz, pa)| {
+            let (s, c) = pa.sin_cos();
+            (pz * c, pz * s)
+        });
+        src.zenith
+            .iter()
+            .map(|z| *z as f64)
+            .zip(src.azimuth.iter().map(|a| *a as f64 + self.rotator_angle))
+            .map(|(z, a)| {
+                let (s, c) = a.sin_cos();
+                (z * c - px, z * s - py)
+            })
+            .map(|(x, y)| (x.hypot(y), y.atan2(x)))
+            .unzip()
+    }
+}
 impl Propagation for Gmt {
     /// Ray traces a `Source` through `Gmt`, ray tracing stops at the exit pupil
     fn propagate(&mut self, src: &mut Source) {
-        if let Some((pz, pa)) = self.pointing_error {
-            let (s, c) = pa.sin_cos();
-            let (px, py) = (pz * c, pz * s);
-            let (zenith, azimuth): (Vec<_>, Vec<_>) = src
-                .zenith
-                .iter()
-                .map(|z| *z as f64)
-                .zip(src.azimuth.iter().map(|a| *a as f64))
-                .map(|(z, a)| {
-                    let (s, c) = a.sin_cos();
-                    (z * c - px, z * s - py)
-                })
-                .map(|(x, y)| (x.hypot(y), y.atan2(x)))
-                .unzip();
+        if self.pointing_error.is_some() || self.rotator_angle != 0. {
+            let (zenith, azimuth) = self.line_of_sight(src);
             src.update(zenith, azimuth);
-            unsafe {
-                src.as_raw_mut_ptr().reset_rays();
-                let rays = &mut src.as_raw_mut_ptr().rays;
-                self.m2.blocking(rays);
-                self.m1.trace(rays);
-                if self.m1_truss_projection {
-                    rays.gmt_truss_onaxis();
-                }
-                rays.gmt_m2_baffle();
-                self.m2.trace(rays);
-                rays.to_sphere1(-5.830, 2.197173);
-            }
+            self.trace(src);
             src.update(
                 src.zenith.iter().map(|x| *x as f64).collect(),
                 src.azimuth.iter().map(|x| *x as f64).collect(),
             );
         } else {
-            unsafe {
-                src.as_raw_mut_ptr().reset_rays();
-                let rays = &mut src.as_raw_mut_ptr().rays;
-                self.m2.blocking(rays);
-                self.m1.trace(rays);
-                if self.m1_truss_projection {
-                    rays.gmt_truss_onaxis();
-                }
-                rays.gmt_m2_baffle();
-                self.m2.trace(rays);
-                rays.to_sphere1(-5.830, 2.197173);
-            }
+            self.trace(src);
         }
     }
     fn time_propagate(&mut self, _secs: f64, src: &mut Source) {
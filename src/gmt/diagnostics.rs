@@ -0,0 +1,374 @@
+//!
+//! # Segment-wise pupil and PSF diagnostics
+//!
+//! [SegmentDiagnostics] isolates GMT segments, or subsets of segments, in the exit pupil
+//! of a [Source] using the source segment mask, leaving the [Gmt] untouched
+//! (as opposed to [Gmt::keep]).
+//! For each subset, a [SubsetDiagnostics] gathers
+//!  - the pupil mask,
+//!  - the phase and amplitude cut-outs,
+//!  - the sub-aperture PSF normalized to the peak of the diffraction limited PSF of the subset,
+//!  - the piston, tip, tilt and focus decomposition of the subset wavefront.
+//!
+//! ```no_run
+//! use crseo::{gmt::diagnostics::SegmentDiagnostics, Builder, FromBuilder, Gmt, Source};
+//! let mut gmt = Gmt::builder().build()?;
+//! let mut src = Source::builder().band("R").build()?;
+//! let diagnostics = SegmentDiagnostics::default()
+//!     .subsets(vec![vec![7], vec![7, 1], vec![7, 1, 4]])
+//!     .compute(&mut gmt, &mut src);
+//! diagnostics[0].save("diagnostics.pkl")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use nalgebra::{Matrix4, Vector4};
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use super::Gmt;
+use crate::{pssn::host::fft2, Source};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiagnosticsError {
+    #[error("failed to create {1}")]
+    Create(#[source] std::io::Error, PathBuf),
+    #[error("failed to write the diagnostics")]
+    Pickle(#[from] serde_pickle::Error),
+}
+pub type Result<T> = std::result::Result<T, DiagnosticsError>;
+
+/// Segment-wise diagnostics settings
+///
+/// Default properties:
+///  - subsets : each segment individually \[\[1\],...,\[7\]\]
+///  - PSF oversampling factor : 2 (Nyquist sampled)
+///  - PSF size : full size
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentDiagnostics {
+    pub subsets: Vec<Vec<u8>>,
+    pub osf: usize,
+    pub n_psf: Option<usize>,
+}
+impl Default for SegmentDiagnostics {
+    fn default() -> Self {
+        Self {
+            subsets: (1..=7).map(|sid| vec![sid]).collect(),
+            osf: 2,
+            n_psf: None,
+        }
+    }
+}
+impl SegmentDiagnostics {
+    /// Sets the segment subsets, segment IDs are in the range \[1,7\]
+    pub fn subsets(self, subsets: Vec<Vec<u8>>) -> Self {
+        assert!(
+            subsets.iter().flatten().all(|sid| (1..=7).contains(sid)),
+            "segment IDs must be in the range [1,7]"
+        );
+        Self { subsets, ..self }
+    }
+    /// Sets the PSF oversampling factor
+    pub fn osf(self, osf: usize) -> Self {
+        Self { osf, ..self }
+    }
+    /// Crops the PSFs to `n_psf`x`n_psf` pixels
+    pub fn n_psf(self, n_psf: usize) -> Self {
+        Self {
+            n_psf: Some(n_psf),
+            ..self
+        }
+    }
+    /// Ray traces the `Source` through the `Gmt` to the exit pupil and computes the diagnostics
+    pub fn compute(&self, gmt: &mut Gmt, src: &mut Source) -> Vec<PupilDiagnostics> {
+        src.through(gmt).xpupil();
+        self.from_source(src)
+    }
+    /// Computes the diagnostics from the current wavefront of the `Source`, one per source
+    pub fn from_source(&self, src: &mut Source) -> Vec<PupilDiagnostics> {
+        let n_px = src.pupil_sampling as usize;
+        let n = n_px * n_px;
+        let (pupil_size, wavelength) = (src.pupil_size, src.wavelength());
+        let mask = src.segment_mask();
+        let amplitude = src.amplitude();
+        let phase = src.phase().clone();
+        mask.chunks(n)
+            .zip(amplitude.chunks(n))
+            .zip(phase.chunks(n))
+            .map(|((mask, amplitude), phase)| {
+                self.evaluate(mask, amplitude, phase, n_px, pupil_size, wavelength)
+            })
+            .collect()
+    }
+    /// Computes the diagnostics from the segment `mask`, the `amplitude` and the `phase` \[m\]
+    /// sampled with `n_px`x`n_px` pixels across the `pupil_size` \[m\]
+    pub fn evaluate(
+        &self,
+        mask: &[i32],
+        amplitude: &[f32],
+        phase: &[f32],
+        n_px: usize,
+        pupil_size: f64,
+        wavelength: f64,
+    ) -> PupilDiagnostics {
+        let n = n_px * self.osf;
+        let fft = FftPlanner::new().plan_fft_forward(n);
+        let subsets = self
+            .subsets
+            .iter()
+            .map(|segments| {
+                let subset_mask: Vec<bool> = mask
+                    .iter()
+                    .zip(amplitude)
+                    .map(|(&m, &a)| a > 0. && segments.iter().any(|&sid| sid as i32 == m))
+                    .collect();
+                let cutout = |data: &[f32]| Cutout::new(data, &subset_mask, n_px);
+                let phase_cutout = cutout(phase);
+                let amplitude_cutout = cutout(amplitude);
+                let flux: f64 = amplitude
+                    .iter()
+                    .zip(&subset_mask)
+                    .filter_map(|(&a, &m)| m.then_some((a * a) as f64))
+                    .sum();
+                // sub-aperture PSF normalized to the diffraction limited peak `(sum(A))^2`
+                let k = 2. * std::f64::consts::PI / wavelength;
+                let mut field = vec![Complex::<f64>::new(0., 0.); n * n];
+                let mut peak0 = 0f64;
+                for j in 0..n_px {
+                    for i in 0..n_px {
+                        let l = i + n_px * j;
+                        if subset_mask[l] {
+                            peak0 += amplitude[l] as f64;
+                            field[i + n * j] =
+                                Complex::from_polar(amplitude[l] as f64, k * phase[l] as f64);
+                        }
+                    }
+                }
+                fft2(&mut field, n, &fft);
+                let n_psf = self.n_psf.unwrap_or(n).min(n);
+                let peak0 = (peak0 * peak0).max(f64::MIN_POSITIVE);
+                let psf: Vec<f32> = (0..n_psf * n_psf)
+                    .map(|l| {
+                        let i = (l % n_psf + n - n_psf / 2) % n;
+                        let j = (l / n_psf + n - n_psf / 2) % n;
+                        (field[i + n * j].norm_sqr() / peak0) as f32
+                    })
+                    .collect();
+                let strehl_ratio = field[0].norm_sqr() / peak0;
+                let psf_peak = psf.iter().cloned().fold(0f32, f32::max) as f64;
+                let (zernike, residual_rms) = zernike_fit(phase, &subset_mask, n_px);
+                SubsetDiagnostics {
+                    segments: segments.clone(),
+                    mask: subset_mask,
+                    phase: phase_cutout,
+                    amplitude: amplitude_cutout,
+                    flux,
+                    psf,
+                    n_psf,
+                    psf_peak,
+                    strehl_ratio,
+                    piston: zernike[0],
+                    tip: zernike[1],
+                    tilt: zernike[2],
+                    focus: zernike[3],
+                    residual_rms,
+                }
+            })
+            .collect();
+        PupilDiagnostics {
+            n_px,
+            pupil_size,
+            wavelength,
+            osf: self.osf,
+            subsets,
+        }
+    }
+}
+
+/// Rectangular cut-out of a pupil map
+///
+/// The pixels outside the subset mask are set to 0
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cutout {
+    /// pixel indices `(i,j)` of the cut-out first pixel in the pupil map
+    pub origin: (usize, usize),
+    /// cut-out size `(width,height)` in pixels
+    pub size: (usize, usize),
+    pub data: Vec<f32>,
+}
+impl Cutout {
+    fn new(data: &[f32], mask: &[bool], n_px: usize) -> Self {
+        let Some((i0, j0, i1, j1)) = mask.iter().enumerate().filter(|(_, &m)| m).fold(
+            None,
+            |bbox: Option<(usize, usize, usize, usize)>, (k, _)| {
+                let (i, j) = (k % n_px, k / n_px);
+                Some(bbox.map_or((i, j, i, j), |(i0, j0, i1, j1)| {
+                    (i0.min(i), j0.min(j), i1.max(i), j1.max(j))
+                }))
+            },
+        ) else {
+            return Default::default();
+        };
+        let (w, h) = (i1 - i0 + 1, j1 - j0 + 1);
+        let data = (0..w * h)
+            .map(|l| {
+                let k = i0 + l % w + n_px * (j0 + l / w);
+                if mask[k] {
+                    data[k]
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        Self {
+            origin: (i0, j0),
+            size: (w, h),
+            data,
+        }
+    }
+}
+
+/// Diagnostics of a subset of segments
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubsetDiagnostics {
+    /// segment IDs
+    pub segments: Vec<u8>,
+    /// pupil mask of the subset
+    pub mask: Vec<bool>,
+    /// wavefront \[m\]
+    pub phase: Cutout,
+    pub amplitude: Cutout,
+    /// sum of the squared amplitude
+    pub flux: f64,
+    /// PSF normalized to the peak of the diffraction limited PSF of the subset
+    pub psf: Vec<f32>,
+    pub n_psf: usize,
+    /// PSF maximum
+    pub psf_peak: f64,
+    /// PSF on-axis intensity
+    pub strehl_ratio: f64,
+    /// piston \[m RMS\]
+    pub piston: f64,
+    /// tip (x-axis) \[m RMS\]
+    pub tip: f64,
+    /// tilt (y-axis) \[m RMS\]
+    pub tilt: f64,
+    /// focus \[m RMS\]
+    pub focus: f64,
+    /// wavefront RMS \[m\] once piston, tip, tilt and focus are removed
+    pub residual_rms: f64,
+}
+
+/// Segment-wise diagnostics of a source exit pupil
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PupilDiagnostics {
+    pub n_px: usize,
+    pub pupil_size: f64,
+    pub wavelength: f64,
+    /// PSF oversampling factor, the PSF pixel scale is `wavelength/(osf*pupil_size)`
+    pub osf: usize,
+    pub subsets: Vec<SubsetDiagnostics>,
+}
+impl PupilDiagnostics {
+    /// Returns the PSF angular pixel scale \[rd\]
+    pub fn psf_pixel_scale(&self) -> f64 {
+        self.wavelength * (self.n_px - 1) as f64 / (self.osf * self.n_px) as f64 / self.pupil_size
+    }
+    /// Saves the diagnostics to a pickle file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(
+            File::create(path).map_err(|e| DiagnosticsError::Create(e, path.to_path_buf()))?,
+        );
+        serde_pickle::to_writer(&mut file, self, Default::default())?;
+        Ok(())
+    }
+}
+
+// Noll normalized piston, tip, tilt and focus fit over the masked pupil
+// with coordinates normalized to the subset radius
+fn zernike_fit(phase: &[f32], mask: &[bool], n_px: usize) -> ([f64; 4], f64) {
+    let xy: Vec<_> = mask
+        .iter()
+        .enumerate()
+        .filter(|(_, &m)| m)
+        .map(|(k, _)| ((k % n_px) as f64, (k / n_px) as f64, phase[k] as f64))
+        .collect();
+    if xy.is_empty() {
+        return ([0.; 4], 0.);
+    }
+    let m = xy.len() as f64;
+    let (xc, yc) = xy
+        .iter()
+        .fold((0., 0.), |(sx, sy), (x, y, _)| (sx + x / m, sy + y / m));
+    let radius = xy
+        .iter()
+        .map(|(x, y, _)| (x - xc).hypot(y - yc))
+        .fold(0f64, f64::max)
+        .max(0.5);
+    let basis = |x: f64, y: f64| {
+        let (x, y) = ((x - xc) / radius, (y - yc) / radius);
+        Vector4::new(
+            1.,
+            2. * x,
+            2. * y,
+            3f64.sqrt() * (2. * (x * x + y * y) - 1.),
+        )
+    };
+    let (ata, atb) = xy.iter().fold(
+        (Matrix4::zeros(), Vector4::zeros()),
+        |(ata, atb), &(x, y, p)| {
+            let z = basis(x, y);
+            (ata + z * z.transpose(), atb + z * p)
+        },
+    );
+    let c = ata
+        .pseudo_inverse(1e-12)
+        .map(|ata_inv| ata_inv * atb)
+        .unwrap_or_else(|_| Vector4::zeros());
+    let residual = xy
+        .iter()
+        .map(|&(x, y, p)| (p - basis(x, y).dot(&c)).powi(2))
+        .sum::<f64>()
+        / m;
+    ([c[0], c[1], c[2], c[3]], residual.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_segments() {
+        let n_px = 32;
+        // two 10x10 pixels segments
+        let mask: Vec<i32> = (0..n_px * n_px)
+            .map(|k| match (k % n_px, k / n_px) {
+                (i, j) if (2..12).contains(&i) && (10..20).contains(&j) => 1,
+                (i, j) if (18..28).contains(&i) && (10..20).contains(&j) => 2,
+                _ => 0,
+            })
+            .collect();
+        let amplitude: Vec<f32> = mask.iter().map(|&m| (m > 0) as i32 as f32).collect();
+        let phase: Vec<f32> = mask
+            .iter()
+            .map(|&m| if m == 2 { 100e-9 } else { 0. })
+            .collect();
+        let pupil = SegmentDiagnostics::default()
+            .subsets(vec![vec![1], vec![2], vec![1, 2]])
+            .evaluate(&mask, &amplitude, &phase, n_px, 1., 500e-9);
+        let [s1, s2, s12] = &pupil.subsets[..] else {
+            panic!("expected 3 subsets")
+        };
+        assert_eq!(s1.phase.size, (10, 10));
+        assert_eq!(s12.phase.size, (26, 10));
+        assert!((s1.strehl_ratio - 1.).abs() < 1e-9);
+        assert!((s2.piston - 100e-9).abs() < 1e-12 && s2.residual_rms < 1e-12);
+        assert!(s12.tip > 0. && s12.strehl_ratio < 1.);
+    }
+}
@@ -1,10 +1,19 @@
 use std::f64::consts::PI;
 use std::fmt::Display;
+use std::ops::{Add, Mul, Sub};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    layout::{Dof, DofLayout, DofName},
+    Gmt, GmtError,
+};
+use crate::wavefrontsensor::Mirror;
 
 pub type GmtResult<T> = std::result::Result<T, GmtError>;
 
 /// Rigid body motions
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum RBM {
     /// Translations
     Txyz(Vec<f64>),
@@ -31,7 +40,7 @@ impl Display for RBM {
     }
 }
 /// Mirror degrees-of-freedom
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum MirrorDof {
     /// Rigid body motions
     RigidBodyMotions((Option<RBM>, Option<RBM>)),
@@ -77,7 +86,7 @@ impl From<MirrorDof> for Vec<f64> {
     }
 }
 /// Segment pair degrees-of-freedom
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SegmentDof {
     M1((Option<MirrorDof>, Option<MirrorDof>)),
     M2((Option<MirrorDof>, Option<MirrorDof>)),
@@ -130,7 +139,7 @@ Degrees-of-freedom of 7 pairs of M1/M2 segments
 The degrees of freedom are ordered segment wise `[Si]` for i in `[1,7]`
 where `Si = [M1,M2]` and `Mj = [Txyz, Rxyz, Modes]`
 */
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct SegmentsDof {
     dof: Option<Vec<(Option<SegmentDof>, Option<SegmentDof>)>>,
     m1_n_mode: usize,
@@ -142,14 +151,15 @@ impl Display for SegmentsDof {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(dofs) = &self.dof {
             writeln!(f, "GMT state (Txyz[nm], Rxyz[mas], modes):")?;
-            Ok(for (k, dof) in dofs.iter().enumerate() {
+            for (k, dof) in dofs.iter().enumerate() {
                 if let (Some(m1), Some(m2)) = dof {
                     writeln!(f, " - S{} M1: {}", k + 1, m1)?;
                     writeln!(f, "      M2: {}", m2)?;
                 }
-            })
+            }
+            Ok(())
         } else {
-            writeln!(f, "")
+            writeln!(f)
         }
     }
 }
@@ -167,26 +177,10 @@ impl IntoIterator for SegmentsDof {
 }
 impl From<SegmentsDof> for Vec<f64> {
     fn from(segments: SegmentsDof) -> Self {
-        let s7_rz = segments.s7_rz;
-        let idx1 = (segments.m1_n_mode + segments.m2_n_mode + 13) * 6;
-        let idx2 = (segments.m1_n_mode + segments.m2_n_mode + 12) * 7 - segments.m2_n_mode - 1;
-        let mut v: Self = segments
-            .into_iter()
-            .flat_map(|segment| match segment {
-                (Some(m1), Some(m2)) => {
-                    let mut a: Vec<f64> = m1.into();
-                    let mut b: Vec<f64> = m2.into();
-                    a.append(&mut b);
-                    a
-                }
-                (Some(m1), None) => m1.into(),
-                (None, Some(m2)) => m2.into(),
-                _ => Vec::new(),
-            })
-            .collect();
-        if !s7_rz {
-            v.remove(idx2);
-            v.remove(idx1);
+        let mut v = segments.full_vec();
+        if !segments.s7_rz {
+            v.remove(segments.s7_m2_rz_index());
+            v.remove(segments.s7_m1_rz_index());
         }
         v
     }
@@ -234,16 +228,14 @@ impl SegmentsDof {
     pub fn m1_n_mode(self, n: usize) -> Self {
         let mut dofs = vec![];
         for dof in self.dof.unwrap().into_iter() {
-            if let (Some(m1), Some(m2)) = dof {
-                if let SegmentDof::M1((Some(rbm), _)) = m1 {
-                    dofs.push((
-                        Some(SegmentDof::M1((
-                            Some(rbm),
-                            Some(MirrorDof::Modes(vec![0f64; n])),
-                        ))),
-                        Some(m2),
-                    ));
-                }
+            if let (Some(SegmentDof::M1((Some(rbm), _))), Some(m2)) = dof {
+                dofs.push((
+                    Some(SegmentDof::M1((
+                        Some(rbm),
+                        Some(MirrorDof::Modes(vec![0f64; n])),
+                    ))),
+                    Some(m2),
+                ));
             }
         }
         Self {
@@ -256,16 +248,14 @@ impl SegmentsDof {
     pub fn m2_n_mode(self, n: usize) -> Self {
         let mut dofs = vec![];
         for dof in self.dof.unwrap().into_iter() {
-            if let (Some(m1), Some(m2)) = dof {
-                if let SegmentDof::M2((Some(rbm), _)) = m2 {
-                    dofs.push((
-                        Some(m1),
-                        Some(SegmentDof::M2((
-                            Some(rbm),
-                            Some(MirrorDof::Modes(vec![0f64; n])),
-                        ))),
-                    ));
-                }
+            if let (Some(m1), Some(SegmentDof::M2((Some(rbm), _)))) = dof {
+                dofs.push((
+                    Some(m1),
+                    Some(SegmentDof::M2((
+                        Some(rbm),
+                        Some(MirrorDof::Modes(vec![0f64; n])),
+                    ))),
+                ));
             }
         }
         Self {
@@ -281,6 +271,7 @@ impl SegmentsDof {
             ..self
         }
     }
+    /// Sets the degrees of freedom from a vector with the same layout as `Vec::from(SegmentsDof)`
     pub fn from_vec(self, vals: Vec<f64>) -> Self {
         let expected_len =
             self.n_segment * (self.m1_n_mode + self.m2_n_mode) + if self.s7_rz { 84 } else { 82 };
@@ -293,44 +284,14 @@ impl SegmentsDof {
         );
         let mut v = vals;
         if !self.s7_rz {
-            let idx = (self.m1_n_mode + self.m2_n_mode + 13) * 6;
-            v.insert(idx, 0f64);
-            let idx = (self.m1_n_mode + self.m2_n_mode + 12) * 7 - self.m2_n_mode - 1;
-            v.insert(idx, 0f64);
-        }
-        use MirrorDof::*;
-        use SegmentDof::*;
-        use RBM::*;
-        let n = self.m1_n_mode + self.m2_n_mode + 12;
-        Self {
-            dof: Some(
-                v.chunks(n)
-                    .map(|s| {
-                        let mut so = s.to_vec();
-                        (
-                            Some(M1((
-                                Some(RigidBodyMotions((
-                                    Some(Txyz(so.drain(..3).collect())),
-                                    Some(Rxyz(so.drain(..3).collect())),
-                                ))),
-                                (self.m1_n_mode > 0)
-                                    .then(|| Modes(so.drain(..self.m1_n_mode).collect())),
-                            ))),
-                            Some(M2((
-                                Some(RigidBodyMotions((
-                                    Some(Txyz(so.drain(..3).collect())),
-                                    Some(Rxyz(so.drain(..3).collect())),
-                                ))),
-                                (self.m2_n_mode > 0)
-                                    .then(|| Modes(so.drain(..self.m2_n_mode).collect())),
-                            ))),
-                        )
-                    })
-                    .collect(),
-            ),
-            ..self
+            v.insert(self.s7_m1_rz_index(), 0f64);
+            v.insert(self.s7_m2_rz_index(), 0f64);
         }
+        self.with_full_vec(v)
     }
+    /// Sets the rigid body motions from a vector `[Si]` for i in `[1,7]` where `Si = [M1,M2]` and `Mj = [Txyz, Rxyz]`
+    ///
+    /// The modes are set to 0
     pub fn from_rigid_body_motions(self, vals: Vec<f64>) -> Self {
         let expected_len = if self.s7_rz { 84 } else { 82 };
         assert_eq!(
@@ -342,15 +303,76 @@ impl SegmentsDof {
         );
         let mut v = vals;
         if !self.s7_rz {
-            let idx = (self.m1_n_mode + self.m2_n_mode + 13) * 6;
-            v.insert(idx, 0f64);
-            let idx = (self.m1_n_mode + self.m2_n_mode + 12) * 7 - self.m2_n_mode - 1;
-            v.insert(idx, 0f64);
+            v.insert(6 * 12 + 5, 0f64);
+            v.push(0f64);
         }
+        let (m1_mode, m2_mode) = (vec![0f64; self.m1_n_mode], vec![0f64; self.m2_n_mode]);
+        let v = v
+            .chunks(12)
+            .flat_map(|s| [&s[..6], &m1_mode, &s[6..], &m2_mode].concat())
+            .collect();
+        self.with_full_vec(v)
+    }
+    /// Returns the layout of the vector form of the degrees of freedom
+    pub fn layout(&self) -> DofLayout {
+        let mirror = |mirror: Mirror, sid: u8, n_mode: usize| {
+            Dof::RBM
+                .into_iter()
+                .filter(move |dof| self.s7_rz || sid < 7 || *dof != Dof::Rz)
+                .chain((0..n_mode).map(Dof::Mode))
+                .map(move |dof| DofName::new(mirror, sid, dof))
+        };
+        (1..=7)
+            .flat_map(|sid| {
+                mirror(Mirror::M1, sid, self.m1_n_mode).chain(mirror(
+                    Mirror::M2,
+                    sid,
+                    self.m2_n_mode,
+                ))
+            })
+            .collect()
+    }
+    // index of M1 S7 Rz in the vector including the center segment clocking
+    fn s7_m1_rz_index(&self) -> usize {
+        (self.m1_n_mode + self.m2_n_mode + 12) * 6 + 5
+    }
+    // index of M2 S7 Rz in the vector including the center segment clocking
+    fn s7_m2_rz_index(&self) -> usize {
+        (self.m1_n_mode + self.m2_n_mode + 12) * 7 - self.m2_n_mode - 1
+    }
+    // number of degrees of freedom per segment
+    fn segment_n_dof(&self) -> usize {
+        self.m1_n_mode + self.m2_n_mode + 12
+    }
+    // all the degrees of freedom including the center segment clocking
+    fn full_vec(&self) -> Vec<f64> {
+        let v: Vec<f64> = self
+            .clone()
+            .into_iter()
+            .flat_map(|segment| match segment {
+                (Some(m1), Some(m2)) => {
+                    let mut a: Vec<f64> = m1.into();
+                    let mut b: Vec<f64> = m2.into();
+                    a.append(&mut b);
+                    a
+                }
+                (Some(m1), None) => m1.into(),
+                (None, Some(m2)) => m2.into(),
+                _ => Vec::new(),
+            })
+            .collect();
+        assert_eq!(
+            v.len(),
+            self.n_segment * self.segment_n_dof(),
+            "incomplete GMT degrees of freedom"
+        );
+        v
+    }
+    fn with_full_vec(self, v: Vec<f64>) -> Self {
         use MirrorDof::*;
         use SegmentDof::*;
         use RBM::*;
-        let n = self.m1_n_mode + self.m2_n_mode + 12;
+        let n = self.segment_n_dof();
         Self {
             dof: Some(
                 v.chunks(n)
@@ -362,14 +384,16 @@ impl SegmentsDof {
                                     Some(Txyz(so.drain(..3).collect())),
                                     Some(Rxyz(so.drain(..3).collect())),
                                 ))),
-                                (self.m1_n_mode > 0).then(|| Modes(vec![0f64; self.m1_n_mode])),
+                                (self.m1_n_mode > 0)
+                                    .then(|| Modes(so.drain(..self.m1_n_mode).collect())),
                             ))),
                             Some(M2((
                                 Some(RigidBodyMotions((
                                     Some(Txyz(so.drain(..3).collect())),
                                     Some(Rxyz(so.drain(..3).collect())),
                                 ))),
-                                (self.m2_n_mode > 0).then(|| Modes(vec![0f64; self.m2_n_mode])),
+                                (self.m2_n_mode > 0)
+                                    .then(|| Modes(so.drain(..self.m2_n_mode).collect())),
                             ))),
                         )
                     })
@@ -378,6 +402,134 @@ impl SegmentsDof {
             ..self
         }
     }
+    /// Reads the state of M1 and M2 segments
+    ///
+    /// The number of modes is set to the number of modes of M1 and M2 segments.
+    /// The clocking of the center segments is read but, as with [SegmentsDof::new],
+    /// it is excluded from the vector form unless [SegmentsDof::include_s7_rz] is set
+    pub fn from_gmt(gmt: &Gmt) -> Self {
+        Self::new()
+            .m1_n_mode(gmt.m1.n_mode)
+            .m2_n_mode(gmt.m2.n_mode)
+            .from_update42(GmtUpdate {
+                m1_rbm: gmt.m1_segments_state(),
+                m2_rbm: gmt.m2_segments_state(),
+                m1_mode: Some(gmt.m1.a.clone()),
+                m2_mode: Some(gmt.m2.a.clone()),
+            })
+    }
+    // one block per segment of the degrees of freedom in the range `[i, i+n)`
+    fn blocks(&self, i: usize, n: usize) -> Vec<Vec<f64>> {
+        self.full_vec()
+            .chunks(self.segment_n_dof())
+            .map(|s| s[i..i + n].to_vec())
+            .collect()
+    }
+    /// Returns M1 rigid body motions `[Txyz, Rxyz]` segment wise
+    pub fn m1_rbm(&self) -> Vec<f64> {
+        self.blocks(0, 6).concat()
+    }
+    /// Returns M1 modes segment wise
+    pub fn m1_modes(&self) -> Vec<f64> {
+        self.blocks(6, self.m1_n_mode).concat()
+    }
+    /// Returns M2 rigid body motions `[Txyz, Rxyz]` segment wise
+    pub fn m2_rbm(&self) -> Vec<f64> {
+        self.blocks(6 + self.m1_n_mode, 6).concat()
+    }
+    /// Returns M2 modes segment wise
+    pub fn m2_modes(&self) -> Vec<f64> {
+        self.blocks(12 + self.m1_n_mode, self.m2_n_mode).concat()
+    }
+    /// Returns the Euclidean norm of the degrees of freedom vector
+    pub fn norm(&self) -> f64 {
+        Vec::<f64>::from(self.clone())
+            .into_iter()
+            .map(|x| x * x)
+            .sum::<f64>()
+            .sqrt()
+    }
+    /// Returns the degrees of freedom in the layout of the arguments of [Gmt::update]
+    pub fn to_update(&self) -> GmtUpdate<Vec<Vec<f64>>> {
+        GmtUpdate {
+            m1_rbm: self.blocks(0, 6),
+            m2_rbm: self.blocks(6 + self.m1_n_mode, 6),
+            m1_mode: (self.m1_n_mode > 0).then(|| self.blocks(6, self.m1_n_mode)),
+            m2_mode: (self.m2_n_mode > 0).then(|| self.blocks(12 + self.m1_n_mode, self.m2_n_mode)),
+        }
+    }
+    /// Returns the degrees of freedom in the layout of the arguments of [Gmt::update42]
+    pub fn to_update42(&self) -> GmtUpdate<Vec<f64>> {
+        let GmtUpdate {
+            m1_rbm,
+            m2_rbm,
+            m1_mode,
+            m2_mode,
+        } = self.to_update();
+        GmtUpdate {
+            m1_rbm: m1_rbm.concat(),
+            m2_rbm: m2_rbm.concat(),
+            m1_mode: m1_mode.map(|x| x.concat()),
+            m2_mode: m2_mode.map(|x| x.concat()),
+        }
+    }
+    /// Sets the degrees of freedom from the arguments of [Gmt::update]
+    ///
+    /// The modes are set to 0 if they are not given
+    pub fn from_update(self, update: GmtUpdate<Vec<Vec<f64>>>) -> Self {
+        let GmtUpdate {
+            m1_rbm,
+            m2_rbm,
+            m1_mode,
+            m2_mode,
+        } = update;
+        self.from_update42(GmtUpdate {
+            m1_rbm: m1_rbm.concat(),
+            m2_rbm: m2_rbm.concat(),
+            m1_mode: m1_mode.map(|x| x.concat()),
+            m2_mode: m2_mode.map(|x| x.concat()),
+        })
+    }
+    /// Sets the degrees of freedom from the arguments of [Gmt::update42]
+    ///
+    /// The modes are set to 0 if they are not given
+    pub fn from_update42(self, update: GmtUpdate<Vec<f64>>) -> Self {
+        let (n1, n2) = (self.m1_n_mode, self.m2_n_mode);
+        let m1_mode = update.m1_mode.unwrap_or_else(|| vec![0f64; 7 * n1]);
+        let m2_mode = update.m2_mode.unwrap_or_else(|| vec![0f64; 7 * n2]);
+        assert_eq!(update.m1_rbm.len(), 42, "expected 42 M1 rigid body motions");
+        assert_eq!(update.m2_rbm.len(), 42, "expected 42 M2 rigid body motions");
+        assert_eq!(m1_mode.len(), 7 * n1, "expected {} M1 modes", 7 * n1);
+        assert_eq!(m2_mode.len(), 7 * n2, "expected {} M2 modes", 7 * n2);
+        let v = (0..7)
+            .flat_map(|i| {
+                [
+                    &update.m1_rbm[i * 6..(i + 1) * 6],
+                    &m1_mode[i * n1..(i + 1) * n1],
+                    &update.m2_rbm[i * 6..(i + 1) * 6],
+                    &m2_mode[i * n2..(i + 1) * n2],
+                ]
+                .concat()
+            })
+            .collect();
+        self.with_full_vec(v)
+    }
+    // element wise operation on 2 states with the same layout
+    fn zip_with(self, other: Self, op: impl Fn(f64, f64) -> f64) -> Self {
+        assert!(
+            self.m1_n_mode == other.m1_n_mode
+                && self.m2_n_mode == other.m2_n_mode
+                && self.s7_rz == other.s7_rz,
+            "GMT degrees of freedom layouts mismatch"
+        );
+        let v = self
+            .full_vec()
+            .into_iter()
+            .zip(other.full_vec())
+            .map(|(a, b)| op(a, b))
+            .collect();
+        self.with_full_vec(v)
+    }
     pub fn segment(&mut self, sid: usize, segment_dof: SegmentDof) -> GmtResult<&mut Self> {
         use MirrorDof::*;
         use SegmentDof::*;
@@ -549,12 +701,114 @@ impl SegmentsDof {
                 }?;
             }
             if !a1.is_empty() {
-                gmt.m1_modes(&mut a1);
+                gmt.m1_modes(&a1);
             }
             if !a2.is_empty() {
-                gmt.m2_modes(&mut a2);
+                gmt.m2_modes(&a2);
             }
         }
         Ok(())
     }
 }
+
+/// GMT state in the layout of the arguments of [Gmt::update] (`T=Vec<Vec<f64>>`)
+/// or [Gmt::update42] (`T=Vec<f64>`)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GmtUpdate<T> {
+    pub m1_rbm: T,
+    pub m2_rbm: T,
+    pub m1_mode: Option<T>,
+    pub m2_mode: Option<T>,
+}
+impl GmtUpdate<Vec<Vec<f64>>> {
+    /// Updates the GMT with [Gmt::update]
+    pub fn apply_to(&self, gmt: &mut Gmt) {
+        gmt.update(
+            Some(&self.m1_rbm),
+            Some(&self.m2_rbm),
+            self.m1_mode.as_ref(),
+            self.m2_mode.as_ref(),
+        );
+    }
+}
+impl GmtUpdate<Vec<f64>> {
+    /// Updates the GMT with [Gmt::update42]
+    pub fn apply_to(&self, gmt: &mut Gmt) {
+        gmt.update42(
+            Some(&self.m1_rbm),
+            Some(&self.m2_rbm),
+            self.m1_mode.as_deref(),
+            self.m2_mode.as_deref(),
+        );
+    }
+}
+
+impl Add for SegmentsDof {
+    type Output = SegmentsDof;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, |a, b| a + b)
+    }
+}
+impl Sub for SegmentsDof {
+    type Output = SegmentsDof;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.zip_with(rhs, |a, b| a - b)
+    }
+}
+impl Mul<f64> for SegmentsDof {
+    type Output = SegmentsDof;
+
+    fn mul(self, rhs: f64) -> Self::Output {
+        let v = self.full_vec().into_iter().map(|x| x * rhs).collect();
+        self.with_full_vec(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vec_round_trip() {
+        let dofs = SegmentsDof::new().m1_n_mode(3).m2_n_mode(2);
+        let v: Vec<f64> = (0..7 * 5 + 82).map(|x| x as f64).collect();
+        let w: Vec<f64> = dofs.clone().from_vec(v.clone()).into();
+        assert_eq!(v, w);
+        let layout = dofs.layout();
+        assert_eq!(layout.len(), v.len());
+        assert_eq!(layout.index_of("M2.S7.mode[1]").unwrap(), v.len() - 1);
+        assert!(layout.index_of("M1.S7.Rz").is_err());
+        let rbm: Vec<f64> = (0..82).map(|x| x as f64).collect();
+        let state = dofs.from_rigid_body_motions(rbm.clone());
+        assert_eq!(state.m2_rbm()[..6], rbm[6..12]);
+        assert_eq!(state.m1_rbm()[36..41], rbm[72..77]);
+        assert_eq!(state.m1_modes(), vec![0f64; 21]);
+    }
+
+    #[test]
+    fn update_round_trip() {
+        let dofs = SegmentsDof::new().m1_n_mode(3).include_s7_rz();
+        let v: Vec<f64> = (0..7 * 3 + 84).map(|x| x as f64).collect();
+        let state = dofs.clone().from_vec(v.clone());
+        let update = state.to_update42();
+        assert_eq!(update.m1_rbm.len(), 42);
+        assert_eq!(update.m1_mode.as_ref().map(|x| x.len()), Some(21));
+        assert!(update.m2_mode.is_none());
+        let w: Vec<f64> = dofs.clone().from_update42(update).into();
+        assert_eq!(v, w);
+        let w: Vec<f64> = dofs.from_update(state.to_update()).into();
+        assert_eq!(v, w);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let dofs = SegmentsDof::new().m2_n_mode(2);
+        let a = dofs.clone().from_vec(vec![1.; 96]);
+        let b = dofs.from_vec(vec![3.; 96]);
+        let c: Vec<f64> = (b.clone() - a.clone() * 2. + a).into();
+        assert_eq!(c, vec![2.; 96]);
+        assert!((b.norm() - 3. * 96f64.sqrt()).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,166 @@
+//!
+//! # GMT global degrees of freedom
+//!
+//! Telescope wide motions expressed in telescope coordinates:
+//!  - the mount pointing error,
+//!  - the M1 and M2 whole mirror rigid body motions,
+//!  - the rotator angle.
+//!
+//! The mirror motions are converted into segment rigid body motions with the segment coordinate systems of the [Gmt],
+//! the pointing error and the rotator angle change the line of sight of the sources
+//! when they are propagated through the [Gmt].
+//!
+//! ```no_run
+//! use crseo::{gmt::global::GlobalDof, Builder, FromBuilder, Gmt, Source};
+//! use skyangle::Conversion;
+//! let mut gmt = Gmt::builder().build()?;
+//! let global = GlobalDof::default()
+//!     .pointing(1f64.from_arcsec(), 0.)
+//!     .m2_rbm([0., 0., 1e-6, 0., 0.1f64.from_arcsec(), 0.])
+//!     .rotator(10f64.from_arcsec());
+//! gmt.set_global_motions(&global)?;
+//! let mut src = Source::builder().build()?;
+//! src.through(&mut gmt).xpupil();
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    dof::{GmtResult, GmtUpdate, SegmentsDof},
+    Gmt,
+};
+
+/// Global telescope degrees of freedom
+///
+/// Default properties:
+///  - pointing: none
+///  - M1 and M2 rigid body motions: 0
+///  - rotator angle: 0
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct GlobalDof {
+    /// mount pointing error as the pair (delta_zenith, azimuth) \[rd\]
+    pub pointing: Option<(f64, f64)>,
+    /// M1 rigid body motions `[Txyz, Rxyz]` about the mirror vertex \[m,rd\]
+    pub m1_rbm: [f64; 6],
+    /// M2 rigid body motions `[Txyz, Rxyz]` about the mirror vertex \[m,rd\]
+    pub m2_rbm: [f64; 6],
+    /// rotation of the field around the optical axis \[rd\]
+    pub rotator: f64,
+}
+impl GlobalDof {
+    /// Sets the mount pointing error (delta_zenith, azimuth) \[rd\]
+    pub fn pointing(self, delta_zenith: f64, azimuth: f64) -> Self {
+        Self {
+            pointing: Some((delta_zenith, azimuth)),
+            ..self
+        }
+    }
+    /// Sets M1 rigid body motions `[Txyz, Rxyz]` \[m,rd\]
+    pub fn m1_rbm(self, m1_rbm: [f64; 6]) -> Self {
+        Self { m1_rbm, ..self }
+    }
+    /// Sets M2 rigid body motions `[Txyz, Rxyz]` \[m,rd\]
+    pub fn m2_rbm(self, m2_rbm: [f64; 6]) -> Self {
+        Self { m2_rbm, ..self }
+    }
+    /// Sets the rotator angle \[rd\]
+    pub fn rotator(self, rotator: f64) -> Self {
+        Self { rotator, ..self }
+    }
+    /// Returns the segment rigid body motions equivalent to the M1 and M2 rigid body motions
+    ///
+    /// The modes are set to 0 and, as with [SegmentsDof::from_gmt],
+    /// the center segment clocking is excluded from the vector form
+    pub fn segments_dof(&self, gmt: &Gmt) -> SegmentsDof {
+        SegmentsDof::new()
+            .m1_n_mode(gmt.m1.n_mode)
+            .m2_n_mode(gmt.m2.n_mode)
+            .from_update42(GmtUpdate {
+                m1_rbm: segments_motion(&self.m1_rbm, &gmt.m1.rigid_body_CS),
+                m2_rbm: segments_motion(&self.m2_rbm, &gmt.m2.rigid_body_CS),
+                m1_mode: None,
+                m2_mode: None,
+            })
+    }
+}
+
+impl Gmt {
+    /// Sets the global degrees of freedom
+    ///
+    /// The current state of the segments is overwritten: the segment rigid body motions are set to
+    /// [GlobalDof::segments_dof] and the modes to 0.
+    /// The segment and global motions are combined by adding [SegmentsDof]s
+    /// and applying the sum with [SegmentsDof::apply_to] instead
+    pub fn set_global_motions(&mut self, global: &GlobalDof) -> GmtResult<&mut Self> {
+        global.segments_dof(self).apply_to(self)?;
+        self.pointing_error = global.pointing;
+        self.rotator_angle = global.rotator;
+        Ok(self)
+    }
+}
+
+// segment rigid body motions `[Txyz, Rxyz]` segment wise from the mirror rigid body motions
+fn segments_motion(rbm: &[f64; 6], cs: &ffi::coordinate_system) -> Vec<f64> {
+    let (origin, rotation) = unsafe {
+        (
+            std::slice::from_raw_parts(cs.origin, 7),
+            std::slice::from_raw_parts(cs.R, 63),
+        )
+    };
+    origin
+        .iter()
+        .zip(rotation.chunks(9))
+        .flat_map(|(o, r)| segment_motion(rbm, [o.x, o.y, o.z], r))
+        .collect()
+}
+
+/// Rigid body motion of a segment from the mirror rigid body motion
+///
+/// The segment coordinate system has its origin at `origin` in the mirror coordinate system
+/// and the row-major `rotation` matrix transforms vectors from the mirror to the segment coordinate system
+fn segment_motion(rbm: &[f64; 6], origin: [f64; 3], rotation: &[f64]) -> [f64; 6] {
+    let (t, r) = (&rbm[..3], &rbm[3..]);
+    // displacement of the segment origin: T + R x o
+    let d = [
+        t[0] + r[1] * origin[2] - r[2] * origin[1],
+        t[1] + r[2] * origin[0] - r[0] * origin[2],
+        t[2] + r[0] * origin[1] - r[1] * origin[0],
+    ];
+    let rotate = |v: &[f64]| -> [f64; 3] {
+        [0, 1, 2].map(|i| (0..3).map(|j| rotation[3 * i + j] * v[j]).sum())
+    };
+    let (t, r) = (rotate(&d), rotate(r));
+    [t[0], t[1], t[2], r[0], r[1], r[2]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: [f64; 9] = [1., 0., 0., 0., 1., 0., 0., 0., 1.];
+
+    #[test]
+    fn piston_and_clocking() {
+        let origin = [8.7, 0., 0.];
+        let rbm = segment_motion(&[0., 0., 1e-6, 0., 0., 0.], origin, &IDENTITY);
+        assert_eq!(rbm, [0., 0., 1e-6, 0., 0., 0.]);
+        // a clocking of the mirror translates the segment along y
+        let rbm = segment_motion(&[0., 0., 0., 0., 0., 1e-6], origin, &IDENTITY);
+        assert!((rbm[1] - 8.7e-6).abs() < 1e-18);
+        assert_eq!(rbm[5], 1e-6);
+    }
+
+    #[test]
+    fn tilted_segment() {
+        // segment clocked by 90deg: the mirror x axis is the segment -y axis
+        let rotation = [0., 1., 0., -1., 0., 0., 0., 0., 1.];
+        let origin = [0., 8.7, 0.];
+        let rbm = segment_motion(&[1e-6, 0., 0., 1e-6, 0., 0.], origin, &rotation);
+        // the mirror Rx lifts the segment along z
+        [0., -1e-6, 8.7e-6, 0., -1e-6, 0.]
+            .iter()
+            .zip(rbm)
+            .for_each(|(e, x)| assert!((e - x).abs() < 1e-18));
+    }
+}
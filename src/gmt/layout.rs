@@ -0,0 +1,339 @@
+//!
+//! # GMT degrees of freedom layouts
+//!
+//! A [DofLayout] names each element of a vector of GMT degrees of freedom,
+//! e.g. `M1.S3.Rx` or `M2.S7.mode[12]`, and maps vectors between layouts.
+//!
+//! ```
+//! use crseo::gmt::{dof::SegmentsDof, layout::DofLayout};
+//! let layout = SegmentsDof::new().m1_n_mode(27).layout();
+//! let i = layout.index_of("M1.S3.mode[0]").unwrap();
+//! let m1_rx = layout.find("M1.*.Rx").unwrap();
+//! let rbm = DofLayout::update42("M1");
+//! let values = vec![1f64; rbm.len()];
+//! let state = rbm.remap(&values, &layout).unwrap();
+//! ```
+
+use std::{fmt::Display, ops::Index, ops::Range, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::wavefrontsensor::{Mirror, SegmentCalibration, DOF, RBM};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LayoutError {
+    #[error("failed to parse {0}, expected `M<1|2>.S<1-7>.<Tx|Ty|Tz|Rx|Ry|Rz|mode[#]>`")]
+    Parse(String),
+    #[error("{0} is not in the layout")]
+    Missing(String),
+    #[error("expected {expected} values, found {found}")]
+    Length { expected: usize, found: usize },
+}
+pub type Result<T> = std::result::Result<T, LayoutError>;
+
+/// Segment degree of freedom
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Dof {
+    Tx,
+    Ty,
+    Tz,
+    Rx,
+    Ry,
+    Rz,
+    /// Segment mode
+    Mode(usize),
+}
+impl Dof {
+    /// Rigid body motions in the `[Txyz, Rxyz]` order
+    pub const RBM: [Dof; 6] = [Dof::Tx, Dof::Ty, Dof::Tz, Dof::Rx, Dof::Ry, Dof::Rz];
+}
+impl Display for Dof {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dof::Mode(i) => write!(f, "mode[{i}]"),
+            rbm => write!(f, "{rbm:?}"),
+        }
+    }
+}
+impl FromStr for Dof {
+    type Err = LayoutError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let dof = match s {
+            "Tx" => Dof::Tx,
+            "Ty" => Dof::Ty,
+            "Tz" => Dof::Tz,
+            "Rx" => Dof::Rx,
+            "Ry" => Dof::Ry,
+            "Rz" => Dof::Rz,
+            _ => s
+                .strip_prefix("mode[")
+                .and_then(|s| s.strip_suffix(']'))
+                .and_then(|i| i.parse().ok())
+                .map(Dof::Mode)
+                .ok_or_else(|| LayoutError::Parse(s.to_string()))?,
+        };
+        Ok(dof)
+    }
+}
+
+/// Name of a GMT degree of freedom
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DofName {
+    pub mirror: Mirror,
+    /// segment ID in the range `[1,7]`
+    pub sid: u8,
+    pub dof: Dof,
+}
+impl DofName {
+    /// Creates a new degree of freedom name
+    pub fn new(mirror: Mirror, sid: u8, dof: Dof) -> Self {
+        assert!(
+            sid > 0 && sid < 8,
+            "segment ID ({sid}) must be in the range [1,7]!"
+        );
+        Self { mirror, sid, dof }
+    }
+    // matches a `M?.S?.?` pattern where any field can be replaced by `*`
+    fn matches(&self, pattern: &str) -> bool {
+        let fields: Vec<_> = pattern.split('.').collect();
+        if fields.len() != 3 {
+            return false;
+        }
+        let name = self.to_string();
+        fields.iter().zip(name.split('.')).all(|(p, n)| match *p {
+            "*" => true,
+            "mode[*]" => n.starts_with("mode["),
+            p => p == n,
+        })
+    }
+}
+impl Display for DofName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}.S{}.{}", self.mirror, self.sid, self.dof)
+    }
+}
+impl FromStr for DofName {
+    type Err = LayoutError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || LayoutError::Parse(s.to_string());
+        let mut fields = s.splitn(3, '.');
+        let mirror = match fields.next() {
+            Some("M1") => Mirror::M1,
+            Some("M2") => Mirror::M2,
+            _ => return Err(err()),
+        };
+        let sid = fields
+            .next()
+            .and_then(|s| s.strip_prefix('S'))
+            .and_then(|s| s.parse::<u8>().ok())
+            .filter(|sid| (1..=7).contains(sid))
+            .ok_or_else(err)?;
+        let dof = fields.next().ok_or_else(err)?.parse().map_err(|_| err())?;
+        Ok(Self { mirror, sid, dof })
+    }
+}
+
+/// Layout of a vector of GMT degrees of freedom
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DofLayout {
+    names: Vec<DofName>,
+}
+impl From<Vec<DofName>> for DofLayout {
+    fn from(names: Vec<DofName>) -> Self {
+        Self { names }
+    }
+}
+impl FromIterator<DofName> for DofLayout {
+    fn from_iter<T: IntoIterator<Item = DofName>>(iter: T) -> Self {
+        Self {
+            names: iter.into_iter().collect(),
+        }
+    }
+}
+impl Index<usize> for DofLayout {
+    type Output = DofName;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.names[index]
+    }
+}
+impl Display for DofLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "DOF layout ({}):", self.len())?;
+        for (i, name) in self.names.iter().enumerate() {
+            writeln!(f, " {i:>4}: {name}")?;
+        }
+        Ok(())
+    }
+}
+impl DofLayout {
+    /// Rigid body motions `[Txyz, Rxyz]` of a mirror segment wise,
+    /// i.e. the layout of the rigid body motions arguments of [Gmt::update42](crate::Gmt::update42)
+    pub fn update42<M: Into<Mirror>>(mirror: M) -> Self {
+        let mirror = mirror.into();
+        (1..=7)
+            .flat_map(|sid| Dof::RBM.map(|dof| DofName::new(mirror, sid, dof)))
+            .collect()
+    }
+    /// `n_mode` modes of a mirror segment wise,
+    /// i.e. the layout of the modes arguments of [Gmt::update42](crate::Gmt::update42)
+    pub fn modes<M: Into<Mirror>>(mirror: M, n_mode: usize) -> Self {
+        let mirror = mirror.into();
+        (1..=7)
+            .flat_map(|sid| (0..n_mode).map(move |i| DofName::new(mirror, sid, Dof::Mode(i))))
+            .collect()
+    }
+    /// Degrees of freedom of a segment calibration of segment #`sid`,
+    /// i.e. the columns of the [SlopesArray](crate::wavefrontsensor::SlopesArray)
+    pub fn segment_calibration(sid: u8, calibration: &SegmentCalibration) -> Self {
+        match calibration {
+            SegmentCalibration::Modes { dof, mirror, .. } => dof
+                .clone()
+                .into_iter()
+                .map(|i| DofName::new(*mirror, sid, Dof::Mode(i)))
+                .collect(),
+            SegmentCalibration::RBM { rbm, mirror, .. } => {
+                let dofs: Vec<Dof> = match rbm {
+                    RBM::Txyz(dof) => dof
+                        .clone()
+                        .unwrap_or(DOF::Range(0..3))
+                        .into_iter()
+                        .map(|i| Dof::RBM[i])
+                        .collect(),
+                    RBM::Rxyz(dof) => dof
+                        .clone()
+                        .unwrap_or(DOF::Range(0..3))
+                        .into_iter()
+                        .map(|i| Dof::RBM[i + 3])
+                        .collect(),
+                    RBM::TRxyz => Dof::RBM.to_vec(),
+                };
+                dofs.into_iter()
+                    .map(|dof| DofName::new(*mirror, sid, dof))
+                    .collect()
+            }
+        }
+    }
+    /// Returns the number of degrees of freedom
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+    /// Checks if the layout is empty
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+    /// Returns the degrees of freedom names
+    pub fn names(&self) -> &[DofName] {
+        &self.names
+    }
+    /// Iterates over the degrees of freedom names
+    pub fn iter(&self) -> std::slice::Iter<'_, DofName> {
+        self.names.iter()
+    }
+    /// Returns the index of a degree of freedom given by its name, e.g. `M1.S3.Rx`
+    pub fn index_of(&self, name: &str) -> Result<usize> {
+        let name: DofName = name.parse()?;
+        self.position(&name)
+            .ok_or_else(|| LayoutError::Missing(name.to_string()))
+    }
+    /// Returns the index of a degree of freedom
+    pub fn position(&self, name: &DofName) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+    /// Returns the indices of the degrees of freedom matching a `mirror.segment.dof` pattern
+    ///
+    /// Any of the 3 fields can be replaced by `*` and the mode number by `mode[*]`,
+    /// e.g. `M1.*.Rx`, `*.S7.*` or `M2.S1.mode[*]`
+    pub fn find(&self, pattern: &str) -> Result<Vec<usize>> {
+        let idx: Vec<_> = self
+            .names
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| name.matches(pattern).then_some(i))
+            .collect();
+        if idx.is_empty() {
+            Err(LayoutError::Missing(pattern.to_string()))
+        } else {
+            Ok(idx)
+        }
+    }
+    /// Returns the layout of a range of degrees of freedom
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        Self {
+            names: self.names[range].to_vec(),
+        }
+    }
+    /// Returns the layout of the degrees of freedom at the given indices
+    pub fn select(&self, indices: &[usize]) -> Self {
+        indices.iter().map(|&i| self.names[i]).collect()
+    }
+    /// Removes the degrees of freedom at the given ordered indices
+    pub fn trim(&mut self, indices: &[usize]) {
+        for (count, idx) in indices.iter().enumerate() {
+            self.names.remove(idx - count);
+        }
+    }
+    /// Appends another layout
+    pub fn extend(&mut self, other: &DofLayout) -> &mut Self {
+        self.names.extend_from_slice(&other.names);
+        self
+    }
+    /// Returns for each degree of freedom of this layout its index in the `other` layout
+    pub fn indices_in(&self, other: &DofLayout) -> Vec<Option<usize>> {
+        self.names.iter().map(|name| other.position(name)).collect()
+    }
+    /// Maps `values` ordered according to this layout to the `to` layout
+    ///
+    /// The degrees of freedom of `to` that are not in this layout are set to `T::default()`
+    pub fn remap<T: Copy + Default>(&self, values: &[T], to: &DofLayout) -> Result<Vec<T>> {
+        if values.len() != self.len() {
+            return Err(LayoutError::Length {
+                expected: self.len(),
+                found: values.len(),
+            });
+        }
+        Ok(to
+            .indices_in(self)
+            .into_iter()
+            .map(|i| i.map_or_else(T::default, |i| values[i]))
+            .collect())
+    }
+    /// Pairs the degrees of freedom names with `values`
+    pub fn label<'a, T>(&'a self, values: &'a [T]) -> impl Iterator<Item = (&'a DofName, &'a T)> {
+        self.names.iter().zip(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        for name in ["M1.S3.Rx", "M2.S7.mode[12]"] {
+            assert_eq!(name.parse::<DofName>().unwrap().to_string(), name);
+        }
+        assert!("M3.S1.Tx".parse::<DofName>().is_err());
+        assert!("M1.S8.Tx".parse::<DofName>().is_err());
+        assert!("M1.S1.mode[x]".parse::<DofName>().is_err());
+    }
+
+    #[test]
+    fn remap() {
+        let rbm = DofLayout::update42("M2");
+        let modes = DofLayout::modes("M2", 2);
+        let mut layout = rbm.clone();
+        layout.extend(&modes);
+        assert_eq!(layout.index_of("M2.S2.Ty").unwrap(), 7);
+        assert_eq!(layout.index_of("M2.S1.mode[1]").unwrap(), 43);
+        assert_eq!(layout.find("*.*.Rz").unwrap().len(), 7);
+        assert_eq!(layout.find("M2.S7.mode[*]").unwrap(), vec![54, 55]);
+        let values: Vec<f64> = (0..modes.len()).map(|x| x as f64).collect();
+        let v = modes.remap(&values, &layout).unwrap();
+        assert_eq!(v[..42], [0f64; 42]);
+        assert_eq!(v[42..], values);
+        assert_eq!(layout.remap(&v, &modes).unwrap(), values);
+    }
+}
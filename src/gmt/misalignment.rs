@@ -0,0 +1,378 @@
+//!
+//! # GMT random misalignments
+//!
+//! Draws random [SegmentsDof] realisations for Monte Carlo alignment studies.
+//!
+//! A [Misalignment] specifies the distribution of the segments degrees of freedom:
+//!  - rigid body motions (or any degree of freedom) with normal or uniform [Tolerance]s
+//!    selected with the `mirror.segment.dof` patterns of [DofLayout::find],
+//!  - M1 and M2 segment figure errors from a [ModalDistribution],
+//!
+//! and a [MisalignmentGenerator] draws the realisations.
+//!
+//! ```no_run
+//! use crseo::{
+//!     gmt::misalignment::{Misalignment, ModalDistribution, Tolerance},
+//!     Builder, FromBuilder, Gmt,
+//! };
+//! use skyangle::Conversion;
+//! let mut gmt = Gmt::builder().m1_n_mode(27).m2_n_mode(66).build()?;
+//! let generator = Misalignment::new(27, 66)
+//!     .tolerance("M1.*.Tz", Tolerance::Normal(75e-6))
+//!     .tolerance("M2.*.Rx", Tolerance::Uniform(1f64.from_arcsec()))
+//!     .tolerance("M2.*.Ry", Tolerance::Uniform(1f64.from_arcsec()))
+//!     .m1_figure(ModalDistribution::Variances(vec![1e-16; 27]))
+//!     .m2_figure(ModalDistribution::PowerLaw { rms: 20e-9, index: 2. })
+//!     .zero_mean()
+//!     .seed(42)
+//!     .generator()?;
+//! for state in generator.take(100) {
+//!     state.apply_to(&mut gmt)?;
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::HashMap;
+
+use nalgebra as na;
+use rand::{rngs::StdRng, RngExt, SeedableRng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    dof::SegmentsDof,
+    layout::{Dof, DofLayout, LayoutError},
+};
+use crate::wavefrontsensor::Mirror;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MisalignmentError {
+    #[error("invalid tolerance pattern")]
+    Layout(#[from] LayoutError),
+    #[error("{0:?} figure error: expected {1} modes, found {2}")]
+    ModeCount(Mirror, usize, usize),
+    #[error("{0:?} figure error: the covariance matrix is not positive definite")]
+    Covariance(Mirror),
+}
+pub type Result<T> = std::result::Result<T, MisalignmentError>;
+
+/// Distribution of a degree of freedom
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Tolerance {
+    /// Zero mean normal distribution with the given standard deviation
+    Normal(f64),
+    /// Uniform distribution in the range `[-bound,bound]`
+    Uniform(f64),
+}
+
+/// Distribution of the modal coefficients of a segment
+///
+/// The segments are drawn independently
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ModalDistribution {
+    /// Uncorrelated modes with the given variances, e.g. a bending modes PSD
+    Variances(Vec<f64>),
+    /// Correlated modes with the given `[n_mode x n_mode]` covariance matrix
+    Covariance(na::DMatrix<f64>),
+    /// Uncorrelated modes with the variance of mode #i proportional to `(i+1)^-index`
+    /// and the total standard deviation `rms`
+    PowerLaw { rms: f64, index: f64 },
+}
+impl ModalDistribution {
+    // lower triangular factor of the covariance matrix
+    fn factor(&self, mirror: Mirror, n_mode: usize) -> Result<na::DMatrix<f64>> {
+        let variances = |v: &[f64]| {
+            if v.len() == n_mode {
+                Ok(na::DMatrix::from_diagonal(&na::DVector::from_iterator(
+                    n_mode,
+                    v.iter().map(|x| x.sqrt()),
+                )))
+            } else {
+                Err(MisalignmentError::ModeCount(mirror, n_mode, v.len()))
+            }
+        };
+        match self {
+            ModalDistribution::Variances(v) => variances(v),
+            ModalDistribution::Covariance(c) => {
+                if c.nrows() != n_mode || c.ncols() != n_mode {
+                    return Err(MisalignmentError::ModeCount(mirror, n_mode, c.nrows()));
+                }
+                c.clone()
+                    .cholesky()
+                    .map(|c| c.l())
+                    .ok_or(MisalignmentError::Covariance(mirror))
+            }
+            ModalDistribution::PowerLaw { rms, index } => {
+                let v: Vec<f64> = (1..=n_mode).map(|i| (i as f64).powf(-index)).collect();
+                let s = rms * rms / v.iter().sum::<f64>();
+                variances(&v.into_iter().map(|x| x * s).collect::<Vec<_>>())
+            }
+        }
+    }
+}
+
+/// GMT misalignment specification
+///
+/// Default properties:
+///  - M1 and M2 modes: 0
+///  - center segment clocking: excluded
+///  - tolerances: none
+///  - figure errors: none
+///  - zero mean: false
+///  - seed: 0
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Misalignment {
+    pub m1_n_mode: usize,
+    pub m2_n_mode: usize,
+    pub s7_rz: bool,
+    /// degrees of freedom patterns and tolerances
+    pub tolerances: Vec<(String, Tolerance)>,
+    pub m1_figure: Option<ModalDistribution>,
+    pub m2_figure: Option<ModalDistribution>,
+    pub zero_mean: bool,
+    pub seed: u64,
+}
+impl Misalignment {
+    /// Creates a new misalignment specification for segments with `m1_n_mode` and `m2_n_mode` modes
+    pub fn new(m1_n_mode: usize, m2_n_mode: usize) -> Self {
+        Self {
+            m1_n_mode,
+            m2_n_mode,
+            ..Default::default()
+        }
+    }
+    /// Adds M1 and M2 center segment clocking to segment RBMs
+    pub fn include_s7_rz(self) -> Self {
+        Self {
+            s7_rz: true,
+            ..self
+        }
+    }
+    /// Sets the tolerance of the degrees of freedom matching the `pattern`, e.g. `M1.*.Tx`
+    ///
+    /// If a degree of freedom matches several patterns, the last one is used
+    pub fn tolerance<S: Into<String>>(mut self, pattern: S, tolerance: Tolerance) -> Self {
+        self.tolerances.push((pattern.into(), tolerance));
+        self
+    }
+    /// Sets the distribution of M1 segment figure errors
+    ///
+    /// The figure errors are added to the modes drawn from the tolerances
+    pub fn m1_figure(self, distribution: ModalDistribution) -> Self {
+        Self {
+            m1_figure: Some(distribution),
+            ..self
+        }
+    }
+    /// Sets the distribution of M2 segment figure errors
+    ///
+    /// The figure errors are added to the modes drawn from the tolerances
+    pub fn m2_figure(self, distribution: ModalDistribution) -> Self {
+        Self {
+            m2_figure: Some(distribution),
+            ..self
+        }
+    }
+    /// Removes the mean over the segments of each degree of freedom
+    ///
+    /// The draws have no global motion nor global figure, but the uniform bounds may be exceeded
+    pub fn zero_mean(self) -> Self {
+        Self {
+            zero_mean: true,
+            ..self
+        }
+    }
+    /// Sets the seed of the random number generator
+    pub fn seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+    /// Returns the [SegmentsDof] layout of the draws
+    pub fn segments_dof(&self) -> SegmentsDof {
+        let dofs = SegmentsDof::new()
+            .m1_n_mode(self.m1_n_mode)
+            .m2_n_mode(self.m2_n_mode);
+        if self.s7_rz {
+            dofs.include_s7_rz()
+        } else {
+            dofs
+        }
+    }
+    /// Creates the random misalignment generator
+    pub fn generator(self) -> Result<MisalignmentGenerator> {
+        MisalignmentGenerator::new(self)
+    }
+}
+
+/// Random misalignment generator
+///
+/// Draws [SegmentsDof] realisations of a [Misalignment] specification
+#[derive(Debug)]
+pub struct MisalignmentGenerator {
+    dofs: SegmentsDof,
+    rng: StdRng,
+    // tolerance of each degree of freedom
+    tolerances: Vec<Option<Tolerance>>,
+    // indices of the modes and the covariance factor of each mirror figure
+    figures: Vec<(Vec<Vec<usize>>, na::DMatrix<f64>)>,
+    // indices of the same degree of freedom over the segments
+    groups: Vec<Vec<usize>>,
+    zero_mean: bool,
+}
+impl MisalignmentGenerator {
+    /// Creates a new generator
+    pub fn new(misalignment: Misalignment) -> Result<Self> {
+        let dofs = misalignment.segments_dof();
+        let layout = dofs.layout();
+        let mut tolerances = vec![None; layout.len()];
+        for (pattern, tolerance) in &misalignment.tolerances {
+            for i in layout.find(pattern)? {
+                tolerances[i] = Some(*tolerance);
+            }
+        }
+        let mut figures = vec![];
+        for (mirror, n_mode, figure) in [
+            (Mirror::M1, misalignment.m1_n_mode, &misalignment.m1_figure),
+            (Mirror::M2, misalignment.m2_n_mode, &misalignment.m2_figure),
+        ] {
+            if let Some(figure) = figure {
+                let factor = figure.factor(mirror, n_mode)?;
+                let indices = (1..=7)
+                    .map(|sid| {
+                        layout
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, name)| {
+                                name.mirror == mirror
+                                    && name.sid == sid
+                                    && matches!(name.dof, Dof::Mode(_))
+                            })
+                            .map(|(i, _)| i)
+                            .collect()
+                    })
+                    .collect();
+                figures.push((indices, factor));
+            }
+        }
+        let mut groups: HashMap<_, Vec<usize>> = HashMap::new();
+        for (i, name) in layout.iter().enumerate() {
+            groups.entry((name.mirror, name.dof)).or_default().push(i);
+        }
+        Ok(Self {
+            dofs,
+            rng: StdRng::seed_from_u64(misalignment.seed),
+            tolerances,
+            figures,
+            groups: groups.into_values().collect(),
+            zero_mean: misalignment.zero_mean,
+        })
+    }
+    /// Returns the layout of the draws
+    pub fn layout(&self) -> DofLayout {
+        self.dofs.layout()
+    }
+    /// Draws a vector of degrees of freedom with the layout of [MisalignmentGenerator::layout]
+    pub fn draw_vec(&mut self) -> Vec<f64> {
+        let mut v: Vec<f64> = self
+            .tolerances
+            .iter()
+            .map(|tolerance| match tolerance {
+                Some(Tolerance::Normal(rms)) => rms * self.rng.sample::<f64, _>(StandardNormal),
+                Some(Tolerance::Uniform(bound)) => bound * (2. * self.rng.random::<f64>() - 1.),
+                None => 0.,
+            })
+            .collect();
+        for (indices, factor) in &self.figures {
+            for idx in indices {
+                let u = na::DVector::from_fn(idx.len(), |_, _| {
+                    self.rng.sample::<f64, _>(StandardNormal)
+                });
+                idx.iter()
+                    .zip((factor * u).iter())
+                    .for_each(|(&i, a)| v[i] += a);
+            }
+        }
+        if self.zero_mean {
+            for group in &self.groups {
+                let mean = group.iter().map(|&i| v[i]).sum::<f64>() / group.len() as f64;
+                group.iter().for_each(|&i| v[i] -= mean);
+            }
+        }
+        v
+    }
+    /// Draws a misalignment
+    pub fn draw(&mut self) -> SegmentsDof {
+        let v = self.draw_vec();
+        self.dofs.clone().from_vec(v)
+    }
+}
+impl Iterator for MisalignmentGenerator {
+    type Item = SegmentsDof;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.draw())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerances() {
+        let mut generator = Misalignment::new(0, 3)
+            .tolerance("M1.*.Tx", Tolerance::Normal(1.))
+            .tolerance("M2.*.Rz", Tolerance::Uniform(2.))
+            .seed(7)
+            .generator()
+            .unwrap();
+        let layout = generator.layout();
+        let tx = layout.find("M1.*.Tx").unwrap();
+        let rz = layout.find("M2.*.Rz").unwrap();
+        let n = 2000;
+        let mut var = 0.;
+        for _ in 0..n {
+            let v = generator.draw_vec();
+            var += tx.iter().map(|&i| v[i] * v[i]).sum::<f64>();
+            assert!(rz.iter().all(|&i| v[i].abs() <= 2.));
+            assert_eq!(v.iter().filter(|x| **x != 0.).count(), 7 + 6);
+        }
+        let rms = (var / (7 * n) as f64).sqrt();
+        assert!((rms - 1.).abs() < 0.05, "rms={rms}");
+        let draw = |seed| {
+            Misalignment::new(0, 3)
+                .tolerance("M1.*.Tx", Tolerance::Normal(1.))
+                .seed(seed)
+                .generator()
+                .unwrap()
+                .draw_vec()
+        };
+        assert_eq!(draw(1), draw(1));
+        assert_ne!(draw(1), draw(2));
+    }
+
+    #[test]
+    fn figure() {
+        let mut generator = Misalignment::new(0, 4)
+            .m2_figure(ModalDistribution::PowerLaw { rms: 1., index: 2. })
+            .zero_mean()
+            .generator()
+            .unwrap();
+        let layout = generator.layout();
+        let n = 2000;
+        let mut var = 0.;
+        for _ in 0..n {
+            let v = generator.draw_vec();
+            let modes = layout.find("M2.*.mode[*]").unwrap();
+            var += modes.iter().map(|&i| v[i] * v[i]).sum::<f64>();
+            let mode0 = layout.find("M2.*.mode[0]").unwrap();
+            assert!(mode0.iter().map(|&i| v[i]).sum::<f64>().abs() < 1e-12);
+        }
+        // the zero mean removes 1/7 of the variance
+        let rms = (var / (6 * n) as f64).sqrt();
+        assert!((rms - 1.).abs() < 0.05, "rms={rms}");
+        assert!(Misalignment::new(2, 0)
+            .m1_figure(ModalDistribution::Variances(vec![1.; 3]))
+            .generator()
+            .is_err());
+    }
+}
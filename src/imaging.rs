@@ -11,6 +11,9 @@ use std::fmt::Display;
 
 use crate::cu::Single;
 
+pub mod geometry;
+pub use geometry::{LensletGeometry, Misregistration, Packing};
+
 /// Lenslet array specifications
 ///
 /// Default properties:
@@ -0,0 +1,402 @@
+//!
+//! # Lenslet array geometry
+//!
+//! Square or hexagonal lenslet arrays that are rotated, shifted and magnified with respect to the pupil
+//! and with a distortion map of the lenslet centers.
+//!
+//! The [Misregistration] of the CEO wavefront sensors ([SH48](crate::wavefrontsensor::SH48), [Pyramid](crate::wavefrontsensor::Pyramid))
+//! is applied to the rays of the guide stars given by [WavefrontSensorBuilder::guide_stars](crate::WavefrontSensorBuilder::guide_stars),
+//! so the calibration and the propagation see the same misregistration.
+//! The hexagonal packing and the distortion map are applied the same way: the rays of the guide stars are
+//! moved from the square lenslets of the CEO sensors to the lenslets of the [LensletGeometry]
+//! (see [LensletGeometry::rays_coordinates]).
+//! [LensletGeometry] gives the lenslet layout in the pupil and the geometric slopes of the lenslets for any geometry.
+//!
+//! # Examples
+//!
+//! ```
+//! use crseo::imaging::{LensletArray, LensletGeometry, Misregistration};
+//! let geometry = LensletGeometry::from(LensletArray::default().n_side_lenslet(48))
+//!     .hexagonal()
+//!     .misregistration(Misregistration::default().shift(0.05, 0.).rotation(1f64.to_radians()));
+//! let centers = geometry.lenslet_centers();
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use super::LensletArray;
+
+/// Misregistration of a lenslet array with respect to the pupil
+///
+/// A point `q` of the lenslet array is mapped into the pupil at `R(rotation)q/magnification + shift`
+///
+/// Default properties:
+///  - shift         : (0,0)m
+///  - rotation      : 0rd
+///  - magnification : 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Misregistration {
+    /// shift \[m\]
+    pub shift: (f64, f64),
+    /// rotation \[rd\]
+    pub rotation: f64,
+    /// magnification
+    pub magnification: f64,
+}
+impl Default for Misregistration {
+    fn default() -> Self {
+        Self {
+            shift: (0., 0.),
+            rotation: 0.,
+            magnification: 1.,
+        }
+    }
+}
+impl Misregistration {
+    /// Sets the shift \[m\]
+    pub fn shift(self, x: f64, y: f64) -> Self {
+        Self {
+            shift: (x, y),
+            ..self
+        }
+    }
+    /// Sets the rotation \[rd\]
+    pub fn rotation(self, rotation: f64) -> Self {
+        Self { rotation, ..self }
+    }
+    /// Sets the magnification
+    pub fn magnification(self, magnification: f64) -> Self {
+        Self {
+            magnification,
+            ..self
+        }
+    }
+    /// Maps the lenslet array coordinates (`x`,`y`) \[m\] into the pupil
+    pub fn to_pupil(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (s, c) = self.rotation.sin_cos();
+        let m = self.magnification;
+        (
+            (c * x - s * y) / m + self.shift.0,
+            (s * x + c * y) / m + self.shift.1,
+        )
+    }
+    /// Maps the pupil coordinates (`x`,`y`) \[m\] into the lenslet array
+    pub fn to_lenslet(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (s, c) = self.rotation.sin_cos();
+        let m = self.magnification;
+        let (u, v) = (x - self.shift.0, y - self.shift.1);
+        (m * (c * u + s * v), m * (c * v - s * u))
+    }
+}
+
+/// Lenslet packing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Packing {
+    /// `n_side_lenslet` rows and columns of square lenslets
+    #[default]
+    Square,
+    /// `n_side_lenslet` rows of `n_side_lenslet` hexagonal lenslets with every other row shifted by half a lenslet
+    Hexagonal,
+}
+
+/// Lenslet array geometry
+///
+/// The lenslet pitch is the distance between the centers of 2 neighboring lenslets,
+/// lenslet centers are indexed with `k=i+n_side_lenslet*j` where `i` and `j` are the column and row indices
+///
+/// Default properties:
+///  - packing         : square
+///  - misregistration : none
+///  - distortion      : none
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LensletGeometry {
+    pub lenslet_array: LensletArray,
+    pub packing: Packing,
+    pub misregistration: Misregistration,
+    pub distortion: Option<Vec<(f64, f64)>>,
+}
+impl From<LensletArray> for LensletGeometry {
+    fn from(lenslet_array: LensletArray) -> Self {
+        Self {
+            lenslet_array,
+            ..Default::default()
+        }
+    }
+}
+impl LensletGeometry {
+    /// Sets the hexagonal packing of the lenslets
+    pub fn hexagonal(self) -> Self {
+        Self {
+            packing: Packing::Hexagonal,
+            ..self
+        }
+    }
+    /// Sets the misregistration of the lenslet array with respect to the pupil
+    pub fn misregistration(self, misregistration: Misregistration) -> Self {
+        Self {
+            misregistration,
+            ..self
+        }
+    }
+    /// Sets the distortion map as the offsets \[m\] of the lenslet centers in the lenslet array
+    pub fn distortion(self, distortion: Vec<(f64, f64)>) -> Self {
+        Self {
+            distortion: Some(distortion),
+            ..self
+        }
+    }
+    /// Returns the number of lenslets
+    pub fn n_lenslet(&self) -> usize {
+        self.lenslet_array.n_side_lenslet.pow(2)
+    }
+    /// Checks that the distortion map, if any, has an offset for each lenslet
+    pub fn check_distortion(&self) -> crate::Result<()> {
+        match self.distortion.as_ref() {
+            Some(distortion) if distortion.len() != self.n_lenslet() => Err(
+                crate::CrseoError::Distortion(self.n_lenslet(), distortion.len()),
+            ),
+            _ => Ok(()),
+        }
+    }
+    // the geometry without the distortion map if the map does not match the lenslet array
+    pub(crate) fn checked(mut self) -> Self {
+        if let Err(e) = self.check_distortion() {
+            log::error!("{e}, the distortion map is ignored");
+            self.distortion = None;
+        }
+        self
+    }
+    // lenslet center in the lenslet array
+    fn center(&self, i: usize, j: usize) -> (f64, f64) {
+        let LensletArray {
+            n_side_lenslet, d, ..
+        } = self.lenslet_array;
+        let h = 0.5 * n_side_lenslet as f64;
+        let (x, y) = match self.packing {
+            Packing::Square => ((i as f64 + 0.5 - h) * d, (j as f64 + 0.5 - h) * d),
+            Packing::Hexagonal => {
+                // mean shift of the rows, so the lattice is centered on the pupil
+                let row_shift = 0.5 * (n_side_lenslet / 2) as f64 / n_side_lenslet as f64;
+                (
+                    (i as f64 + 0.5 + 0.5 * (j % 2) as f64 - row_shift - h) * d,
+                    (j as f64 + 0.5 - h) * d * 0.75f64.sqrt(),
+                )
+            }
+        };
+        self.distortion.as_ref().map_or((x, y), |distortion| {
+            let (dx, dy) = distortion[i + n_side_lenslet * j];
+            (x + dx, y + dy)
+        })
+    }
+    /// Returns `true` if the lenslets are square packed without distortion
+    pub fn is_square(&self) -> bool {
+        self.packing == Packing::Square && self.distortion.is_none()
+    }
+    /// Returns the \[x,y\] coordinates \[m\] of the rays that sample the lenslets of a square CEO lenslet array
+    ///
+    /// The rays are sampled on a square grid of `n_px`×`n_px` samples across `pupil_size` with `k=i+n_px*j`.
+    /// The samples of each lenslet of the square array, with a pitch of `pupil_size/n_side_lenslet`,
+    /// are moved to the lenslet with the same indices in this geometry.
+    /// The coordinates are given in the lenslet array, the misregistration is not applied
+    pub fn rays_coordinates(&self, n_px: usize, pupil_size: f64) -> (Vec<f64>, Vec<f64>) {
+        let n = self.lenslet_array.n_side_lenslet;
+        let d = pupil_size / n as f64;
+        let dx = pupil_size / (n_px - 1) as f64;
+        let h = 0.5 * n as f64;
+        let square = Self::from(self.lenslet_array.pitch(d));
+        let lenslet = |x: f64| ((x / d + h).floor().max(0.) as usize).min(n - 1);
+        (0..n_px * n_px)
+            .map(|k| {
+                let xy = (
+                    (k % n_px) as f64 * dx - 0.5 * pupil_size,
+                    (k / n_px) as f64 * dx - 0.5 * pupil_size,
+                );
+                let (i, j) = (lenslet(xy.0), lenslet(xy.1));
+                let (sx, sy) = square.center(i, j);
+                let (cx, cy) = self.center(i, j);
+                (xy.0 - sx + cx, xy.1 - sy + cy)
+            })
+            .unzip()
+    }
+    /// Returns the lenslet centers \[m\] in the pupil
+    pub fn lenslet_centers(&self) -> Vec<(f64, f64)> {
+        let n = self.lenslet_array.n_side_lenslet;
+        (0..n * n)
+            .map(|k| self.misregistration.to_pupil(self.center(k % n, k / n)))
+            .collect()
+    }
+    /// Returns the index of the lenslet that contains the point (`x`,`y`) \[m\] of the pupil
+    pub fn lenslet_index(&self, (x, y): (f64, f64)) -> Option<usize> {
+        let LensletArray {
+            n_side_lenslet, d, ..
+        } = self.lenslet_array;
+        let n = n_side_lenslet as i64;
+        let h = 0.5 * n_side_lenslet as f64;
+        let (u, v) = self.misregistration.to_lenslet((x, y));
+        let row_pitch = match self.packing {
+            Packing::Square => d,
+            Packing::Hexagonal => d * 0.75f64.sqrt(),
+        };
+        let j0 = (v / row_pitch + h).floor() as i64;
+        let i0 = (u / d + h).floor() as i64;
+        // nearest lenslet center among the neighbors of the nominal lenslet
+        let (k, (du, dv)) = (j0 - 1..=j0 + 1)
+            .flat_map(|j| (i0 - 1..=i0 + 1).map(move |i| (i, j)))
+            .filter(|(i, j)| *i >= 0 && *i < n && *j >= 0 && *j < n)
+            .map(|(i, j)| {
+                let (cu, cv) = self.center(i as usize, j as usize);
+                ((i + n * j) as usize, (u - cu, v - cv))
+            })
+            .min_by(|(_, a), (_, b)| a.0.hypot(a.1).total_cmp(&b.0.hypot(b.1)))?;
+        let inside = match self.packing {
+            Packing::Square => du.abs() <= 0.5 * d && dv.abs() <= 0.5 * d,
+            Packing::Hexagonal => {
+                du.abs() <= 0.5 * d && 0.5 * du.abs() + 0.75f64.sqrt() * dv.abs() <= 0.5 * d
+            }
+        };
+        inside.then_some(k)
+    }
+    /// Returns the geometric slopes \[rd\] `[sx,sy]` of the lenslets
+    ///
+    /// The slopes are the averages of the `phase` \[m\] gradient within the lenslets in the lenslet array coordinates.
+    /// The `amplitude` and the `phase` are sampled on a square grid of size `pupil_size` \[m\],
+    /// the slopes of the lenslets without any sample are 0
+    pub fn slopes(&self, amplitude: &[f32], phase: &[f32], pupil_size: f64) -> Vec<f64> {
+        let n = (amplitude.len() as f64).sqrt() as usize;
+        let dx = pupil_size / (n - 1) as f64;
+        let n_lenslet = self.n_lenslet();
+        let (s, c) = self.misregistration.rotation.sin_cos();
+        let mut sxy = vec![0f64; 2 * n_lenslet];
+        let mut count = vec![0usize; n_lenslet];
+        let valid = |i: usize, j: usize| amplitude[i + n * j] > 0.;
+        for j in 1..n - 1 {
+            for i in 1..n - 1 {
+                if !(valid(i, j)
+                    && valid(i - 1, j)
+                    && valid(i + 1, j)
+                    && valid(i, j - 1)
+                    && valid(i, j + 1))
+                {
+                    continue;
+                }
+                let xy = (
+                    (i as f64 - 0.5 * (n - 1) as f64) * dx,
+                    (j as f64 - 0.5 * (n - 1) as f64) * dx,
+                );
+                let Some(k) = self.lenslet_index(xy) else {
+                    continue;
+                };
+                let gx = (phase[i + 1 + n * j] - phase[i - 1 + n * j]) as f64 / (2. * dx);
+                let gy = (phase[i + n * (j + 1)] - phase[i + n * (j - 1)]) as f64 / (2. * dx);
+                sxy[k] += c * gx + s * gy;
+                sxy[k + n_lenslet] += c * gy - s * gx;
+                count[k] += 1;
+            }
+        }
+        let (sx, sy) = sxy.split_at_mut(n_lenslet);
+        sx.iter_mut()
+            .zip(sy.iter_mut())
+            .zip(&count)
+            .filter(|(_, c)| **c > 0)
+            .for_each(|((sx, sy), c)| {
+                *sx /= *c as f64;
+                *sy /= *c as f64;
+            });
+        sxy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tilt() {
+        let n = 65;
+        let amplitude = vec![1f32; n * n];
+        let phase: Vec<f32> = (0..n * n).map(|k| 1e-6 * (k % n) as f32 / 16.).collect();
+        let lenslet_array = LensletArray::default().n_side_lenslet(4).pitch(1. / 4.);
+        let geometry = LensletGeometry::from(lenslet_array);
+        let sxy = geometry.slopes(&amplitude, &phase, 4.);
+        assert!(sxy[..16].iter().all(|s| (s - 1e-6).abs() < 1e-10));
+        assert!(sxy[16..].iter().all(|s| s.abs() < 1e-12));
+        // the slopes rotate with the lenslet array
+        let geometry = LensletGeometry::from(lenslet_array)
+            .misregistration(Misregistration::default().rotation(std::f64::consts::FRAC_PI_2));
+        let sxy = geometry.slopes(&amplitude, &phase, 4.);
+        assert!(sxy[..16].iter().all(|s| s.abs() < 1e-12));
+        assert!(sxy[16..].iter().all(|s| (s + 1e-6).abs() < 1e-10));
+    }
+
+    #[test]
+    fn hexagonal() {
+        let n = 8;
+        let distortion = (0..n * n)
+            .map(|k| (1e-2 * (k % 3) as f64, -1e-2 * (k % 5) as f64))
+            .collect();
+        let geometry = LensletGeometry::from(LensletArray::default().n_side_lenslet(n))
+            .hexagonal()
+            .misregistration(
+                Misregistration::default()
+                    .shift(0.3, -0.1)
+                    .rotation(0.1)
+                    .magnification(1.05),
+            )
+            .distortion(distortion);
+        geometry
+            .lenslet_centers()
+            .into_iter()
+            .enumerate()
+            .for_each(|(k, xy)| assert_eq!(geometry.lenslet_index(xy), Some(k)));
+        assert_eq!(geometry.lenslet_index((30., 0.)), None);
+    }
+
+    #[test]
+    fn distortion_length() {
+        let geometry = LensletGeometry::from(LensletArray::default().n_side_lenslet(4))
+            .distortion(vec![(0., 0.); 9]);
+        assert!(matches!(
+            geometry.check_distortion(),
+            Err(crate::CrseoError::Distortion(16, 9))
+        ));
+        let geometry = geometry.checked();
+        assert!(geometry.is_square());
+        assert_eq!(geometry.rays_coordinates(17, 1.).0.len(), 17 * 17);
+    }
+
+    #[test]
+    fn hexagonal_centroid() {
+        for n in [7, 8] {
+            let geometry =
+                LensletGeometry::from(LensletArray::default().n_side_lenslet(n)).hexagonal();
+            let (x, y) = geometry
+                .lenslet_centers()
+                .into_iter()
+                .fold((0., 0.), |(x, y), (cx, cy)| (x + cx, y + cy));
+            let n_lenslet = geometry.n_lenslet() as f64;
+            assert!((x / n_lenslet).abs() < 1e-12 && (y / n_lenslet).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn rays_coordinates() {
+        let (n, n_px_lenslet) = (4, 4);
+        let n_px = n * n_px_lenslet + 1;
+        let lenslet_array = LensletArray::default().n_side_lenslet(n).pitch(1.);
+        // square lenslets are sampled on the regular grid
+        let (x, y) = LensletGeometry::from(lenslet_array).rays_coordinates(n_px, 4.);
+        (0..n_px * n_px).for_each(|k| {
+            assert!((x[k] - ((k % n_px) as f64 * 0.25 - 2.)).abs() < 1e-12);
+            assert!((y[k] - ((k / n_px) as f64 * 0.25 - 2.)).abs() < 1e-12);
+        });
+        // the samples at the center of the square lenslets are moved to the hexagonal lenslet centers
+        let geometry = LensletGeometry::from(lenslet_array).hexagonal();
+        let (x, y) = geometry.rays_coordinates(n_px, 4.);
+        let centers = geometry.lenslet_centers();
+        (0..n * n).for_each(|l| {
+            let k = (l % n) * n_px_lenslet + 2 + n_px * ((l / n) * n_px_lenslet + 2);
+            assert!((x[k] - centers[l].0).abs() < 1e-12);
+            assert!((y[k] - centers[l].1).abs() < 1e-12);
+        });
+    }
+}
@@ -0,0 +1,299 @@
+//!
+//! # Sodium laser guide star
+//!
+//! Models a laser guide star (LGS) created by the back-scattering of a laser beam in the mesospheric sodium layer.
+//!
+//! A [LaserGuideStar] is made of a [SodiumProfile] and of the position of the laser launch telescope in the pupil.
+//! It is attached to a [Source](crate::Source) with [SourceBuilder::laser_guide_star](crate::builders::SourceBuilder::laser_guide_star)
+//! which sets the source at the sodium centroid altitude, so the ray tracing through the [Atmosphere](crate::Atmosphere) layers accounts for the cone effect.
+//!
+//! The perspective elongation of the spots is applied to the detector frame of the diffractive
+//! [ShackHartmann](crate::ShackHartmann) each time a laser guide star is propagated through it.
+//! The sodium layer is evolved with [Source::evolve_sodium](crate::Source::evolve_sodium) which also moves the source to the new centroid altitude.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use crseo::{lgs::{LaserGuideStar, SodiumProfile}, Builder, FromBuilder, Source};
+//! let lgs = LaserGuideStar::new(SodiumProfile::new(90e3, 10e3)).launch_position((0., 15.));
+//! let mut src = Source::builder().laser_guide_star(lgs).build().unwrap();
+//! ```
+
+use rand::RngExt;
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+/// Sodium layer time variability
+///
+/// The centroid altitude and the thickness of the sodium layer are both modeled as
+/// Ornstein-Uhlenbeck processes around their nominal values
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SodiumVariability {
+    /// centroid altitude standard deviation \[m\]
+    pub altitude_rms: f64,
+    /// thickness standard deviation \[m\]
+    pub thickness_rms: f64,
+    /// correlation time \[s\]
+    pub time_constant: f64,
+}
+impl Default for SodiumVariability {
+    fn default() -> Self {
+        Self {
+            altitude_rms: 500.,
+            thickness_rms: 1e3,
+            time_constant: 60.,
+        }
+    }
+}
+
+/// Sodium layer density profile
+///
+/// The profile is a gaussian function of the altitude with a full width at half maximum given by the layer thickness
+///
+/// Default properties:
+///  - centroid altitude : 90km
+///  - thickness         : 10km
+///  - sampling          : 21 altitudes
+///  - variability       : none
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SodiumProfile {
+    /// centroid altitude \[m\]
+    pub altitude: f64,
+    /// thickness (full width at half maximum) \[m\]
+    pub thickness: f64,
+    /// number of altitude samples
+    pub n_sample: usize,
+    pub variability: Option<SodiumVariability>,
+    #[serde(skip)]
+    drift: (f64, f64),
+}
+impl Default for SodiumProfile {
+    fn default() -> Self {
+        Self {
+            altitude: 90e3,
+            thickness: 10e3,
+            n_sample: 21,
+            variability: None,
+            drift: (0., 0.),
+        }
+    }
+}
+impl SodiumProfile {
+    /// Creates a new sodium profile with the centroid `altitude` and the `thickness` in meters
+    pub fn new(altitude: f64, thickness: f64) -> Self {
+        Self {
+            altitude,
+            thickness,
+            ..Default::default()
+        }
+    }
+    /// Sets the number of altitude samples
+    pub fn n_sample(self, n_sample: usize) -> Self {
+        assert!(
+            n_sample > 0,
+            "the sodium profile must have at least 1 sample"
+        );
+        Self { n_sample, ..self }
+    }
+    /// Sets the time variability of the centroid altitude and of the thickness
+    pub fn variability(self, variability: SodiumVariability) -> Self {
+        Self {
+            variability: Some(variability),
+            ..self
+        }
+    }
+    /// Returns the current centroid altitude \[m\]
+    pub fn centroid_altitude(&self) -> f64 {
+        self.altitude + self.drift.0
+    }
+    /// Returns the current thickness \[m\]
+    pub fn current_thickness(&self) -> f64 {
+        (self.thickness + self.drift.1).max(0.)
+    }
+    /// Returns the sampled profile as pairs of altitude \[m\] and normalized density
+    ///
+    /// The profile is sampled over 3 times the thickness around the centroid altitude
+    pub fn density(&self) -> Vec<(f64, f64)> {
+        let h0 = self.centroid_altitude();
+        let fwhm = self.current_thickness();
+        if self.n_sample == 1 || fwhm == 0. {
+            return vec![(h0, 1.)];
+        }
+        let sigma = fwhm / (8. * 2f64.ln()).sqrt();
+        let dh = 3. * fwhm / (self.n_sample - 1) as f64;
+        let samples: Vec<_> = (0..self.n_sample)
+            .map(|i| {
+                let h = h0 + (i as f64 - 0.5 * (self.n_sample - 1) as f64) * dh;
+                (h, (-0.5 * ((h - h0) / sigma).powi(2)).exp())
+            })
+            .collect();
+        let sum: f64 = samples.iter().map(|(_, w)| w).sum();
+        samples.into_iter().map(|(h, w)| (h, w / sum)).collect()
+    }
+    /// Evolves the centroid altitude and the thickness by `secs` seconds
+    ///
+    /// Does nothing if the profile has no [SodiumVariability]
+    pub fn evolve<R: rand::Rng>(&mut self, secs: f64, rng: &mut R) {
+        let Some(SodiumVariability {
+            altitude_rms,
+            thickness_rms,
+            time_constant,
+        }) = self.variability
+        else {
+            return;
+        };
+        let a = (-secs / time_constant).exp();
+        let b = (1. - a * a).sqrt();
+        let mut normal = || rng.sample::<f64, _>(StandardNormal);
+        self.drift.0 = a * self.drift.0 + b * altitude_rms * normal();
+        self.drift.1 = a * self.drift.1 + b * thickness_rms * normal();
+    }
+}
+
+/// Laser guide star
+///
+/// Default properties:
+///  - sodium profile  : [SodiumProfile::default]
+///  - launch position : pupil center
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct LaserGuideStar {
+    pub sodium: SodiumProfile,
+    /// launch telescope \[x,y\] coordinates in the pupil \[m\]
+    pub launch: (f64, f64),
+}
+impl LaserGuideStar {
+    /// Creates a new laser guide star from a sodium profile
+    pub fn new(sodium: SodiumProfile) -> Self {
+        Self {
+            sodium,
+            ..Default::default()
+        }
+    }
+    /// Sets the launch telescope \[x,y\] coordinates in the pupil \[m\]
+    pub fn launch_position(self, launch: (f64, f64)) -> Self {
+        Self { launch, ..self }
+    }
+    /// Returns the altitude \[m\] of the source at the centroid of the sodium layer
+    pub fn height(&self) -> f64 {
+        self.sodium.centroid_altitude()
+    }
+    /// Returns the elongation kernel of the lenslet centered at the \[x,y\] pupil coordinates
+    ///
+    /// The kernel is a `n_px`x`n_px` image, with the pixel `k = i + n_px*j`, sampled at the detector `pixel_scale` \[rd\]
+    pub fn kernel(&self, xy: (f64, f64), pixel_scale: f64, n_px: usize) -> Vec<f32> {
+        let (dx, dy) = (xy.0 - self.launch.0, xy.1 - self.launch.1);
+        let h0 = self.height();
+        let c = 0.5 * (n_px - 1) as f64;
+        let mut kernel = vec![0f32; n_px * n_px];
+        for (h, w) in self.sodium.density() {
+            let s = 1. / h - 1. / h0;
+            let x = c + dx * s / pixel_scale;
+            let y = c + dy * s / pixel_scale;
+            let (i, j) = (x.floor(), y.floor());
+            let (u, v) = (x - i, y - j);
+            for (ii, jj, wk) in [
+                (i, j, (1. - u) * (1. - v)),
+                (i + 1., j, u * (1. - v)),
+                (i, j + 1., (1. - u) * v),
+                (i + 1., j + 1., u * v),
+            ] {
+                if ii >= 0. && jj >= 0. && (ii as usize) < n_px && (jj as usize) < n_px {
+                    kernel[ii as usize + n_px * jj as usize] += (w * wk) as f32;
+                }
+            }
+        }
+        kernel
+    }
+    /// Elongates the spots of a Shack-Hartmann detector frame
+    ///
+    /// The frame is made of `n_side_lenslet`x`n_side_lenslet` framelets of `n_px_framelet`x`n_px_framelet` pixels,
+    /// `lenslet_size` is the lenslet pitch \[m\] and `pixel_scale` the detector pixel scale \[rd\].
+    /// Each framelet is convolved with the elongation kernel of the lenslet,
+    /// the light elongated beyond the framelet is lost.
+    /// The frame may contain several sensors stacked one after the other.
+    pub fn elongate(
+        &self,
+        frame: &mut [f32],
+        n_side_lenslet: usize,
+        n_px_framelet: usize,
+        lenslet_size: f64,
+        pixel_scale: f64,
+    ) {
+        let n_px = n_side_lenslet * n_px_framelet;
+        let n_kernel = 2 * n_px_framelet - 1;
+        let ck = n_px_framelet as i64 - 1;
+        for frame in frame.chunks_mut(n_px * n_px) {
+            for j_let in 0..n_side_lenslet {
+                for i_let in 0..n_side_lenslet {
+                    let xy = (
+                        (i_let as f64 + 0.5 - 0.5 * n_side_lenslet as f64) * lenslet_size,
+                        (j_let as f64 + 0.5 - 0.5 * n_side_lenslet as f64) * lenslet_size,
+                    );
+                    let kernel = self.kernel(xy, pixel_scale, n_kernel);
+                    let (i0, j0) = (i_let * n_px_framelet, j_let * n_px_framelet);
+                    let framelet: Vec<f32> = (0..n_px_framelet)
+                        .flat_map(|j| {
+                            let k = i0 + n_px * (j0 + j);
+                            frame[k..k + n_px_framelet].to_vec()
+                        })
+                        .collect();
+                    for j in 0..n_px_framelet {
+                        for i in 0..n_px_framelet {
+                            let mut value = 0f32;
+                            for (l, &f) in framelet.iter().enumerate() {
+                                if f == 0. {
+                                    continue;
+                                }
+                                let (fi, fj) =
+                                    ((l % n_px_framelet) as i64, (l / n_px_framelet) as i64);
+                                let ki = i as i64 - fi + ck;
+                                let kj = j as i64 - fj + ck;
+                                value += f * kernel[(ki + n_kernel as i64 * kj) as usize];
+                            }
+                            frame[i0 + i + n_px * (j0 + j)] = value;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_flux() {
+        let lgs = LaserGuideStar::default().launch_position((0., 15.));
+        let kernel = lgs.kernel((0., -10.), 5e-6, 31);
+        let flux: f32 = kernel.iter().sum();
+        assert!((flux - 1.).abs() < 1e-5);
+        let on_launch: f32 = lgs.kernel((0., 15.), 5e-6, 31).iter().sum();
+        assert!((on_launch - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn source_sodium() {
+        use crate::{builders::SourceBuilder, Builder, FromBuilder, Source};
+        use rand::SeedableRng;
+        let lgs = LaserGuideStar::new(SodiumProfile::new(90e3, 10e3).variability(
+            SodiumVariability {
+                altitude_rms: 500.,
+                thickness_rms: 0.,
+                time_constant: 1.,
+            },
+        ));
+        let mut src = Source::builder()
+            .laser_guide_star(lgs.clone())
+            .build()
+            .unwrap();
+        assert_eq!(SourceBuilder::from(&src).laser_guide_star, Some(lgs));
+        let h0 = src.height();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        src.evolve_sodium(1., &mut rng);
+        let h = src.laser_guide_star().unwrap().height();
+        assert!(h != h0);
+        assert!((src.height() - h).abs() < 1e-2 * h);
+    }
+}
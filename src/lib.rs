@@ -28,10 +28,12 @@ pub mod error;
 pub mod fwhm;
 pub mod gmt;
 pub mod imaging;
+pub mod lgs;
 pub mod lmmse;
+pub mod metrics;
 pub mod pssn;
 pub mod raytracing;
-//pub mod sensitivities;
+pub mod sensitivities;
 // pub mod piston_sensor;
 pub mod segment_piston_sensor;
 pub mod source;
@@ -60,9 +62,9 @@ pub use lmmse::LinearMinimumMeanSquareError;
 pub use segment_piston_sensor::SegmentPistonSensor;
 
 #[doc(inline)]
-pub use pssn::{PSSn, PSSnEstimates};
-//#[doc(inline)]
-//pub use sensitivities::OpticalSensitivities;
+pub use pssn::{HostPSSn, PSSn, PSSnEstimates};
+#[doc(inline)]
+pub use sensitivities::{LinearOpticalModel, OpticalSensitivities};
 // #[doc(inline)]
 // pub use piston_sensor::{PistonSensor, PistonSensorBuilder};
 #[doc(inline)]
@@ -0,0 +1,488 @@
+//!
+//! # Image quality metrics
+//!
+//! Image quality metrics computed on the host from [Frame]s or from optical transfer functions.
+//!
+//! An [Image] is a square image of `n`x`n` pixels with pixel index `k = i + n*j`
+//! and an angular pixel scale; the image coordinates are given with respect to the image center
+//! at pixel `((n-1)/2,(n-1)/2)`, and lengths are in units of the pixel scale.
+//!
+//! ```no_run
+//! use crseo::metrics::{Image, Profile};
+//! # let frame: crseo::imaging::Frame = vec![0f32; 64 * 64].into();
+//! for image in Image::from_frame(&frame, 1e-3 / 206265.) {
+//!     let ee80 = image.ee80()?;
+//!     let fit = image.fit(Profile::Moffat)?;
+//!     println!("EE80: {ee80:e}, FWHM: {:e}", fit.fwhm());
+//! }
+//! # Ok::<(), crseo::metrics::MetricsError>(())
+//! ```
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::imaging::Frame;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetricsError {
+    #[error("the image flux is not positive")]
+    Flux,
+    #[error("the image energy never reaches {0} of the total flux")]
+    Fraction(f64),
+    #[error("the {0:?} profile fit did not converge")]
+    Fit(Profile),
+}
+pub type Result<T> = std::result::Result<T, MetricsError>;
+
+/// Number of sub-pixels per pixel side used to integrate the energy curves
+const SUBPIXEL: usize = 8;
+
+/// Square image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    data: Vec<f64>,
+    n: usize,
+    pixel_scale: f64,
+}
+impl Image {
+    /// Creates a new image from `n`x`n` pixels with the given pixel scale
+    pub fn new(data: &[f32], n: usize, pixel_scale: f64) -> Self {
+        assert_eq!(data.len(), n * n, "expected {}x{} pixels", n, n);
+        Self {
+            data: data.iter().map(|&x| x as f64).collect(),
+            n,
+            pixel_scale,
+        }
+    }
+    /// Returns the images of the [Frame], one per source
+    pub fn from_frame(frame: &Frame, pixel_scale: f64) -> Vec<Self> {
+        let n = frame.resolution;
+        let data: Vec<f32> = frame.into();
+        data.chunks(n * n)
+            .map(|data| Self::new(data, n, pixel_scale))
+            .collect()
+    }
+    /// Returns the image pixels
+    pub fn data(&self) -> &[f64] {
+        &self.data
+    }
+    /// Returns the image size in pixels
+    pub fn n_px(&self) -> usize {
+        self.n
+    }
+    /// Returns the pixel scale
+    pub fn pixel_scale(&self) -> f64 {
+        self.pixel_scale
+    }
+    /// Returns the image total flux
+    pub fn flux(&self) -> f64 {
+        self.data.iter().sum()
+    }
+    // pixel coordinates with respect to the image center
+    fn xy(&self, k: usize) -> (f64, f64) {
+        let c = 0.5 * (self.n - 1) as f64;
+        ((k % self.n) as f64 - c, (k / self.n) as f64 - c)
+    }
+    fn positive_flux(&self) -> Result<f64> {
+        let flux = self.flux();
+        if flux > 0. {
+            Ok(flux)
+        } else {
+            Err(MetricsError::Flux)
+        }
+    }
+    /// Returns the image centroid
+    pub fn centroid(&self) -> Result<(f64, f64)> {
+        let (cx, cy) = self.centroid_px()?;
+        Ok((cx * self.pixel_scale, cy * self.pixel_scale))
+    }
+    fn centroid_px(&self) -> Result<(f64, f64)> {
+        let flux = self.positive_flux()?;
+        let (sx, sy) = self
+            .data
+            .iter()
+            .enumerate()
+            .fold((0., 0.), |(sx, sy), (k, &f)| {
+                let (x, y) = self.xy(k);
+                (sx + x * f, sy + y * f)
+            });
+        Ok((sx / flux, sy / flux))
+    }
+    /// Returns the image second order central moments `(xx,yy,xy)`
+    pub fn second_moments(&self) -> Result<(f64, f64, f64)> {
+        let (xx, yy, xy) = self.second_moments_px()?;
+        let s2 = self.pixel_scale * self.pixel_scale;
+        Ok((xx * s2, yy * s2, xy * s2))
+    }
+    fn second_moments_px(&self) -> Result<(f64, f64, f64)> {
+        let flux = self.positive_flux()?;
+        let (cx, cy) = self.centroid_px()?;
+        let (xx, yy, xy) =
+            self.data
+                .iter()
+                .enumerate()
+                .fold((0., 0., 0.), |(xx, yy, xy), (k, &f)| {
+                    let (x, y) = self.xy(k);
+                    let (x, y) = (x - cx, y - cy);
+                    (xx + x * x * f, yy + y * y * f, xy + x * y * f)
+                });
+        Ok((xx / flux, yy / flux, xy / flux))
+    }
+    /// Returns the image ellipticity from the second order moments
+    ///
+    /// The ellipticity is `1-b/a` where `a` and `b` are the major and minor axis
+    pub fn ellipticity(&self) -> Result<f64> {
+        let (xx, yy, xy) = self.second_moments_px()?;
+        let (l1, l2) = eigenvalues(xx, yy, xy);
+        Ok(1. - (l2.max(0.) / l1).sqrt())
+    }
+    /// Returns the noise equivalent area
+    ///
+    /// The NEA is given by `(sum(I))^2/sum(I^2)` in units of the pixel scale squared
+    pub fn nea(&self) -> Result<f64> {
+        let flux = self.positive_flux()?;
+        let s: f64 = self.data.iter().map(|x| x * x).sum();
+        Ok(flux * flux / s * self.pixel_scale * self.pixel_scale)
+    }
+    /// Returns the Strehl ratio as the ratio of the normalized peaks of the image and of the `reference` image
+    ///
+    /// The reference is the diffraction limited image sampled as the image
+    pub fn strehl_ratio(&self, reference: &Image) -> Result<f64> {
+        let peak = |image: &Image| -> Result<f64> {
+            let flux = image.positive_flux()?;
+            Ok(image.data.iter().cloned().fold(f64::MIN, f64::max) / flux)
+        };
+        Ok(peak(self)? / peak(reference)?)
+    }
+    // cumulated energy within the sub-pixel distances to the centroid,
+    // the sub-pixels are binned by distance with a bin width of 1/SUBPIXEL^2 pixel
+    fn cumulative_energy(&self, distance: impl Fn(f64, f64) -> f64) -> Result<Vec<(f64, f64)>> {
+        let flux = self.positive_flux()?;
+        let (cx, cy) = self.centroid_px()?;
+        let d = 1. / SUBPIXEL as f64;
+        let w = d * d / flux;
+        let bin = d * d;
+        let mut energy = vec![0f64; 1];
+        self.data.iter().enumerate().for_each(|(k, &f)| {
+            let (x, y) = self.xy(k);
+            for l in 0..SUBPIXEL * SUBPIXEL {
+                let u = x - cx - 0.5 + d * (0.5 + (l % SUBPIXEL) as f64);
+                let v = y - cy - 0.5 + d * (0.5 + (l / SUBPIXEL) as f64);
+                let i = (distance(u, v) / bin).ceil() as usize;
+                if i >= energy.len() {
+                    energy.resize(i + 1, 0.);
+                }
+                energy[i] += f * w;
+            }
+        });
+        let mut cum = 0.;
+        Ok(energy
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| {
+                cum += e;
+                (i as f64 * bin, cum)
+            })
+            .collect())
+    }
+    fn energy_at(energy: &[(f64, f64)], size: f64) -> f64 {
+        match energy.partition_point(|(d, _)| *d <= size) {
+            0 => 0.,
+            i => energy[i - 1].1,
+        }
+    }
+    fn size_at(&self, energy: &[(f64, f64)], fraction: f64) -> Result<f64> {
+        energy
+            .iter()
+            .find(|(_, e)| *e >= fraction)
+            .map(|(d, _)| 2. * d * self.pixel_scale)
+            .ok_or(MetricsError::Fraction(fraction))
+    }
+    /// Returns the fraction of the total flux encircled within circles of the given `diameters`
+    ///
+    /// The circles are centered on the image centroid
+    pub fn encircled_energy(&self, diameters: &[f64]) -> Result<Vec<f64>> {
+        let energy = self.cumulative_energy(f64::hypot)?;
+        Ok(diameters
+            .iter()
+            .map(|d| Self::energy_at(&energy, 0.5 * d / self.pixel_scale))
+            .collect())
+    }
+    /// Returns the fraction of the total flux within squares of the given `widths`
+    ///
+    /// The squares are centered on the image centroid
+    pub fn ensquared_energy(&self, widths: &[f64]) -> Result<Vec<f64>> {
+        let energy = self.cumulative_energy(|x, y| x.abs().max(y.abs()))?;
+        Ok(widths
+            .iter()
+            .map(|w| Self::energy_at(&energy, 0.5 * w / self.pixel_scale))
+            .collect())
+    }
+    /// Returns the diameter of the circle that encircles the `fraction` of the total flux
+    pub fn encircled_energy_diameter(&self, fraction: f64) -> Result<f64> {
+        let energy = self.cumulative_energy(f64::hypot)?;
+        self.size_at(&energy, fraction)
+    }
+    /// Returns the width of the square that contains the `fraction` of the total flux
+    pub fn ensquared_energy_width(&self, fraction: f64) -> Result<f64> {
+        let energy = self.cumulative_energy(|x, y| x.abs().max(y.abs()))?;
+        self.size_at(&energy, fraction)
+    }
+    /// Returns the diameter of the circle that encircles 50% of the total flux
+    pub fn ee50(&self) -> Result<f64> {
+        self.encircled_energy_diameter(0.5)
+    }
+    /// Returns the diameter of the circle that encircles 80% of the total flux
+    pub fn ee80(&self) -> Result<f64> {
+        self.encircled_energy_diameter(0.8)
+    }
+    /// Fits a 2D elliptical [Profile] to the image
+    pub fn fit(&self, profile: Profile) -> Result<Fit> {
+        let (xx, yy, xy) = self.second_moments_px()?;
+        let (cx, cy) = self.centroid_px()?;
+        let (l1, l2) = eigenvalues(xx, yy, xy);
+        let angle = 0.5 * (2. * xy).atan2(xx - yy);
+        let (peak, background) = self
+            .data
+            .iter()
+            .fold((f64::MIN, f64::MAX), |(a, b), &x| (a.max(x), b.min(x)));
+        let (s1, s2) = (l1.sqrt().max(0.5), l2.max(0.).sqrt().max(0.5));
+        let p0 = match profile {
+            Profile::Gaussian => vec![peak - background, cx, cy, s1, s2, angle, background],
+            Profile::Moffat => vec![
+                peak - background,
+                cx,
+                cy,
+                2.08 * s1,
+                2.08 * s2,
+                angle,
+                background,
+                2.5,
+            ],
+        };
+        let xy: Vec<_> = (0..self.data.len()).map(|k| self.xy(k)).collect();
+        let model = |p: &[f64], (x, y): (f64, f64)| -> f64 {
+            let (c, s) = (p[5].cos(), p[5].sin());
+            let (dx, dy) = (x - p[1], y - p[2]);
+            let u = (c * dx + s * dy) / p[3];
+            let v = (-s * dx + c * dy) / p[4];
+            let r2 = u * u + v * v;
+            match profile {
+                Profile::Gaussian => p[6] + p[0] * (-0.5 * r2).exp(),
+                Profile::Moffat => p[6] + p[0] * (1. + r2).powf(-p[7]),
+            }
+        };
+        let (p, residual) = levenberg_marquardt(p0, |p| {
+            xy.iter()
+                .zip(&self.data)
+                .map(|(&xy, f)| model(p, xy) - f)
+                .collect()
+        })
+        .ok_or(MetricsError::Fit(profile))?;
+        let (w1, w2) = match profile {
+            Profile::Gaussian => (2. * (2f64.ln() * 2.).sqrt(), 2. * (2f64.ln() * 2.).sqrt()),
+            Profile::Moffat => {
+                let w = 2. * (2f64.powf(1. / p[7]) - 1.).sqrt();
+                (w, w)
+            }
+        };
+        let (a, b) = ((w1 * p[3]).abs(), (w2 * p[4]).abs());
+        let (major, minor, angle) = if a >= b {
+            (a, b, p[5])
+        } else {
+            (b, a, p[5] + std::f64::consts::FRAC_PI_2)
+        };
+        let ps = self.pixel_scale;
+        Ok(Fit {
+            profile,
+            amplitude: p[0],
+            background: p[6],
+            centroid: (p[1] * ps, p[2] * ps),
+            fwhm: (major * ps, minor * ps),
+            angle: angle.rem_euclid(std::f64::consts::PI),
+            beta: match profile {
+                Profile::Gaussian => None,
+                Profile::Moffat => Some(p[7]),
+            },
+            residual,
+        })
+    }
+}
+
+/// 2D elliptical profiles
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Profile {
+    /// `exp(-r^2/2)`
+    Gaussian,
+    /// `(1+r^2)^(-beta)`
+    Moffat,
+}
+
+/// 2D elliptical profile fit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fit {
+    pub profile: Profile,
+    /// profile peak above the background
+    pub amplitude: f64,
+    pub background: f64,
+    pub centroid: (f64, f64),
+    /// major and minor axis full width at half maximum
+    pub fwhm: (f64, f64),
+    /// major axis angle \[rd\] with respect to the x-axis
+    pub angle: f64,
+    /// Moffat power index
+    pub beta: Option<f64>,
+    /// sum of the squared residuals
+    pub residual: f64,
+}
+impl Fit {
+    /// Returns the geometric mean of the major and minor axis full width at half maximum
+    pub fn fwhm(&self) -> f64 {
+        (self.fwhm.0 * self.fwhm.1).sqrt()
+    }
+    /// Returns the fit ellipticity `1-minor/major`
+    pub fn ellipticity(&self) -> f64 {
+        1. - self.fwhm.1 / self.fwhm.0
+    }
+}
+
+/// Returns the Strehl ratio from the volumes of the `otf` and of the `reference` OTF
+///
+/// Both OTFs are given as interleaved real and imaginary parts with the zero frequency
+/// at the first element, as returned by [PSSn::telescope_error_otf](crate::PSSn::telescope_error_otf)
+pub fn otf_strehl_ratio(otf: &[f32], reference: &[f32]) -> f64 {
+    let volume = |otf: &[f32]| -> f64 {
+        otf.iter().step_by(2).map(|&x| x as f64).sum::<f64>() / otf[0] as f64
+    };
+    volume(otf) / volume(reference)
+}
+
+// eigenvalues of the symmetric 2x2 matrix [[xx,xy],[xy,yy]], largest first
+fn eigenvalues(xx: f64, yy: f64, xy: f64) -> (f64, f64) {
+    let m = 0.5 * (xx + yy);
+    let d = (0.25 * (xx - yy).powi(2) + xy * xy).sqrt();
+    (m + d, m - d)
+}
+
+// Levenberg-Marquardt least squares with a forward difference Jacobian
+fn levenberg_marquardt(
+    mut p: Vec<f64>,
+    residuals: impl Fn(&[f64]) -> Vec<f64>,
+) -> Option<(Vec<f64>, f64)> {
+    let chi2 = |r: &[f64]| r.iter().map(|x| x * x).sum::<f64>();
+    let mut r = residuals(&p);
+    let mut c = chi2(&r);
+    let mut lambda = 1e-3;
+    for _ in 0..200 {
+        let jacobian = DMatrix::from_fn(r.len(), p.len(), |_, _| 0.);
+        let jacobian = (0..p.len()).fold(jacobian, |mut jacobian, j| {
+            let h = 1e-6 * p[j].abs().max(1e-6);
+            let mut q = p.clone();
+            q[j] += h;
+            residuals(&q)
+                .iter()
+                .zip(&r)
+                .enumerate()
+                .for_each(|(i, (rq, r))| jacobian[(i, j)] = (rq - r) / h);
+            jacobian
+        });
+        let jtj = jacobian.transpose() * &jacobian;
+        let jtr = jacobian.transpose() * DVector::from_column_slice(&r);
+        loop {
+            let mut a = jtj.clone();
+            for i in 0..p.len() {
+                a[(i, i)] += lambda * jtj[(i, i)].max(1e-12);
+            }
+            let delta = a.lu().solve(&(-&jtr))?;
+            let q: Vec<f64> = p.iter().zip(delta.iter()).map(|(p, d)| p + d).collect();
+            let rq = residuals(&q);
+            let cq = chi2(&rq);
+            if cq.is_finite() && cq <= c {
+                let converged = (c - cq) <= 1e-12 * c.max(f64::MIN_POSITIVE);
+                p = q;
+                r = rq;
+                c = cq;
+                lambda = (lambda / 10.).max(1e-12);
+                if converged {
+                    return Some((p, c));
+                }
+                break;
+            }
+            lambda *= 10.;
+            if lambda > 1e12 {
+                // no step decreases the residuals: the fit has converged only if
+                // the Gauss-Newton step does not predict any significant decrease
+                let mut a = jtj.clone();
+                for i in 0..p.len() {
+                    a[(i, i)] += 1e-12 * jtj[(i, i)].max(1e-12);
+                }
+                let delta = a.lu().solve(&(-&jtr))?;
+                let predicted = -jtr.dot(&delta);
+                return (predicted <= 1e-8 * c).then_some((p, c));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gaussian(n: usize, sigma: f64) -> Image {
+        let c = 0.5 * (n - 1) as f64;
+        let data: Vec<f32> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64 - c - 0.3, (k / n) as f64 - c + 0.2);
+                (-0.5 * (x * x + y * y) / (sigma * sigma)).exp() as f32
+            })
+            .collect();
+        Image::new(&data, n, 1.)
+    }
+
+    #[test]
+    fn gaussian_metrics() {
+        let sigma = 3.;
+        let image = gaussian(64, sigma);
+        let fwhm = 2. * (2. * 2f64.ln()).sqrt() * sigma;
+        let (cx, cy) = image.centroid().unwrap();
+        assert!((cx - 0.3).abs() < 1e-6 && (cy + 0.2).abs() < 1e-6);
+        // the EE50 diameter of a Gaussian is its FWHM
+        let ee50 = image.ee50().unwrap();
+        assert!((ee50 / fwhm - 1.).abs() < 1e-2, "EE50: {ee50}");
+        let nea = image.nea().unwrap();
+        let nea0 = 4. * std::f64::consts::PI * sigma * sigma;
+        assert!((nea / nea0 - 1.).abs() < 1e-3, "NEA: {nea}");
+        let fit = image.fit(Profile::Gaussian).unwrap();
+        assert!(
+            (fit.fwhm() / fwhm - 1.).abs() < 1e-4,
+            "FWHM: {}",
+            fit.fwhm()
+        );
+        assert!(fit.ellipticity() < 1e-4);
+        assert!(image.ellipticity().unwrap() < 1e-4);
+    }
+
+    #[test]
+    fn levenberg_marquardt_convergence() {
+        let (p, c) = levenberg_marquardt(vec![0., 0.], |p| {
+            (0..10)
+                .map(|x| p[0] + p[1] * x as f64 - (1. + 2. * x as f64))
+                .collect()
+        })
+        .unwrap();
+        assert!((p[0] - 1.).abs() < 1e-6 && (p[1] - 2.).abs() < 1e-6 && c < 1e-12);
+        // the residuals increase in any direction from a point that is not a minimum
+        assert!(
+            levenberg_marquardt(vec![0.], |p| vec![if p[0] == 0. { 1. } else { 2. }]).is_none()
+        );
+    }
+
+    #[test]
+    fn strehl_ratio() {
+        let reference = gaussian(64, 2.);
+        let image = gaussian(64, 4.);
+        let sr = image.strehl_ratio(&reference).unwrap();
+        assert!((sr - 0.25).abs() < 1e-2, "Strehl: {sr}");
+    }
+}
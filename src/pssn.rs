@@ -8,6 +8,9 @@ use ffi::pssn;
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::fmt;
 
+pub mod host;
+pub use host::HostPSSn;
+
 /// CEO PSSn estimator
 ///
 #[derive(Debug, Clone)]
@@ -0,0 +1,314 @@
+//!
+//! # Host PSSn estimator
+//!
+//! A CPU implementation of the normalized point source sensitivity (PSSn).
+//!
+//! [HostPSSn] computes the telescope OTF from the exit pupil amplitude and phase
+//! by autocorrelation of the complex amplitude with FFTs,
+//! and combines it with the von Kármán atmospheric OTF.
+//! The OTFs are accumulated over time steps as with [PSSn::integrate](crate::PSSn::integrate)
+//! and the PSSn values are given by [HostPSSn::peek].
+//!
+//! The telescope OTF and the accumulation work on slices, so the PSSn can be computed
+//! from phase maps without a GPU; [Source](crate::Source) based methods are provided for convenience.
+
+use std::{marker::PhantomData, sync::Arc};
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+use super::{AtmosphereTelescopeError, PSSnErrors, TelescopeError};
+use crate::{atmosphere::theory::VonKarman, Propagation, Source};
+
+type FftPair = (Arc<dyn Fft<f64>>, Arc<dyn Fft<f64>>);
+
+/// Host PSSn estimator
+pub struct HostPSSn<S> {
+    pub r0_at_zenith: f64,
+    pub oscale: f64,
+    pub zenith_angle: f64,
+    pub wavelength: f64,
+    /// PSSn estimates
+    pub estimates: Vec<f64>,
+    n_px: usize,
+    n_otf: usize,
+    fft: Option<FftPair>,
+    atmosphere_otf: Vec<f64>,
+    reference_otf: Vec<Vec<Complex<f64>>>,
+    otf: Vec<Vec<Complex<f64>>>,
+    n_sample: usize,
+    mode: PhantomData<S>,
+}
+impl<S: PSSnErrors> HostPSSn<S> {
+    /// Creates a new `HostPSSn` from r0 at zenith \[m\] at 500nm, the outer scale \[m\] and the zenith angle \[rd\]
+    pub fn new(r0_at_zenith: f64, oscale: f64, zenith_angle: f64) -> Self {
+        Self {
+            r0_at_zenith,
+            oscale,
+            zenith_angle,
+            wavelength: 500e-9,
+            estimates: vec![],
+            n_px: 0,
+            n_otf: 0,
+            fft: None,
+            atmosphere_otf: vec![],
+            reference_otf: vec![],
+            otf: vec![],
+            n_sample: 0,
+            mode: PhantomData,
+        }
+    }
+    /// Returns r0 \[m\] along the line of sight at the wavelength
+    pub fn r0(&self) -> f64 {
+        (self.r0_at_zenith.powf(-5. / 3.) / self.zenith_angle.cos()).powf(-3. / 5.)
+            * (self.wavelength / 500e-9).powf(1.2)
+    }
+    /// Initializes the atmosphere and the reference telescope transfer functions
+    ///
+    /// `amplitude` and `opd` \[m\] are the exit pupil amplitude and optical path difference
+    /// of one or several sources, each one sampled with `n_px`x`n_px` pixels across the `pupil_size` \[m\]
+    pub fn reference_from(
+        &mut self,
+        amplitude: &[f32],
+        opd: &[f32],
+        n_px: usize,
+        pupil_size: f64,
+        wavelength: f64,
+    ) -> &mut Self {
+        assert_eq!(amplitude.len(), opd.len());
+        self.n_px = n_px;
+        self.n_otf = 2 * n_px;
+        self.wavelength = wavelength;
+        let mut planner = FftPlanner::new();
+        self.fft = Some((
+            planner.plan_fft_forward(self.n_otf),
+            planner.plan_fft_inverse(self.n_otf),
+        ));
+        self.atmosphere_otf = self.von_karman_otf(pupil_size / (n_px - 1) as f64);
+        self.reference_otf = amplitude
+            .chunks(n_px * n_px)
+            .zip(opd.chunks(n_px * n_px))
+            .map(|(a, p)| self.telescope_otf(a, p))
+            .collect();
+        self.estimates = vec![0.; self.reference_otf.len()];
+        self.reset();
+        self
+    }
+    /// Initializes the atmosphere and the reference telescope transfer functions from a `Source`
+    pub fn reference(&mut self, src: &mut Source) -> &mut Self {
+        let amplitude = src.amplitude();
+        let opd = src.phase().clone();
+        let n_px = src.pupil_sampling as usize;
+        let (pupil_size, wavelength) = (src.pupil_size, src.wavelength());
+        self.reference_from(&amplitude, &opd, n_px, pupil_size, wavelength)
+    }
+    /// Integrates the telescope optical transfer function
+    pub fn accumulate_from(&mut self, amplitude: &[f32], opd: &[f32]) {
+        assert!(
+            !self.reference_otf.is_empty(),
+            "HostPSSn reference is not set"
+        );
+        let n = self.n_px * self.n_px;
+        let otfs: Vec<_> = amplitude
+            .chunks(n)
+            .zip(opd.chunks(n))
+            .map(|(a, p)| self.telescope_otf(a, p))
+            .collect();
+        if self.otf.is_empty() {
+            self.otf = otfs;
+        } else {
+            self.otf.iter_mut().zip(otfs).for_each(|(otf, o)| {
+                otf.iter_mut().zip(o).for_each(|(x, y)| *x += y);
+            });
+        }
+        self.n_sample += 1;
+    }
+    /// Integrates the `Source` optical transfer function
+    pub fn integrate(&mut self, src: &mut Source) {
+        let amplitude = src.amplitude();
+        let opd = src.phase().clone();
+        self.accumulate_from(&amplitude, &opd);
+    }
+    /// Resets the integrated optical transfer function
+    pub fn reset(&mut self) -> &mut Self {
+        self.otf.clear();
+        self.n_sample = 0;
+        self
+    }
+    /// Returns the number of integrated samples
+    pub fn n_sample(&self) -> usize {
+        self.n_sample
+    }
+    /// Returns the mean telescope OTF of each source as interleaved real and imaginary parts
+    pub fn telescope_error_otf(&self) -> Vec<f32> {
+        let n = self.n_sample.max(1) as f64;
+        self.otf
+            .iter()
+            .flat_map(|otf| {
+                otf.iter()
+                    .flat_map(|x| [(x.re / n) as f32, (x.im / n) as f32])
+            })
+            .collect()
+    }
+    /// Returns the atmosphere OTF
+    pub fn atmosphere_otf(&self) -> &[f64] {
+        &self.atmosphere_otf
+    }
+    fn von_karman_otf(&self, pixel_size: f64) -> Vec<f64> {
+        let atm = VonKarman::new(self.r0(), self.oscale);
+        let n = self.n_otf;
+        // radial look-up table of the structure function
+        let r_max = pixel_size * n as f64 * std::f64::consts::FRAC_1_SQRT_2 + pixel_size;
+        let n_r = 2 * n;
+        let dr = r_max / (n_r - 1) as f64;
+        let otf_r: Vec<f64> = (0..n_r)
+            .map(|i| atm.long_exposure_otf(i as f64 * dr))
+            .collect();
+        let wrap = |i: usize| {
+            if i < n / 2 {
+                i as f64
+            } else {
+                i as f64 - n as f64
+            }
+        };
+        (0..n * n)
+            .map(|k| {
+                let r = pixel_size * wrap(k % n).hypot(wrap(k / n));
+                let u = r / dr;
+                let i = (u.floor() as usize).min(n_r - 2);
+                let w = u - i as f64;
+                otf_r[i] * (1. - w) + otf_r[i + 1] * w
+            })
+            .collect()
+    }
+    // OTF as the autocorrelation of the complex amplitude, normalized to 1 at the origin
+    fn telescope_otf(&self, amplitude: &[f32], opd: &[f32]) -> Vec<Complex<f64>> {
+        let (n_px, n) = (self.n_px, self.n_otf);
+        let (forward, inverse) = self.fft.as_ref().expect("HostPSSn FFTs are not planned");
+        let k = 2. * std::f64::consts::PI / self.wavelength;
+        let mut field = vec![Complex::<f64>::new(0., 0.); n * n];
+        for j in 0..n_px {
+            for i in 0..n_px {
+                let l = i + n_px * j;
+                field[i + n * j] = Complex::from_polar(amplitude[l] as f64, k * opd[l] as f64);
+            }
+        }
+        fft2(&mut field, n, forward);
+        field
+            .iter_mut()
+            .for_each(|x| *x = Complex::new(x.norm_sqr(), 0.));
+        fft2(&mut field, n, inverse);
+        let o0 = field[0].re;
+        if o0 > 0. {
+            field.iter_mut().for_each(|x| *x /= o0);
+        }
+        field
+    }
+    fn ratio(&self, with_atmosphere: bool) -> Vec<f64> {
+        let n = self.n_sample.max(1) as f64;
+        self.otf
+            .iter()
+            .zip(&self.reference_otf)
+            .map(|(otf, otf0)| {
+                let (num, den) = otf.iter().zip(otf0).zip(&self.atmosphere_otf).fold(
+                    (0., 0.),
+                    |(num, den), ((o, o0), c)| {
+                        let o = if with_atmosphere { o / n * c } else { o / n };
+                        (num + o.norm_sqr(), den + (o0 * c).norm_sqr())
+                    },
+                );
+                num / den
+            })
+            .collect()
+    }
+}
+
+impl HostPSSn<TelescopeError> {
+    /// Estimates the `PSSn` values
+    ///
+    /// The integrated OTF contains the telescope errors only and it is combined with the atmosphere OTF
+    pub fn peek(&mut self) -> &mut Self {
+        self.estimates = self.ratio(true);
+        self
+    }
+}
+impl HostPSSn<AtmosphereTelescopeError> {
+    /// Estimates the `PSSn` values
+    ///
+    /// The integrated OTF already contains the atmosphere
+    pub fn peek(&mut self) -> &mut Self {
+        self.estimates = self.ratio(false);
+        self
+    }
+}
+
+impl<S: PSSnErrors> Propagation for HostPSSn<S> {
+    fn propagate(&mut self, src: &mut Source) {
+        self.integrate(src);
+    }
+    fn time_propagate(&mut self, _secs: f64, src: &mut Source) {
+        self.integrate(src);
+    }
+}
+
+// in-place 2D FFT of a n x n array
+pub(crate) fn fft2(data: &mut [Complex<f64>], n: usize, fft: &Arc<dyn Fft<f64>>) {
+    fft.process(data);
+    let mut transposed = vec![Complex::new(0., 0.); n * n];
+    for j in 0..n {
+        for i in 0..n {
+            transposed[j + n * i] = data[i + n * j];
+        }
+    }
+    fft.process(&mut transposed);
+    for j in 0..n {
+        for i in 0..n {
+            data[i + n * j] = transposed[j + n * i];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pupil(n: usize) -> Vec<f32> {
+        (0..n * n)
+            .map(|k| {
+                let x = (k % n) as f32 - 0.5 * (n - 1) as f32;
+                let y = (k / n) as f32 - 0.5 * (n - 1) as f32;
+                if x.hypot(y) <= 0.5 * n as f32 {
+                    1.
+                } else {
+                    0.
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn perfect_telescope() {
+        let n = 32;
+        let amplitude = pupil(n);
+        let opd = vec![0f32; n * n];
+        let mut pssn = HostPSSn::<TelescopeError>::new(0.16, 25., 0.);
+        pssn.reference_from(&amplitude, &opd, n, 25.5, 500e-9);
+        pssn.accumulate_from(&amplitude, &opd);
+        let value = pssn.peek().estimates[0];
+        assert!((value - 1.).abs() < 1e-9, "PSSn={value}");
+    }
+
+    #[test]
+    fn aberrated_telescope() {
+        let n = 32;
+        let amplitude = pupil(n);
+        let opd: Vec<f32> = (0..n * n)
+            .map(|k| 100e-9 * ((k % n) as f32 * 0.2).sin() * ((k / n) as f32 * 0.3).cos())
+            .collect();
+        let mut pssn = HostPSSn::<TelescopeError>::new(0.16, 25., 0.);
+        pssn.reference_from(&amplitude, &vec![0f32; n * n], n, 2.55, 500e-9);
+        pssn.accumulate_from(&amplitude, &opd);
+        pssn.accumulate_from(&amplitude, &opd);
+        let value = pssn.peek().estimates[0];
+        assert!(value < 0.99 && value > 0.9, "PSSn={value}");
+    }
+}
@@ -3,6 +3,7 @@ use std::fmt::Display;
 
 pub use builder::SegmentPistonSensorBuilder;
 use skyangle::Conversion;
+pub mod holographic;
 pub mod processing;
 
 use crate::{cu::Single, imaging::Frame, Cu, FromBuilder, Propagation};
@@ -0,0 +1,446 @@
+//!
+//! # Holographic dispersed fringe sensor
+//!
+//! A dispersed fringe sensor model where the fringes of the 12 segment pairs are coded by holographic gratings
+//! and all appear in a single detector image.
+//!
+//! Each segment pair is sampled by a square sub-aperture straddling the gap between the segments
+//! with a mask across the middle of the sub-aperture that separates the fringes from the central peak
+//! in the Fourier transform of the image.
+//! The hologram disperses the light of each sub-aperture along the image rows
+//! and sends the dispersed fringes of each pair to its own location in the image,
+//! the pairs being ordered as the [differential pistons](crate::wavefrontsensor::phasing::EDGES)
+//! on a grid of 4 rows by 3 columns.
+//! The fringes across the image columns are the cut of the sub-aperture PSF perpendicular to the gap.
+//!
+//! [HolographicFringes] processes the image into the 12 differential pistons: the phase of the fringes
+//! in each spectral channel is unwrapped across the channels and the differential piston is
+//! the slope of the phase with respect to the wavenumber.
+//! The capture range is `1/(2δσ)` where `δσ` is the wavenumber step between 2 spectral channels.
+//!
+//! ```no_run
+//! use crseo::{
+//!     segment_piston_sensor::holographic::{HolographicFringeSensor, HolographicFringes},
+//!     Builder, FromBuilder, Gmt, Source,
+//! };
+//! let mut gmt = Gmt::builder().build()?;
+//! let mut src = Source::builder().band("J").pupil_sampling(512).build()?;
+//! let mut hdfs = HolographicFringeSensor::builder().build()?;
+//! src.through(&mut gmt).xpupil().through(&mut hdfs);
+//! let mut fringes = HolographicFringes::from(&hdfs);
+//! println!("{:?}", fringes.intercept().intercept);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::f64::consts::PI;
+
+use ffi::dev2host_int;
+use serde::{Deserialize, Serialize};
+
+use crate::{wavefrontsensor::phasing::EDGES, Builder, FromBuilder, Propagation};
+
+/// Holographic dispersed fringe sensor builder
+///
+/// Default properties
+///  - lenslet size        : 1.5m
+///  - middle mask width   : 0.5m
+///  - spectral band       : 1.17-1.33micron
+///  - # spectral channels : 32
+///  - # pixel/fringes     : 32
+///  - pixel scale         : Nyquist sampling of the lenslet at the shortest wavelength
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HolographicFringeSensorBuilder {
+    lenslet_size: f64,
+    middle_mask_width: f64,
+    band: (f64, f64),
+    n_wavelength: usize,
+    n_px: usize,
+    pixel_scale: Option<f64>,
+}
+impl Default for HolographicFringeSensorBuilder {
+    fn default() -> Self {
+        Self {
+            lenslet_size: 1.5,
+            middle_mask_width: 0.5,
+            band: (1.17e-6, 1.33e-6),
+            n_wavelength: 32,
+            n_px: 32,
+            pixel_scale: None,
+        }
+    }
+}
+impl HolographicFringeSensorBuilder {
+    /// Sets the lenslet size in meters
+    pub fn lenslet_size(mut self, lenslet_size: f64) -> Self {
+        self.lenslet_size = lenslet_size;
+        self
+    }
+    /// Sets the lenslet mask width in meters
+    ///
+    /// The fringes are separated from the central peak in the Fourier transform of the image
+    /// if the mask width is at least a third of the lenslet size
+    pub fn middle_mask_width(mut self, middle_mask_width: f64) -> Self {
+        self.middle_mask_width = middle_mask_width;
+        self
+    }
+    /// Sets the shortest and longest wavelengths in meters
+    pub fn band(mut self, lambda_min: f64, lambda_max: f64) -> Self {
+        self.band = (lambda_min, lambda_max);
+        self
+    }
+    /// Sets the number of spectral channels
+    pub fn n_wavelength(mut self, n_wavelength: usize) -> Self {
+        self.n_wavelength = n_wavelength;
+        self
+    }
+    /// Sets the number of pixels across the fringes
+    pub fn n_px(mut self, n_px: usize) -> Self {
+        self.n_px = n_px;
+        self
+    }
+    /// Sets the pixel scale in radians
+    pub fn pixel_scale(mut self, pixel_scale: f64) -> Self {
+        self.pixel_scale = Some(pixel_scale);
+        self
+    }
+}
+impl Builder for HolographicFringeSensorBuilder {
+    type Component = HolographicFringeSensor;
+
+    fn build(self) -> crate::Result<Self::Component> {
+        let (lambda_min, lambda_max) = self.band;
+        let wavelengths = (0..self.n_wavelength)
+            .map(|i| {
+                lambda_min
+                    + (lambda_max - lambda_min) * i as f64 / (self.n_wavelength - 1).max(1) as f64
+            })
+            .collect();
+        Ok(HolographicFringeSensor {
+            lenslet_size: self.lenslet_size,
+            middle_mask_width: self.middle_mask_width,
+            wavelengths,
+            n_px: self.n_px,
+            pixel_scale: self
+                .pixel_scale
+                .unwrap_or(0.5 * lambda_min / self.lenslet_size),
+            frame: vec![0f32; 12 * self.n_wavelength * self.n_px],
+            n_frame: 0,
+        })
+    }
+}
+
+/// Holographic dispersed fringe sensor model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HolographicFringeSensor {
+    lenslet_size: f64,
+    middle_mask_width: f64,
+    wavelengths: Vec<f64>,
+    n_px: usize,
+    pixel_scale: f64,
+    frame: Vec<f32>,
+    n_frame: usize,
+}
+impl FromBuilder for HolographicFringeSensor {
+    type ComponentBuilder = HolographicFringeSensorBuilder;
+}
+impl Propagation for HolographicFringeSensor {
+    fn propagate(&mut self, src: &mut crate::Source) {
+        let n_ray_total = src.as_raw_mut_ptr().rays.N_RAY_TOTAL as usize;
+        let n_ray = n_ray_total / src.size as usize;
+        let mut mask = vec![0i32; n_ray_total];
+        unsafe {
+            dev2host_int(
+                mask.as_mut_ptr(),
+                src.as_raw_mut_ptr().rays.d__piston_mask,
+                n_ray_total as i32,
+            );
+        }
+        let pupil_size = src.pupil_size;
+        let amplitude = src.amplitude();
+        let opd = src.phase().clone();
+        for ((mask, amplitude), opd) in mask
+            .chunks(n_ray)
+            .zip(amplitude.chunks(n_ray))
+            .zip(opd.chunks(n_ray))
+        {
+            let frame = self.fringes(amplitude, opd, mask, pupil_size);
+            self.frame.iter_mut().zip(frame).for_each(|(f, x)| *f += x);
+        }
+        self.n_frame += 1;
+    }
+
+    fn time_propagate(&mut self, _secs: f64, src: &mut crate::Source) {
+        self.propagate(src);
+    }
+}
+impl HolographicFringeSensor {
+    /// Returns the detector frame
+    ///
+    /// The frame has `4` x `# spectral channels` rows and `3` x `# pixel/fringes` columns
+    pub fn frame(&self) -> &[f32] {
+        &self.frame
+    }
+    /// Returns the frame size (rows,columns)
+    pub fn frame_size(&self) -> (usize, usize) {
+        (4 * self.wavelengths.len(), 3 * self.n_px)
+    }
+    /// Returns the wavelengths of the spectral channels
+    pub fn wavelengths(&self) -> &[f64] {
+        &self.wavelengths
+    }
+    /// Gets the current # of accumulated frames
+    pub fn n_frame(&self) -> usize {
+        self.n_frame
+    }
+    /// Resets the detector frame
+    pub fn reset(&mut self) -> &mut Self {
+        self.frame.fill(0f32);
+        self.n_frame = 0;
+        self
+    }
+    // angle of the pixel `j` across the fringes
+    fn theta(&self, j: usize) -> f64 {
+        (j as f64 - 0.5 * (self.n_px - 1) as f64) * self.pixel_scale
+    }
+    /// Returns the detector frame of the pupil `amplitude` and `opd` \[m\]
+    ///
+    /// The segment of each pupil sample is given by `mask` and `pupil_size` is the pupil sampling length
+    pub fn fringes(
+        &self,
+        amplitude: &[f32],
+        opd: &[f32],
+        mask: &[i32],
+        pupil_size: f64,
+    ) -> Vec<f32> {
+        let n = (amplitude.len() as f64).sqrt() as usize;
+        let dx = pupil_size / (n - 1) as f64;
+        let xy = |l: usize| {
+            (
+                ((l % n) as f64 - 0.5 * (n - 1) as f64) * dx,
+                ((l / n) as f64 - 0.5 * (n - 1) as f64) * dx,
+            )
+        };
+        // segment centroids
+        let centroids: Vec<Option<(f64, f64)>> = (1..=7)
+            .map(|sid| {
+                let (x, y, c) = mask
+                    .iter()
+                    .zip(amplitude)
+                    .enumerate()
+                    .filter(|(_, (m, a))| **m == sid && **a > 0.)
+                    .fold((0., 0., 0.), |(x, y, c), (l, _)| {
+                        let (u, v) = xy(l);
+                        (x + u, y + v, c + 1.)
+                    });
+                (c > 0.).then_some((x / c, y / c))
+            })
+            .collect();
+        let half = 0.5 * self.lenslet_size;
+        let n_wavelength = self.wavelengths.len();
+        let (n_row, n_col) = self.frame_size();
+        let mut frame = vec![0f32; n_row * n_col];
+        for (e, &(a, b)) in EDGES.iter().enumerate() {
+            let (Some((xa, ya)), Some((xb, yb))) = (centroids[a - 1], centroids[b - 1]) else {
+                continue;
+            };
+            // sub-aperture samples: distance across the gap, amplitude and opd
+            let (xm, ym) = (0.5 * (xa + xb), 0.5 * (ya + yb));
+            let r = (xb - xa).hypot(yb - ya);
+            let (nx, ny) = ((xb - xa) / r, (yb - ya) / r);
+            let samples: Vec<(f64, f64, f64)> = mask
+                .iter()
+                .zip(amplitude.iter().zip(opd))
+                .enumerate()
+                .filter_map(|(l, (m, (a_l, o)))| {
+                    if (*m as usize != a && *m as usize != b) || *a_l <= 0. {
+                        return None;
+                    }
+                    let (x, y) = xy(l);
+                    let s = (x - xm) * nx + (y - ym) * ny;
+                    let t = -(x - xm) * ny + (y - ym) * nx;
+                    let masked = s.abs() < 0.5 * self.middle_mask_width;
+                    (s.abs() < half && t.abs() < half && !masked).then_some((
+                        s,
+                        *a_l as f64,
+                        *o as f64,
+                    ))
+                })
+                .collect();
+            let (i0, j0) = ((e / 3) * n_wavelength, (e % 3) * self.n_px);
+            for (i, wavelength) in self.wavelengths.iter().enumerate() {
+                let k = 2. * PI / wavelength;
+                for j in 0..self.n_px {
+                    let theta = self.theta(j);
+                    let (re, im) = samples.iter().fold((0., 0.), |(re, im), (s, a, o)| {
+                        let (s, c) = (k * (o - s * theta)).sin_cos();
+                        (re + a * c, im + a * s)
+                    });
+                    frame[j0 + j + n_col * (i0 + i)] = (re * re + im * im) as f32;
+                }
+            }
+        }
+        frame
+    }
+}
+
+/// Holographic dispersed fringe sensor data processing
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HolographicFringes {
+    data: Vec<Vec<f32>>,
+    n_px: usize,
+    wavelengths: Vec<f64>,
+    pixel_scale: f64,
+    // distance between the centers of the 2 halves of the lenslet
+    baseline: f64,
+    threshold: Option<f32>,
+    /// differential pistons \[m\], `None` for the pairs with less than 2 spectral channels with visible fringes
+    pub intercept: Vec<Option<f32>>,
+    reference: Option<Vec<Option<f32>>>,
+}
+impl From<&HolographicFringeSensor> for HolographicFringes {
+    fn from(hdfs: &HolographicFringeSensor) -> Self {
+        let n_wavelength = hdfs.wavelengths.len();
+        let n_col = 3 * hdfs.n_px;
+        let data = (0..12)
+            .map(|e| {
+                let (i0, j0) = ((e / 3) * n_wavelength, (e % 3) * hdfs.n_px);
+                hdfs.frame
+                    .chunks(n_col)
+                    .skip(i0)
+                    .take(n_wavelength)
+                    .flat_map(|row| row[j0..j0 + hdfs.n_px].to_vec())
+                    .collect()
+            })
+            .collect();
+        Self {
+            data,
+            n_px: hdfs.n_px,
+            wavelengths: hdfs.wavelengths.clone(),
+            pixel_scale: hdfs.pixel_scale,
+            baseline: 0.5 * (hdfs.lenslet_size + hdfs.middle_mask_width),
+            threshold: Some(0.05),
+            ..Default::default()
+        }
+    }
+}
+impl HolographicFringes {
+    /// Sets the differential pistons subtracted from the estimates
+    pub fn set_reference(&mut self, hdfs: &HolographicFringes) -> &mut Self {
+        self.reference = Some(hdfs.intercept.clone());
+        self
+    }
+    /// Sets the smallest fringe visibility of the spectral channels used in the estimation
+    pub fn threshold(self, t: f64) -> Self {
+        Self {
+            threshold: Some(t as f32),
+            ..self
+        }
+    }
+    /// Returns the fringe complex amplitude in each spectral channel of the pair `e`
+    ///
+    /// The fringe complex amplitude is normalized to the flux in the channel.
+    /// The fringes are apodized with a Hann window to reduce the leakage of the central peak.
+    pub fn fringe(&self, e: usize) -> Vec<(f64, f64)> {
+        let n = self.n_px as f64;
+        self.data[e]
+            .chunks(self.n_px)
+            .zip(&self.wavelengths)
+            .map(|(row, wavelength)| {
+                // the fringe frequency is set by the distance between the 2 halves of the lenslet
+                let k = 2. * PI * self.baseline / wavelength;
+                let (re, im, flux) =
+                    row.iter()
+                        .enumerate()
+                        .fold((0., 0., 0.), |(re, im, flux), (j, i)| {
+                            let theta = (j as f64 - 0.5 * (n - 1.)) * self.pixel_scale;
+                            let (s, c) = (-k * theta).sin_cos();
+                            let w = (PI * (j as f64 + 0.5) / n).sin().powi(2);
+                            let i = *i as f64;
+                            (re + w * i * c, im + w * i * s, flux + w * i)
+                        });
+                if flux > 0. {
+                    (re / flux, im / flux)
+                } else {
+                    (0., 0.)
+                }
+            })
+            .collect()
+    }
+    /// Computes the differential pistons
+    ///
+    /// The differential piston of a pair is `None` if the fringes are visible in less than 2 spectral channels
+    /// or if the reference of the pair is `None`
+    pub fn intercept(&mut self) -> &mut Self {
+        self.intercept = (0..12)
+            .map(|e| {
+                // (wavenumber,fringe phase) of the channels with visible fringes, by increasing wavenumber
+                let mut channels: Vec<(f64, f64)> = self
+                    .fringe(e)
+                    .into_iter()
+                    .zip(&self.wavelengths)
+                    .filter(|((re, im), _)| {
+                        re.hypot(*im) > self.threshold.unwrap_or_default() as f64
+                    })
+                    .map(|((re, im), wavelength)| (1. / wavelength, im.atan2(re)))
+                    .collect();
+                if channels.len() < 2 {
+                    return None;
+                }
+                channels.sort_by(|a, b| a.0.total_cmp(&b.0));
+                for i in 1..channels.len() {
+                    let d = channels[i].1 - channels[i - 1].1;
+                    channels[i].1 -= 2. * PI * (d / (2. * PI)).round();
+                }
+                let n = channels.len() as f64;
+                let (s, p) = channels
+                    .iter()
+                    .fold((0., 0.), |(s, p), (sigma, phi)| (s + sigma, p + phi));
+                let (s, p) = (s / n, p / n);
+                let (sp, ss) = channels.iter().fold((0., 0.), |(sp, ss), (sigma, phi)| {
+                    (sp + (sigma - s) * (phi - p), ss + (sigma - s).powi(2))
+                });
+                (ss > 0.).then(|| (sp / ss / (2. * PI)) as f32)
+            })
+            .collect();
+        if let Some(r) = &self.reference {
+            self.intercept
+                .iter_mut()
+                .zip(r.iter())
+                .for_each(|(i, r)| *i = i.zip(*r).map(|(i, r)| i - r));
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // square pupil with segment #7 on the left and segment #1, with the piston `p`, on the right
+    fn pupil(n: usize, p: f32) -> (Vec<f32>, Vec<f32>, Vec<i32>) {
+        let left = |l: usize| (l % n) < n / 2;
+        (
+            vec![1f32; n * n],
+            (0..n * n).map(|l| if left(l) { 0. } else { p }).collect(),
+            (0..n * n).map(|l| if left(l) { 7 } else { 1 }).collect(),
+        )
+    }
+
+    #[test]
+    fn piston() {
+        let mut hdfs = HolographicFringeSensor::builder().build().unwrap();
+        for p in [0f32, 3e-6, -20e-6] {
+            let (amplitude, opd, mask) = pupil(128, p);
+            hdfs.frame = hdfs.fringes(&amplitude, &opd, &mask, 25.5);
+            let mut fringes = HolographicFringes::from(&hdfs);
+            let intercept = fringes.intercept().intercept.clone();
+            // p_7-p_1
+            assert!(intercept[0].is_some_and(|x| (x + p).abs() < 1e-8));
+            // the other pairs are missing
+            assert!(intercept[1..].iter().all(|x| x.is_none()));
+            // no spectral channel with a fringe visibility larger than 2
+            let mut fringes = HolographicFringes::from(&hdfs).threshold(2.);
+            assert!(fringes.intercept().intercept.iter().all(|x| x.is_none()));
+        }
+    }
+}
@@ -1,515 +1,604 @@
-//use super::{error::OpticalSensitivitiesError, Matrix, WindLoadedGmtInner};
-use crate::ceo;
-use bincode;
+//!
+//! # Optical sensitivities
+//!
+//! Sensitivity matrices of optical outputs with respect to the GMT M1 and M2 degrees of freedom.
+//!
+//! The degrees of freedom are the rigid body motions and the modes of M1 and M2 segments,
+//! ordered as in [SegmentsDof]: segment wise `[Si]` for i in `[1,7]`
+//! where `Si = [M1,M2]` and `Mj = [Txyz, Rxyz, Modes]`,
+//! the clocking of M1 and M2 center segments being excluded unless [OpticalSensitivitiesBuilder::include_s7_rz] is set.
+//!
+//! The sensitivities are computed with central finite differences around a GMT state
+//! (the aligned state by default) for all the sources of the [SourceBuilder],
+//! i.e. the field grid.
+//!
+//! ```no_run
+//! use crseo::{
+//!     sensitivities::{OpticalSensitivities, SensitivityOutput},
+//!     Gmt, Source, FromBuilder,
+//! };
+//! use skyangle::Conversion;
+//! let sens = OpticalSensitivities::builder()
+//!     .gmt(Gmt::builder().m1_n_mode(27))
+//!     .source(Source::builder().size(3).on_ring(6f32.from_arcmin()))
+//!     .outputs(vec![
+//!         SensitivityOutput::Wavefront { n_mode: 15 },
+//!         SensitivityOutput::SegmentPiston,
+//!     ])
+//!     .compute()?;
+//! sens.save("optical_sensitivities.pkl")?;
+//! let piston = sens[SensitivityOutput::SegmentPiston].matrix();
+//! # Ok::<(), crseo::sensitivities::SensitivitiesError>(())
+//! ```
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    ops::Index,
+    path::{Path, PathBuf},
+};
+
 use nalgebra as na;
 use serde::{Deserialize, Serialize};
 use skyangle::Conversion;
-use std::{env, fs::File, io, io::BufReader, io::BufWriter, path::Path, time::Instant};
-use thiserror::Error;
 
-type Matrix =
-    na::Matrix<f64, na::Dynamic, na::Dynamic, na::VecStorage<f64, na::Dynamic, na::Dynamic>>;
+use crate::{
+    builders::{GmtBuilder, SourceBuilder},
+    gmt::{dof::SegmentsDof, GmtError},
+    pssn::TelescopeError,
+    Builder, CrseoError, HostPSSn, Source,
+};
+
+pub mod linear;
+#[doc(inline)]
+pub use linear::LinearOpticalModel;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SensitivitiesError {
+    #[error("failed to build the optical model")]
+    Crseo(#[from] CrseoError),
+    #[error("failed to update the GMT")]
+    Gmt(#[from] GmtError),
+    #[error("failed to open {1}")]
+    Open(#[source] std::io::Error, PathBuf),
+    #[error("failed to create {1}")]
+    Create(#[source] std::io::Error, PathBuf),
+    #[error("failed to (de)serialize the sensitivities")]
+    Pickle(#[from] serde_pickle::Error),
+    #[error("the sensitivities do not include {0:?}")]
+    Missing(SensitivityOutput),
+    #[error("{0:?} is requested without a PSSn evaluator")]
+    PSSn(SensitivityOutput),
+}
+pub type Result<T> = std::result::Result<T, SensitivitiesError>;
 
-#[derive(Error, Debug)]
-pub enum OpticalSensitivitiesError {
-    #[error("sensitivities file not found (optical_sensitivities.rs.bin)")]
-    SensitivityFile(#[from] io::Error),
-    #[error("sensitivities cannot be loaded from optical_sensitivities.rs.bin")]
-    SensitivityData(#[from] bincode::Error),
-    #[error("CFD_REPO environment variable missing")]
-    CFDREPO(#[from] env::VarError),
-    #[error("segment tip-tilt sensitivity is missing")]
+/// Optical outputs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SensitivityOutput {
+    /// Wavefront Noll's Zernike coefficients \[m RMS\] from piston to mode `n_mode` (`n_mode` rows per source)
+    Wavefront { n_mode: usize },
+    /// Segment piston \[m\] (7 rows per source)
+    SegmentPiston,
+    /// Segment tip-tilt \[rd\] (14 rows per source, x then y)
     SegmentTipTilt,
+    /// Image centroid \[rd\] (x and y rows for all the sources)
+    ImageCentroid,
+    /// PSSn gradient (1 row per source)
+    PSSn,
+    /// PSSn second derivatives with respect to each degree of freedom (1 row per source)
+    PSSnCurvature,
 }
 
-/// Optical sensitivities
+/// Sensitivity matrix
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sensitivity {
+    pub output: SensitivityOutput,
+    pub n_row: usize,
+    /// column-wise matrix data, one column per degree of freedom
+    pub data: Vec<f64>,
+    /// outputs at the GMT state around which the sensitivities are computed
+    #[serde(default)]
+    pub value: Vec<f64>,
+    /// column-wise second derivatives with respect to each degree of freedom
+    #[serde(default)]
+    pub curvature: Vec<f64>,
+}
+impl Sensitivity {
+    /// Returns the number of degrees of freedom
+    pub fn n_dof(&self) -> usize {
+        self.data.len() / self.n_row
+    }
+    /// Returns the sensitivity matrix
+    pub fn matrix(&self) -> na::DMatrix<f64> {
+        na::DMatrix::from_column_slice(self.n_row, self.n_dof(), &self.data)
+    }
+    /// Returns the second derivatives matrix, if any
+    pub fn curvature_matrix(&self) -> Option<na::DMatrix<f64>> {
+        (self.curvature.len() == self.data.len())
+            .then(|| na::DMatrix::from_column_slice(self.n_row, self.n_dof(), &self.curvature))
+    }
+}
+
+/// [OpticalSensitivities] builder
 ///
-/// Transform M1 and M2 rigid body motions into wavefront and wavefront piston and tip-tilt modes
-#[derive(Serialize, Deserialize, Clone)]
-pub enum OpticalSensitivities {
-    /// Wavefront sensitivity [nx84] where n in the pupil resolution
-    Wavefront(Vec<f64>),
-    /// Exit pupil tip-tilt sensitivity [2x84]
-    TipTilt(Vec<f64>),
-    /// Exit pupil segment tip-tilt [14x84]
-    SegmentTipTilt(Vec<f64>),
-    /// Exit pupil segment piston [7x84]
-    SegmentPiston(Vec<f64>),
-    SegmentMask(Vec<i32>),
+/// Default properties:
+///  - GMT : default [GmtBuilder]
+///  - source : default [SourceBuilder]
+///  - outputs : wavefront (first 15 Zernikes), segment piston, segment tip-tilt and image centroid
+///  - S7 Rz : excluded
+///  - strokes : 1μm (translations), 1arcsec (rotations), 1e-6 (modes)
+///  - state : aligned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpticalSensitivitiesBuilder {
+    gmt: GmtBuilder,
+    src: SourceBuilder,
+    outputs: Vec<SensitivityOutput>,
+    s7_rz: bool,
+    strokes: (f64, f64, f64),
+    state: Option<Vec<f64>>,
+    pssn: (f64, f64, f64),
 }
-impl<'a> From<&'a OpticalSensitivities> for &'a [f64] {
-    fn from(sens: &'a OpticalSensitivities) -> Self {
-        use OpticalSensitivities::*;
-        match sens {
-            Wavefront(val) | TipTilt(val) | SegmentTipTilt(val) | SegmentPiston(val) => {
-                Some(val.as_slice())
-            }
-            _ => None,
+impl Default for OpticalSensitivitiesBuilder {
+    fn default() -> Self {
+        use SensitivityOutput::*;
+        Self {
+            gmt: Default::default(),
+            src: Default::default(),
+            outputs: vec![
+                Wavefront { n_mode: 15 },
+                SegmentPiston,
+                SegmentTipTilt,
+                ImageCentroid,
+            ],
+            s7_rz: false,
+            strokes: (1e-6, 1f64.from_arcsec(), 1e-6),
+            state: None,
+            pssn: (0.16, 25., 30f64.to_radians()),
         }
-        .unwrap()
     }
 }
-impl<'a> From<&'a OpticalSensitivities> for na::DMatrix<f64> {
-    fn from(sens: &'a OpticalSensitivities) -> Self {
-        use OpticalSensitivities::*;
-        match sens {
-            Wavefront(val) => Some(na::DMatrix::from_column_slice(val.len() / 84, 84, val)),
-            TipTilt(val) => Some(na::DMatrix::from_column_slice(2, 84, val)),
-            SegmentTipTilt(val) => Some(na::DMatrix::from_column_slice(14, 84, val)),
-            SegmentPiston(val) => Some(na::DMatrix::from_column_slice(7, 84, val)),
-            _ => None,
+impl OpticalSensitivitiesBuilder {
+    /// Sets the GMT model
+    pub fn gmt(self, gmt: GmtBuilder) -> Self {
+        Self { gmt, ..self }
+    }
+    /// Sets the sources, i.e. the field grid
+    pub fn source(self, src: SourceBuilder) -> Self {
+        Self { src, ..self }
+    }
+    /// Sets the optical outputs
+    pub fn outputs(self, outputs: Vec<SensitivityOutput>) -> Self {
+        Self { outputs, ..self }
+    }
+    /// Adds M1 and M2 center segment clocking to segment RBMs
+    pub fn include_s7_rz(self) -> Self {
+        Self {
+            s7_rz: true,
+            ..self
         }
-        .unwrap()
     }
-}
-pub fn from_opticals(senses: &[OpticalSensitivities]) -> na::DMatrix<f64> {
-    let mats: Vec<na::DMatrix<f64>> = senses.iter().map(|s| s.into()).collect();
-    let n_rows = mats.iter().map(|m| m.nrows()).sum::<usize>();
-    let cols: Vec<_> = mats
-        .iter()
-        .flat_map(|m| {
-            m.column_iter()
-                .map(|c| na::DVector::from_column_slice(c.as_slice()))
-                .collect::<Vec<na::DVector<f64>>>()
-        })
-        .collect();
-    let data: Vec<_> = (0..84)
-        .flat_map(|k| {
-            (0..senses.len())
-                .flat_map(|l| cols[k + l * 84].as_slice().to_vec())
-                .collect::<Vec<f64>>()
-        })
-        .collect();
-    na::DMatrix::from_column_slice(n_rows, 84, &data)
-}
-
-impl OpticalSensitivities {
-    /// Loads precomputed optical sensitivities
+    /// Sets the strokes of the translations \[m\], of the rotations \[rd\] and of the modes
+    pub fn strokes(self, translation: f64, rotation: f64, mode: f64) -> Self {
+        Self {
+            strokes: (translation, rotation, mode),
+            ..self
+        }
+    }
+    /// Sets the GMT state around which the sensitivities are computed
     ///
-    /// Look in the current directory for the file: "optical_sensitivities.rs.bin"
-    pub fn load() -> Result<Vec<Self>, OpticalSensitivitiesError> {
-        let data_path = Path::new(".").join("optical_sensitivities.rs.bin");
-        println!("loading sensitivities from {:?}", data_path);
-        Ok(bincode::deserialize_from(BufReader::with_capacity(
-            100_000,
-            File::open(data_path)?,
-        ))?)
+    /// The state is a [SegmentsDof] vector
+    pub fn state(self, state: Vec<f64>) -> Self {
+        Self {
+            state: Some(state),
+            ..self
+        }
     }
-    /// Returns M1 wavefront sensitivities [nx42]
-    pub fn m1_wavefront(&self) -> Result<Matrix, OpticalSensitivitiesError> {
-        match self {
-            OpticalSensitivities::Wavefront(sens) => {
-                let n = sens.len() / 84;
-                let (_, m1_tr) = sens.split_at(n * 42);
-                Ok(na::DMatrix::from_iterator(
-                    n,
-                    42,
-                    m1_tr.chunks(n).flat_map(|x| x.to_vec()),
-                ))
-            }
-            _ => Err(OpticalSensitivitiesError::SegmentTipTilt),
+    /// Sets the atmosphere r0 at zenith \[m\], outer scale \[m\] and zenith angle \[rd\] of the PSSn
+    pub fn pssn_atmosphere(self, r0_at_zenith: f64, oscale: f64, zenith_angle: f64) -> Self {
+        Self {
+            pssn: (r0_at_zenith, oscale, zenith_angle),
+            ..self
         }
     }
-    /// Returns M2 segment tip-tilt sensitivities [14x14]
-    pub fn m2_rxy(&self) -> Result<Matrix, OpticalSensitivitiesError> {
-        match self {
-            OpticalSensitivities::SegmentTipTilt(sens) => {
-                let (_, m2_tr) = sens.split_at(14 * 42);
-                Ok(na::DMatrix::from_iterator(
-                    14,
-                    14,
-                    m2_tr
-                        .chunks(14 * 3)
-                        .skip(1)
-                        .step_by(2)
-                        .flat_map(|x| (&x[..14 * 2]).to_vec()),
-                ))
-            }
-            _ => Err(OpticalSensitivitiesError::SegmentTipTilt),
+    // strokes ordered as the degrees of freedom
+    fn dof_strokes(&self, m1_n_mode: usize, m2_n_mode: usize) -> Vec<f64> {
+        let (t, r, m) = self.strokes;
+        let mirror = |n_mode: usize| {
+            let mut s = vec![t; 3];
+            s.extend(vec![r; 3]);
+            s.extend(vec![m; n_mode]);
+            s
+        };
+        let mut strokes: Vec<f64> = (0..7)
+            .flat_map(|_| {
+                let mut s = mirror(m1_n_mode);
+                s.extend(mirror(m2_n_mode));
+                s
+            })
+            .collect();
+        if !self.s7_rz {
+            let n = m1_n_mode + m2_n_mode + 12;
+            strokes.remove(6 * n + m1_n_mode + 11);
+            strokes.remove(6 * n + 5);
         }
+        strokes
     }
-    /*
-       pub fn into_optics(&self, optics_model: &WindLoadedGmtInner) -> Vec<f64> {
-           //        let n_sample = optics_model.n_sample;
-           let rbm = &optics_model.rbm;
-           match self {
-               /*OpticalSensitivities::Wavefront(sens) => {
-                   let n = sens.len() / 84;
-                   //println!("n: {}", n);
-                   let sensitivity = na::DMatrix::from_column_slice(n, 84, sens);
-                   //let now = Instant::now();
-                   let wfe_var = {
-                       let n_buf = 1_000;
-                       let mut buf = na::DMatrix::<f64>::zeros(n, n_buf);
-                       let mut s = 0;
-                       let mut var = 0f64;
-                       loop {
-                           if s + n_buf > n_sample {
-                               s -= n_buf;
-                               let n_last = n_sample - s;
-                               let mut buf = na::DMatrix::<f64>::zeros(n, n_last);
-                               buf.gemm(1f64, &sensitivity, &rbm.columns(s, n_last), 0f64);
-                               var += buf.row_variance().as_slice().into_iter().sum::<f64>();
-                               break var;
-                           } else {
-                               buf.gemm(1f64, &sensitivity, &rbm.columns(s, n_buf), 0f64);
-                               var += buf.row_variance().as_slice().into_iter().sum::<f64>();
-                           }
-                           s += n_buf;
-                       }
-                   };
-                   let value = 1e9 * (wfe_var / n_sample as f64).sqrt();
-                   OpticalWindLoad::Wavefront(value)
-                   /*println!(
-                       "Wavefront: {:6.0}nm in {:.3}s", value,
-                       now.elapsed().as_secs_f64()
-                   );*/
-               }*/
-               OpticalSensitivities::TipTilt(sens) => {
-                   let sensitivity = na::DMatrix::from_column_slice(2, 84, sens);
-                   let tip_tilt = (sensitivity * rbm).map(|x| x.to_mas());
-                   tip_tilt.as_slice().to_owned()
-               }
-               OpticalSensitivities::SegmentTipTilt(sens) => {
-                   let sensitivity = na::DMatrix::from_column_slice(14, 84, sens);
-                   let segment_tip_tilt = (sensitivity * rbm).map(|x| x.to_mas());
-                   segment_tip_tilt.as_slice().to_owned()
-               }
-               OpticalSensitivities::SegmentPiston(sens) => {
-                   let sensitivity = na::DMatrix::from_column_slice(7, 84, sens);
-                   let segment_piston = (sensitivity * rbm).map(|x| x * 1e9);
-                   let mut v: Vec<f64> = vec![];
-                   for (k, row) in segment_piston.row_iter().take(6).enumerate() {
-                       //println!("{}: {:?}", k, row.shape());
-                       v.extend(
-                           &mut segment_piston
-                               .rows(k + 1, 6 - k)
-                               .row_iter()
-                               .flat_map(|y| (y - row).as_slice().to_owned()),
-                       );
-                   }
-                   segment_piston.as_slice().to_owned()
-               }
-               _ => unimplemented!(),
-           }
-       }
-       pub fn transform(&self, optics_model: &WindLoadedGmtInner) -> OpticalWindLoad {
-           let n_sample = optics_model.n_sample;
-           let rbm = &optics_model.rbm;
-           match self {
-               OpticalSensitivities::Wavefront(sens) => {
-                   let n = sens.len() / 84;
-                   //println!("n: {}", n);
-                   let sensitivity = na::DMatrix::from_column_slice(n, 84, sens);
-                   //let now = Instant::now();
-                   let wfe_var = {
-                       let n_buf = 1_000;
-                       let mut buf = na::DMatrix::<f64>::zeros(n, n_buf);
-                       let mut s = 0;
-                       let mut var = 0f64;
-                       loop {
-                           if s + n_buf > n_sample {
-                               s -= n_buf;
-                               let n_last = n_sample - s;
-                               let mut buf = na::DMatrix::<f64>::zeros(n, n_last);
-                               buf.gemm(1f64, &sensitivity, &rbm.columns(s, n_last), 0f64);
-                               var += buf.row_variance().as_slice().into_iter().sum::<f64>();
-                               break var;
-                           } else {
-                               buf.gemm(1f64, &sensitivity, &rbm.columns(s, n_buf), 0f64);
-                               var += buf.row_variance().as_slice().into_iter().sum::<f64>();
-                           }
-                           s += n_buf;
-                       }
-                   };
-                   let value = 1e9 * (wfe_var / n_sample as f64).sqrt();
-                   OpticalWindLoad::Wavefront(value)
-                   /*println!(
-                       "Wavefront: {:6.0}nm in {:.3}s", value,
-                       now.elapsed().as_secs_f64()
-                   );*/
-               }
-               OpticalSensitivities::TipTilt(sens) => {
-                   let sensitivity = na::DMatrix::from_column_slice(2, 84, sens);
-                   let tip_tilt = (sensitivity * rbm).map(|x| x.to_mas());
-                   let values = tip_tilt
-                       .column_variance()
-                       .map(|x| x.sqrt())
-                       .as_slice()
-                       .to_owned();
-                   //println!("TT: {:2.0?}mas", &values);
-                   OpticalWindLoad::TipTilt(values)
-               }
-               OpticalSensitivities::SegmentTipTilt(sens) => {
-                   let sensitivity = na::DMatrix::from_column_slice(14, 84, sens);
-                   let segment_tip_tilt = (sensitivity * rbm).map(|x| x.to_mas());
-                   let values: Vec<_> = segment_tip_tilt
-                       .column_variance()
-                       .map(|x| x.sqrt())
-                       .as_slice()
-                       .chunks(7)
-                       .map(|x| x.to_owned())
-                       .collect();
-                   //println!("Segment TT: {:2.0?}mas", values,);
-                   OpticalWindLoad::SegmentTipTilt(values)
-               }
-               OpticalSensitivities::SegmentPiston(sens) => {
-                   let sensitivity = na::DMatrix::from_column_slice(7, 84, sens);
-                   let segment_piston = (sensitivity * rbm).map(|x| x * 1e9);
-                   let mut v: Vec<f64> = vec![];
-                   for (k, row) in segment_piston.row_iter().take(6).enumerate() {
-                       //println!("{}: {:?}", k, row.shape());
-                       v.extend(
-                           &mut segment_piston
-                               .rows(k + 1, 6 - k)
-                               .row_iter()
-                               .flat_map(|y| (y - row).as_slice().to_owned()),
-                       );
-                   }
-                   let value = (na::DMatrix::from_vec(n_sample, 21, v)
-                       .column_variance()
-                       .sum()
-                       / n_sample as f64)
-                       .sqrt();
-                   let values = segment_piston
-                       .column_variance()
-                       .map(|x| x.sqrt())
-                       .as_slice()
-                       .to_owned();
-                   //println!("Diff. piston std: {:5.0}nm", value,);
-                   //println!("Piston: {:3.0?}nm ; ", &values);
-                   OpticalWindLoad::Piston([
-                       PistonWindLoad::DifferentialSegmentPiston(value),
-                       PistonWindLoad::SegmentPiston(values),
-                   ])
-               }
-               OpticalSensitivities::SegmentMask(_) => OpticalWindLoad::WavefrontWoSegmentPiston(None),
-           }
-       }
-    */
-    /// Computes all the sensitivities
-    ///
-    /// Optionally provides an optical model or uses: [`ceo!(GMT)`](crate::GMT) and [`ceo!(SOURCE)`](crate::SOURCE)
-    pub fn compute(
-        model: Option<(crate::Gmt, crate::Source)>,
-    ) -> std::result::Result<Vec<OpticalSensitivities>, Box<dyn std::error::Error>> {
-        println!("Computing optical sensitivities ...");
-        let now = Instant::now();
-        let (mut gmt, mut src) = model.unwrap_or((ceo!(GMT), ceo!(SOURCE)));
-        let stroke_fn = |dof| if dof < 3 { 1e-6 } else { 1f64.from_arcsec() };
-
-        let mut tip_tilt = vec![];
-        let mut segment_piston = vec![];
-        let mut segment_tip_tilt = vec![];
-        let mut phase = vec![];
-        let n = (src.pupil_sampling * src.pupil_sampling) as usize;
-        let mut amplitude = vec![true; n];
-        for sid in 0..7 {
-            for dof in 0..6 {
-                let mut m1_rbm = vec![vec![0.; 6]; 7];
-                let stroke = stroke_fn(dof);
-
-                m1_rbm[sid][dof] = stroke;
-                gmt.update(Some(&m1_rbm), None, None, None);
-
-                src.through(&mut gmt).xpupil();
-                amplitude
-                    .iter_mut()
-                    .zip(src.amplitude().into_iter())
-                    .for_each(|(b, a)| {
-                        *b = a > 0f32 && *b;
-                    });
-                let push_phase = src.phase().to_owned();
-                let push_tip_tilt = src.gradients();
-                let push_segment_piston = src.segment_piston();
-                let push_segment_tip_tilt = src.segment_gradients();
+    /// Computes the sensitivities
+    pub fn compute(self) -> Result<OpticalSensitivities> {
+        let mut gmt = self.gmt.clone().build()?;
+        let mut src = self.src.clone().build()?;
+        let (m1_n_mode, m2_n_mode) = (gmt.m1.n_mode, gmt.m2.n_mode);
+        let dofs = {
+            let dofs = SegmentsDof::new().m1_n_mode(m1_n_mode).m2_n_mode(m2_n_mode);
+            if self.s7_rz {
+                dofs.include_s7_rz()
+            } else {
+                dofs
+            }
+        };
+        let strokes = self.dof_strokes(m1_n_mode, m2_n_mode);
+        let n_dof = strokes.len();
+        let state = self.state.clone().unwrap_or_else(|| vec![0f64; n_dof]);
+        assert_eq!(
+            state.len(),
+            n_dof,
+            "expected a state with {} elements, found {}",
+            n_dof,
+            state.len()
+        );
 
-                m1_rbm[sid][dof] = -stroke;
-                gmt.update(Some(&m1_rbm), None, None, None);
+        // aligned GMT: PSSn reference and wavefront projection
+        dofs.clone()
+            .from_vec(vec![0f64; n_dof])
+            .apply_to(&mut gmt)?;
+        src.through(&mut gmt).xpupil();
+        let mut evaluator = Evaluator::new(&self, &mut src);
 
-                src.through(&mut gmt).xpupil();
-                amplitude
-                    .iter_mut()
-                    .zip(src.amplitude().into_iter())
-                    .for_each(|(b, a)| {
-                        *b = a > 0f32 && *b;
-                    });
-                phase.extend(
-                    src.phase()
-                        .to_owned()
-                        .into_iter()
-                        .zip(push_phase.into_iter())
-                        .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke),
-                );
-                tip_tilt.extend(
-                    src.gradients()
-                        .into_iter()
-                        .zip(push_tip_tilt.into_iter())
-                        .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke),
-                );
-                segment_piston.extend(
-                    src.segment_piston()
-                        .into_iter()
-                        .zip(push_segment_piston.into_iter())
-                        .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke),
-                );
-                segment_tip_tilt.extend(
-                    src.segment_gradients()
-                        .into_iter()
-                        .zip(push_segment_tip_tilt.into_iter())
-                        .flat_map(|(left, right)| {
-                            left.into_iter()
-                                .zip(right.into_iter())
-                                .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke)
-                                .collect::<Vec<f64>>()
-                        }),
-                );
+        let mut eval = |v: Vec<f64>| -> Result<Vec<Vec<f64>>> {
+            dofs.clone().from_vec(v).apply_to(&mut gmt)?;
+            src.through(&mut gmt).xpupil();
+            evaluator.outputs(&self.outputs, &mut src)
+        };
+        let f0 = eval(state.clone())?;
+        let mut data: Vec<Vec<f64>> = vec![vec![]; self.outputs.len()];
+        let mut curvature: Vec<Vec<f64>> = vec![vec![]; self.outputs.len()];
+        for (i, &stroke) in strokes.iter().enumerate() {
+            let mut v = state.clone();
+            v[i] += stroke;
+            let f_push = eval(v)?;
+            let mut v = state.clone();
+            v[i] -= stroke;
+            let f_pull = eval(v)?;
+            for (k, output) in self.outputs.iter().enumerate() {
+                let (fp, fm, f0) = (&f_push[k], &f_pull[k], &f0[k]);
+                let d2: Vec<f64> = fp
+                    .iter()
+                    .zip(fm)
+                    .zip(f0)
+                    .map(|((p, m), z)| (p - 2. * z + m) / (stroke * stroke))
+                    .collect();
+                match output {
+                    SensitivityOutput::PSSnCurvature => data[k].extend_from_slice(&d2),
+                    _ => data[k].extend(fp.iter().zip(fm).map(|(p, m)| 0.5 * (p - m) / stroke)),
+                }
+                curvature[k].extend(d2);
             }
         }
-        for sid in 0..7 {
-            for dof in 0..6 {
-                let mut m2_rbm = vec![vec![0.; 6]; 7];
-                let stroke = stroke_fn(dof);
-
-                m2_rbm[sid][dof] = stroke;
-                gmt.update(None, Some(&m2_rbm), None, None);
-
-                src.through(&mut gmt).xpupil();
-                amplitude
-                    .iter_mut()
-                    .zip(src.amplitude().into_iter())
-                    .for_each(|(b, a)| {
-                        *b = a > 0f32 && *b;
-                    });
-                let push_phase = src.phase().to_owned();
-                let push_tip_tilt = src.gradients();
-                let push_segment_piston = src.segment_piston();
-                let push_segment_tip_tilt = src.segment_gradients();
+        dofs.from_vec(vec![0f64; n_dof]).apply_to(&mut gmt)?;
 
-                m2_rbm[sid][dof] = -stroke;
-                gmt.update(None, Some(&m2_rbm), None, None);
+        let sensitivities = self
+            .outputs
+            .iter()
+            .zip(data)
+            .zip(curvature)
+            .zip(f0)
+            .map(|(((&output, data), curvature), value)| Sensitivity {
+                output,
+                n_row: data.len() / n_dof,
+                data,
+                value,
+                curvature,
+            })
+            .collect();
+        Ok(OpticalSensitivities {
+            m1_n_mode,
+            m2_n_mode,
+            s7_rz: self.s7_rz,
+            strokes,
+            state,
+            zenith: self.src.zenith.iter().map(|&x| x as f64).collect(),
+            azimuth: self.src.azimuth.iter().map(|&x| x as f64).collect(),
+            wavelength: src.wavelength(),
+            sensitivities,
+        })
+    }
+}
 
-                src.through(&mut gmt).xpupil();
-                amplitude
-                    .iter_mut()
-                    .zip(src.amplitude().into_iter())
-                    .for_each(|(b, a)| {
-                        *b = a > 0f32 && *b;
-                    });
-                phase.extend(
-                    src.phase()
-                        .to_owned()
-                        .into_iter()
-                        .zip(push_phase.into_iter())
-                        .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke),
-                );
-                tip_tilt.extend(
-                    src.gradients()
-                        .into_iter()
-                        .zip(push_tip_tilt.into_iter())
-                        .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke),
-                );
-                segment_piston.extend(
-                    src.segment_piston()
-                        .into_iter()
-                        .zip(push_segment_piston.into_iter())
-                        .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke),
-                );
-                segment_tip_tilt.extend(
-                    src.segment_gradients()
-                        .into_iter()
-                        .zip(push_segment_tip_tilt.into_iter())
-                        .flat_map(|(left, right)| {
-                            left.into_iter()
-                                .zip(right.into_iter())
-                                .map(|(l, r)| 0.5f64 * (r as f64 - l as f64) / stroke)
-                                .collect::<Vec<f64>>()
-                        }),
-                );
-            }
+// Evaluates the optical outputs from a source in the exit pupil
+struct Evaluator {
+    n_px: usize,
+    projections: Vec<(Vec<usize>, na::DMatrix<f64>)>,
+    pssn: Option<HostPSSn<TelescopeError>>,
+}
+impl Evaluator {
+    fn new(builder: &OpticalSensitivitiesBuilder, src: &mut Source) -> Self {
+        let n_px = src.pupil_sampling as usize;
+        let amplitude = src.amplitude();
+        let n_mode = builder
+            .outputs
+            .iter()
+            .filter_map(|output| match output {
+                SensitivityOutput::Wavefront { n_mode } => Some(*n_mode),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default();
+        let projections = if n_mode > 0 {
+            amplitude
+                .chunks(n_px * n_px)
+                .map(|amplitude| zernike_projection(amplitude, n_px, n_mode))
+                .collect()
+        } else {
+            vec![]
+        };
+        let pssn = builder
+            .outputs
+            .iter()
+            .any(|output| {
+                matches!(
+                    output,
+                    SensitivityOutput::PSSn | SensitivityOutput::PSSnCurvature
+                )
+            })
+            .then(|| {
+                let (r0, oscale, zenith_angle) = builder.pssn;
+                let mut pssn = HostPSSn::<TelescopeError>::new(r0, oscale, zenith_angle);
+                pssn.reference(src);
+                pssn
+            });
+        Self {
+            n_px,
+            projections,
+            pssn,
         }
-        let optical_sensitivities = vec![
-            OpticalSensitivities::Wavefront(
-                phase
-                    .chunks(n)
-                    .flat_map(|pp| {
-                        pp.iter()
-                            .zip(amplitude.iter())
-                            .filter(|(_, a)| **a)
-                            .map(|(p, _)| *p)
-                            .collect::<Vec<f64>>()
-                    })
-                    .collect(),
-            ),
-            OpticalSensitivities::TipTilt(tip_tilt),
-            OpticalSensitivities::SegmentPiston(segment_piston),
-            OpticalSensitivities::SegmentTipTilt(segment_tip_tilt),
-            OpticalSensitivities::SegmentMask(
-                src.segment_mask()
-                    .iter()
-                    .zip(amplitude.iter())
-                    .filter(|(_, a)| **a)
-                    .map(|(p, _)| *p)
-                    .collect(),
-            ),
-        ];
-        println!(" ... done in {:.3}s", now.elapsed().as_secs_f64());
-        Ok(optical_sensitivities)
+    }
+    // Returns an error if a PSSn output is requested without a PSSn evaluator
+    fn outputs(
+        &mut self,
+        outputs: &[SensitivityOutput],
+        src: &mut Source,
+    ) -> Result<Vec<Vec<f64>>> {
+        let mut pssn: Option<Vec<f64>> = None;
+        outputs
+            .iter()
+            .map(|output| {
+                Ok(match output {
+                    SensitivityOutput::Wavefront { n_mode } => {
+                        let n = self.n_px * self.n_px;
+                        src.phase()
+                            .chunks(n)
+                            .zip(&self.projections)
+                            .flat_map(|(phase, (idx, projection))| {
+                                let p = na::DVector::from_iterator(
+                                    idx.len(),
+                                    idx.iter().map(|&k| phase[k] as f64),
+                                );
+                                let c = projection * p;
+                                c.as_slice()[..*n_mode].to_vec()
+                            })
+                            .collect()
+                    }
+                    SensitivityOutput::SegmentPiston => src.segment_piston(),
+                    SensitivityOutput::SegmentTipTilt => {
+                        let sxy = src.segment_gradients();
+                        let n = src.size as usize * 7;
+                        (0..src.size as usize)
+                            .flat_map(|i| {
+                                let (x, y) =
+                                    (&sxy[i * 7..(i + 1) * 7], &sxy[n + i * 7..n + (i + 1) * 7]);
+                                x.iter().chain(y).cloned().collect::<Vec<f64>>()
+                            })
+                            .collect()
+                    }
+                    SensitivityOutput::ImageCentroid => src.gradients(),
+                    SensitivityOutput::PSSn | SensitivityOutput::PSSnCurvature => match &pssn {
+                        Some(estimates) => estimates.clone(),
+                        None => {
+                            let evaluator = self
+                                .pssn
+                                .as_mut()
+                                .ok_or(SensitivitiesError::PSSn(*output))?;
+                            evaluator.reset();
+                            evaluator.integrate(src);
+                            let estimates = evaluator.peek().estimates.clone();
+                            pssn = Some(estimates.clone());
+                            estimates
+                        }
+                    },
+                })
+            })
+            .collect()
     }
 }
-/// Sensitivities serialization into a bincode file
-pub trait Bin {
-    fn dump(self) -> Result<Self, Box<dyn std::error::Error>>
-    where
-        Self: Sized;
-    fn load() -> Result<Self, Box<dyn std::error::Error>>
-    where
-        Self: Sized;
+
+/// Optical sensitivity matrices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpticalSensitivities {
+    /// number of M1 modes per segment
+    pub m1_n_mode: usize,
+    /// number of M2 modes per segment
+    pub m2_n_mode: usize,
+    /// whether the center segment clocking is included
+    pub s7_rz: bool,
+    /// degrees of freedom strokes
+    pub strokes: Vec<f64>,
+    /// GMT state around which the sensitivities are computed
+    pub state: Vec<f64>,
+    /// field sources zenith angles \[rd\]
+    pub zenith: Vec<f64>,
+    /// field sources azimuth angles \[rd\]
+    pub azimuth: Vec<f64>,
+    pub wavelength: f64,
+    pub sensitivities: Vec<Sensitivity>,
 }
-impl Bin for Vec<OpticalSensitivities> {
-    /// Serializes sensitivities
-    ///
-    /// Saves sensitivities into the file: "optical_sensitivities.rs.bin"
-    fn dump(self) -> Result<Self, Box<dyn std::error::Error>> {
-        //let repo = env::var("CFD_REPO")?;
-        let path = Path::new(".");
-        bincode::serialize_into(
-            BufWriter::with_capacity(
-                100_000,
-                File::create(path.join("optical_sensitivities.rs.bin"))?,
-            ),
-            &self,
-        )?;
-        Ok(self)
+impl OpticalSensitivities {
+    /// Creates a default [OpticalSensitivitiesBuilder]
+    pub fn builder() -> OpticalSensitivitiesBuilder {
+        Default::default()
+    }
+    /// Returns the number of degrees of freedom
+    pub fn n_dof(&self) -> usize {
+        self.strokes.len()
+    }
+    /// Returns the sensitivity of an output
+    pub fn get(&self, output: SensitivityOutput) -> Result<&Sensitivity> {
+        self.sensitivities
+            .iter()
+            .find(|s| s.output == output)
+            .ok_or(SensitivitiesError::Missing(output))
+    }
+    /// Returns a [SegmentsDof] for the degrees of freedom layout of the sensitivities
+    pub fn segments_dof(&self) -> SegmentsDof {
+        let dofs = SegmentsDof::new()
+            .m1_n_mode(self.m1_n_mode)
+            .m2_n_mode(self.m2_n_mode);
+        if self.s7_rz {
+            dofs.include_s7_rz()
+        } else {
+            dofs
+        }
     }
-    fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        //let repo = env::var("CFD_REPO")?;
-        let data_path = Path::new("optical_sensitivities.rs.bin");
-        println!("Loading sensitivities from {:?}", data_path);
-        bincode::deserialize_from(BufReader::with_capacity(100_000, File::open(data_path)?))
-            .map_err(|e| e.into())
+    /// Saves the sensitivities to a pickle file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(
+            File::create(path).map_err(|e| SensitivitiesError::Create(e, path.to_path_buf()))?,
+        );
+        serde_pickle::to_writer(&mut file, self, Default::default())?;
+        Ok(())
+    }
+    /// Loads the sensitivities from a pickle file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = BufReader::new(
+            File::open(path).map_err(|e| SensitivitiesError::Open(e, path.to_path_buf()))?,
+        );
+        Ok(serde_pickle::from_reader(file, Default::default())?)
     }
 }
+impl Index<SensitivityOutput> for OpticalSensitivities {
+    type Output = Sensitivity;
 
-impl PartialEq<OpticalSensitivities> for OpticalSensitivities {
-    fn eq(&self, other: &OpticalSensitivities) -> bool {
-        use OpticalSensitivities::*;
-        match (self, other) {
-            (Wavefront(_), Wavefront(_)) => true,
-            (TipTilt(_), TipTilt(_)) => true,
-            (SegmentTipTilt(_), SegmentTipTilt(_)) => true,
-            (SegmentPiston(_), SegmentPiston(_)) => true,
-            (SegmentMask(_), SegmentMask(_)) => true,
-            _ => false,
-        }
+    /// Returns the sensitivity of an output
+    ///
+    /// # Panics
+    ///
+    /// The sensitivities must include the output, use [OpticalSensitivities::get] otherwise
+    fn index(&self, output: SensitivityOutput) -> &Self::Output {
+        self.get(output).unwrap()
     }
 }
-impl std::ops::Index<OpticalSensitivities> for &[OpticalSensitivities] {
-    type Output = OpticalSensitivities;
 
-    fn index(&self, index: OpticalSensitivities) -> &Self::Output {
-        self.iter()
-            .find_map(|s| if index == *s { Some(s) } else { None })
-            .unwrap()
+// Noll's indices `(n,m)` of mode `j`
+fn noll(j: usize) -> (usize, isize) {
+    let mut n = 0;
+    while (n + 1) * (n + 2) / 2 < j {
+        n += 1;
+    }
+    let k = j - n * (n + 1) / 2 - 1;
+    let m = (n % 2 + 2 * ((k + (n + 1) % 2) / 2)) as isize;
+    (n, if j.is_multiple_of(2) { m } else { -m })
+}
+// Noll's normalized Zernike polynomial `j` at polar coordinates `(r,o)` in the unit disk
+pub(crate) fn zernike(j: usize, r: f64, o: f64) -> f64 {
+    let (n, m) = noll(j);
+    let ma = m.unsigned_abs();
+    let fact = |k: usize| (1..=k).map(|x| x as f64).product::<f64>();
+    let radial: f64 = (0..=(n - ma) / 2)
+        .map(|s| {
+            let sign = if s % 2 == 0 { 1. } else { -1. };
+            sign * fact(n - s) / (fact(s) * fact((n + ma) / 2 - s) * fact((n - ma) / 2 - s))
+                * r.powi((n - 2 * s) as i32)
+        })
+        .sum();
+    let norm = ((n + 1) as f64 * if m == 0 { 1. } else { 2. }).sqrt();
+    norm * radial
+        * match m {
+            0 => 1.,
+            m if m > 0 => (m as f64 * o).cos(),
+            m => (-m as f64 * o).sin(),
+        }
+}
+// least squares projection of the illuminated pupil onto the first `n_mode` Zernike polynomials
+fn zernike_projection(
+    amplitude: &[f32],
+    n_px: usize,
+    n_mode: usize,
+) -> (Vec<usize>, na::DMatrix<f64>) {
+    let idx: Vec<usize> = amplitude
+        .iter()
+        .enumerate()
+        .filter_map(|(k, &a)| (a > 0.).then_some(k))
+        .collect();
+    let c = 0.5 * (n_px - 1) as f64;
+    let z = na::DMatrix::from_fn(idx.len(), n_mode, |i, j| {
+        let (x, y) = (
+            ((idx[i] % n_px) as f64 - c) / c,
+            ((idx[i] / n_px) as f64 - c) / c,
+        );
+        zernike(j + 1, x.hypot(y), y.atan2(x))
+    });
+    let projection = z
+        .pseudo_inverse(1e-9)
+        .unwrap_or_else(|_| na::DMatrix::zeros(n_mode, idx.len()));
+    (idx, projection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noll_indices() {
+        let nm: Vec<_> = (1..=11).map(noll).collect();
+        assert_eq!(
+            nm,
+            vec![
+                (0, 0),
+                (1, 1),
+                (1, -1),
+                (2, 0),
+                (2, -2),
+                (2, 2),
+                (3, -1),
+                (3, 1),
+                (3, -3),
+                (3, 3),
+                (4, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn zernike_projection_focus() {
+        let n_px = 64;
+        let c = 0.5 * (n_px - 1) as f64;
+        let amplitude: Vec<f32> = (0..n_px * n_px)
+            .map(|k| {
+                let (x, y) = ((k % n_px) as f64 - c, (k / n_px) as f64 - c);
+                (x.hypot(y) <= c) as i32 as f32
+            })
+            .collect();
+        let (idx, projection) = zernike_projection(&amplitude, n_px, 6);
+        let phase = na::DVector::from_iterator(
+            idx.len(),
+            idx.iter().map(|&k| {
+                let (x, y) = (((k % n_px) as f64 - c) / c, ((k / n_px) as f64 - c) / c);
+                1e-7 * zernike(4, x.hypot(y), y.atan2(x))
+            }),
+        );
+        let a = projection * phase;
+        assert!((a[3] - 1e-7).abs() < 1e-12);
+        assert!(a.iter().enumerate().all(|(i, a)| i == 3 || a.abs() < 1e-12));
     }
 }
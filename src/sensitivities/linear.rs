@@ -0,0 +1,254 @@
+//!
+//! # Linear optical model
+//!
+//! A surrogate of the GMT optical model that applies [OpticalSensitivities]
+//! and wavefront sensor [Calibration](crate::Calibration) matrices to a GMT state
+//! instead of ray tracing.
+//!
+//! The outputs `y` are linearized around the GMT state `x0` of the sensitivities:
+//! `y = y0 + J(x-x0)`, and with the second order correction:
+//! `y = y0 + J(x-x0) + D(x-x0)^2/2` where `D` are the second derivatives
+//! with respect to each degree of freedom.
+//!
+//! The model implements [Propagation] so it can replace the GMT and the wavefront sensors in the loop code,
+//! the [Source] propagated through the model is not used: the outputs are computed for the guide stars
+//! and the wavelength of the sensitivities.
+//!
+//! ```no_run
+//! use crseo::{
+//!     gmt::dof::SegmentsDof,
+//!     sensitivities::{LinearOpticalModel, OpticalSensitivities, SensitivityOutput},
+//! };
+//! let sens = OpticalSensitivities::load("optical_sensitivities.pkl")?;
+//! let mut model = LinearOpticalModel::from(&sens).second_order(true);
+//! let state = SegmentsDof::new().from_vec(vec![1e-7; 82]);
+//! model.update(state).compute();
+//! let piston = model.output(SensitivityOutput::SegmentPiston);
+//! # Ok::<(), crseo::sensitivities::SensitivitiesError>(())
+//! ```
+
+use nalgebra as na;
+
+use super::{OpticalSensitivities, SensitivityOutput};
+use crate::{Propagation, Source};
+
+/// Linear optical model outputs
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelOutput {
+    /// Optical outputs of the [OpticalSensitivities]
+    Optical(SensitivityOutput),
+    /// Wavefront sensor data
+    Wfs(String),
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    output: ModelOutput,
+    value: na::DVector<f64>,
+    jacobian: na::DMatrix<f64>,
+    curvature: Option<na::DMatrix<f64>>,
+    // degrees of freedom of the Jacobian columns, all if `None`
+    dofs: Option<Vec<usize>>,
+    y: na::DVector<f64>,
+}
+impl Block {
+    fn propagate(&mut self, delta: &na::DVector<f64>, second_order: bool) {
+        let delta = match &self.dofs {
+            Some(dofs) => na::DVector::from_iterator(dofs.len(), dofs.iter().map(|&i| delta[i])),
+            None => delta.clone(),
+        };
+        self.y.copy_from(&self.value);
+        self.y.gemv(1., &self.jacobian, &delta, 1.);
+        if let (true, Some(curvature)) = (second_order, &self.curvature) {
+            self.y
+                .gemv(0.5, curvature, &delta.component_mul(&delta), 1.);
+        }
+    }
+}
+
+/// Linear optical model
+#[derive(Debug, Clone)]
+pub struct LinearOpticalModel {
+    state0: na::DVector<f64>,
+    delta: na::DVector<f64>,
+    blocks: Vec<Block>,
+    second_order: bool,
+}
+impl From<&OpticalSensitivities> for LinearOpticalModel {
+    /// Creates a linear optical model from all the optical sensitivities
+    fn from(sens: &OpticalSensitivities) -> Self {
+        let n_dof = sens.n_dof();
+        let blocks = sens
+            .sensitivities
+            .iter()
+            .filter(|s| s.output != SensitivityOutput::PSSnCurvature)
+            .map(|s| Block {
+                output: ModelOutput::Optical(s.output),
+                value: if s.value.len() == s.n_row {
+                    na::DVector::from_column_slice(&s.value)
+                } else {
+                    na::DVector::zeros(s.n_row)
+                },
+                jacobian: s.matrix(),
+                curvature: s.curvature_matrix(),
+                dofs: None,
+                y: na::DVector::zeros(s.n_row),
+            })
+            .collect();
+        Self {
+            state0: na::DVector::from_column_slice(&sens.state),
+            delta: na::DVector::zeros(n_dof),
+            blocks,
+            second_order: false,
+        }
+    }
+}
+impl LinearOpticalModel {
+    /// Enables or disables the second order correction
+    pub fn second_order(self, second_order: bool) -> Self {
+        Self {
+            second_order,
+            ..self
+        }
+    }
+    /// Adds a wavefront sensor
+    ///
+    /// The columns of the `calibration` matrix are the sensor responses to the degrees of freedom
+    /// at the indices `dofs` of the GMT state vector, e.g. [Calibration::matrix](crate::Calibration::matrix).
+    pub fn wfs(
+        mut self,
+        name: impl Into<String>,
+        calibration: na::DMatrix<f64>,
+        dofs: Vec<usize>,
+    ) -> Self {
+        assert_eq!(
+            calibration.ncols(),
+            dofs.len(),
+            "the calibration matrix has {} columns, expected {}",
+            calibration.ncols(),
+            dofs.len()
+        );
+        assert!(
+            dofs.iter().all(|&i| i < self.delta.len()),
+            "the degrees of freedom indices must be less than {}",
+            self.delta.len()
+        );
+        let n = calibration.nrows();
+        self.blocks.push(Block {
+            output: ModelOutput::Wfs(name.into()),
+            value: na::DVector::zeros(n),
+            jacobian: calibration,
+            curvature: None,
+            dofs: Some(dofs),
+            y: na::DVector::zeros(n),
+        });
+        self
+    }
+    /// Returns the number of degrees of freedom
+    pub fn n_dof(&self) -> usize {
+        self.delta.len()
+    }
+    /// Returns the model outputs
+    pub fn outputs(&self) -> Vec<&ModelOutput> {
+        self.blocks.iter().map(|b| &b.output).collect()
+    }
+    /// Updates the GMT state
+    ///
+    /// The state is a [SegmentsDof](crate::gmt::dof::SegmentsDof) or a vector with the same layout
+    pub fn update(&mut self, state: impl Into<Vec<f64>>) -> &mut Self {
+        let state: Vec<f64> = state.into();
+        assert_eq!(
+            state.len(),
+            self.delta.len(),
+            "expected a state with {} elements, found {}",
+            self.delta.len(),
+            state.len()
+        );
+        self.delta
+            .iter_mut()
+            .zip(state)
+            .zip(self.state0.iter())
+            .for_each(|((d, x), x0)| *d = x - x0);
+        self
+    }
+    /// Computes the outputs for the current GMT state
+    pub fn compute(&mut self) -> &mut Self {
+        let (delta, second_order) = (&self.delta, self.second_order);
+        self.blocks
+            .iter_mut()
+            .for_each(|block| block.propagate(delta, second_order));
+        self
+    }
+    fn find(&self, output: &ModelOutput) -> Option<&[f64]> {
+        self.blocks
+            .iter()
+            .find(|b| b.output == *output)
+            .map(|b| b.y.as_slice())
+    }
+    /// Returns an optical output
+    pub fn output(&self, output: SensitivityOutput) -> Option<&[f64]> {
+        self.find(&ModelOutput::Optical(output))
+    }
+    /// Returns the data of a wavefront sensor
+    pub fn wfs_data(&self, name: &str) -> Option<&[f64]> {
+        self.find(&ModelOutput::Wfs(name.to_string()))
+    }
+    /// Returns the segment piston \[m\]
+    pub fn segment_piston(&self) -> Option<&[f64]> {
+        self.output(SensitivityOutput::SegmentPiston)
+    }
+    /// Returns the image motion \[rd\]
+    pub fn image_motion(&self) -> Option<&[f64]> {
+        self.output(SensitivityOutput::ImageCentroid)
+    }
+}
+
+impl Propagation for LinearOpticalModel {
+    /// Computes the outputs for the current GMT state, `src` is not used
+    fn propagate(&mut self, _src: &mut Source) {
+        self.compute();
+    }
+    /// Computes the outputs for the current GMT state, the model has no dynamics and `src` is not used
+    fn time_propagate(&mut self, _secs: f64, src: &mut Source) {
+        self.propagate(src);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensitivities::Sensitivity;
+
+    #[test]
+    fn quadratic() {
+        // y = 1 + 2x0 - x1 + (x0^2 + 4x1^2)/2
+        let sens = OpticalSensitivities {
+            m1_n_mode: 0,
+            m2_n_mode: 0,
+            s7_rz: false,
+            strokes: vec![1e-6; 2],
+            state: vec![0.; 2],
+            zenith: vec![0.],
+            azimuth: vec![0.],
+            wavelength: 500e-9,
+            sensitivities: vec![Sensitivity {
+                output: SensitivityOutput::PSSn,
+                n_row: 1,
+                data: vec![2., -1.],
+                value: vec![1.],
+                curvature: vec![1., 4.],
+            }],
+        };
+        let mut model = LinearOpticalModel::from(&sens).wfs(
+            "wfs",
+            na::DMatrix::from_column_slice(2, 1, &[1., 2.]),
+            vec![1],
+        );
+        model.update(vec![1., 2.]).compute();
+        assert_eq!(model.output(SensitivityOutput::PSSn), Some(&[1.][..]));
+        assert_eq!(model.wfs_data("wfs"), Some(&[2., 4.][..]));
+        let mut model = model.second_order(true);
+        model.compute();
+        assert_eq!(model.output(SensitivityOutput::PSSn), Some(&[9.5][..]));
+    }
+}
@@ -22,10 +22,13 @@
 //! let mut src = ceo!(Source, size = [3] , on_ring = [8f32.from_arcmin()]);
 //! ```
 
-use crate::{builders::SourceBuilder, cu::Int, utilities::Mask};
+use crate::{
+    builders::SourceBuilder, cu::Int, imaging::Misregistration, lgs::LaserGuideStar,
+    utilities::Mask,
+};
 
 use super::{cu::Double, cu::Single, Centroiding, Cu, FromBuilder};
-use ffi::{bundle, dev2host, dev2host_int, source, vector};
+use ffi::{bundle, dev2host, dev2host_int, host2dev, source, vector};
 use serde::{Deserialize, Serialize};
 use skyangle::Conversion;
 
@@ -89,6 +92,8 @@ pub struct Source {
     pub zenith: Vec<f32>,
     pub azimuth: Vec<f32>,
     pub magnitude: Vec<f32>,
+    pub(crate) laser_guide_star: Option<LaserGuideStar>,
+    pub(crate) misregistration: Option<Misregistration>,
 }
 impl Display for Source {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -155,6 +160,8 @@ impl Source {
             zenith: vec![],
             azimuth: vec![],
             magnitude: vec![],
+            laser_guide_star: None,
+            misregistration: None,
         }
     }
     /// Creates a new `Source` with the arguments:
@@ -172,6 +179,8 @@ impl Source {
             zenith: vec![0.0; size as usize],
             azimuth: vec![0.0; size as usize],
             magnitude: vec![0.0; size as usize],
+            laser_guide_star: None,
+            misregistration: None,
         }
     }
     pub fn pupil_sampling(&self) -> usize {
@@ -234,6 +243,52 @@ impl Source {
     pub fn xy(&self) -> (f64, f64) {
         (self._c_.theta_x as f64, self._c_.theta_x as f64)
     }
+    /// Returns the `Source` height \[m\], infinite unless the source is a laser guide star
+    pub fn height(&self) -> f64 {
+        self._c_.height as f64
+    }
+    /// Returns the laser guide star, if the `Source` is a laser guide star
+    pub fn laser_guide_star(&self) -> Option<&LaserGuideStar> {
+        self.laser_guide_star.as_ref()
+    }
+    /// Evolves the sodium layer of the laser guide star by `secs` seconds
+    ///
+    /// The `Source` height is set to the new altitude of the sodium layer centroid.
+    /// Does nothing if the `Source` is not a laser guide star
+    pub fn evolve_sodium<R: rand::Rng>(&mut self, secs: f64, rng: &mut R) {
+        let Some(lgs) = self.laser_guide_star.as_mut() else {
+            return;
+        };
+        lgs.sodium.evolve(secs, rng);
+        let height = lgs.height();
+        self.set_height(height);
+    }
+    // Sets the height of the sources on the host and on the device
+    fn set_height(&mut self, height: f64) {
+        self._c_.height = height as f32;
+        self._c_._height_64_ = height;
+        if self._c_.dev_ptr.is_null() {
+            return;
+        }
+        let n = self.size as usize * std::mem::size_of::<source>() / std::mem::size_of::<f32>();
+        let mut sources = vec![self._c_; self.size as usize];
+        unsafe {
+            dev2host(
+                sources.as_mut_ptr() as *mut f32,
+                self._c_.dev_ptr as *mut f32,
+                n as i32,
+            );
+            sources.iter_mut().for_each(|src| {
+                src.height = height as f32;
+                src._height_64_ = height;
+            });
+            host2dev(
+                self._c_.dev_ptr as *mut f32,
+                sources.as_mut_ptr() as *mut f32,
+                n as i32,
+            );
+        }
+    }
     /// Updates the `zenith` and `azimuth` of the `Source`
     pub fn update(&mut self, mut zenith: Vec<f64>, mut azimuth: Vec<f64>) {
         unsafe {
@@ -530,6 +585,19 @@ impl Source {
         phase.from_ptr(self._c_.wavefront.phase);
         phase.into()
     }
+    /// Sets the wavefront `amplitude` and `phase` \[m\] of the `Source`
+    ///
+    /// Both slices have the length of the wavefronts of all the sources
+    pub(crate) fn set_wavefront(&mut self, amplitude: &mut [f32], phase: &mut [f32]) -> &mut Self {
+        let n = self._c_.wavefront.N_PX;
+        assert_eq!(amplitude.len(), n as usize);
+        assert_eq!(phase.len(), n as usize);
+        unsafe {
+            host2dev(self._c_.wavefront.amplitude, amplitude.as_mut_ptr(), n);
+            host2dev(self._c_.wavefront.phase, phase.as_mut_ptr(), n);
+        }
+        self
+    }
     /// Returns the wavefront amplitude in the exit pupil of the telescope
     pub fn amplitude(&mut self) -> Vec<f32> {
         let n = self._c_.wavefront.N_PX;
@@ -0,0 +1,453 @@
+//!
+//! # Fourier domain wavefront sensor models
+//!
+//! Linear models of the Shack-Hartmann and of the pyramid wavefront sensors
+//! where the sensors are transfer functions applied to the phase in the spatial frequency domain.
+//!
+//! The [FourierSensor] trait gives, from the transfer functions of a sensor,
+//!  - the measurements of a phase screen, including the aliasing of the frequencies beyond the sensor Nyquist frequency and the noise,
+//!  - the least-squares reconstructor in the spatial frequency domain,
+//!  - the power spectral densities and the variances of the fitting, aliasing and noise errors of the reconstructed phase.
+//!
+//! The turbulence statistics are given by [VonKarman].
+//!
+//! All the phase quantities are in radians at the wavelength of r0.
+//!
+//! # Examples
+//!
+//! ```
+//! use crseo::{
+//!     atmosphere::theory::VonKarman,
+//!     wavefrontsensor::fourier::{FourierPyramid, FourierSensor, FourierShackHartmann},
+//! };
+//! let atm = VonKarman::new(0.16, 25.).at_wavelength(1.65e-6);
+//! let sh = FourierShackHartmann::new(25.5 / 48.).noise_variance(1e-2);
+//! let pym = FourierPyramid::new(25.5 / 92., 25.5).modulation(2.);
+//! for wfs in [&sh as &dyn FourierSensor, &pym] {
+//!     let error = wfs.error_variance(&atm, 64);
+//!     println!("{:?}: {:.3}rd²", error, error.sum());
+//! }
+//! ```
+
+use std::f64::consts::PI;
+
+use rand::{rngs::StdRng, RngExt, SeedableRng};
+use rand_distr::StandardNormal;
+use rustfft::{num_complex::Complex, FftPlanner};
+
+use crate::{atmosphere::theory::VonKarman, pssn::host::fft2};
+
+/// Number of replicas of the spectrum on each side of the sensor frequency domain in the aliasing error
+pub const ALIASING_ORDER: i32 = 8;
+
+/// Fitting, aliasing and noise errors of the reconstructed phase
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReconstructionError {
+    pub fitting: f64,
+    pub aliasing: f64,
+    pub noise: f64,
+}
+impl ReconstructionError {
+    /// Returns the sum of the errors
+    pub fn sum(&self) -> f64 {
+        self.fitting + self.aliasing + self.noise
+    }
+}
+
+/// Linear wavefront sensor model in the spatial frequency domain
+///
+/// The sensor samples the measurements on a square grid of pitch [pitch](FourierSensor::pitch)
+/// and the phase is reconstructed within the square frequency domain bounded by the Nyquist frequency `1/(2 pitch)`
+pub trait FourierSensor {
+    /// Returns the sampling pitch \[m\] of the measurements
+    fn pitch(&self) -> f64;
+    /// Returns the variance of the noise of a measurement
+    fn noise(&self) -> f64;
+    /// Returns the transfer functions from the phase to the measurements along the X and Y axis
+    /// at the spatial frequency (`fx`,`fy`) \[m⁻¹\]
+    fn transfer(&self, fx: f64, fy: f64) -> (Complex<f64>, Complex<f64>);
+    /// Returns the sensor Nyquist frequency \[m⁻¹\]
+    fn cutoff(&self) -> f64 {
+        0.5 / self.pitch()
+    }
+    /// Returns the least-squares reconstructor from the measurements along the X and Y axis
+    /// to the phase at the spatial frequency (`fx`,`fy`) \[m⁻¹\]
+    ///
+    /// The reconstructor is 0 outside the sensor frequency domain and where the sensor is blind
+    fn reconstructor(&self, fx: f64, fy: f64) -> (Complex<f64>, Complex<f64>) {
+        let fc = self.cutoff();
+        let zero = Complex::new(0., 0.);
+        if fx.abs() > fc || fy.abs() > fc {
+            return (zero, zero);
+        }
+        let (gx, gy) = self.transfer(fx, fy);
+        let g2 = gx.norm_sqr() + gy.norm_sqr();
+        if g2 > f64::EPSILON {
+            (gx.conj() / g2, gy.conj() / g2)
+        } else {
+            (zero, zero)
+        }
+    }
+    /// Returns the fitting, aliasing and noise power spectral densities \[rd²m²\]
+    /// of the reconstructed phase at the spatial frequency (`fx`,`fy`) \[m⁻¹\]
+    fn error_psd(&self, atm: &VonKarman, fx: f64, fy: f64) -> ReconstructionError {
+        let fc = self.cutoff();
+        if fx.abs() > fc || fy.abs() > fc {
+            return ReconstructionError {
+                fitting: atm.psd(fx.hypot(fy)),
+                ..Default::default()
+            };
+        }
+        let (rx, ry) = self.reconstructor(fx, fy);
+        let df = 1. / self.pitch();
+        let mut aliasing = 0.;
+        for m in -ALIASING_ORDER..=ALIASING_ORDER {
+            for n in -ALIASING_ORDER..=ALIASING_ORDER {
+                if m == 0 && n == 0 {
+                    continue;
+                }
+                let (fx_mn, fy_mn) = (fx + m as f64 * df, fy + n as f64 * df);
+                let (gx, gy) = self.transfer(fx_mn, fy_mn);
+                aliasing += (rx * gx + ry * gy).norm_sqr() * atm.psd(fx_mn.hypot(fy_mn));
+            }
+        }
+        ReconstructionError {
+            fitting: 0.,
+            aliasing,
+            noise: self.noise() * self.pitch().powi(2) * (rx.norm_sqr() + ry.norm_sqr()),
+        }
+    }
+    /// Returns the fitting, aliasing and noise variances \[rd²\] of the reconstructed phase
+    ///
+    /// The aliasing and noise power spectral densities are integrated on a grid of `n`x`n` frequencies
+    /// across the sensor frequency domain, the grid does not include the null frequency.
+    /// The fitting variance is integrated analytically outside the sensor frequency domain
+    fn error_variance(&self, atm: &VonKarman, n: usize) -> ReconstructionError {
+        let fc = self.cutoff();
+        let df = 2. * fc / n as f64;
+        let f = |i: usize| (i as f64 + 0.5) * df - fc;
+        let mut error = ReconstructionError::default();
+        for j in 0..n {
+            for i in 0..n {
+                let psd = self.error_psd(atm, f(i), f(j));
+                error.aliasing += psd.aliasing;
+                error.noise += psd.noise;
+            }
+        }
+        error.aliasing *= df * df;
+        error.noise *= df * df;
+        // ∫_r^∞ Φ(f) f df = 3/5 Φ(r)(r²+1/L0²) for the von Kármán spectrum
+        let n_angle = 1000;
+        let da = 2. * PI / n_angle as f64;
+        error.fitting = (0..n_angle)
+            .map(|k| {
+                let a = (k as f64 + 0.5) * da;
+                let r = fc / a.cos().abs().max(a.sin().abs());
+                0.6 * atm.psd(r) * (r * r + atm.oscale.powi(-2))
+            })
+            .sum::<f64>()
+            * da;
+        error
+    }
+    /// Returns the measurements `[sx,sy]` of the `phase` screen \[rd\]
+    ///
+    /// The phase is a square screen sampled with `dx` \[m\], `dx` is a divider of the sensor pitch.
+    /// The measurements of the screen frequencies beyond the sensor Nyquist frequency
+    /// are aliased into the sensor frequency domain
+    fn slopes(&self, phase: &[f64], dx: f64) -> Vec<f64> {
+        let n = (phase.len() as f64).sqrt() as usize;
+        let m = (self.pitch() / dx).round().max(1.) as usize;
+        let n_sub = n / m;
+        let mut planner = FftPlanner::new();
+        let (forward, inverse) = (planner.plan_fft_forward(n), planner.plan_fft_inverse(n));
+        let mut spectrum: Vec<Complex<f64>> = phase.iter().map(|p| Complex::new(*p, 0.)).collect();
+        fft2(&mut spectrum, n, &forward);
+        let wrap = |i: usize| {
+            if i < n / 2 {
+                i as f64
+            } else {
+                i as f64 - n as f64
+            }
+        };
+        let df = 1. / (n as f64 * dx);
+        // the measurements are sampled at the center of the sub-apertures
+        let s = 0.5 * (m - 1) as f64 * dx;
+        let (mut sx, mut sy): (Vec<_>, Vec<_>) = spectrum
+            .iter()
+            .enumerate()
+            .map(|(l, p)| {
+                let (fx, fy) = (wrap(l % n) * df, wrap(l / n) * df);
+                let (gx, gy) = self.transfer(fx, fy);
+                let shift = Complex::from_polar(1. / (n * n) as f64, 2. * PI * (fx + fy) * s);
+                (gx * p * shift, gy * p * shift)
+            })
+            .unzip();
+        fft2(&mut sx, n, &inverse);
+        fft2(&mut sy, n, &inverse);
+        [sx, sy]
+            .iter()
+            .flat_map(|s| {
+                (0..n_sub * n_sub).map(move |k| s[(k % n_sub) * m + n * (k / n_sub) * m].re)
+            })
+            .collect()
+    }
+    /// Returns the measurements `[sx,sy]` of the `phase` screen \[rd\] with the measurement noise
+    ///
+    /// The noise is a white Gaussian noise drawn from a random generator seeded with `seed`
+    fn noisy_slopes(&self, phase: &[f64], dx: f64, seed: u64) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let sigma = self.noise().sqrt();
+        self.slopes(phase, dx)
+            .into_iter()
+            .map(|s| s + sigma * rng.sample::<f64, _>(StandardNormal))
+            .collect()
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0. {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Fourier domain Shack-Hartmann model
+///
+/// The measurements are the phase gradients \[rd/m\] averaged over the square lenslets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FourierShackHartmann {
+    /// lenslet size \[m\]
+    pub pitch: f64,
+    /// noise variance \[rd²/m²\]
+    pub noise_variance: f64,
+}
+impl FourierShackHartmann {
+    /// Creates a noiseless Shack-Hartmann with the lenslet size `pitch` \[m\]
+    pub fn new(pitch: f64) -> Self {
+        Self {
+            pitch,
+            noise_variance: 0.,
+        }
+    }
+    /// Sets the noise variance \[rd²/m²\] of the phase gradients
+    pub fn noise_variance(self, noise_variance: f64) -> Self {
+        Self {
+            noise_variance,
+            ..self
+        }
+    }
+}
+impl FourierSensor for FourierShackHartmann {
+    fn pitch(&self) -> f64 {
+        self.pitch
+    }
+    fn noise(&self) -> f64 {
+        self.noise_variance
+    }
+    fn transfer(&self, fx: f64, fy: f64) -> (Complex<f64>, Complex<f64>) {
+        let d = self.pitch;
+        let average = sinc(d * fx) * sinc(d * fy);
+        (
+            Complex::new(0., 2. * PI * fx * average),
+            Complex::new(0., 2. * PI * fy * average),
+        )
+    }
+}
+
+/// Fourier domain pyramid model
+///
+/// The measurements are the normalized intensity differences between the pupil images
+/// with the circular modulation of the pyramid apex.
+/// The pyramid behaves as a slope sensor for the spatial frequencies below the modulation frequency
+/// and as a phase sensor above the modulation frequency (Vérinaud, 2004)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FourierPyramid {
+    /// pupil sampling \[m\]
+    pub pitch: f64,
+    /// telescope diameter \[m\]
+    pub diameter: f64,
+    /// modulation radius in units of λ/D
+    pub modulation: f64,
+    /// noise variance
+    pub noise_variance: f64,
+}
+impl FourierPyramid {
+    /// Creates a noiseless and unmodulated pyramid with the pupil sampling `pitch` \[m\]
+    /// for a telescope of diameter `diameter` \[m\]
+    pub fn new(pitch: f64, diameter: f64) -> Self {
+        Self {
+            pitch,
+            diameter,
+            modulation: 0.,
+            noise_variance: 0.,
+        }
+    }
+    /// Sets the modulation radius in units of λ/D
+    pub fn modulation(self, modulation: f64) -> Self {
+        Self { modulation, ..self }
+    }
+    /// Sets the noise variance of the measurements
+    pub fn noise_variance(self, noise_variance: f64) -> Self {
+        Self {
+            noise_variance,
+            ..self
+        }
+    }
+    // response along one axis
+    fn response(&self, f: f64) -> f64 {
+        let f_mod = self.modulation / self.diameter;
+        if f == 0. {
+            0.
+        } else if f.abs() < f_mod {
+            2. * (f / f_mod).asin() / PI
+        } else {
+            f.signum()
+        }
+    }
+    /// Returns the optical gains of the pyramid at the spatial `frequencies` \[m⁻¹\] along the X axis
+    ///
+    /// The optical gains are given by a heuristic model of the partially corrected PSF seen by the pyramid,
+    /// it is not the convolutional model of Chambouleyron et al. (2020):
+    /// the PSF is a diffraction limited core weighted by the Strehl ratio, that keeps the unperturbed response,
+    /// and a halo given by the power spectral density `residual_psd` \[rd²m²\] of the residual phase.
+    /// At the frequency `f`, the halo contributes with the fraction of its energy, averaged over the modulation circle,
+    /// that is closer to the apex than `f` along the X axis.
+    /// The PSF halo is sampled on a grid of `n`x`n` frequencies up to 4 times the Nyquist frequency of the pupil sampling.
+    /// The optical gains are the ratios between the sensitivities of the pyramid with and without the residual phase
+    pub fn optical_gains<F: Fn(f64, f64) -> f64>(
+        &self,
+        frequencies: &[f64],
+        residual_psd: F,
+        n: usize,
+    ) -> Vec<f64> {
+        let f_max = 4. * self.cutoff();
+        let df = 2. * f_max / n as f64;
+        let u = |i: usize| (i as f64 + 0.5) * df - f_max;
+        // marginal of the residual phase PSD along the X axis
+        let marginal: Vec<f64> = (0..n)
+            .map(|i| (0..n).map(|j| residual_psd(u(i), u(j))).sum::<f64>() * df * df)
+            .collect();
+        let variance: f64 = marginal.iter().sum();
+        let strehl = (-variance).exp();
+        let f_mod = self.modulation / self.diameter;
+        let n_theta = if f_mod > 0. { 360 } else { 1 };
+        frequencies
+            .iter()
+            .map(|&f| {
+                let h = self.response(f).abs();
+                if h == 0. || variance == 0. {
+                    return 1.;
+                }
+                // fraction of the modulated PSF halo that contributes to the signal
+                let halo = (0..n_theta)
+                    .map(|k| {
+                        let x = f_mod * (2. * PI * (k as f64 + 0.5) / n_theta as f64).cos();
+                        marginal
+                            .iter()
+                            .enumerate()
+                            .filter(|(i, _)| (x + u(*i)).abs() < f.abs())
+                            .map(|(_, m)| m)
+                            .sum::<f64>()
+                    })
+                    .sum::<f64>()
+                    / (n_theta as f64 * variance);
+                (strehl * h + (1. - strehl) * halo) / h
+            })
+            .collect()
+    }
+}
+impl FourierSensor for FourierPyramid {
+    fn pitch(&self) -> f64 {
+        self.pitch
+    }
+    fn noise(&self) -> f64 {
+        self.noise_variance
+    }
+    fn transfer(&self, fx: f64, fy: f64) -> (Complex<f64>, Complex<f64>) {
+        let d = self.pitch;
+        let average = sinc(d * fx) * sinc(d * fy);
+        (
+            Complex::new(0., self.response(fx) * average),
+            Complex::new(0., self.response(fy) * average),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sh_sinusoid() {
+        let (n, dx) = (64, 0.1);
+        let f0 = 2. / (n as f64 * dx);
+        let phase: Vec<f64> = (0..n * n)
+            .map(|k| (2. * PI * f0 * (k % n) as f64 * dx).cos())
+            .collect();
+        let wfs = FourierShackHartmann::new(0.4);
+        let slopes = wfs.slopes(&phase, dx);
+        let n_sub = n / 4;
+        let (sx, sy) = slopes.split_at(n_sub * n_sub);
+        sx.iter().enumerate().for_each(|(k, s)| {
+            let x = ((k % n_sub) as f64 + 0.375) * 0.4;
+            let s0 = -2. * PI * f0 * (2. * PI * f0 * x).sin() * sinc(0.4 * f0);
+            assert!((s - s0).abs() < 1e-9, "{s} {s0}");
+        });
+        assert!(sy.iter().all(|s| s.abs() < 1e-9));
+    }
+
+    #[test]
+    fn sh_fitting() {
+        // Kolmogorov fitting error of a square frequency domain: 0.23(d/r0)^5/3
+        let atm = VonKarman::new(0.1, 1e8);
+        let error = FourierShackHartmann::new(0.5).error_variance(&atm, 16);
+        let fitting = 0.23 * 5f64.powf(5. / 3.);
+        assert!(
+            (error.fitting - fitting).abs() < 0.05 * fitting,
+            "{error:?}"
+        );
+        assert!(error.aliasing > 0. && error.noise == 0.);
+    }
+
+    #[test]
+    fn pyramid_response() {
+        let pym = FourierPyramid::new(0.25, 25.).modulation(4.);
+        // linear (slope sensor) below the modulation frequency
+        let r = pym.response(0.01) / pym.response(0.02);
+        assert!((r - 0.5).abs() < 1e-3);
+        // saturated (phase sensor) above the modulation frequency
+        assert_eq!(pym.response(0.5), 1.);
+    }
+
+    #[test]
+    fn pyramid_constant_phase() {
+        let (n, dx) = (64, 0.1);
+        let pym = FourierPyramid::new(0.4, n as f64 * dx);
+        assert_eq!(pym.response(0.), 0.);
+        let slopes = pym.slopes(&vec![1.; n * n], dx);
+        assert!(slopes.iter().all(|s| s.abs() < 1e-9));
+        // a phase that varies only along Y gives no signal along X
+        let f0 = 2. / (n as f64 * dx);
+        let phase: Vec<f64> = (0..n * n)
+            .map(|k| (2. * PI * f0 * (k / n) as f64 * dx).cos())
+            .collect();
+        let slopes = pym.slopes(&phase, dx);
+        let (sx, sy) = slopes.split_at(slopes.len() / 2);
+        assert!(sx.iter().all(|s| s.abs() < 1e-9));
+        assert!(sy.iter().any(|s| s.abs() > 1e-3));
+    }
+
+    #[test]
+    fn pyramid_optical_gains() {
+        let pym = FourierPyramid::new(0.25, 25.);
+        let frequencies = [0.05, 0.2, 1.];
+        let no_residual = pym.optical_gains(&frequencies, |_, _| 0., 64);
+        assert!(no_residual.iter().all(|g| *g == 1.));
+        let atm = VonKarman::new(0.5, 25.);
+        let gains = pym.optical_gains(&frequencies, |fx, fy| atm.psd(fx.hypot(fy)), 64);
+        // the optical gains are reduced by the residual phase, the most at the low frequencies
+        assert!(gains.iter().all(|g| *g < 1.));
+        assert!(gains[0] < gains[1] && gains[1] < gains[2]);
+    }
+}
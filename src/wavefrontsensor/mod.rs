@@ -12,28 +12,39 @@
 //! let mut wfs = ceo!(ShackHartmann<Geometric>);
 //! ```
 
-use super::{Cu, Mask, Single, Source};
-use ffi::{geometricShackHartmann, mask, shackHartmann};
+use super::{lgs::LaserGuideStar, Cu, Mask, Single, Source};
+use ffi::{geometricShackHartmann, host2dev, mask, shackHartmann};
 use std::f32;
 
+pub mod fourier;
 pub mod shackhartmann;
-pub use shackhartmann::{ShackHartmann, ShackHartmannBuilder};
+pub use shackhartmann::{
+    ProbeBuilder, ShackHartmann, ShackHartmannBuilder, ShackHartmannGroup,
+    ShackHartmannGroupBuilder,
+};
 mod sh48;
 pub use sh48::SH48;
 mod sh24;
 pub use sh24::SH24;
+pub mod ncpa;
+pub use ncpa::{NcpaError, NcpaPhase, NonCommonPath, NonCommonPathAberration};
+pub mod valid_lenslet;
+pub use valid_lenslet::{ValidLensletStrategy, ValidLenslets};
 
 mod segment_wise;
 pub use segment_wise::{
+    curvature_sensor::{CurvatureSensor, CurvatureSensorBuilder},
     data_processing::{
-        Calibration, DataRef, Mirror, SegmentCalibration, Slopes, SlopesArray, Stroke,
-        TruncatedPseudoInverse, DOF, RBM,
+        Calibration, DataRef, Mirror, SegmentCalibration, Slopes, SlopesArray, SlopesLayout,
+        SlopesPipeline, SlopesProcessing, Stroke, TruncatedPseudoInverse, DOF, RBM,
     },
     differential_piston_sensor::{DifferentialPistonSensor, DifferentialPistonSensorBuilder},
     geom_shack::{GeomShack, GeomShackBuilder},
     phase_sensor::{PhaseSensor, PhaseSensorBuilder},
+    phasing,
     piston_sensor::{PistonSensor, PistonSensorBuilder},
-    pyramid::{Pyramid, PyramidBuilder, PyramidCalibration},
+    pyramid::{OpticalGainTracker, Pyramid, PyramidBuilder, PyramidCalibration},
+    zernike_sensor::{ZernikeSensor, ZernikeSensorBuilder},
     Frame, GmtSegmentation, SegmentWiseSensor, SegmentWiseSensorBuilder,
 };
 
@@ -83,6 +94,10 @@ pub trait Model: Clone + Send {
     fn frame(&self) -> Option<Vec<f32>>;
     fn n_frame(&self) -> usize;
     fn valid_lenslet_from(&mut self, wfs: &mut mask);
+    /// Elongates the spots of the laser guide star added to the detector `frame` by the last propagation
+    ///
+    /// `frame` is the detector frame before the propagation of the laser guide star
+    fn elongate(&mut self, lgs: &LaserGuideStar, wavelength: f64, frame: Vec<f32>);
 }
 impl Model for Geometric {
     fn new() -> Self {
@@ -208,6 +223,7 @@ impl Model for Geometric {
     fn n_frame(&self) -> usize {
         0
     }
+    fn elongate(&mut self, _lgs: &LaserGuideStar, _wavelength: f64, _frame: Vec<f32>) {}
     fn valid_lenslet_from(&mut self, wfs: &mut mask) {
         unsafe {
             self.valid_lenslet.reset();
@@ -355,6 +371,35 @@ impl Model for Diffractive {
     fn n_frame(&self) -> usize {
         self.camera.N_FRAME as usize
     }
+    fn elongate(&mut self, lgs: &LaserGuideStar, wavelength: f64, frame: Vec<f32>) {
+        let Some(mut lgs_frame) = self.frame() else {
+            return;
+        };
+        lgs_frame
+            .iter_mut()
+            .zip(&frame)
+            .for_each(|(lgs_px, px)| *lgs_px -= px);
+        let pixel_scale = self.camera.BIN_IMAGE as f64 * wavelength
+            / (self.DFT_osf as f64 * self.lenslet_pitch as f64);
+        lgs.elongate(
+            &mut lgs_frame,
+            self.N_SIDE_LENSLET as usize,
+            self.camera.N_PX_CAMERA as usize,
+            self.lenslet_pitch as f64,
+            pixel_scale,
+        );
+        lgs_frame
+            .iter_mut()
+            .zip(frame)
+            .for_each(|(lgs_px, px)| *lgs_px += px);
+        unsafe {
+            host2dev(
+                self.camera.d__frame,
+                lgs_frame.as_mut_ptr(),
+                lgs_frame.len() as i32,
+            );
+        }
+    }
     fn valid_lenslet_from(&mut self, wfs: &mut mask) {
         unsafe {
             self.valid_lenslet.reset();
@@ -0,0 +1,338 @@
+//!
+//! # Non-common path aberrations
+//!
+//! Static aberrations that are seen by a wavefront sensor but not by the science path.
+//!
+//! A [NonCommonPathAberration] is a Zernike or a map based phase, either the same for all the guide stars
+//! or a function of the guide star direction.
+//! [NonCommonPath] is inserted in front of any [WavefrontSensor]:
+//! the aberration is added to the [Source] phase before the propagation through the wavefront sensor
+//! and removed right after, so the [Source] phase is left unchanged for the other optical paths.
+//!
+//! The slope offsets measured with the aberrations on a perfect wavefront are subtracted from the
+//! wavefront sensor data to compensate the non-common path aberrations.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use crseo::{
+//!     wavefrontsensor::{Geometric, NonCommonPath, NonCommonPathAberration},
+//!     Builder, FromBuilder, Gmt, ShackHartmann, WavefrontSensor, WavefrontSensorBuilder,
+//! };
+//! # fn main() -> anyhow::Result<()> {
+//! let wfs_builder = ShackHartmann::<Geometric>::builder().lenslet_array(48, 16, 25.5 / 48.);
+//! let mut gmt = Gmt::builder().build()?;
+//! let mut src = wfs_builder.guide_stars(None).build()?;
+//! let ncpa = NonCommonPathAberration::zernike(vec![0., 0., 0., 50e-9, 20e-9]);
+//! let mut wfs = NonCommonPath::new(wfs_builder.build()?, ncpa, &src)?;
+//! src.through(&mut gmt).xpupil();
+//! wfs.calibrate(&mut src, 0.5);
+//! wfs.compensate(&mut src);
+//! # Ok(())
+//! # }
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sensitivities::zernike, wavefrontsensor::Calibration, Propagation, Source, WavefrontSensor,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum NcpaError {
+    #[error("expected a non-common path phase map of length {0}, found {1}")]
+    MapLength(usize, usize),
+    #[error("expected {0} non-common path phases, found {1}")]
+    GuideStars(usize, usize),
+}
+pub type Result<T> = std::result::Result<T, NcpaError>;
+
+/// Non-common path phase
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NcpaPhase {
+    /// Coefficients \[m\] of the Noll's normalized Zernike polynomials starting with the piston
+    ///
+    /// The Zernike polynomials are defined on the disk of diameter the pupil size of the guide stars
+    Zernike(Vec<f64>),
+    /// Phase map \[m\] sampled as the wavefront of a guide star
+    Map(Vec<f32>),
+}
+impl NcpaPhase {
+    // Phase [m] on a square grid of n_px x n_px samples
+    fn opd(&self, n_px: usize) -> Result<Vec<f32>> {
+        match self {
+            NcpaPhase::Zernike(coefs) => {
+                let c = 0.5 * (n_px - 1) as f64;
+                Ok((0..n_px * n_px)
+                    .map(|k| {
+                        let (x, y) = (((k % n_px) as f64 - c) / c, ((k / n_px) as f64 - c) / c);
+                        let (r, o) = (x.hypot(y), y.atan2(x));
+                        if r > 1. {
+                            return 0f32;
+                        }
+                        coefs
+                            .iter()
+                            .enumerate()
+                            .map(|(j, a)| a * zernike(j + 1, r, o))
+                            .sum::<f64>() as f32
+                    })
+                    .collect())
+            }
+            NcpaPhase::Map(map) if map.len() == n_px * n_px => Ok(map.clone()),
+            NcpaPhase::Map(map) => Err(NcpaError::MapLength(n_px * n_px, map.len())),
+        }
+    }
+}
+
+/// Non-common path aberrations
+///
+/// The aberrations are either the same for all the guide stars (1 phase)
+/// or given for each guide star (as many phases as guide stars)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NonCommonPathAberration {
+    phases: Vec<NcpaPhase>,
+}
+impl NonCommonPathAberration {
+    /// Creates the same aberrations for all the guide stars from the Zernike coefficients \[m\]
+    pub fn zernike(coefs: Vec<f64>) -> Self {
+        Self {
+            phases: vec![NcpaPhase::Zernike(coefs)],
+        }
+    }
+    /// Creates the same aberrations for all the guide stars from a phase map \[m\]
+    pub fn map(map: Vec<f32>) -> Self {
+        Self {
+            phases: vec![NcpaPhase::Map(map)],
+        }
+    }
+    /// Creates the aberrations for each guide star
+    pub fn per_guide_star(phases: Vec<NcpaPhase>) -> Self {
+        Self { phases }
+    }
+    /// Creates field dependent aberrations
+    ///
+    /// The aberrations of each guide star are given by the function of the guide star `zenith` and `azimuth` \[rd\]
+    pub fn field_dependent<F>(zenith: &[f32], azimuth: &[f32], phase: F) -> Self
+    where
+        F: Fn(f32, f32) -> NcpaPhase,
+    {
+        Self {
+            phases: zenith
+                .iter()
+                .zip(azimuth)
+                .map(|(z, a)| phase(*z, *a))
+                .collect(),
+        }
+    }
+    /// Returns the phase \[m\] of `n_src` guide stars sampled with `n_px`x`n_px` samples
+    ///
+    /// Returns an error if the number of phases or the length of the phase maps do not match the sampling
+    pub fn opd(&self, n_src: usize, n_px: usize) -> Result<Vec<f32>> {
+        match self.phases.as_slice() {
+            [phase] => Ok(phase.opd(n_px)?.repeat(n_src)),
+            phases if phases.len() == n_src => phases
+                .iter()
+                .map(|phase| phase.opd(n_px))
+                .collect::<Result<Vec<_>>>()
+                .map(|opd| opd.concat()),
+            phases => Err(NcpaError::GuideStars(n_src, phases.len())),
+        }
+    }
+}
+
+/// Non-common path aberrations in front of a wavefront sensor
+///
+/// The slope offsets are subtracted from the wavefront sensor [data](WavefrontSensor::data)
+///
+/// # Panics
+///
+/// The sources propagated through the non-common path must be sampled as the guide stars given to
+/// [new](NonCommonPath::new) or to [resample](NonCommonPath::resample)
+pub struct NonCommonPath<W: WavefrontSensor> {
+    wfs: W,
+    aberration: NonCommonPathAberration,
+    opd: Vec<f32>,
+    offsets: Option<Vec<f64>>,
+}
+impl<W: WavefrontSensor> NonCommonPath<W> {
+    /// Inserts the non-common path `aberration` in front of the wavefront sensor
+    ///
+    /// The aberrations are sampled as the guide stars `src` of the wavefront sensor,
+    /// an error is returned if the aberrations do not match the guide stars sampling
+    pub fn new(wfs: W, aberration: NonCommonPathAberration, src: &Source) -> Result<Self> {
+        let mut ncpa = Self {
+            wfs,
+            aberration,
+            opd: Vec::new(),
+            offsets: None,
+        };
+        ncpa.resample(src)?;
+        Ok(ncpa)
+    }
+    /// Samples the non-common path aberrations as the guide stars `src`
+    ///
+    /// An error is returned if the aberrations do not match the guide stars sampling
+    pub fn resample(&mut self, src: &Source) -> Result<&mut Self> {
+        let n_src = src.size as usize;
+        let n_px = src.pupil_sampling as usize;
+        self.opd = self.aberration.opd(n_src, n_px)?;
+        Ok(self)
+    }
+    /// Returns a reference to the wavefront sensor
+    pub fn sensor(&self) -> &W {
+        &self.wfs
+    }
+    /// Returns a mutable reference to the wavefront sensor
+    pub fn sensor_mut(&mut self) -> &mut W {
+        &mut self.wfs
+    }
+    /// Returns the wavefront sensor
+    pub fn into_sensor(self) -> W {
+        self.wfs
+    }
+    /// Returns the non-common path aberrations
+    pub fn aberration(&self) -> &NonCommonPathAberration {
+        &self.aberration
+    }
+    // Checks that the source is sampled as the non-common path phase
+    fn check_sampling(&self, src: &Source) {
+        let n_px = src.pupil_sampling as usize;
+        assert_eq!(
+            self.opd.len(),
+            src.size as usize * n_px * n_px,
+            "the source is not sampled as the non-common path aberrations, see `NonCommonPath::resample`"
+        );
+    }
+    /// Returns the slope offsets induced by the non-common path aberrations
+    ///
+    /// `src` is propagated to the exit pupil of a perfect telescope and the offsets are the difference
+    /// between the wavefront sensor data with and without the non-common path aberrations
+    pub fn slope_offsets(&mut self, src: &mut Source) -> Vec<f64> {
+        self.wfs.reset();
+        self.propagate(src);
+        self.wfs.process();
+        let with_ncpa = self.wfs.data();
+        self.wfs.reset();
+        self.wfs.propagate(src);
+        self.wfs.process();
+        let without_ncpa = self.wfs.data();
+        self.wfs.reset();
+        with_ncpa
+            .into_iter()
+            .zip(without_ncpa)
+            .map(|(w, wo)| w - wo)
+            .collect()
+    }
+    /// Sets the slope offsets
+    pub fn set_slope_offsets(&mut self, offsets: Vec<f64>) -> &mut Self {
+        self.offsets = Some(offsets);
+        self
+    }
+    /// Returns the slope offsets, if any
+    pub fn offsets(&self) -> Option<&[f64]> {
+        self.offsets.as_deref()
+    }
+    /// Computes and sets the slope offsets that compensate the non-common path aberrations
+    ///
+    /// See [slope_offsets](NonCommonPath::slope_offsets)
+    pub fn compensate(&mut self, src: &mut Source) -> &mut Self {
+        let offsets = self.slope_offsets(src);
+        self.set_slope_offsets(offsets)
+    }
+}
+impl<W: WavefrontSensor> Propagation for NonCommonPath<W> {
+    fn propagate(&mut self, src: &mut Source) {
+        self.check_sampling(src);
+        src.add(self.opd.as_slice());
+        self.wfs.propagate(src);
+        src.sub(self.opd.as_slice());
+    }
+    fn time_propagate(&mut self, secs: f64, src: &mut Source) {
+        self.check_sampling(src);
+        src.add(self.opd.as_slice());
+        self.wfs.time_propagate(secs, src);
+        src.sub(self.opd.as_slice());
+    }
+}
+impl<W: WavefrontSensor> WavefrontSensor for NonCommonPath<W> {
+    /// Calibrates the wavefront sensor without the non-common path aberrations
+    fn calibrate(&mut self, src: &mut Source, threshold: f64) {
+        self.wfs.calibrate(src, threshold);
+    }
+    fn reset(&mut self) {
+        self.wfs.reset();
+    }
+    fn process(&mut self) {
+        self.wfs.process();
+    }
+    fn readout(&mut self) {
+        self.wfs.readout();
+    }
+    /// Returns the wavefront sensor data minus the slope offsets
+    fn data(&mut self) -> Vec<f64> {
+        let data = self.wfs.data();
+        match self.offsets.as_ref() {
+            Some(offsets) => data.into_iter().zip(offsets).map(|(d, o)| d - o).collect(),
+            None => data,
+        }
+    }
+    fn frame(&self) -> Option<Vec<f32>> {
+        self.wfs.frame()
+    }
+    fn n_frame(&self) -> usize {
+        self.wfs.n_frame()
+    }
+    fn valid_lenslet_from(&mut self, wfs: &mut dyn WavefrontSensor) {
+        self.wfs.valid_lenslet_from(wfs);
+    }
+    fn valid_lenslet(&mut self) -> &mut ffi::mask {
+        self.wfs.valid_lenslet()
+    }
+    fn n_valid_lenslet(&mut self) -> Vec<usize> {
+        self.wfs.n_valid_lenslet()
+    }
+    fn left_multiply(&self, calibration: &Calibration) -> Option<Vec<f32>> {
+        self.wfs.left_multiply(calibration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zernike_opd() {
+        let n_px = 101;
+        let ncpa = NonCommonPathAberration::zernike(vec![0., 0., 0., 100e-9]);
+        let opd = ncpa.opd(2, n_px).unwrap();
+        assert_eq!(opd.len(), 2 * n_px * n_px);
+        assert_eq!(opd[..n_px * n_px], opd[n_px * n_px..]);
+        // focus rms over the disk is the Zernike coefficient
+        let disk: Vec<f64> = opd[..n_px * n_px]
+            .iter()
+            .enumerate()
+            .filter(|(k, _)| {
+                let c = 0.5 * (n_px - 1) as f64;
+                ((k % n_px) as f64 - c).hypot((k / n_px) as f64 - c) <= c
+            })
+            .map(|(_, x)| *x as f64)
+            .collect();
+        let n = disk.len() as f64;
+        let mean = disk.iter().sum::<f64>() / n;
+        let rms = (disk.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt();
+        assert!((rms - 100e-9).abs() < 1e-9, "{rms}");
+    }
+
+    #[test]
+    fn field_dependent() {
+        let ncpa = NonCommonPathAberration::field_dependent(&[0., 1e-3], &[0., 1.], |z, _| {
+            NcpaPhase::Map(vec![z; 4])
+        });
+        assert_eq!(
+            ncpa.opd(2, 2).unwrap(),
+            vec![0., 0., 0., 0., 1e-3, 1e-3, 1e-3, 1e-3]
+        );
+        assert!(matches!(ncpa.opd(3, 2), Err(NcpaError::GuideStars(3, 2))));
+        assert!(matches!(ncpa.opd(2, 3), Err(NcpaError::MapLength(9, 4))));
+    }
+}
@@ -3,12 +3,16 @@ use serde::{Deserialize, Serialize};
 
 use crate::{builders::SourceBuilder, Builder, WavefrontSensor, WavefrontSensorBuilder};
 
+pub mod curvature_sensor;
 pub mod data_processing;
 pub mod differential_piston_sensor;
 pub mod geom_shack;
+mod host_sensor;
 pub mod phase_sensor;
+pub mod phasing;
 pub mod piston_sensor;
 pub mod pyramid;
+pub mod zernike_sensor;
 use data_processing::{Calibration, DataRef, SegmentCalibration, Slopes, SlopesArray};
 
 use super::Pyramid;
@@ -0,0 +1,19 @@
+mod builder;
+use crate::builders::SourceBuilder;
+pub use builder::CurvatureSensorBuilder;
+mod curvature_sensor;
+pub use curvature_sensor::CurvatureSensor;
+
+pub use super::data_processing;
+use super::host_sensor::{host_wavefront_sensor, HostSensor};
+use crate::{SegmentWiseSensorBuilder, WavefrontSensorBuilder};
+
+impl WavefrontSensorBuilder for CurvatureSensorBuilder {
+    fn guide_stars(&self, gs: Option<SourceBuilder>) -> SourceBuilder {
+        gs.unwrap_or_default()
+            .size(self.n_gs)
+            .pupil_sampling(self.pupil_sampling())
+    }
+}
+
+host_wavefront_sensor!(CurvatureSensor);
@@ -0,0 +1,96 @@
+use rustfft::FftPlanner;
+use serde::{Deserialize, Serialize};
+
+use crate::{imaging::LensletArray, Builder, SegmentWiseSensorBuilder};
+
+use super::CurvatureSensor;
+
+/// `CurvatureSensor` builder
+///
+/// Default properties:
+///  - lenslet array: 16x16 sub-apertures of 8x8 pixels
+///  - \# of guide stars: 1
+///  - defocus distance: 200km
+///  - flux threshold: 0.5
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CurvatureSensorBuilder {
+    lenslet_array: LensletArray,
+    pub(super) n_gs: usize,
+    distance: f64,
+    flux_threshold: f64,
+}
+impl Default for CurvatureSensorBuilder {
+    fn default() -> Self {
+        Self {
+            lenslet_array: LensletArray {
+                n_side_lenslet: 16,
+                n_px_lenslet: 8,
+                d: 25.5 / 16.,
+            },
+            n_gs: 1,
+            distance: 200e3,
+            flux_threshold: 0.5,
+        }
+    }
+}
+impl CurvatureSensorBuilder {
+    /// Sets the number of sub-apertures across the pupil and the number of pixels across a sub-aperture
+    pub fn lenslet(mut self, n_side_lenslet: usize, n_px_lenslet: usize) -> Self {
+        self.lenslet_array = LensletArray {
+            n_side_lenslet,
+            n_px_lenslet,
+            d: 25.5 / n_side_lenslet as f64,
+        };
+        self
+    }
+    /// Sets the number of guide stars
+    pub fn size(mut self, n: usize) -> Self {
+        self.n_gs = n;
+        self
+    }
+    /// Sets the distance \[m\] between the pupil and the intra and extra focal planes conjugated in the pupil space
+    pub fn distance(mut self, distance: f64) -> Self {
+        self.distance = distance;
+        self
+    }
+    /// Sets the fraction of the largest sub-aperture flux above which a sub-aperture is valid
+    pub fn flux_threshold(mut self, flux_threshold: f64) -> Self {
+        self.flux_threshold = flux_threshold;
+        self
+    }
+}
+
+impl SegmentWiseSensorBuilder for CurvatureSensorBuilder {
+    fn pupil_sampling(&self) -> usize {
+        let LensletArray {
+            n_side_lenslet,
+            n_px_lenslet,
+            ..
+        } = self.lenslet_array;
+        n_side_lenslet * n_px_lenslet + 1
+    }
+}
+
+impl Builder for CurvatureSensorBuilder {
+    type Component = CurvatureSensor;
+
+    fn build(self) -> crate::Result<Self::Component> {
+        let n_fft = 2 * self.pupil_sampling();
+        let mut planner = FftPlanner::new();
+        let n_lenslet = self.lenslet_array.n_side_lenslet.pow(2) * self.n_gs;
+        Ok(CurvatureSensor {
+            lenslet_array: self.lenslet_array,
+            n_gs: self.n_gs,
+            distance: self.distance,
+            flux_threshold: self.flux_threshold,
+            intra: vec![0f64; n_lenslet],
+            extra: vec![0f64; n_lenslet],
+            n_frame: 0,
+            fft: (
+                planner.plan_fft_forward(n_fft),
+                planner.plan_fft_inverse(n_fft),
+            ),
+            valid: Default::default(),
+        })
+    }
+}
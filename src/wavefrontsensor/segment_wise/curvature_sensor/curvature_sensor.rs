@@ -0,0 +1,319 @@
+use std::{f64::consts::PI, ops::Mul, sync::Arc};
+
+use indicatif::ProgressBar;
+use rustfft::{num_complex::Complex, Fft};
+
+use crate::{
+    builders::SourceBuilder, imaging::LensletArray, pssn::host::fft2, Builder, FromBuilder, Gmt,
+    Propagation, SegmentWiseSensor, WavefrontSensor,
+};
+
+use super::{
+    super::host_sensor::{calibrate_segment, HostSensor, ValidElements},
+    data_processing::{Calibration, DataRef, Slopes, SlopesArray},
+    CurvatureSensorBuilder,
+};
+
+/// Curvature wavefront sensor
+///
+/// The exit pupil field is Fresnel propagated to the intra and extra focal planes
+/// and the intensities in both planes are integrated in the sub-apertures.
+/// The sensor signal is the normalized intensity difference `(I_extra-I_intra)/(I_extra+I_intra)`
+/// in each sub-aperture.
+pub struct CurvatureSensor {
+    pub(super) lenslet_array: LensletArray,
+    pub(super) n_gs: usize,
+    pub(super) distance: f64,
+    pub(super) flux_threshold: f64,
+    pub(super) intra: Vec<f64>,
+    pub(super) extra: Vec<f64>,
+    pub(super) n_frame: usize,
+    pub(super) fft: (Arc<dyn Fft<f64>>, Arc<dyn Fft<f64>>),
+    pub(super) valid: ValidElements,
+}
+impl FromBuilder for CurvatureSensor {
+    type ComponentBuilder = CurvatureSensorBuilder;
+}
+impl Propagation for CurvatureSensor {
+    fn propagate(&mut self, src: &mut crate::Source) {
+        let n = self.pupil_sampling();
+        let n_lenslet = self.lenslet_array.n_side_lenslet.pow(2);
+        let wavelength = src.wavelength();
+        let amplitude = src.amplitude();
+        let phase = src.phase().clone();
+        for (k, (amplitude, phase)) in amplitude
+            .chunks(n * n)
+            .zip(phase.chunks(n * n))
+            .take(self.n_gs)
+            .enumerate()
+        {
+            let intra = self.defocused_flux(amplitude, phase, wavelength, -self.distance);
+            let extra = self.defocused_flux(amplitude, phase, wavelength, self.distance);
+            self.intra[k * n_lenslet..]
+                .iter_mut()
+                .zip(intra)
+                .for_each(|(f, i)| *f += i);
+            self.extra[k * n_lenslet..]
+                .iter_mut()
+                .zip(extra)
+                .for_each(|(f, e)| *f += e);
+        }
+        self.n_frame += 1;
+    }
+
+    fn time_propagate(&mut self, _secs: f64, src: &mut crate::Source) {
+        self.propagate(src)
+    }
+}
+
+impl HostSensor for CurvatureSensor {
+    fn n_guide_star(&self) -> usize {
+        self.n_gs
+    }
+    fn clear(&mut self) {
+        self.intra.fill(0f64);
+        self.extra.fill(0f64);
+        self.n_frame = 0;
+    }
+    /// Returns the sum of the intra and extra focal fluxes in each sub-aperture
+    fn element_flux(&self) -> Vec<f64> {
+        self.intra
+            .iter()
+            .zip(&self.extra)
+            .map(|(i, e)| i + e)
+            .collect()
+    }
+    fn element_signal(&self) -> Vec<f64> {
+        self.signal()
+    }
+    fn intensity_frame(&self) -> Option<Vec<f32>> {
+        None
+    }
+    fn frame_count(&self) -> usize {
+        self.n_frame
+    }
+    fn valid_elements(&mut self) -> &mut ValidElements {
+        &mut self.valid
+    }
+}
+
+impl CurvatureSensor {
+    /// Returns the sub-aperture fluxes in the intra focal plane
+    pub fn intra(&self) -> &[f64] {
+        &self.intra
+    }
+    /// Returns the sub-aperture fluxes in the extra focal plane
+    pub fn extra(&self) -> &[f64] {
+        &self.extra
+    }
+    /// Normalized intensity differences as `[s_1,...,s_i,...,s_n]` where `n` is the number of guide stars
+    ///
+    /// The signal is 0 in the sub-apertures without flux
+    pub fn signal(&self) -> Vec<f64> {
+        self.intra
+            .iter()
+            .zip(&self.extra)
+            .map(|(i, e)| if i + e > 0. { (e - i) / (e + i) } else { 0. })
+            .collect()
+    }
+    /// Returns the fluxes in the sub-apertures after propagating the field over the distance `z` \[m\]
+    ///
+    /// The field is propagated with the angular spectrum method on a grid twice the size of the pupil
+    pub fn defocused_flux(
+        &self,
+        amplitude: &[f32],
+        phase: &[f32],
+        wavelength: f64,
+        z: f64,
+    ) -> Vec<f64> {
+        let LensletArray {
+            n_side_lenslet,
+            n_px_lenslet,
+            d,
+        } = self.lenslet_array;
+        let n = self.pupil_sampling();
+        let m = 2 * n;
+        let o = (m - n) / 2;
+        let (forward, inverse) = &self.fft;
+        let k = 2. * PI / wavelength;
+        let mut field = vec![Complex::<f64>::new(0., 0.); m * m];
+        for j in 0..n {
+            for i in 0..n {
+                let l = i + n * j;
+                field[i + o + m * (j + o)] =
+                    Complex::from_polar(amplitude[l] as f64, k * phase[l] as f64);
+            }
+        }
+        fft2(&mut field, m, forward);
+        let df = (n - 1) as f64 / (m as f64 * d * n_side_lenslet as f64);
+        let wrap = |i: usize| {
+            if i < m / 2 {
+                i as f64
+            } else {
+                i as f64 - m as f64
+            }
+        };
+        field.iter_mut().enumerate().for_each(|(l, x)| {
+            let f2 = (wrap(l % m).powi(2) + wrap(l / m).powi(2)) * df * df;
+            *x *= Complex::from_polar(1., -PI * wavelength * z * f2);
+        });
+        fft2(&mut field, m, inverse);
+        let mut flux = vec![0f64; n_side_lenslet * n_side_lenslet];
+        for j in 0..n {
+            for i in 0..n {
+                let u = (i / n_px_lenslet).min(n_side_lenslet - 1);
+                let v = (j / n_px_lenslet).min(n_side_lenslet - 1);
+                flux[u + n_side_lenslet * v] += field[i + o + m * (j + o)].norm_sqr();
+            }
+        }
+        flux
+    }
+}
+
+impl SegmentWiseSensor for CurvatureSensor {
+    fn pupil_sampling(&self) -> usize {
+        let LensletArray {
+            n_side_lenslet,
+            n_px_lenslet,
+            ..
+        } = self.lenslet_array;
+        n_side_lenslet * n_px_lenslet + 1
+    }
+    fn zeroed_segment(&mut self, sid: usize, src_builder: Option<SourceBuilder>) -> DataRef {
+        let LensletArray { n_side_lenslet, .. } = self.lenslet_array;
+        let mut gmt = Gmt::builder().build().unwrap();
+        gmt.keep(&[sid as i32]);
+        let mut src = src_builder
+            .unwrap_or_default()
+            .pupil_sampling(self.pupil_sampling())
+            .build()
+            .unwrap();
+        self.reset();
+        src.through(&mut gmt).xpupil().through(self);
+        // Setting the sub-apertures mask restricted to the segment
+        let flux = self.element_flux();
+        let max_flux = flux.iter().cloned().fold(0f64, f64::max);
+        let pupil = nalgebra::DMatrix::<f32>::from_iterator(
+            n_side_lenslet,
+            n_side_lenslet * self.n_gs,
+            flux.into_iter()
+                .map(|f| (f > self.flux_threshold * max_flux) as u8 as f32),
+        );
+        let mut data_ref = DataRef::new(pupil);
+        data_ref.set_ref_with(Slopes::from((&data_ref, &*self)));
+        self.reset();
+        data_ref
+    }
+    fn into_slopes(&self, data_ref: &DataRef) -> Slopes {
+        Slopes::from((data_ref, self))
+    }
+    fn calibrate_segment(
+        &mut self,
+        src_builder: Option<SourceBuilder>,
+        sid: usize,
+        n_mode: usize,
+        pb: Option<ProgressBar>,
+    ) -> SlopesArray {
+        calibrate_segment(self, src_builder, sid, n_mode, pb)
+    }
+}
+
+impl From<(&DataRef, &CurvatureSensor)> for Slopes {
+    /// Computes the normalized intensity differences in the valid sub-apertures
+    fn from((data_ref, wfs): (&DataRef, &CurvatureSensor)) -> Self {
+        let signal = wfs.signal().into_iter().map(|x| x as f32);
+        let mut sxy: Vec<f32> = if let Some(mask) = data_ref.mask.as_ref() {
+            signal
+                .zip(mask.iter())
+                .filter_map(|(s, &m)| m.then_some(s))
+                .collect()
+        } else {
+            signal.collect()
+        };
+        if let Some(Slopes(sxy0)) = data_ref.sxy0.as_ref() {
+            sxy.iter_mut()
+                .zip(sxy0)
+                .for_each(|(sxy, sxy0)| *sxy -= *sxy0);
+        }
+        Slopes(sxy)
+    }
+}
+
+type V = nalgebra::DVector<f32>;
+
+impl Mul<&CurvatureSensor> for &SlopesArray {
+    type Output = Option<Vec<f32>>;
+    /// Multiplies the pseudo-inverse of the calibration matrix with the [CurvatureSensor] measurements
+    fn mul(self, wfs: &CurvatureSensor) -> Self::Output {
+        let slopes = Slopes::from((&self.data_ref, wfs));
+        self.inverse
+            .as_ref()
+            .map(|pinv| pinv * V::from(slopes))
+            .map(|x| x.as_slice().to_vec())
+    }
+}
+impl Mul<&CurvatureSensor> for &Calibration {
+    type Output = Option<Vec<f32>>;
+    /// Multiplies the pseudo-inverse of the calibration matrix with the [CurvatureSensor] measurements
+    fn mul(self, wfs: &CurvatureSensor) -> Self::Output {
+        Some(self.iter().flat_map(|x| x * wfs).flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // square pupil with a defocus of `a` \[m\] at the edge
+    fn pupil(n: usize, a: f32) -> (Vec<f32>, Vec<f32>) {
+        let r = |k: usize| {
+            let x = 2. * (k % n) as f32 / (n - 1) as f32 - 1.;
+            let y = 2. * (k / n) as f32 / (n - 1) as f32 - 1.;
+            x * x + y * y
+        };
+        (
+            (0..n * n).map(|_| 1.).collect(),
+            (0..n * n).map(|k| a * r(k)).collect(),
+        )
+    }
+
+    #[test]
+    fn flat_wavefront() {
+        let wfs = CurvatureSensor::builder().lenslet(4, 8).build().unwrap();
+        let (amplitude, phase) = pupil(wfs.pupil_sampling(), 0.);
+        let intra = wfs.defocused_flux(&amplitude, &phase, 500e-9, -wfs.distance);
+        let extra = wfs.defocused_flux(&amplitude, &phase, 500e-9, wfs.distance);
+        let total: f64 = intra.iter().sum();
+        intra
+            .iter()
+            .zip(&extra)
+            .for_each(|(i, e)| assert!((i - e).abs() < 1e-9 * total));
+    }
+
+    #[test]
+    fn defocus() {
+        let mut wfs = CurvatureSensor::builder().lenslet(4, 8).build().unwrap();
+        let (amplitude, phase) = pupil(wfs.pupil_sampling(), 50e-9);
+        wfs.intra = wfs.defocused_flux(&amplitude, &phase, 500e-9, -wfs.distance);
+        wfs.extra = wfs.defocused_flux(&amplitude, &phase, 500e-9, wfs.distance);
+        let s = wfs.signal();
+        // the inner and the corner sub-apertures see opposite curvature signals
+        assert!(s[5].abs() > 1e-4 && s[10].abs() > 1e-4);
+        assert!(s[5] * s[0] < 0. && s[10] * s[15] < 0.);
+    }
+
+    #[test]
+    fn calibrate() {
+        use crate::WavefrontSensorBuilder;
+        let builder = CurvatureSensor::builder().lenslet(8, 8);
+        let mut src = builder.guide_stars(None).build().unwrap();
+        let mut gmt = Gmt::builder().build().unwrap();
+        let mut wfs = builder.build().unwrap();
+        src.through(&mut gmt).xpupil();
+        WavefrontSensor::calibrate(&mut wfs, &mut src, 0.5);
+        src.through(&mut wfs);
+        let n_valid: usize = wfs.n_valid_lenslet().iter().sum();
+        assert!(n_valid > 0 && n_valid < 64);
+        assert_eq!(wfs.data().len(), n_valid);
+    }
+}
@@ -4,5 +4,7 @@ mod slopes_array;
 pub use slopes_array::{SlopesArray, TruncatedPseudoInverse};
 mod data_ref;
 pub use data_ref::DataRef;
+mod pipeline;
+pub use pipeline::{SlopesLayout, SlopesPipeline, SlopesPipelineError, SlopesProcessing};
 mod calibration;
 pub use calibration::{Calibration, Mirror, SegmentCalibration, Stroke, DOF, RBM};
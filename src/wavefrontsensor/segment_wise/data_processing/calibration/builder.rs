@@ -4,9 +4,11 @@ use indicatif::ProgressBar;
 // use serde::{Deserialize, Serialize};
 use crate::{
     builders::SourceBuilder,
+    gmt::layout::DofLayout,
     wavefrontsensor::{Slopes, SlopesArray},
     Builder, FromBuilder, Gmt, Propagation, SegmentWiseSensor,
 };
+use serde::{Deserialize, Serialize};
 
 /* #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct CalibrationBuilder {
@@ -232,7 +234,7 @@ impl SegmentCalibration {
     }
 }
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Mirror {
     M1,
     M2,
@@ -382,6 +384,7 @@ impl SegmentCalibration {
                 slopes
             }
         };
-        (data_ref, slopes).into()
+        SlopesArray::from((data_ref, slopes))
+            .with_layout(DofLayout::segment_calibration(sid as u8, self))
     }
 }
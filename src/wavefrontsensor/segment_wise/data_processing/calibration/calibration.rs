@@ -10,6 +10,7 @@ use slopes::Slopes;
 
 use crate::{
     builders::SourceBuilder,
+    gmt::layout::DofLayout,
     wavefrontsensor::{
         segment_wise::data_processing::{
             slopes, slopes_array::SlopesArrayError, TruncatedPseudoInverse,
@@ -148,6 +149,18 @@ impl Calibration {
     pub fn len(&self) -> usize {
         self.data.len()
     }
+    /// Returns the degrees of freedom [DofLayout] of the reconstructor outputs
+    ///
+    /// The layouts of all the [SlopesArray] are concatenated, `None` is returned if one is missing
+    pub fn layout(&self) -> Option<DofLayout> {
+        self.iter()
+            .try_fold(DofLayout::default(), |mut layout, sa| {
+                sa.layout().map(|l| {
+                    layout.extend(l);
+                    layout
+                })
+            })
+    }
     /// Returns the interaction matrices
     pub fn interaction_matrices(&self) -> Vec<DMatrix<f32>> {
         self.iter().map(|s| s.interaction_matrix()).collect()
@@ -177,11 +190,16 @@ impl Calibration {
         let SlopesArray {
             mut slopes,
             data_ref,
+            mut layout,
             ..
         } = sa_iter.next().unwrap();
         for mut sa in sa_iter {
             if sa.data_ref == data_ref {
                 slopes.append(&mut sa.slopes);
+                layout = layout.zip(sa.layout).map(|(mut a, b)| {
+                    a.extend(&b);
+                    a
+                });
             } else {
                 return Err(CalibrationError::Collect);
             }
@@ -191,6 +209,7 @@ impl Calibration {
                 slopes,
                 data_ref,
                 inverse: None,
+                layout,
             }],
             ..Default::default()
         })
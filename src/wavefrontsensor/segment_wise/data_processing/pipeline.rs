@@ -0,0 +1,428 @@
+use serde::{Deserialize, Serialize};
+
+use super::{DataRef, Slopes};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SlopesPipelineError {
+    #[error("the slopes pipeline has no segment map")]
+    MissingSegments,
+    #[error("expected {0} slopes, found {1}")]
+    Length(usize, usize),
+    #[error("expected an even number of slopes for the {1:?} layout, found {0}")]
+    Odd(usize, SlopesLayout),
+}
+pub type Result<T> = std::result::Result<T, SlopesPipelineError>;
+
+/// Ordering of the slopes in the measurements vector
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum SlopesLayout {
+    /// Pairs of slopes \[sx,sy\] one after the other: \[sx0,sy0,sx1,sy1,...\]
+    #[default]
+    Interleaved,
+    /// All the slopes along X followed by all the slopes along Y: \[sx0,sx1,...,sy0,sy1,...\]
+    Split,
+    /// A single measurement per element, like the pyramid piston sensor: \[s0,s1,...\]
+    Scalar,
+}
+impl SlopesLayout {
+    // Splits the measurements vector into one vector per axis
+    fn split(&self, s: Vec<f32>) -> Result<Vec<Vec<f32>>> {
+        if *self != SlopesLayout::Scalar && !s.len().is_multiple_of(2) {
+            return Err(SlopesPipelineError::Odd(s.len(), *self));
+        }
+        Ok(match self {
+            SlopesLayout::Interleaved => vec![
+                s.iter().step_by(2).cloned().collect(),
+                s.iter().skip(1).step_by(2).cloned().collect(),
+            ],
+            SlopesLayout::Split => {
+                let (sx, sy) = s.split_at(s.len() / 2);
+                vec![sx.to_vec(), sy.to_vec()]
+            }
+            SlopesLayout::Scalar => vec![s],
+        })
+    }
+    // Merges the vectors of each axis back into the measurements vector
+    fn merge(&self, mut axes: Vec<Vec<f32>>) -> Vec<f32> {
+        match self {
+            SlopesLayout::Interleaved => axes[0]
+                .iter()
+                .zip(&axes[1])
+                .flat_map(|(sx, sy)| [*sx, *sy])
+                .collect(),
+            SlopesLayout::Split | SlopesLayout::Scalar => axes.drain(..).flatten().collect(),
+        }
+    }
+}
+
+/// Slopes processing steps
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum SlopesProcessing {
+    /// Subtracts the reference slopes
+    Reference(Slopes),
+    /// Divides the slopes of each element by the corresponding flux
+    FluxNormalization(Vec<f32>),
+    /// Removes the mean of the slopes along each axis
+    TipTiltRemoval,
+    /// Replaces the slopes by the mean of the slopes of the same segment
+    SegmentAverage,
+    /// Rejects the slopes that are further than `.0` times the robust standard deviation away from the median
+    ///
+    /// The robust standard deviation is 1.4826 times the median absolute deviation
+    OutlierRejection(f32),
+    /// Rejects the slopes of all the segments but the given segments
+    SegmentMask(Vec<u8>),
+}
+
+/// Slopes processing pipeline
+///
+/// The processing steps are applied in the order they are added to the pipeline.
+/// The slopes that are rejected by a step are set to 0 and are ignored by the following steps.
+///
+/// The slopes are ordered according to the [SlopesLayout], by default pairs of slopes \[sx,sy\].
+/// The segment map gives the ID (1 to 7) of the segment seen by each element (pair of slopes or scalar measurement),
+/// it is required by [SegmentAverage](SlopesProcessing::SegmentAverage)
+/// and [SegmentMask](SlopesProcessing::SegmentMask)
+///
+/// # Examples
+///
+/// ```
+/// use crseo::wavefrontsensor::{SlopesPipeline, Slopes};
+/// let slopes = Slopes::from(vec![1f32, 2., 3., 4.]);
+/// let pipeline = SlopesPipeline::new()
+///     .reference(Slopes::from(vec![1f32; 4]))
+///     .tip_tilt_removal();
+/// let processed: Vec<f32> = pipeline.process(slopes).unwrap().into();
+/// assert_eq!(processed, vec![-1., -1., 1., 1.]);
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SlopesPipeline {
+    #[serde(default)]
+    layout: SlopesLayout,
+    segments: Option<Vec<u8>>,
+    steps: Vec<SlopesProcessing>,
+}
+impl SlopesPipeline {
+    /// Creates an empty pipeline
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Sets the ordering of the slopes
+    pub fn layout(self, layout: SlopesLayout) -> Self {
+        Self { layout, ..self }
+    }
+    /// Adds a processing step
+    pub fn step(mut self, step: SlopesProcessing) -> Self {
+        self.steps.push(step);
+        self
+    }
+    /// Adds the reference slopes subtraction
+    pub fn reference(self, sxy0: Slopes) -> Self {
+        self.step(SlopesProcessing::Reference(sxy0))
+    }
+    /// Adds the subtraction of the reference slopes of a [DataRef], if any
+    pub fn reference_from(self, data_ref: &DataRef) -> Self {
+        match data_ref.sxy0.as_ref() {
+            Some(sxy0) => self.reference(sxy0.clone()),
+            None => self,
+        }
+    }
+    /// Adds the flux normalization
+    pub fn flux_normalization(self, flux: Vec<f32>) -> Self {
+        self.step(SlopesProcessing::FluxNormalization(flux))
+    }
+    /// Adds the removal of the global tip-tilt
+    pub fn tip_tilt_removal(self) -> Self {
+        self.step(SlopesProcessing::TipTiltRemoval)
+    }
+    /// Adds the per-segment averaging
+    pub fn segment_average(self) -> Self {
+        self.step(SlopesProcessing::SegmentAverage)
+    }
+    /// Adds the outlier rejection
+    pub fn outlier_rejection(self, n_sigma: f32) -> Self {
+        self.step(SlopesProcessing::OutlierRejection(n_sigma))
+    }
+    /// Adds the masking of all the segments but `sids`
+    pub fn segment_mask(self, sids: Vec<u8>) -> Self {
+        self.step(SlopesProcessing::SegmentMask(sids))
+    }
+    /// Sets the segment map
+    pub fn segments(self, segments: Vec<u8>) -> Self {
+        Self {
+            segments: Some(segments),
+            ..self
+        }
+    }
+    /// Sets the segment map from the mask of a [DataRef] and from the segment masks
+    ///
+    /// The segment masks are given in the order of the segments ID, as returned by
+    /// [Calibration::masks](super::Calibration::masks)
+    pub fn segments_from<'a>(
+        self,
+        data_ref: &DataRef,
+        segment_masks: impl IntoIterator<Item = Option<&'a nalgebra::DMatrix<bool>>>,
+    ) -> Self {
+        let Some(mask) = data_ref.mask() else {
+            return self;
+        };
+        let mut segments = vec![0u8; mask.len()];
+        segment_masks
+            .into_iter()
+            .zip(1u8..)
+            .filter_map(|(m, sid)| m.map(|m| (m, sid)))
+            .for_each(|(m, sid)| {
+                segments
+                    .iter_mut()
+                    .zip(m.iter())
+                    .filter(|(_, m)| **m)
+                    .for_each(|(s, _)| *s = sid)
+            });
+        self.segments(
+            mask.iter()
+                .zip(segments)
+                .filter_map(|(m, s)| m.then_some(s))
+                .collect(),
+        )
+    }
+    /// Returns the segment map
+    pub fn segment_map(&self) -> Option<&[u8]> {
+        self.segments.as_deref()
+    }
+    /// Processes the slopes
+    pub fn process(&self, slopes: Slopes) -> Result<Slopes> {
+        let mut axes = self.layout.split(slopes.0)?;
+        let n = axes[0].len();
+        if let Some(segments) = self.segments.as_ref() {
+            if segments.len() != n {
+                return Err(SlopesPipelineError::Length(
+                    axes.len() * segments.len(),
+                    axes.len() * n,
+                ));
+            }
+        }
+        let mut valid = vec![true; n];
+        for step in &self.steps {
+            match step {
+                SlopesProcessing::Reference(sxy0) => {
+                    let axes0 = self.layout.split(sxy0.0.clone())?;
+                    if axes0[0].len() != n {
+                        return Err(SlopesPipelineError::Length(axes.len() * n, sxy0.len()));
+                    }
+                    axes.iter_mut()
+                        .zip(&axes0)
+                        .for_each(|(s, s0)| s.iter_mut().zip(s0).for_each(|(s, s0)| *s -= s0));
+                }
+                SlopesProcessing::FluxNormalization(flux) => {
+                    if flux.len() != n {
+                        return Err(SlopesPipelineError::Length(
+                            axes.len() * n,
+                            axes.len() * flux.len(),
+                        ));
+                    }
+                    for s in axes.iter_mut() {
+                        s.iter_mut()
+                            .zip(flux)
+                            .filter(|(_, f)| **f > 0.)
+                            .for_each(|(s, f)| *s /= f);
+                    }
+                    valid
+                        .iter_mut()
+                        .zip(flux)
+                        .filter(|(_, f)| **f <= 0.)
+                        .for_each(|(v, _)| *v = false);
+                }
+                SlopesProcessing::TipTiltRemoval => {
+                    for s in axes.iter_mut() {
+                        if let Some(m) = mean(s, &valid, |_| true) {
+                            s.iter_mut().for_each(|s| *s -= m);
+                        }
+                    }
+                }
+                SlopesProcessing::SegmentAverage => {
+                    let segments = self
+                        .segments
+                        .as_ref()
+                        .ok_or(SlopesPipelineError::MissingSegments)?;
+                    for s in axes.iter_mut() {
+                        for sid in 1..=7u8 {
+                            if let Some(m) = mean(s, &valid, |k| segments[k] == sid) {
+                                s.iter_mut()
+                                    .zip(segments)
+                                    .filter(|(_, s)| **s == sid)
+                                    .for_each(|(s, _)| *s = m);
+                            }
+                        }
+                    }
+                }
+                SlopesProcessing::OutlierRejection(n_sigma) => {
+                    for s in axes.iter() {
+                        let values: Vec<f32> = s
+                            .iter()
+                            .zip(&valid)
+                            .filter_map(|(s, v)| v.then_some(*s))
+                            .collect();
+                        let Some(m) = median(values.clone()) else {
+                            continue;
+                        };
+                        let Some(mad) = median(values.iter().map(|x| (x - m).abs()).collect())
+                        else {
+                            continue;
+                        };
+                        let threshold = n_sigma * 1.4826 * mad;
+                        s.iter()
+                            .zip(valid.iter_mut())
+                            .filter(|(s, _)| (**s - m).abs() > threshold)
+                            .for_each(|(_, v)| *v = false);
+                    }
+                }
+                SlopesProcessing::SegmentMask(sids) => {
+                    let segments = self
+                        .segments
+                        .as_ref()
+                        .ok_or(SlopesPipelineError::MissingSegments)?;
+                    valid
+                        .iter_mut()
+                        .zip(segments)
+                        .filter(|(_, s)| !sids.contains(s))
+                        .for_each(|(v, _)| *v = false);
+                }
+            }
+            for s in axes.iter_mut() {
+                s.iter_mut()
+                    .zip(&valid)
+                    .filter(|(_, v)| !**v)
+                    .for_each(|(s, _)| *s = 0.);
+            }
+        }
+        Ok(Slopes(self.layout.merge(axes)))
+    }
+    /// Returns the mean slopes of each segment, with one value per axis
+    ///
+    /// The segment mean is `None` if the segment is not seen by any slopes
+    pub fn segment_means(&self, slopes: &Slopes) -> Result<Vec<Option<Vec<f32>>>> {
+        let segments = self
+            .segments
+            .as_ref()
+            .ok_or(SlopesPipelineError::MissingSegments)?;
+        let axes = self.layout.split(slopes.0.clone())?;
+        if segments.len() != axes[0].len() {
+            return Err(SlopesPipelineError::Length(
+                axes.len() * segments.len(),
+                slopes.len(),
+            ));
+        }
+        let valid = vec![true; segments.len()];
+        Ok((1..=7u8)
+            .map(|sid| {
+                axes.iter()
+                    .map(|s| mean(s, &valid, |k| segments[k] == sid))
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+// Mean of the valid slopes selected with the predicate on the slopes index
+fn mean(s: &[f32], valid: &[bool], predicate: impl Fn(usize) -> bool) -> Option<f32> {
+    let (n, sum) = s
+        .iter()
+        .zip(valid)
+        .enumerate()
+        .filter(|(k, (_, v))| **v && predicate(*k))
+        .fold((0usize, 0f32), |(n, sum), (_, (s, _))| (n + 1, sum + s));
+    (n > 0).then(|| sum / n as f32)
+}
+
+fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    Some(if n.is_multiple_of(2) {
+        0.5 * (values[n / 2 - 1] + values[n / 2])
+    } else {
+        values[n / 2]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments() {
+        let slopes = Slopes::from(vec![1f32, 2., 3., 4., 5., 6., 7., 8.]);
+        let pipeline = SlopesPipeline::new()
+            .segments(vec![1, 1, 2, 2])
+            .segment_average();
+        assert_eq!(
+            Vec::<f32>::from(pipeline.process(slopes.clone()).unwrap()),
+            vec![2., 3., 2., 3., 6., 7., 6., 7.]
+        );
+        assert_eq!(
+            pipeline.segment_means(&slopes).unwrap()[..3],
+            [Some(vec![2., 3.]), Some(vec![6., 7.]), None]
+        );
+        let pipeline = SlopesPipeline::new()
+            .segments(vec![1, 1, 2, 2])
+            .segment_mask(vec![2])
+            .tip_tilt_removal();
+        assert_eq!(
+            Vec::<f32>::from(pipeline.process(slopes).unwrap()),
+            vec![0., 0., 0., 0., -1., -1., 1., 1.]
+        );
+        assert!(SlopesPipeline::new()
+            .segment_average()
+            .process(Slopes::from(vec![0f32; 2]))
+            .is_err());
+    }
+
+    #[test]
+    fn outliers() {
+        let mut sxy: Vec<f32> = (0..20).flat_map(|k| [k as f32 * 1e-2, 1.]).collect();
+        sxy[10] = 10.;
+        let flux = vec![2f32; 20];
+        let slopes = SlopesPipeline::new()
+            .flux_normalization(flux)
+            .outlier_rejection(3.)
+            .process(Slopes::from(sxy))
+            .unwrap();
+        assert_eq!(slopes.0[10..12], [0., 0.]);
+        assert!((slopes.0[12] - 3e-2).abs() < 1e-6 && slopes.0[13] == 0.5);
+    }
+
+    #[test]
+    fn layouts() {
+        let interleaved = SlopesPipeline::new()
+            .segments(vec![1, 1, 2, 2])
+            .segment_average()
+            .process(Slopes::from(vec![1f32, 2., 3., 4., 5., 6., 7., 8.]))
+            .unwrap();
+        let split = SlopesPipeline::new()
+            .layout(SlopesLayout::Split)
+            .segments(vec![1, 1, 2, 2])
+            .segment_average()
+            .process(Slopes::from(vec![1f32, 3., 5., 7., 2., 4., 6., 8.]))
+            .unwrap();
+        assert_eq!(interleaved.0, vec![2., 3., 2., 3., 6., 7., 6., 7.]);
+        assert_eq!(split.0, vec![2., 2., 6., 6., 3., 3., 7., 7.]);
+        let scalar = SlopesPipeline::new()
+            .layout(SlopesLayout::Scalar)
+            .tip_tilt_removal()
+            .process(Slopes::from(vec![1f32, 2., 3.]))
+            .unwrap();
+        assert_eq!(scalar.0, vec![-1., 0., 1.]);
+        assert!(matches!(
+            SlopesPipeline::new().process(Slopes::from(vec![1f32, 2., 3.])),
+            Err(SlopesPipelineError::Odd(3, SlopesLayout::Interleaved))
+        ));
+        assert!(matches!(
+            SlopesPipeline::new()
+                .reference(Slopes::from(vec![0f32; 2]))
+                .process(Slopes::from(vec![0f32; 4])),
+            Err(SlopesPipelineError::Length(4, 2))
+        ));
+    }
+}
@@ -8,6 +8,7 @@ use nalgebra::DMatrix;
 use serde::{Deserialize, Serialize};
 
 use super::{DataRef, Slopes};
+use crate::gmt::layout::DofLayout;
 
 type Mat = nalgebra::DMatrix<f32>;
 
@@ -57,6 +58,9 @@ pub struct SlopesArray {
     pub data_ref: DataRef,
     #[serde(skip)]
     pub(crate) inverse: Option<Mat>,
+    /// degrees of freedom of the columns
+    #[serde(default)]
+    pub(crate) layout: Option<DofLayout>,
 }
 
 impl From<(DataRef, Vec<Slopes>)> for SlopesArray {
@@ -112,6 +116,17 @@ impl Display for SlopesArray {
             self.slopes.iter().map(|s| s.len()).collect::<Vec<_>>()
         )?;
         writeln!(f, " * data ref.: {:}", self.data_ref)?;
+        if let Some(layout) = &self.layout {
+            writeln!(
+                f,
+                " * DOFs: {}",
+                layout
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
         if let Some(mat) = &self.inverse {
             writeln!(f, " * inverse: {:?}", mat.shape())?;
         }
@@ -148,6 +163,23 @@ impl SlopesArray {
             ..Default::default()
         }
     }
+    /// Sets the degrees of freedom [DofLayout] of the columns
+    pub fn with_layout(self, layout: DofLayout) -> Self {
+        assert_eq!(
+            layout.len(),
+            self.ncols(),
+            "the layout and the slopes array number of columns mismatch"
+        );
+        Self {
+            layout: Some(layout),
+            ..self
+        }
+    }
+    /// Returns the degrees of freedom [DofLayout] of the columns,
+    /// i.e. of the outputs of the reconstructor
+    pub fn layout(&self) -> Option<&DofLayout> {
+        self.layout.as_ref()
+    }
     /// Returns the number of rows and columns of the [SlopesArray]
     pub fn shape(&self) -> (usize, usize) {
         (self.slopes[0].len(), self.slopes.len())
@@ -232,6 +264,9 @@ impl SlopesArray {
     }
     /// Removes the [Slopes] at given indices `idxs` in the [Slopes] vector
     pub fn trim(&mut self, idxs: Vec<usize>) {
+        if let Some(layout) = self.layout.as_mut() {
+            layout.trim(&idxs);
+        }
         let mut count = 0;
         for idx in idxs.into_iter() {
             let i = idx - count;
@@ -240,6 +275,8 @@ impl SlopesArray {
         }
     }
     pub fn insert_rows(&mut self, rows: Vec<usize>) {
+        // the reconstructor outputs do not match the columns anymore
+        self.layout = None;
         if let Some(inverse) = self.inverse.take() {
             self.inverse = Some(rows.into_iter().fold(inverse, |a, i| a.insert_row(i, 0f32)));
         }
@@ -0,0 +1,227 @@
+//! Common implementation of the segment-wise sensors which signals are computed on the host
+//!
+//! The [CurvatureSensor](super::curvature_sensor::CurvatureSensor) and the [ZernikeSensor](super::zernike_sensor::ZernikeSensor)
+//! integrate their intensities on the host at each propagation: there is no detector to read out and no frame to process.
+//! They share the [WavefrontSensor](crate::WavefrontSensor) implementation derived from [HostSensor] with `host_wavefront_sensor!`
+//! and the segment calibration with [calibrate_segment].
+
+use indicatif::ProgressBar;
+
+use crate::{
+    builders::SourceBuilder, cu::Single, Builder, Cu, FromBuilder, Gmt, Mask, SegmentWiseSensor,
+};
+
+use super::data_processing::SlopesArray;
+
+/// Valid elements (sub-apertures or pupil pixels) of a sensor computed on the host
+///
+/// The valid elements are kept on the host and in a CEO mask
+#[derive(Default)]
+pub struct ValidElements {
+    valid: Vec<bool>,
+    mask: Option<Mask>,
+}
+impl ValidElements {
+    /// Sets the valid elements
+    pub fn set(&mut self, valid: Vec<bool>) {
+        let mut cu_valid: Cu<Single> = valid
+            .iter()
+            .map(|v| *v as u8 as f32)
+            .collect::<Vec<f32>>()
+            .into();
+        let mut mask = Mask::new();
+        mask.build(valid.len()).filter(&mut cu_valid);
+        self.mask = Some(mask);
+        self.valid = valid;
+    }
+    /// Sets the valid elements from a CEO mask
+    pub fn set_from(&mut self, mask: &mut ffi::mask) {
+        let mut f: Cu<Single> = Cu::vector(mask.nel as usize);
+        f.from_ptr(mask.f);
+        self.set(Vec::<f32>::from(f).into_iter().map(|f| f > 0.).collect());
+    }
+    /// Returns the valid elements, empty if the sensor has not been calibrated
+    pub fn as_slice(&self) -> &[bool] {
+        &self.valid
+    }
+    /// Returns the CEO mask of the valid elements
+    ///
+    /// All the `n` elements are valid if the sensor has not been calibrated
+    pub fn mask(&mut self, n: usize) -> &mut ffi::mask {
+        if self.mask.is_none() {
+            self.set(vec![true; n]);
+        }
+        self.mask.as_mut().unwrap().as_raw_mut_ptr()
+    }
+    /// Returns the number of valid elements of each of the `n_gs` guide stars
+    pub fn count(&self, n_gs: usize) -> Vec<usize> {
+        if self.valid.is_empty() {
+            return vec![];
+        }
+        self.valid
+            .chunks(self.valid.len() / n_gs)
+            .map(|v| v.iter().filter(|v| **v).count())
+            .collect()
+    }
+}
+
+/// Segment-wise sensors which intensities are integrated on the host
+pub trait HostSensor {
+    /// Returns the number of guide stars
+    fn n_guide_star(&self) -> usize;
+    /// Clears the integrated intensities
+    fn clear(&mut self);
+    /// Returns the flux in each element of all the guide stars
+    fn element_flux(&self) -> Vec<f64>;
+    /// Returns the signal in each element of all the guide stars
+    fn element_signal(&self) -> Vec<f64>;
+    /// Returns the integrated intensities, if any
+    fn intensity_frame(&self) -> Option<Vec<f32>>;
+    /// Returns the number of integrated frames
+    fn frame_count(&self) -> usize;
+    /// Returns the valid elements
+    fn valid_elements(&mut self) -> &mut ValidElements;
+}
+
+/// Implements [WavefrontSensor](crate::WavefrontSensor) for a [HostSensor]
+///
+/// The calibration selects the elements which flux is larger than `threshold` times the maximum flux
+/// of each guide star and the [data](crate::WavefrontSensor::data) is the signal in the valid elements.
+/// The signal is computed when the sensor is propagated so [process](crate::WavefrontSensor::process)
+/// and [readout](crate::WavefrontSensor::readout) do nothing
+macro_rules! host_wavefront_sensor {
+    ($sensor:ty) => {
+        impl $crate::WavefrontSensor for $sensor {
+            fn calibrate(&mut self, src: &mut $crate::Source, threshold: f64) {
+                use $crate::Propagation;
+                HostSensor::clear(self);
+                self.propagate(src);
+                let flux = HostSensor::element_flux(self);
+                let n = flux.len() / HostSensor::n_guide_star(self);
+                let valid = flux
+                    .chunks(n)
+                    .flat_map(|flux| {
+                        let max_flux = flux.iter().cloned().fold(0f64, f64::max);
+                        flux.iter()
+                            .map(move |f| *f > threshold * max_flux)
+                            .collect::<Vec<bool>>()
+                    })
+                    .collect();
+                HostSensor::valid_elements(self).set(valid);
+                HostSensor::clear(self);
+            }
+
+            fn reset(&mut self) {
+                HostSensor::clear(self);
+            }
+
+            fn process(&mut self) {}
+
+            fn readout(&mut self) {}
+
+            fn data(&mut self) -> Vec<f64> {
+                let signal = HostSensor::element_signal(self);
+                match HostSensor::valid_elements(self).as_slice() {
+                    [] => signal,
+                    valid => signal
+                        .into_iter()
+                        .zip(valid)
+                        .filter_map(|(s, v)| v.then_some(s))
+                        .collect(),
+                }
+            }
+
+            fn frame(&self) -> Option<Vec<f32>> {
+                HostSensor::intensity_frame(self)
+            }
+
+            fn n_frame(&self) -> usize {
+                HostSensor::frame_count(self)
+            }
+
+            fn valid_lenslet_from(&mut self, wfs: &mut dyn $crate::WavefrontSensor) {
+                let n = HostSensor::element_flux(self).len();
+                let mask = wfs.valid_lenslet();
+                assert_eq!(
+                    mask.nel as usize, n,
+                    "expected a mask of {} elements, found {}",
+                    n, mask.nel
+                );
+                HostSensor::valid_elements(self).set_from(mask);
+            }
+
+            fn valid_lenslet(&mut self) -> &mut ffi::mask {
+                let n = HostSensor::element_flux(self).len();
+                HostSensor::valid_elements(self).mask(n)
+            }
+
+            fn n_valid_lenslet(&mut self) -> Vec<usize> {
+                let n_gs = HostSensor::n_guide_star(self);
+                HostSensor::valid_elements(self).count(n_gs)
+            }
+
+            fn left_multiply(
+                &self,
+                calibration: &$crate::wavefrontsensor::Calibration,
+            ) -> Option<Vec<f32>> {
+                calibration * self
+            }
+        }
+    };
+}
+pub(crate) use host_wavefront_sensor;
+
+/// Calibrates the sensor with the Karhunen-Loeve modes of the M2 segment #`sid`
+///
+/// Each mode is pushed and pulled with an amplitude of 1e-2rd peak phase
+pub fn calibrate_segment<W: SegmentWiseSensor>(
+    wfs: &mut W,
+    src_builder: Option<SourceBuilder>,
+    sid: usize,
+    n_mode: usize,
+    pb: Option<ProgressBar>,
+) -> SlopesArray {
+    let data_ref = wfs.zeroed_segment(sid, src_builder.clone());
+
+    let mut gmt = Gmt::builder().m2("Karhunen-Loeve", n_mode).build().unwrap();
+    gmt.keep(&[sid as i32]);
+    let mut src = src_builder
+        .unwrap_or_default()
+        .pupil_sampling(wfs.pupil_sampling())
+        .build()
+        .unwrap();
+    let o2p = 2. * std::f64::consts::PI / src.wavelength();
+
+    let mut slopes = vec![];
+    for kl_mode in 0..n_mode {
+        if let Some(pb) = pb.as_ref() {
+            pb.inc(1)
+        }
+        let kl_a0 = 1e-6;
+        gmt.m2_modes_ij(sid - 1, kl_mode, kl_a0);
+        src.through(&mut gmt).xpupil();
+        let phase_max = src
+            .phase()
+            .iter()
+            .fold(0f64, |max, opd| max.max(o2p * opd.abs() as f64));
+        let kl_coef = 1e-2 * kl_a0 / phase_max;
+
+        wfs.reset();
+        gmt.m2_modes_ij(sid - 1, kl_mode, kl_coef);
+        src.through(&mut gmt).xpupil().through(wfs);
+        let slopes_push = wfs.into_slopes(&data_ref);
+        wfs.reset();
+
+        gmt.m2_modes_ij(sid - 1, kl_mode, -kl_coef);
+        src.through(&mut gmt).xpupil().through(wfs);
+        let slopes_pull = wfs.into_slopes(&data_ref);
+        wfs.reset();
+
+        slopes.push((slopes_push - slopes_pull) / (2. * kl_coef as f32));
+    }
+    if let Some(pb) = pb.as_ref() {
+        pb.finish()
+    }
+
+    (data_ref, slopes).into()
+}
@@ -0,0 +1,428 @@
+//!
+//! # Segment phasing
+//!
+//! Absolute segment piston estimation from wrapped piston measurements.
+//!
+//! The measurements are the 12 differential pistons of the [DifferentialPistonSensor](super::DifferentialPistonSensor):
+//! the 6 differences between the center segment and the outer segments
+//! followed by the 6 differences between neighbouring outer segments,
+//! each one known modulo the wrapping period i.e. the wavelength.
+//! The segment pistons are estimated by
+//!  1. combining the measurements at 2 wavelengths into measurements at the synthetic wavelength,
+//!  2. removing the residues of the closed loops of the segment graph with branch cuts,
+//!  3. unwrapping the measurements from the coarsest to the finest wrapping period,
+//!  4. integrating the unwrapped differential pistons in the least square sense.
+//!
+//! A [CaptureRange] simulation gives the success rate of the estimation with respect to the segment piston amplitude.
+//!
+//! ```
+//! use crseo::wavefrontsensor::phasing::{Phasing, WrappedPiston};
+//! let piston = [0.3e-6, -1.1e-6, 0.7e-6, 0., 1.2e-6, -0.4e-6, 0.];
+//! let estimate = Phasing::default()
+//!     .measurement(WrappedPiston::from_pistons(0.8e-6, &piston)?)
+//!     .measurement(WrappedPiston::from_pistons(0.9e-6, &piston)?)
+//!     .estimate()?;
+//! println!("{:?}", estimate.piston);
+//! # Ok::<(), crseo::wavefrontsensor::phasing::PhasingError>(())
+//! ```
+
+use nalgebra as na;
+use rand::{rngs::StdRng, RngExt, SeedableRng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PhasingError {
+    #[error("expected {0} piston measurements, found {1}")]
+    Length(usize, usize),
+    #[error("no piston measurements")]
+    Empty,
+    #[error("the wrapping periods must be positive and distinct, found {0:?}")]
+    Period(Vec<f64>),
+}
+pub type Result<T> = std::result::Result<T, PhasingError>;
+
+/// Number of differential piston measurements
+pub const N_EDGE: usize = 12;
+/// Segment pairs `(a,b)` of the differential pistons `p_a-p_b`
+pub const EDGES: [(usize, usize); N_EDGE] = [
+    (7, 1),
+    (7, 2),
+    (7, 3),
+    (7, 4),
+    (7, 5),
+    (7, 6),
+    (1, 2),
+    (2, 3),
+    (3, 4),
+    (4, 5),
+    (5, 6),
+    (6, 1),
+];
+
+/// Wraps `x` in the interval \[-period/2,period/2\]
+pub fn wrap(x: f64, period: f64) -> f64 {
+    x - period * (x / period).round()
+}
+
+/// Returns the synthetic wavelength of 2 wavelengths
+pub fn synthetic_wavelength(lambda_1: f64, lambda_2: f64) -> f64 {
+    lambda_1 * lambda_2 / (lambda_1 - lambda_2).abs()
+}
+
+/// Returns the differential pistons of the 7 segment pistons
+pub fn differential_pistons(piston: &[f64]) -> Vec<f64> {
+    EDGES
+        .iter()
+        .map(|&(a, b)| piston[a - 1] - piston[b - 1])
+        .collect()
+}
+
+/// Wrapped differential piston measurements
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WrappedPiston {
+    /// wrapping period i.e. the wavelength \[m\]
+    pub period: f64,
+    /// differential pistons ordered as [EDGES] \[m\]
+    pub edges: Vec<f64>,
+}
+impl WrappedPiston {
+    /// Creates new measurements from the 12 differential pistons of a [DifferentialPistonSensor](super::DifferentialPistonSensor)
+    pub fn new<T: Into<f64> + Copy>(period: f64, data: &[T]) -> Result<Self> {
+        if data.len() != N_EDGE {
+            return Err(PhasingError::Length(N_EDGE, data.len()));
+        }
+        Ok(Self {
+            period,
+            edges: data.iter().map(|&x| wrap(x.into(), period)).collect(),
+        })
+    }
+    /// Creates new measurements from the 7 segment pistons of a [PistonSensor](super::PistonSensor)
+    pub fn from_pistons<T: Into<f64> + Copy>(period: f64, piston: &[T]) -> Result<Self> {
+        if piston.len() != 7 {
+            return Err(PhasingError::Length(7, piston.len()));
+        }
+        let piston: Vec<f64> = piston.iter().map(|&x| x.into()).collect();
+        Self::new(period, &differential_pistons(&piston))
+    }
+    /// Combines 2 measurements into the measurements at the synthetic wavelength
+    pub fn synthetic(&self, other: &Self) -> Self {
+        let period = synthetic_wavelength(self.period, other.period);
+        let (short, long) = if self.period < other.period {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Self {
+            period,
+            edges: short
+                .edges
+                .iter()
+                .zip(&long.edges)
+                .map(|(s, l)| wrap(period * (s / short.period - l / long.period), period))
+                .collect(),
+        }
+    }
+    /// Returns the residues of the 6 triangles (7,i,i+1) of the segment graph
+    ///
+    /// A residue is the number of wrapping periods that the differential pistons add up to around a triangle,
+    /// all the residues are 0 if the measurements are consistent
+    pub fn residues(&self) -> [i32; 6] {
+        let d = &self.edges;
+        std::array::from_fn(|t| ((d[t] + d[6 + t] - d[(t + 1) % 6]) / self.period).round() as i32)
+    }
+    /// Removes the residues with branch cuts
+    ///
+    /// Residues of opposite signs in neighbouring triangles are removed by unwrapping the edge between them
+    /// and the other residues by unwrapping the edge of the triangle on the rim of the segment graph
+    pub fn branch_cuts(&mut self) -> &mut Self {
+        let mut r = self.residues();
+        for t in 0..6 {
+            let u = (t + 1) % 6;
+            if r[t] * r[u] < 0 {
+                let c = r[t].signum() * r[t].abs().min(r[u].abs());
+                self.edges[u] += c as f64 * self.period;
+                r[t] -= c;
+                r[u] += c;
+            }
+        }
+        for (t, r) in r.into_iter().enumerate() {
+            self.edges[6 + t] -= r as f64 * self.period;
+        }
+        self
+    }
+}
+
+/// Segment piston estimates
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PistonEstimate {
+    /// segment pistons with respect to the center segment \[m\]
+    pub piston: Vec<f64>,
+    /// confidence in the segment pistons from 0 (ambiguous) to 1
+    ///
+    /// The confidence is the smallest margin to the rounding ambiguity of the differential pistons of a segment
+    pub confidence: Vec<f64>,
+    /// rms of the differential pistons fit residuals \[m\]
+    pub residual: f64,
+}
+
+/// Segment piston estimator
+///
+/// The measurements at each wavelength and at the synthetic wavelengths of all the pairs of wavelengths
+/// are unwrapped from the longest to the shortest wrapping period.
+/// The differential pistons must be within half the longest period.
+///
+/// Default properties:
+///  - measurements: none
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Phasing {
+    measurements: Vec<WrappedPiston>,
+}
+impl Phasing {
+    /// Adds measurements
+    pub fn measurement(mut self, measurement: WrappedPiston) -> Self {
+        self.measurements.push(measurement);
+        self
+    }
+    /// Returns the measurements sorted from the longest to the shortest wrapping period
+    pub fn levels(&self) -> Result<Vec<WrappedPiston>> {
+        if self.measurements.is_empty() {
+            return Err(PhasingError::Empty);
+        }
+        let periods: Vec<_> = self.measurements.iter().map(|m| m.period).collect();
+        if periods.iter().any(|p| *p <= 0.)
+            || periods
+                .iter()
+                .enumerate()
+                .any(|(i, p)| periods[i + 1..].contains(p))
+        {
+            return Err(PhasingError::Period(periods));
+        }
+        let mut levels = self.measurements.clone();
+        for (i, a) in self.measurements.iter().enumerate() {
+            for b in &self.measurements[i + 1..] {
+                levels.push(a.synthetic(b));
+            }
+        }
+        levels.sort_by(|a, b| b.period.total_cmp(&a.period));
+        Ok(levels)
+    }
+    /// Estimates the segment pistons
+    pub fn estimate(&self) -> Result<PistonEstimate> {
+        let levels = self.levels()?;
+        let mut coarse = levels[0].clone();
+        coarse.branch_cuts();
+        let mut margin: Vec<_> = coarse
+            .edges
+            .iter()
+            .map(|d| (1. - 2. * d.abs() / coarse.period).max(0.))
+            .collect();
+        let (mut piston, mut residual) = integrate(&coarse.edges);
+        for level in &levels[1..] {
+            let edges: Vec<_> = level
+                .edges
+                .iter()
+                .zip(differential_pistons(&piston))
+                .zip(margin.iter_mut())
+                .map(|((d, p), m)| {
+                    let n = ((p - d) / level.period).round();
+                    let r = p - d - n * level.period;
+                    *m = m.min(1. - 2. * r.abs() / level.period);
+                    d + n * level.period
+                })
+                .collect();
+            (piston, residual) = integrate(&edges);
+        }
+        let confidence = (1..=7)
+            .map(|sid| {
+                EDGES
+                    .iter()
+                    .zip(&margin)
+                    .filter(|((a, b), _)| *a == sid || *b == sid)
+                    .fold(1f64, |c, (_, m)| c.min(*m))
+            })
+            .collect();
+        Ok(PistonEstimate {
+            piston,
+            confidence,
+            residual,
+        })
+    }
+}
+
+// least square segment pistons, with the center segment piston set to 0, and the rms of the fit residuals
+fn integrate(edges: &[f64]) -> (Vec<f64>, f64) {
+    let b = na::DMatrix::<f64>::from_fn(N_EDGE, 6, |i, j| {
+        let (a, b) = EDGES[i];
+        (a == j + 1) as u8 as f64 - (b == j + 1) as u8 as f64
+    });
+    let d = na::DVector::from_column_slice(edges);
+    let p = (b.transpose() * &b)
+        .cholesky()
+        .expect("the segment graph is connected")
+        .solve(&(b.transpose() * &d));
+    let residual = ((&b * &p - d).norm_squared() / N_EDGE as f64).sqrt();
+    let mut piston = p.as_slice().to_vec();
+    piston.push(0.);
+    (piston, residual)
+}
+
+/// Capture range simulator
+///
+/// Estimates the segment pistons of random segment piston realisations
+/// and counts the estimates that are within the tolerance of the true pistons.
+/// The segment pistons are uniformly distributed in \[-amplitude,amplitude\]
+/// and white noise is added to the differential pistons before wrapping.
+///
+/// Default properties:
+///  - wavelengths: none
+///  - noise: 0
+///  - \# of trials: 100
+///  - tolerance: 1/10th of the shortest wavelength
+///  - seed: 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRange {
+    wavelengths: Vec<f64>,
+    noise: f64,
+    n_trial: usize,
+    tolerance: Option<f64>,
+    seed: u64,
+}
+impl Default for CaptureRange {
+    fn default() -> Self {
+        Self {
+            wavelengths: vec![],
+            noise: 0.,
+            n_trial: 100,
+            tolerance: None,
+            seed: 0,
+        }
+    }
+}
+impl CaptureRange {
+    /// Creates a new capture range simulator for the given wavelengths \[m\]
+    pub fn new(wavelengths: Vec<f64>) -> Self {
+        Self {
+            wavelengths,
+            ..Default::default()
+        }
+    }
+    /// Sets the rms of the differential pistons noise \[m\]
+    pub fn noise(self, noise: f64) -> Self {
+        Self { noise, ..self }
+    }
+    /// Sets the number of trials for each amplitude
+    pub fn n_trial(self, n_trial: usize) -> Self {
+        Self { n_trial, ..self }
+    }
+    /// Sets the largest error of a successful estimate \[m\]
+    pub fn tolerance(self, tolerance: f64) -> Self {
+        Self {
+            tolerance: Some(tolerance),
+            ..self
+        }
+    }
+    /// Sets the seed of the random number generator
+    pub fn seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+    /// Returns the fraction of successful estimates for the segment pistons `amplitude` \[m\]
+    pub fn success_rate(&self, amplitude: f64) -> Result<f64> {
+        let tolerance = self.tolerance.unwrap_or(
+            0.1 * self
+                .wavelengths
+                .iter()
+                .cloned()
+                .fold(f64::INFINITY, f64::min),
+        );
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut n_success = 0;
+        for _ in 0..self.n_trial {
+            let piston: Vec<f64> = (0..7)
+                .map(|_| amplitude * (2. * rng.random::<f64>() - 1.))
+                .collect();
+            let edges = differential_pistons(&piston);
+            let phasing = self.wavelengths.iter().try_fold(
+                Phasing::default(),
+                |phasing, &wavelength| -> Result<Phasing> {
+                    let data: Vec<f64> = edges
+                        .iter()
+                        .map(|e| e + self.noise * rng.sample::<f64, _>(StandardNormal))
+                        .collect();
+                    Ok(phasing.measurement(WrappedPiston::new(wavelength, &data)?))
+                },
+            )?;
+            let estimate = phasing.estimate()?;
+            if estimate
+                .piston
+                .iter()
+                .zip(&piston)
+                .all(|(e, p)| (e - (p - piston[6])).abs() < tolerance)
+            {
+                n_success += 1;
+            }
+        }
+        Ok(n_success as f64 / self.n_trial as f64)
+    }
+    /// Returns the pairs (amplitude,success rate)
+    pub fn sweep(&self, amplitudes: impl IntoIterator<Item = f64>) -> Result<Vec<(f64, f64)>> {
+        amplitudes
+            .into_iter()
+            .map(|a| self.success_rate(a).map(|s| (a, s)))
+            .collect()
+    }
+    /// Returns the largest of the increasing `amplitudes` up to which the success rate is at least `threshold`
+    pub fn capture_range(
+        &self,
+        amplitudes: impl IntoIterator<Item = f64>,
+        threshold: f64,
+    ) -> Result<Option<f64>> {
+        let mut range = None;
+        for a in amplitudes {
+            if self.success_rate(a)? < threshold {
+                break;
+            }
+            range = Some(a);
+        }
+        Ok(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwrap() {
+        let piston = [0.3e-6, -1.1e-6, 0.7e-6, 0., 1.2e-6, -0.4e-6, 0.];
+        let mut fine = WrappedPiston::from_pistons(0.8e-6, &piston).unwrap();
+        assert!(fine.residues().iter().any(|r| *r != 0));
+        fine.branch_cuts();
+        assert_eq!(fine.residues(), [0; 6]);
+        let estimate = Phasing::default()
+            .measurement(WrappedPiston::from_pistons(0.8e-6, &piston).unwrap())
+            .measurement(WrappedPiston::from_pistons(0.9e-6, &piston).unwrap())
+            .estimate()
+            .unwrap();
+        estimate
+            .piston
+            .iter()
+            .zip(piston)
+            .for_each(|(e, p)| assert!((e - p).abs() < 1e-12));
+        assert!(estimate.confidence.iter().all(|c| *c > 0.));
+    }
+
+    #[test]
+    fn capture_range() {
+        let sim = CaptureRange::new(vec![0.8e-6, 0.9e-6])
+            .noise(1e-9)
+            .n_trial(50);
+        assert_eq!(sim.success_rate(1e-6).unwrap(), 1.);
+        assert!(sim.success_rate(5e-6).unwrap() < 1.);
+        let range = sim
+            .capture_range((1..=10).map(|i| i as f64 * 0.5e-6), 1.)
+            .unwrap()
+            .unwrap();
+        assert!((1e-6..5e-6).contains(&range));
+    }
+}
@@ -4,6 +4,8 @@ pub use builder::PyramidBuilder;
 mod pyramid;
 pub use pyramid::{Pyramid, PyramidCalibration};
 mod piston_sensor;
+mod optical_gains;
+pub use optical_gains::OpticalGainTracker;
 
 pub use super::data_processing;
 
@@ -16,10 +18,28 @@ struct Modulation {
 }
 
 impl WavefrontSensorBuilder for PyramidBuilder {
+    /// Returns the guide stars of the pyramid
+    ///
+    /// The rays of the guide stars are moved to the hexagonal or distorted equivalent lenslets, if any,
+    /// and the guide stars are given the misregistration of the pyramid pupils.
+    /// A distortion map that does not match the equivalent lenslet array is ignored
     fn guide_stars(&self, gs: Option<SourceBuilder>) -> SourceBuilder {
-        gs.unwrap_or_default()
-            .rays_azimuth(0.5 * std::f64::consts::FRAC_PI_6)
-            .pupil_sampling(self.pupil_sampling())
+        let gs = gs
+            .unwrap_or_default()
+            .rays_azimuth(0.5 * std::f64::consts::FRAC_PI_6);
+        let n_px = self.pupil_sampling();
+        let geometry = self.geometry(gs.pupil_size).checked();
+        let gs = if geometry.is_square() {
+            gs
+        } else {
+            let (x, y) = geometry.rays_coordinates(n_px, gs.pupil_size);
+            gs.rays_coordinates(x, y)
+        }
+        .pupil_sampling(n_px);
+        match self.misregistration {
+            Some(misregistration) => gs.misregistration(misregistration),
+            None => gs,
+        }
     }
 }
 
@@ -2,7 +2,7 @@ use ffi::pyramid;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    imaging::LensletArray,
+    imaging::{LensletArray, LensletGeometry, Misregistration, Packing},
     wavefrontsensor::{Calibration, GmtSegmentation, SegmentWiseSensorBuilder},
     Builder, CrseoError,
 };
@@ -16,6 +16,9 @@ use super::{piston_sensor::PistonSensor, Modulation, Pyramid};
 ///   - n_px_lenslet: 8px
 ///   - lenslet_pitch: 0
 ///   - no modulation
+///   - no misregistration
+///   - packing: square
+///   - no distortion
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PyramidBuilder {
     pub lenslet_array: LensletArray,
@@ -23,6 +26,12 @@ pub struct PyramidBuilder {
     alpha: f32,
     n_gs: i32,
     pub piston_sensor: Option<PistonSensor>,
+    #[serde(default)]
+    pub misregistration: Option<Misregistration>,
+    #[serde(default)]
+    pub packing: Packing,
+    #[serde(default)]
+    pub distortion: Option<Vec<(f64, f64)>>,
 }
 impl Default for PyramidBuilder {
     fn default() -> Self {
@@ -36,6 +45,9 @@ impl Default for PyramidBuilder {
             alpha: 0.5f32,
             n_gs: 1,
             piston_sensor: None,
+            misregistration: None,
+            packing: Packing::Square,
+            distortion: None,
         }
     }
 }
@@ -61,6 +73,34 @@ impl PyramidBuilder {
         });
         self
     }
+    /// Sets the misregistration of the pyramid pupils with respect to the telescope pupil
+    pub fn misregistration(mut self, misregistration: Misregistration) -> Self {
+        self.misregistration = Some(misregistration);
+        self
+    }
+    /// Sets the hexagonal packing of the equivalent lenslets
+    pub fn hexagonal(mut self) -> Self {
+        self.packing = Packing::Hexagonal;
+        self
+    }
+    /// Sets the distortion map as the offsets \[m\] of the equivalent lenslet centers
+    ///
+    /// The map has an offset for each equivalent lenslet with `k=i+n_lenslet*j`,
+    /// its length is checked when the pyramid is built
+    pub fn distortion(mut self, distortion: Vec<(f64, f64)>) -> Self {
+        self.distortion = Some(distortion);
+        self
+    }
+    /// Returns the geometry of the equivalent lenslet array across a pupil of size `pupil_size` \[m\]
+    pub fn geometry(&self, pupil_size: f64) -> LensletGeometry {
+        let n_side_lenslet = self.lenslet_array.n_side_lenslet;
+        LensletGeometry {
+            lenslet_array: self.lenslet_array.pitch(pupil_size / n_side_lenslet as f64),
+            packing: self.packing,
+            misregistration: self.misregistration.unwrap_or_default(),
+            distortion: self.distortion.clone(),
+        }
+    }
     pub fn piston_sensor<G: Into<GmtSegmentation>>(
         &mut self,
         calibration: &Calibration,
@@ -101,6 +141,8 @@ impl Builder for PyramidBuilder {
     type Component = Pyramid;
 
     fn build(self) -> crate::Result<Self::Component> {
+        // the pitch of the equivalent lenslets does not matter to check the distortion map
+        self.geometry(1.).check_distortion()?;
         let mut pym = Pyramid {
             _c_: pyramid::default(),
             lenslet_array: self.lenslet_array,
@@ -0,0 +1,119 @@
+use std::f64::consts::PI;
+
+use rustfft::num_complex::Complex;
+
+/// Pyramid optical gains tracking with probe signals
+///
+/// Sinusoidal probes at the same temporal frequency are added to the modal commands of the deformable mirror
+/// and the probes are demodulated from both the pyramid modal estimates and the mirror commands.
+/// The optical gain of a mode is the ratio between the probe in the modal estimates
+/// and the probe in the residual wavefront seen by the pyramid.
+///
+/// Default properties:
+///  - forgetting factor: 1 (no forgetting)
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpticalGainTracker {
+    amplitude: Vec<f64>,
+    frequency: f64,
+    leak: f64,
+    step: usize,
+    estimate: Vec<Complex<f64>>,
+    residual: Vec<Complex<f64>>,
+}
+impl OpticalGainTracker {
+    /// Creates a new tracker with the probe `amplitude` of each mode
+    ///
+    /// The probe `frequency` is normalized to the loop sampling frequency and must be less than 0.5
+    pub fn new(amplitude: Vec<f64>, frequency: f64) -> Self {
+        let n = amplitude.len();
+        Self {
+            amplitude,
+            frequency,
+            leak: 1.,
+            step: 0,
+            estimate: vec![Complex::new(0., 0.); n],
+            residual: vec![Complex::new(0., 0.); n],
+        }
+    }
+    /// Sets the forgetting factor of the demodulation
+    ///
+    /// The past demodulated probes are multiplied by `leak` at each step
+    pub fn leak(self, leak: f64) -> Self {
+        Self { leak, ..self }
+    }
+    /// Returns the probes to add to the modal commands at the current step
+    pub fn probe(&self) -> Vec<f64> {
+        let s = (2. * PI * self.frequency * self.step as f64).sin();
+        self.amplitude.iter().map(|a| a * s).collect()
+    }
+    /// Demodulates the probes in the modal `estimate` of the pyramid and in the modal `command`
+    ///
+    /// `command` is the mirror command, probes included, applied while the pyramid was integrating
+    /// and the wavefront residual is the turbulence minus the mirror command
+    pub fn update(&mut self, estimate: &[f32], command: &[f64]) -> &mut Self {
+        let e = Complex::from_polar(1., -2. * PI * self.frequency * self.step as f64);
+        let leak = self.leak;
+        self.estimate
+            .iter_mut()
+            .zip(estimate)
+            .for_each(|(z, x)| *z = leak * *z + e * *x as f64);
+        self.residual
+            .iter_mut()
+            .zip(command)
+            .for_each(|(z, x)| *z = leak * *z - e * x);
+        self.step += 1;
+        self
+    }
+    /// Returns the optical gains
+    ///
+    /// The gains are set to 1 until the probes have been demodulated
+    pub fn gains(&self) -> Vec<f32> {
+        self.estimate
+            .iter()
+            .zip(&self.residual)
+            .map(|(m, r)| {
+                let r2 = r.norm_sqr();
+                if r2 > 0. {
+                    ((m * r.conj()).re / r2) as f32
+                } else {
+                    1.
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_loop() {
+        let optical_gains = [0.6, 0.8];
+        let mut tracker = OpticalGainTracker::new(vec![1e-2; 2], 0.2);
+        let mut correction = [0f64; 2];
+        for k in 0..2000 {
+            let turbulence = (2. * PI * 0.013 * k as f64).cos();
+            let command: Vec<f64> = correction
+                .iter()
+                .zip(tracker.probe())
+                .map(|(c, p)| c + p)
+                .collect();
+            let estimate: Vec<f32> = command
+                .iter()
+                .zip(&optical_gains)
+                .map(|(c, g)| (g * (turbulence - c)) as f32)
+                .collect();
+            correction
+                .iter_mut()
+                .zip(&estimate)
+                .for_each(|(c, e)| *c += 0.5 * *e as f64);
+            tracker.update(&estimate, &command);
+        }
+        tracker
+            .gains()
+            .iter()
+            .zip(optical_gains)
+            .for_each(|(g, g0)| assert!((*g as f64 - g0).abs() < 1e-2 * g0, "{g} {g0}"));
+    }
+}
@@ -403,6 +403,7 @@ pub struct PyramidCalibration {
     p_filter: Vec<bool>,
     offset: Vec<f32>,
     estimator: DMatrix<f32>,
+    optical_gains: Option<Vec<f32>>,
 }
 impl PyramidCalibration {
     pub fn new(
@@ -452,8 +453,32 @@ impl PyramidCalibration {
             p_filter,
             offset,
             estimator,
+            optical_gains: None,
         })
     }
+    /// Sets the optical gains of the modes
+    ///
+    /// The modal estimates are divided by the optical gains
+    /// to compensate the loss of sensitivity of the pyramid under residual turbulence
+    ///
+    /// # Panics
+    ///
+    /// There must be one optical gain per mode and all the optical gains must be strictly positive
+    pub fn set_optical_gains(&mut self, optical_gains: Vec<f32>) -> &mut Self {
+        assert_eq!(
+            optical_gains.len(),
+            self.estimator.nrows(),
+            "expected {} optical gains, found {}",
+            self.estimator.nrows(),
+            optical_gains.len()
+        );
+        assert!(
+            optical_gains.iter().all(|g| *g > 0.),
+            "the optical gains must be strictly positive"
+        );
+        self.optical_gains = Some(optical_gains);
+        self
+    }
 }
 
 impl Mul<&Pyramid> for &PyramidCalibration {
@@ -475,9 +500,37 @@ impl Mul<&Pyramid> for &PyramidCalibration {
             .zip(&self.offset)
             .map(|(s, s0)| s - *s0)
             .collect();
-        let c = (&self.estimator * DVector::from_column_slice(&sxy))
+        let mut c = (&self.estimator * DVector::from_column_slice(&sxy))
             .as_slice()
             .to_vec();
+        if let Some(optical_gains) = self.optical_gains.as_ref() {
+            c.iter_mut().zip(optical_gains).for_each(|(c, g)| *c /= g);
+        }
         Some(c)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optical_gains() {
+        let calibration = || PyramidCalibration {
+            h_filter: vec![],
+            p_filter: vec![],
+            offset: vec![],
+            estimator: DMatrix::zeros(3, 0),
+            optical_gains: None,
+        };
+        let mut calib = calibration();
+        calib.set_optical_gains(vec![0.5, 0.8, 1.]);
+        assert_eq!(calib.optical_gains, Some(vec![0.5, 0.8, 1.]));
+        for optical_gains in [vec![0.5, 0.8], vec![0.5, 0., 1.]] {
+            assert!(std::panic::catch_unwind(|| {
+                calibration().set_optical_gains(optical_gains);
+            })
+            .is_err());
+        }
+    }
+}
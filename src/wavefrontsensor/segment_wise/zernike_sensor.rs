@@ -0,0 +1,19 @@
+mod builder;
+use crate::builders::SourceBuilder;
+pub use builder::ZernikeSensorBuilder;
+mod zernike_sensor;
+pub use zernike_sensor::ZernikeSensor;
+
+pub use super::data_processing;
+use super::host_sensor::{host_wavefront_sensor, HostSensor};
+use crate::{SegmentWiseSensorBuilder, WavefrontSensorBuilder};
+
+impl WavefrontSensorBuilder for ZernikeSensorBuilder {
+    fn guide_stars(&self, gs: Option<SourceBuilder>) -> SourceBuilder {
+        gs.unwrap_or_default()
+            .size(self.n_gs)
+            .pupil_sampling(self.pupil_sampling())
+    }
+}
+
+host_wavefront_sensor!(ZernikeSensor);
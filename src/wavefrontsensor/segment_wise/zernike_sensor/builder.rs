@@ -0,0 +1,92 @@
+use rustfft::FftPlanner;
+use serde::{Deserialize, Serialize};
+
+use crate::{Builder, SegmentWiseSensorBuilder};
+
+use super::ZernikeSensor;
+
+/// `ZernikeSensor` builder
+///
+/// Default properties:
+///  - pupil sampling: 129px
+///  - \# of guide stars: 1
+///  - focal plane oversampling: 4
+///  - dot diameter: 1.06λ/D
+///  - dot phase shift: π/2
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ZernikeSensorBuilder {
+    pupil_sampling: usize,
+    pub(super) n_gs: usize,
+    oversampling: usize,
+    dot_diameter: f64,
+    phase_shift: f64,
+}
+impl Default for ZernikeSensorBuilder {
+    fn default() -> Self {
+        Self {
+            pupil_sampling: 129,
+            n_gs: 1,
+            oversampling: 4,
+            dot_diameter: 1.06,
+            phase_shift: std::f64::consts::FRAC_PI_2,
+        }
+    }
+}
+impl ZernikeSensorBuilder {
+    /// Sets the number of pixels across the pupil
+    pub fn pupil_sampling(mut self, pupil_sampling: usize) -> Self {
+        self.pupil_sampling = pupil_sampling;
+        self
+    }
+    /// Sets the number of guide stars
+    pub fn size(mut self, n: usize) -> Self {
+        self.n_gs = n;
+        self
+    }
+    /// Sets the focal plane sampling as a fraction `1/oversampling` of λ/D
+    pub fn oversampling(mut self, oversampling: usize) -> Self {
+        self.oversampling = oversampling;
+        self
+    }
+    /// Sets the diameter of the focal plane phase dot in units of λ/D
+    pub fn dot_diameter(mut self, dot_diameter: f64) -> Self {
+        self.dot_diameter = dot_diameter;
+        self
+    }
+    /// Sets the phase shift \[rd\] of the focal plane phase dot
+    pub fn phase_shift(mut self, phase_shift: f64) -> Self {
+        self.phase_shift = phase_shift;
+        self
+    }
+}
+
+impl SegmentWiseSensorBuilder for ZernikeSensorBuilder {
+    fn pupil_sampling(&self) -> usize {
+        self.pupil_sampling
+    }
+}
+
+impl Builder for ZernikeSensorBuilder {
+    type Component = ZernikeSensor;
+
+    fn build(self) -> crate::Result<Self::Component> {
+        let n_fft = self.oversampling * self.pupil_sampling;
+        let mut planner = FftPlanner::new();
+        Ok(ZernikeSensor {
+            pupil_sampling: self.pupil_sampling,
+            n_gs: self.n_gs,
+            oversampling: self.oversampling,
+            dot_diameter: self.dot_diameter,
+            phase_shift: self.phase_shift,
+            intensity: vec![0f64; self.pupil_sampling.pow(2) * self.n_gs],
+            flux: vec![0f64; self.n_gs],
+            n_pupil: vec![0; self.n_gs],
+            n_frame: 0,
+            fft: (
+                planner.plan_fft_forward(n_fft),
+                planner.plan_fft_inverse(n_fft),
+            ),
+            valid: Default::default(),
+        })
+    }
+}
@@ -0,0 +1,289 @@
+use std::{f64::consts::PI, ops::Mul, sync::Arc};
+
+use indicatif::ProgressBar;
+use rustfft::{num_complex::Complex, Fft};
+
+use crate::{
+    builders::SourceBuilder, pssn::host::fft2, Builder, FromBuilder, Gmt, Propagation,
+    SegmentWiseSensor, WavefrontSensor,
+};
+
+use super::{
+    super::host_sensor::{calibrate_segment, HostSensor, ValidElements},
+    data_processing::{Calibration, DataRef, Slopes, SlopesArray},
+    ZernikeSensorBuilder,
+};
+
+/// Zernike phase contrast wavefront sensor
+///
+/// The exit pupil field is focused on a phase mask made of a dot that shifts the phase of the core of the PSF.
+/// The reference wave diffracted by the dot interferes with the pupil field
+/// and the pupil plane intensity is linear with respect to small phase errors, segment pistons included.
+pub struct ZernikeSensor {
+    pub(super) pupil_sampling: usize,
+    pub(super) n_gs: usize,
+    pub(super) oversampling: usize,
+    pub(super) dot_diameter: f64,
+    pub(super) phase_shift: f64,
+    pub(super) intensity: Vec<f64>,
+    pub(super) flux: Vec<f64>,
+    pub(super) n_pupil: Vec<usize>,
+    pub(super) n_frame: usize,
+    pub(super) fft: (Arc<dyn Fft<f64>>, Arc<dyn Fft<f64>>),
+    pub(super) valid: ValidElements,
+}
+impl FromBuilder for ZernikeSensor {
+    type ComponentBuilder = ZernikeSensorBuilder;
+}
+impl Propagation for ZernikeSensor {
+    fn propagate(&mut self, src: &mut crate::Source) {
+        let n_px = self.pupil_sampling.pow(2);
+        let wavelength = src.wavelength();
+        let amplitude = src.amplitude();
+        let phase = src.phase().clone();
+        for (k, (amplitude, phase)) in amplitude
+            .chunks(n_px)
+            .zip(phase.chunks(n_px))
+            .take(self.n_gs)
+            .enumerate()
+        {
+            let intensity = self.pupil_intensity(amplitude, phase, wavelength);
+            self.intensity[k * n_px..]
+                .iter_mut()
+                .zip(intensity)
+                .for_each(|(i, x)| *i += x);
+            self.flux[k] += amplitude.iter().map(|a| (a * a) as f64).sum::<f64>();
+            self.n_pupil[k] = amplitude.iter().filter(|a| **a > 0.).count();
+        }
+        self.n_frame += 1;
+    }
+
+    fn time_propagate(&mut self, _secs: f64, src: &mut crate::Source) {
+        self.propagate(src)
+    }
+}
+
+impl HostSensor for ZernikeSensor {
+    fn n_guide_star(&self) -> usize {
+        self.n_gs
+    }
+    fn clear(&mut self) {
+        self.intensity.fill(0f64);
+        self.flux.fill(0f64);
+        self.n_frame = 0;
+    }
+    /// Returns the pupil plane intensities
+    fn element_flux(&self) -> Vec<f64> {
+        self.intensity.clone()
+    }
+    fn element_signal(&self) -> Vec<f64> {
+        self.signal()
+    }
+    fn intensity_frame(&self) -> Option<Vec<f32>> {
+        Some(self.intensity.iter().map(|x| *x as f32).collect())
+    }
+    fn frame_count(&self) -> usize {
+        self.n_frame
+    }
+    fn valid_elements(&mut self) -> &mut ValidElements {
+        &mut self.valid
+    }
+}
+
+impl ZernikeSensor {
+    /// Returns the pupil plane intensity after the phase mask
+    ///
+    /// The field `E` becomes `E-(1-exp(iθ))F⁻¹[M.F[E]]` where `M` is the dot and `θ` its phase shift
+    pub fn pupil_intensity(&self, amplitude: &[f32], phase: &[f32], wavelength: f64) -> Vec<f64> {
+        let n = self.pupil_sampling;
+        let m = self.oversampling * n;
+        let (forward, inverse) = &self.fft;
+        let k = 2. * PI / wavelength;
+        let mut field = vec![Complex::<f64>::new(0., 0.); m * m];
+        for j in 0..n {
+            for i in 0..n {
+                let l = i + n * j;
+                field[i + m * j] = Complex::from_polar(amplitude[l] as f64, k * phase[l] as f64);
+            }
+        }
+        let mut reference = field.clone();
+        fft2(&mut reference, m, forward);
+        // focal plane pixel in units of λ/D
+        let px = (n - 1) as f64 / m as f64;
+        let wrap = |i: usize| {
+            if i < m / 2 {
+                i as f64
+            } else {
+                i as f64 - m as f64
+            }
+        };
+        reference.iter_mut().enumerate().for_each(|(l, x)| {
+            if px * wrap(l % m).hypot(wrap(l / m)) >= 0.5 * self.dot_diameter {
+                *x = Complex::new(0., 0.);
+            }
+        });
+        fft2(&mut reference, m, inverse);
+        let shift =
+            (Complex::new(1., 0.) - Complex::from_polar(1., self.phase_shift)) / (m * m) as f64;
+        let mut intensity = vec![0f64; n * n];
+        for j in 0..n {
+            for i in 0..n {
+                let l = i + m * j;
+                intensity[i + n * j] = (field[l] - shift * reference[l]).norm_sqr();
+            }
+        }
+        intensity
+    }
+    /// Pupil plane intensities normalized to the mean flux per pupil pixel
+    /// as `[s_1,...,s_i,...,s_n]` where `n` is the number of guide stars
+    pub fn signal(&self) -> Vec<f64> {
+        self.intensity
+            .chunks(self.pupil_sampling.pow(2))
+            .zip(self.flux.iter().zip(&self.n_pupil))
+            .flat_map(|(intensity, (flux, n))| {
+                let mean = if *n > 0 { flux / *n as f64 } else { 0. };
+                intensity
+                    .iter()
+                    .map(move |i| if mean > 0. { i / mean } else { 0. })
+            })
+            .collect()
+    }
+}
+
+impl SegmentWiseSensor for ZernikeSensor {
+    fn pupil_sampling(&self) -> usize {
+        self.pupil_sampling
+    }
+    fn zeroed_segment(&mut self, sid: usize, src_builder: Option<SourceBuilder>) -> DataRef {
+        let n = self.pupil_sampling;
+        // Setting the pupil mask restricted to the segment
+        let mut gmt = Gmt::builder().build().unwrap();
+        gmt.keep(&[sid as i32]);
+        let mut src = src_builder
+            .unwrap_or_default()
+            .pupil_sampling(n)
+            .build()
+            .unwrap();
+        src.through(&mut gmt).xpupil();
+        let pupil = nalgebra::DMatrix::<f32>::from_iterator(
+            n,
+            n * src.size as usize,
+            src.amplitude().into_iter(),
+        );
+        let mut data_ref = DataRef::new(pupil);
+
+        self.reset();
+        src.through(self);
+        data_ref.set_ref_with(Slopes::from((&data_ref, &*self)));
+        self.reset();
+        data_ref
+    }
+    fn into_slopes(&self, data_ref: &DataRef) -> Slopes {
+        Slopes::from((data_ref, self))
+    }
+    fn calibrate_segment(
+        &mut self,
+        src_builder: Option<SourceBuilder>,
+        sid: usize,
+        n_mode: usize,
+        pb: Option<ProgressBar>,
+    ) -> SlopesArray {
+        calibrate_segment(self, src_builder, sid, n_mode, pb)
+    }
+}
+
+impl From<(&DataRef, &ZernikeSensor)> for Slopes {
+    /// Computes the normalized pupil intensities in the pupil mask
+    fn from((data_ref, wfs): (&DataRef, &ZernikeSensor)) -> Self {
+        let signal = wfs.signal().into_iter().map(|x| x as f32);
+        let mut sxy: Vec<f32> = if let Some(mask) = data_ref.mask.as_ref() {
+            signal
+                .zip(mask.iter())
+                .filter_map(|(s, &m)| m.then_some(s))
+                .collect()
+        } else {
+            signal.collect()
+        };
+        if let Some(Slopes(sxy0)) = data_ref.sxy0.as_ref() {
+            sxy.iter_mut()
+                .zip(sxy0)
+                .for_each(|(sxy, sxy0)| *sxy -= *sxy0);
+        }
+        Slopes(sxy)
+    }
+}
+
+type V = nalgebra::DVector<f32>;
+
+impl Mul<&ZernikeSensor> for &SlopesArray {
+    type Output = Option<Vec<f32>>;
+    /// Multiplies the pseudo-inverse of the calibration matrix with the [ZernikeSensor] measurements
+    fn mul(self, wfs: &ZernikeSensor) -> Self::Output {
+        let slopes = Slopes::from((&self.data_ref, wfs));
+        self.inverse
+            .as_ref()
+            .map(|pinv| pinv * V::from(slopes))
+            .map(|x| x.as_slice().to_vec())
+    }
+}
+impl Mul<&ZernikeSensor> for &Calibration {
+    type Output = Option<Vec<f32>>;
+    /// Multiplies the pseudo-inverse of the calibration matrix with the [ZernikeSensor] measurements
+    fn mul(self, wfs: &ZernikeSensor) -> Self::Output {
+        Some(self.iter().flat_map(|x| x * wfs).flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // disk pupil with a piston `p` \[m\] on the right half
+    fn pupil(n: usize, p: f32) -> (Vec<f32>, Vec<f32>) {
+        let xy = |k: usize| {
+            (
+                (k % n) as f32 - 0.5 * (n - 1) as f32,
+                (k / n) as f32 - 0.5 * (n - 1) as f32,
+            )
+        };
+        (0..n * n)
+            .map(|k| {
+                let (x, y) = xy(k);
+                if x.hypot(y) <= 0.5 * (n - 1) as f32 {
+                    (1., if x > 0. { p } else { 0. })
+                } else {
+                    (0., 0.)
+                }
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn no_dot() {
+        let wfs = ZernikeSensor::builder()
+            .pupil_sampling(32)
+            .dot_diameter(0.)
+            .build()
+            .unwrap();
+        let (amplitude, phase) = pupil(32, 100e-9);
+        wfs.pupil_intensity(&amplitude, &phase, 500e-9)
+            .iter()
+            .zip(&amplitude)
+            .for_each(|(i, a)| assert!((i - (a * a) as f64).abs() < 1e-9));
+    }
+
+    #[test]
+    fn piston() {
+        let wfs = ZernikeSensor::builder().pupil_sampling(32).build().unwrap();
+        let intensity = |p: f32| {
+            let (amplitude, phase) = pupil(32, p);
+            wfs.pupil_intensity(&amplitude, &phase, 500e-9)
+        };
+        let (i0, i_push, i_pull) = (intensity(0.), intensity(5e-9), intensity(-5e-9));
+        // the intensity changes are linear with the piston on the pistoned half of the pupil
+        let k = 24 + 32 * 16;
+        let (d_push, d_pull) = (i_push[k] - i0[k], i_pull[k] - i0[k]);
+        assert!(d_push.abs() > 1e-3);
+        assert!((d_push + d_pull).abs() < 0.1 * d_push.abs());
+    }
+}
@@ -1,7 +1,7 @@
 use std::ops::{Deref, DerefMut};
 
 use super::Model;
-use crate::{Builder, wavefrontsensor::ShackHartmannBuilder};
+use crate::{Builder, imaging::Misregistration, wavefrontsensor::ShackHartmannBuilder};
 
 /// `ShackHartmann` "SH48" builder for GMT AGWS model
 ///
@@ -46,4 +46,16 @@ impl<T: Model> SH48<T> {
     pub fn n_sensor(self, n_sensor: usize) -> Self {
         Self(self.0.n_sensor(n_sensor))
     }
+    /// Sets the misregistration of the lenslet array with respect to the pupil
+    pub fn misregistration(self, misregistration: Misregistration) -> Self {
+        Self(self.0.misregistration(misregistration))
+    }
+    /// Sets the hexagonal packing of the lenslets
+    pub fn hexagonal(self) -> Self {
+        Self(self.0.hexagonal())
+    }
+    /// Sets the distortion map as the offsets \[m\] of the lenslet centers in the lenslet array
+    pub fn distortion(self, distortion: Vec<(f64, f64)>) -> Self {
+        Self(self.0.distortion(distortion))
+    }
 }
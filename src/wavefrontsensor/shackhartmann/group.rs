@@ -0,0 +1,591 @@
+//!
+//! # Shack-Hartmann probes group
+//!
+//! A group of Shack-Hartmann wavefront sensors, or probes, with the same lenslet array and detector
+//! but each one with its own guide star magnitude, detector noise, valid lenslets and lenslet array registration.
+//!
+//! Each probe owns its guide star: the guide stars are propagated through the atmosphere and the telescope
+//! with [ShackHartmannGroup::through] and then the group is used as a single [WavefrontSensor].
+//! A [Source] with one guide star per probe, like the one given by [WavefrontSensorBuilder::guide_stars],
+//! can also be propagated through the group: the wavefront of each guide star is copied into the guide star of the probe.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use crseo::{
+//!     imaging::NoiseDataSheet,
+//!     wavefrontsensor::{Geometric, ProbeBuilder, ShackHartmannGroupBuilder},
+//!     Builder, FromBuilder, Gmt, ShackHartmann, WavefrontSensor,
+//! };
+//! # fn main() -> anyhow::Result<()> {
+//! let z = 6f32.to_radians() / 60.;
+//! let sh48 = ShackHartmann::<Geometric>::builder().lenslet_array(48, 16, 25.5 / 48.);
+//! let mut agws = ShackHartmannGroupBuilder::from(sh48)
+//!     .probes((0..4).map(|i| {
+//!         ProbeBuilder::new(z, i as f32 * std::f32::consts::FRAC_PI_2)
+//!             .magnitude(12. + i as f32)
+//!             .noise_specs(NoiseDataSheet::new(1. + i as f64))
+//!     }))
+//!     .build()?;
+//! let mut gmt = Gmt::builder().build()?;
+//! agws.through(&mut gmt).xpupil();
+//! agws.calibrate_probes(0.5);
+//! agws.reset();
+//! agws.through(&mut gmt).xpupil().propagate_probes();
+//! agws.readout();
+//! agws.process();
+//! let slopes = agws.data();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut, Mul},
+};
+
+use super::{Model, ShackHartmann, ShackHartmannBuilder};
+use crate::{
+    builders::SourceBuilder,
+    cu::Single,
+    imaging::{Detector, Misregistration, NoiseDataSheet},
+    utilities,
+    wavefrontsensor::{
+        Calibration, DataRef, Slopes, SlopesArray, ValidLensletStrategy, ValidLenslets,
+    },
+    Builder, Cu, FromBuilder, Mask, Propagation, Result, Source, WavefrontSensor,
+    WavefrontSensorBuilder,
+};
+
+/// Probe builder
+///
+/// Default properties:
+///  - zenith    : 0rd
+///  - azimuth   : 0rd
+///  - magnitude : 0
+///  - detector noise: the one of the group
+///  - misregistration: the one of the group
+///  - valid lenslets: from the lenslet flux
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeBuilder {
+    pub zenith: f32,
+    pub azimuth: f32,
+    pub magnitude: f32,
+    pub noise_specs: Option<NoiseDataSheet>,
+    pub misregistration: Option<Misregistration>,
+    pub valid_lenslets: Option<ValidLenslets>,
+}
+impl ProbeBuilder {
+    /// Creates a probe with the guide star at `zenith` and `azimuth` \[rd\]
+    pub fn new(zenith: f32, azimuth: f32) -> Self {
+        Self {
+            zenith,
+            azimuth,
+            ..Default::default()
+        }
+    }
+    /// Sets the guide star magnitude
+    pub fn magnitude(self, magnitude: f32) -> Self {
+        Self { magnitude, ..self }
+    }
+    /// Sets the detector noise specifications
+    pub fn noise_specs(self, noise_specs: NoiseDataSheet) -> Self {
+        Self {
+            noise_specs: Some(noise_specs),
+            ..self
+        }
+    }
+    /// Sets the misregistration of the lenslet array with respect to the pupil
+    pub fn misregistration(self, misregistration: Misregistration) -> Self {
+        Self {
+            misregistration: Some(misregistration),
+            ..self
+        }
+    }
+    /// Sets the valid lenslets
+    pub fn valid_lenslets(self, valid_lenslets: ValidLenslets) -> Self {
+        Self {
+            valid_lenslets: Some(valid_lenslets),
+            ..self
+        }
+    }
+}
+
+/// [ShackHartmannGroup] builder
+///
+/// The Shack-Hartmann builder is shared by all the probes and the number of sensors of each probe is 1
+///
+/// Default properties:
+///  - sensor: [ShackHartmannBuilder] default
+///  - probes: none
+///  - guide star template: [SourceBuilder] default
+#[derive(Debug, Clone)]
+pub struct ShackHartmannGroupBuilder<T: Model> {
+    pub sensor: ShackHartmannBuilder<T>,
+    pub probes: Vec<ProbeBuilder>,
+    pub template: Option<SourceBuilder>,
+}
+impl<T: Model> Default for ShackHartmannGroupBuilder<T> {
+    fn default() -> Self {
+        Self {
+            sensor: Default::default(),
+            probes: Vec::new(),
+            template: None,
+        }
+    }
+}
+impl<T: Model> From<ShackHartmannBuilder<T>> for ShackHartmannGroupBuilder<T> {
+    fn from(sensor: ShackHartmannBuilder<T>) -> Self {
+        Self {
+            sensor,
+            ..Default::default()
+        }
+    }
+}
+impl<T: Model> ShackHartmannGroupBuilder<T> {
+    /// Adds a probe
+    pub fn probe(mut self, probe: ProbeBuilder) -> Self {
+        self.probes.push(probe);
+        self
+    }
+    /// Adds several probes
+    pub fn probes(mut self, probes: impl IntoIterator<Item = ProbeBuilder>) -> Self {
+        self.probes.extend(probes);
+        self
+    }
+    /// Sets the template of the guide stars
+    ///
+    /// The template sets the properties shared by all the guide stars, like the photometric band
+    pub fn template(self, template: SourceBuilder) -> Self {
+        Self {
+            template: Some(template),
+            ..self
+        }
+    }
+    /// Returns the number of probes
+    pub fn n_probe(&self) -> usize {
+        self.probes.len()
+    }
+    // Shack-Hartmann and guide star builders of each probe
+    fn probe_builders(&self) -> Vec<(ShackHartmannBuilder<T>, SourceBuilder)> {
+        self.probes
+            .iter()
+            .map(|probe| {
+                let sensor = ShackHartmannBuilder {
+                    n_sensor: 1,
+                    detector: Detector {
+                        noise_specs: probe.noise_specs.or(self.sensor.detector.noise_specs),
+                        ..self.sensor.detector
+                    },
+                    misregistration: probe.misregistration.or(self.sensor.misregistration),
+                    ..self.sensor.clone()
+                };
+                let gs = sensor
+                    .guide_stars(self.template.clone())
+                    .zenith_azimuth(vec![probe.zenith], vec![probe.azimuth])
+                    .magnitude(vec![probe.magnitude]);
+                (sensor, gs)
+            })
+            .collect()
+    }
+}
+impl<T: Model> WavefrontSensorBuilder for ShackHartmannGroupBuilder<T> {
+    /// Returns the guide stars of all the probes as a single source
+    ///
+    /// The source has the same lenslet array registration for all the guide stars:
+    /// the probes misregistration if all the probes share the same one, the group misregistration otherwise.
+    /// For probes with different misregistrations, the guide stars of each probe are given by
+    /// [probe_builders](ShackHartmannGroupBuilder::probe_builders) and are the ones built with the group
+    fn guide_stars(&self, template: Option<SourceBuilder>) -> SourceBuilder {
+        let (zenith, azimuth): (Vec<_>, Vec<_>) =
+            self.probes.iter().map(|p| (p.zenith, p.azimuth)).unzip();
+        let misregistrations: Vec<_> = self
+            .probes
+            .iter()
+            .map(|p| p.misregistration.or(self.sensor.misregistration))
+            .collect();
+        let misregistration = if misregistrations.windows(2).all(|m| m[0] == m[1]) {
+            misregistrations
+                .first()
+                .copied()
+                .unwrap_or(self.sensor.misregistration)
+        } else {
+            log::warn!(
+                "the probes have different misregistrations, the guide stars use the group misregistration"
+            );
+            self.sensor.misregistration
+        };
+        ShackHartmannBuilder {
+            n_sensor: self.n_probe(),
+            misregistration,
+            ..self.sensor.clone()
+        }
+        .guide_stars(template.or(self.template.clone()))
+        .zenith_azimuth(zenith, azimuth)
+        .magnitude(self.probes.iter().map(|p| p.magnitude).collect())
+    }
+}
+impl<T: Model> Builder for ShackHartmannGroupBuilder<T> {
+    type Component = ShackHartmannGroup<T>;
+
+    fn build(self) -> Result<Self::Component> {
+        let probes = self
+            .probe_builders()
+            .into_iter()
+            .zip(self.probes)
+            .map(|((sensor, gs), probe)| {
+                Ok(Probe {
+                    wfs: sensor.build()?,
+                    gs: gs.build()?,
+                    valid_lenslets: probe.valid_lenslets,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShackHartmannGroup {
+            probes,
+            valid_lenslet: Mask::new(),
+        })
+    }
+}
+
+/// Shack-Hartmann wavefront sensor and guide star of a probe
+pub struct Probe<T: Model> {
+    pub wfs: ShackHartmann<T>,
+    pub gs: Source,
+    pub valid_lenslets: Option<ValidLenslets>,
+}
+
+/// Group of Shack-Hartmann probes
+///
+/// The measurements of the group are the measurements of the probes concatenated
+pub struct ShackHartmannGroup<T: Model> {
+    probes: Vec<Probe<T>>,
+    valid_lenslet: Mask,
+}
+impl<T: Model> FromBuilder for ShackHartmannGroup<T> {
+    type ComponentBuilder = ShackHartmannGroupBuilder<T>;
+}
+impl<T: Model> Deref for ShackHartmannGroup<T> {
+    type Target = [Probe<T>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.probes
+    }
+}
+impl<T: Model> DerefMut for ShackHartmannGroup<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.probes
+    }
+}
+impl<T: Model> ShackHartmannGroup<T> {
+    /// Propagates the guide stars of the probes through `system`
+    pub fn through<P: Propagation>(&mut self, system: &mut P) -> &mut Self {
+        self.probes
+            .iter_mut()
+            .for_each(|probe| system.propagate(&mut probe.gs));
+        self
+    }
+    /// Propagates the guide stars of the probes to the exit pupil of the telescope
+    pub fn xpupil(&mut self) -> &mut Self {
+        self.probes.iter_mut().for_each(|probe| {
+            probe.gs.xpupil();
+        });
+        self
+    }
+    /// Propagates the guide stars of the probes through the probes wavefront sensors
+    pub fn propagate_probes(&mut self) -> &mut Self {
+        self.probes
+            .iter_mut()
+            .for_each(|probe| probe.wfs.propagate(&mut probe.gs));
+        self
+    }
+    /// Sets the valid lenslets and the reference slopes of the probes
+    ///
+    /// The valid lenslets of a probe are the ones given to [ProbeBuilder::valid_lenslets]
+    /// or the lenslets with a flux larger than `threshold` times the largest lenslet flux
+    pub fn calibrate_probes(&mut self, threshold: f64) -> &mut Self {
+        self.probes
+            .iter_mut()
+            .for_each(|probe| match probe.valid_lenslets.as_ref() {
+                Some(valid_lenslets) => probe.wfs.set_valid_lenslets(valid_lenslets, &mut probe.gs),
+                None => WavefrontSensor::calibrate(&mut probe.wfs, &mut probe.gs, threshold),
+            });
+        self
+    }
+    /// Selects the valid lenslets of the probes with the given `strategy` and sets the reference slopes
+    pub fn set_valid_lenslets_with(&mut self, strategy: ValidLensletStrategy) -> &mut Self {
+        self.probes.iter_mut().for_each(|probe| {
+            probe.valid_lenslets = Some(probe.wfs.set_valid_lenslets_with(&mut probe.gs, strategy));
+        });
+        self
+    }
+    /// Returns the valid lenslets of the probes
+    pub fn valid_lenslets(&self) -> Vec<Option<&ValidLenslets>> {
+        self.probes
+            .iter()
+            .map(|probe| probe.valid_lenslets.as_ref())
+            .collect()
+    }
+    /// Returns the number of lenslets of a probe
+    pub fn n_lenslet(&self) -> usize {
+        self.probes
+            .first()
+            .map_or(0, |probe| probe.wfs.n_side_lenslet.pow(2) as usize)
+    }
+    /// Returns the centroids \[sx,sy\] of all the lenslets of each probe
+    pub fn centroids(&self) -> Vec<Vec<f32>> {
+        self.probes
+            .iter()
+            .map(|probe| probe.wfs.centroids.clone().into())
+            .collect()
+    }
+}
+impl<T: Model> Propagation for ShackHartmannGroup<T> {
+    /// Propagates a [Source] with one guide star per probe through the probes
+    ///
+    /// The wavefront of each guide star of `src` is copied into the guide star of the matching probe
+    /// which is then propagated through the probe wavefront sensor.
+    /// If `src` does not have as many guide stars as there are probes or if the pupil sampling differs,
+    /// an error is logged and nothing is propagated
+    fn propagate(&mut self, src: &mut Source) {
+        let n_probe = self.probes.len();
+        if src.size as usize != n_probe
+            || self
+                .probes
+                .iter()
+                .any(|probe| probe.gs.size != 1 || probe.gs.pupil_sampling != src.pupil_sampling)
+        {
+            log::error!(
+                "expected a source with {} guide stars sampled with {} pixels, found {} guide stars sampled with {} pixels",
+                n_probe,
+                self.probes
+                    .first()
+                    .map_or(src.pupil_sampling, |probe| probe.gs.pupil_sampling),
+                src.size,
+                src.pupil_sampling
+            );
+            return;
+        }
+        let n = (src.pupil_sampling * src.pupil_sampling) as usize;
+        let mut amplitude = src.amplitude();
+        let mut phase = src.phase().clone();
+        self.probes
+            .iter_mut()
+            .zip(amplitude.chunks_mut(n).zip(phase.chunks_mut(n)))
+            .for_each(|(probe, (amplitude, phase))| {
+                probe.gs.set_wavefront(amplitude, phase);
+                probe.wfs.propagate(&mut probe.gs);
+            });
+    }
+    fn time_propagate(&mut self, _secs: f64, src: &mut Source) {
+        self.propagate(src)
+    }
+}
+impl<T: Model> WavefrontSensor for ShackHartmannGroup<T> {
+    /// Calibrates the probes with their own guide stars, `src` is not used
+    fn calibrate(&mut self, _src: &mut Source, threshold: f64) {
+        self.calibrate_probes(threshold);
+    }
+    fn reset(&mut self) {
+        self.probes.iter_mut().for_each(|probe| probe.wfs.reset());
+    }
+    fn process(&mut self) {
+        self.probes.iter_mut().for_each(|probe| probe.wfs.process());
+    }
+    /// Reads out the detectors with the noise specifications of each probe
+    fn readout(&mut self) {
+        self.probes.iter_mut().for_each(|probe| probe.wfs.readout());
+    }
+    fn data(&mut self) -> Vec<f64> {
+        self.probes
+            .iter_mut()
+            .flat_map(|probe| probe.wfs.data())
+            .collect()
+    }
+    fn frame(&self) -> Option<Vec<f32>> {
+        self.probes
+            .iter()
+            .map(|probe| probe.wfs.frame())
+            .collect::<Option<Vec<_>>>()
+            .map(|frames| frames.concat())
+    }
+    fn n_frame(&self) -> usize {
+        self.probes.first().map_or(0, |probe| probe.wfs.n_frame())
+    }
+    /// Sets the valid lenslets of the probes from the valid lenslets of `wfs` and sets the reference slopes
+    ///
+    /// The valid lenslets of `wfs` are either the same for all the probes or given for each probe.
+    /// The guide stars of the probes must have been propagated to the exit pupil
+    fn valid_lenslet_from(&mut self, wfs: &mut dyn WavefrontSensor) {
+        let n_lenslet = self.n_lenslet();
+        let mask = utilities::Mask {
+            _c_: UnsafeCell::new(*wfs.valid_lenslet()),
+        }
+        .to_vec();
+        assert!(
+            mask.len() == n_lenslet || mask.len() == n_lenslet * self.probes.len(),
+            "expected a valid lenslet mask of {} or {} lenslets, found {}",
+            n_lenslet,
+            n_lenslet * self.probes.len(),
+            mask.len()
+        );
+        self.probes
+            .iter_mut()
+            .zip(mask.chunks(n_lenslet).cycle())
+            .for_each(|(probe, mask)| {
+                let mask: Vec<i32> = mask.iter().map(|m| *m as i32).collect();
+                probe.wfs.set_valid_lenslet(&mask);
+                probe.wfs.set_reference_slopes(&mut probe.gs);
+                probe.valid_lenslets = None;
+            });
+    }
+    /// Returns the valid lenslets of all the probes
+    fn valid_lenslet(&mut self) -> &mut ffi::mask {
+        let mut lenslet_mask: Cu<Single> = self
+            .probes
+            .iter_mut()
+            .flat_map(|probe| Vec::<f32>::from(probe.wfs.lenslet_mask()))
+            .collect::<Vec<f32>>()
+            .into();
+        self.valid_lenslet = Mask::new();
+        self.valid_lenslet
+            .build(self.n_lenslet() * self.probes.len())
+            .filter(&mut lenslet_mask);
+        self.valid_lenslet.as_raw_mut_ptr()
+    }
+    fn n_valid_lenslet(&mut self) -> Vec<usize> {
+        self.probes
+            .iter_mut()
+            .flat_map(|probe| WavefrontSensor::n_valid_lenslet(&mut probe.wfs))
+            .collect()
+    }
+    fn left_multiply(&self, calibration: &Calibration) -> Option<Vec<f32>> {
+        calibration * self
+    }
+}
+
+impl<T: Model> From<(&DataRef, &ShackHartmannGroup<T>)> for Slopes {
+    /// Computes the centroids \[sx,sy\] of the lenslets in the mask of all the probes
+    fn from((data_ref, group): (&DataRef, &ShackHartmannGroup<T>)) -> Self {
+        let n_lenslet = group.n_lenslet();
+        let sxy = group.centroids().into_iter().flat_map(|c| {
+            let (sx, sy) = c.split_at(n_lenslet);
+            sx.iter()
+                .zip(sy)
+                .flat_map(|(sx, sy)| [*sx, *sy])
+                .collect::<Vec<f32>>()
+        });
+        let mut sxy: Vec<f32> = if let Some(mask) = data_ref.mask.as_ref() {
+            sxy.collect::<Vec<f32>>()
+                .chunks(2)
+                .zip(mask.iter())
+                .filter_map(|(s, &m)| m.then_some(s))
+                .flatten()
+                .cloned()
+                .collect()
+        } else {
+            sxy.collect()
+        };
+        if let Some(Slopes(sxy0)) = data_ref.sxy0.as_ref() {
+            sxy.iter_mut()
+                .zip(sxy0)
+                .for_each(|(sxy, sxy0)| *sxy -= *sxy0);
+        }
+        Slopes(sxy)
+    }
+}
+
+type V = nalgebra::DVector<f32>;
+
+impl<T: Model> Mul<&ShackHartmannGroup<T>> for &SlopesArray {
+    type Output = Option<Vec<f32>>;
+    /// Multiplies the pseudo-inverse of the calibration matrix with the [ShackHartmannGroup] measurements
+    fn mul(self, group: &ShackHartmannGroup<T>) -> Self::Output {
+        let slopes = Slopes::from((&self.data_ref, group));
+        self.inverse
+            .as_ref()
+            .map(|pinv| pinv * V::from(slopes))
+            .map(|x| x.as_slice().to_vec())
+    }
+}
+impl<T: Model> Mul<&ShackHartmannGroup<T>> for &Calibration {
+    type Output = Option<Vec<f32>>;
+    /// Multiplies the pseudo-inverse of the calibration matrix with the [ShackHartmannGroup] measurements
+    fn mul(self, group: &ShackHartmannGroup<T>) -> Self::Output {
+        Some(self.iter().flat_map(|x| x * group).flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wavefrontsensor::Geometric;
+
+    #[test]
+    fn probe_builders() {
+        let group = ShackHartmannGroupBuilder::<Geometric>::from(
+            ShackHartmannBuilder::new().lenslet_array(48, 16, 25.5 / 48.),
+        )
+        .probes((0..4).map(|i| {
+            ProbeBuilder::new(1e-3, i as f32)
+                .magnitude(i as f32)
+                .noise_specs(NoiseDataSheet::new(i as f64))
+        }))
+        .probe(ProbeBuilder::new(0., 0.).misregistration(Misregistration::default().rotation(0.1)));
+        let builders = group.probe_builders();
+        assert_eq!(builders.len(), 5);
+        builders
+            .iter()
+            .take(4)
+            .zip(0..)
+            .for_each(|((sensor, gs), i)| {
+                assert_eq!(sensor.n_sensor, 1);
+                assert_eq!(
+                    sensor.detector.noise_specs.map(|n| n.exposure_time),
+                    Some(i as f64)
+                );
+                assert_eq!(gs.magnitude, vec![i as f32]);
+                assert_eq!(gs.azimuth, vec![i as f32]);
+                assert_eq!(gs.misregistration, None);
+            });
+        assert_eq!(builders[4].0.detector.noise_specs, None);
+        assert_eq!(builders[4].1.misregistration.map(|m| m.rotation), Some(0.1));
+        assert_eq!(group.guide_stars(None).magnitude, vec![0., 1., 2., 3., 0.]);
+    }
+
+    #[test]
+    fn guide_stars_misregistration() {
+        let misregistration = Misregistration::default().shift(0.1, 0.);
+        let group = ShackHartmannGroupBuilder::<Geometric>::from(
+            ShackHartmannBuilder::new().lenslet_array(48, 16, 25.5 / 48.),
+        )
+        .probes((0..2).map(|i| ProbeBuilder::new(1e-3, i as f32).misregistration(misregistration)));
+        assert_eq!(
+            group.guide_stars(None).misregistration,
+            Some(misregistration)
+        );
+        let group = group.probe(ProbeBuilder::new(0., 0.));
+        assert_eq!(group.guide_stars(None).misregistration, None);
+    }
+
+    #[test]
+    fn guide_stars_hexagonal() {
+        let n_px = 48 * 16 + 1;
+        let group = ShackHartmannGroupBuilder::<Geometric>::from(
+            ShackHartmannBuilder::new()
+                .lenslet_array(48, 16, 25.5 / 48.)
+                .hexagonal(),
+        )
+        .probes((0..2).map(|i| ProbeBuilder::new(1e-3, i as f32)));
+        let gs = group.guide_stars(None);
+        assert_eq!(
+            gs.rays_coordinates.map(|(x, y)| (x.len(), y.len())),
+            Some((n_px * n_px, n_px * n_px))
+        );
+        assert_eq!(gs.pupil_sampling.side(), n_px);
+        assert!(group
+            .probe_builders()
+            .into_iter()
+            .all(|(_, gs)| gs.rays_coordinates.is_some()));
+    }
+}
@@ -1,11 +1,13 @@
 use super::{Diffractive, Geometric, Model};
 use crate::{
     builders::SourceBuilder,
-    imaging::{Detector, LensletArray, NoiseDataSheet},
+    imaging::{Detector, LensletArray, LensletGeometry, Misregistration, NoiseDataSheet, Packing},
     Builder, Cu, FromBuilder, Result, Source, WavefrontSensor, WavefrontSensorBuilder,
 };
 pub mod sensor;
 pub use sensor::ShackHartmann;
+pub mod group;
+pub use group::{Probe, ProbeBuilder, ShackHartmannGroup, ShackHartmannGroupBuilder};
 use serde::{Deserialize, Serialize};
 
 /// `ShackHartmann` builder
@@ -20,6 +22,10 @@ use serde::{Deserialize, Serialize};
 ///    - n_px_framelet: 512px
 ///    - n_px_imagelet: None\[512px\]
 ///    - osf: None\[2\]
+///  - packing: square
+///  - distortion: none
+///
+/// Since version 3.0, the builder is [Clone] but no longer [Copy]: the distortion map is held in a [Vec]
 ///
 /// # Examples
 ///
@@ -27,11 +33,17 @@ use serde::{Deserialize, Serialize};
 /// use crseo::{Builder, FromBuilder,  ShackHartmann, Geometric};
 /// let mut wfs = ShackHartmann::<Geometric>::builder().build();
 /// ```
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShackHartmannBuilder<T: Model> {
     pub n_sensor: usize,
     pub lenslet_array: LensletArray,
     pub detector: Detector,
+    #[serde(default)]
+    pub misregistration: Option<Misregistration>,
+    #[serde(default)]
+    pub packing: Packing,
+    #[serde(default)]
+    pub distortion: Option<Vec<(f64, f64)>>,
     marker: std::marker::PhantomData<T>,
 }
 impl<T: Model> FromBuilder for ShackHartmann<T> {
@@ -42,6 +54,9 @@ impl<T: Model> PartialEq for ShackHartmannBuilder<T> {
         self.n_sensor == other.n_sensor
             && self.lenslet_array == other.lenslet_array
             && self.detector == other.detector
+            && self.misregistration == other.misregistration
+            && self.packing == other.packing
+            && self.distortion == other.distortion
     }
 }
 impl<T: Model> Default for ShackHartmannBuilder<T> {
@@ -50,6 +65,9 @@ impl<T: Model> Default for ShackHartmannBuilder<T> {
             n_sensor: 1,
             lenslet_array: LensletArray::default(),
             detector: Detector::default(),
+            misregistration: None,
+            packing: Packing::Square,
+            distortion: None,
             marker: std::marker::PhantomData,
         }
     }
@@ -85,21 +103,73 @@ impl<T: Model> ShackHartmannBuilder<T> {
             ..self
         }
     }
+    /// Sets the misregistration of the lenslet array with respect to the pupil
+    pub fn misregistration(self, misregistration: Misregistration) -> Self {
+        Self {
+            misregistration: Some(misregistration),
+            ..self
+        }
+    }
+    /// Sets the hexagonal packing of the lenslets
+    pub fn hexagonal(self) -> Self {
+        Self {
+            packing: Packing::Hexagonal,
+            ..self
+        }
+    }
+    /// Sets the distortion map as the offsets \[m\] of the lenslet centers in the lenslet array
+    ///
+    /// The map has an offset for each lenslet with `k=i+n_side_lenslet*j`,
+    /// its length is checked when the sensor is built
+    pub fn distortion(self, distortion: Vec<(f64, f64)>) -> Self {
+        Self {
+            distortion: Some(distortion),
+            ..self
+        }
+    }
+    /// Returns the geometry of the lenslet array
+    pub fn geometry(&self) -> LensletGeometry {
+        LensletGeometry {
+            lenslet_array: self.lenslet_array,
+            packing: self.packing,
+            misregistration: self.misregistration.unwrap_or_default(),
+            distortion: self.distortion.clone(),
+        }
+    }
 }
 impl<T: Model> WavefrontSensorBuilder for ShackHartmannBuilder<T> {
+    /// Returns the guide stars of the sensors
+    ///
+    /// The rays of the guide stars are moved to the hexagonal or distorted lenslets, if any,
+    /// and the guide stars are given the misregistration of the lenslet array.
+    /// A distortion map that does not match the lenslet array is ignored
     fn guide_stars(&self, template: Option<SourceBuilder>) -> SourceBuilder {
         let LensletArray {
             n_side_lenslet,
             n_px_lenslet,
             d,
         } = self.lenslet_array;
-        match template {
+        let n_px = n_px_lenslet * n_side_lenslet + 1;
+        let pupil_size = d * n_side_lenslet as f64;
+        let gs = match template {
             Some(src) => src,
             None => Source::builder(),
         }
         .size(self.n_sensor)
-        .pupil_size(d * n_side_lenslet as f64)
-        .pupil_sampling(n_px_lenslet * n_side_lenslet + 1)
+        .pupil_size(pupil_size);
+        let geometry = self.geometry().checked();
+        // the rays of the distorted lenslets keep the sampling of the square lenslets
+        let gs = if geometry.is_square() {
+            gs
+        } else {
+            let (x, y) = geometry.rays_coordinates(n_px, pupil_size);
+            gs.rays_coordinates(x, y)
+        }
+        .pupil_sampling(n_px);
+        match self.misregistration {
+            Some(misregistration) => gs.misregistration(misregistration),
+            None => gs,
+        }
     }
 
     fn detector_noise_specs(self, noise_specs: NoiseDataSheet) -> Self {
@@ -111,6 +181,7 @@ impl<T: Model> WavefrontSensorBuilder for ShackHartmannBuilder<T> {
 impl<T: Model> Builder for ShackHartmannBuilder<T> {
     type Component = ShackHartmann<T>;
     fn build(self) -> Result<ShackHartmann<T>> {
+        self.geometry().check_distortion()?;
         let LensletArray {
             n_side_lenslet,
             n_px_lenslet,
@@ -1,5 +1,13 @@
+use std::cell::UnsafeCell;
+
 use super::{Diffractive, Geometric, Model, WavefrontSensor};
-use crate::{cu::Single, imaging::NoiseDataSheet, Cu, Mask, Propagation, Source};
+use crate::{
+    cu::Single,
+    imaging::NoiseDataSheet,
+    utilities,
+    wavefrontsensor::{ValidLensletStrategy, ValidLenslets},
+    Cu, Mask, Propagation, Source,
+};
 
 /// shackhartmann wrapper
 pub struct ShackHartmann<S: Model> {
@@ -114,6 +122,46 @@ impl<M: Model> ShackHartmann<M> {
     pub fn set_valid_lenslet(&mut self, lenslet_mask: &[i32]) {
         <M as Model>::set_valid_lenslet(&mut self._c_, lenslet_mask);
     }
+    /// Returns the valid lenslets selected with the given `strategy`
+    ///
+    /// `src` are the guide stars of the wavefront sensor after propagation to the exit pupil
+    pub fn valid_lenslets(
+        &self,
+        src: &mut Source,
+        strategy: ValidLensletStrategy,
+    ) -> ValidLenslets {
+        ValidLenslets::new(
+            strategy,
+            self.n_side_lenslet as usize,
+            self.n_sensor as usize,
+            &src.amplitude(),
+            &src.rays().segment(),
+        )
+    }
+    /// Sets the valid lenslets and the reference slopes
+    ///
+    /// `src` are the guide stars of the wavefront sensor after propagation to the exit pupil
+    pub fn set_valid_lenslets(&mut self, valid_lenslets: &ValidLenslets, src: &mut Source) {
+        self.set_valid_lenslet(&valid_lenslets.to_i32());
+        self.set_reference_slopes(src);
+    }
+    /// Selects the valid lenslets with the given `strategy`, sets them with the reference slopes
+    /// and returns the valid lenslets
+    pub fn set_valid_lenslets_with(
+        &mut self,
+        src: &mut Source,
+        strategy: ValidLensletStrategy,
+    ) -> ValidLenslets {
+        let valid_lenslets = self.valid_lenslets(src, strategy);
+        self.set_valid_lenslets(&valid_lenslets, src);
+        valid_lenslets
+    }
+    /// Returns the valid lenslets mask
+    pub fn valid_lenslet_mask(&mut self) -> utilities::Mask {
+        utilities::Mask {
+            _c_: UnsafeCell::new(*<M as Model>::valid_lenslet(&mut self._c_)),
+        }
+    }
     pub fn filter(&mut self, lenslet_mask: &mut Mask) -> Cu<Single> {
         <M as Model>::filter(&mut self._c_, lenslet_mask)
     }
@@ -152,8 +200,17 @@ impl<S: Model> Drop for ShackHartmann<S> {
     }
 }
 impl<M: Model> Propagation for ShackHartmann<M> {
+    /// Propagates the guide stars through the wavefront sensor
+    ///
+    /// The spots of laser guide stars are elongated on the detector frame
     fn propagate(&mut self, src: &mut Source) {
+        let frame = src
+            .laser_guide_star()
+            .and_then(|_| <M as Model>::frame(&self._c_));
         <M as Model>::propagate(&mut self._c_, src);
+        if let (Some(lgs), Some(frame)) = (src.laser_guide_star(), frame) {
+            <M as Model>::elongate(&mut self._c_, lgs, src.wavelength(), frame);
+        }
     }
     fn time_propagate(&mut self, _secs: f64, src: &mut Source) {
         self.propagate(src)
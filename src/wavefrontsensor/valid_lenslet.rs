@@ -0,0 +1,362 @@
+//!
+//! # Valid lenslets
+//!
+//! Selection of the valid lenslets of a Shack-Hartmann wavefront sensor with a [ValidLensletStrategy].
+//!
+//! The strategies are evaluated from the wavefront amplitude and from the segment map of the guide stars
+//! in the exit pupil of the telescope.
+//! The lenslets are indexed with `k=i+n_side_lenslet*j` where `i` and `j` are the column and row indices,
+//! the lenslets of the different guide stars are concatenated.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use crseo::{
+//!     wavefrontsensor::{Geometric, ValidLensletStrategy},
+//!     Builder, FromBuilder, Gmt, ShackHartmann, WavefrontSensorBuilder,
+//! };
+//! # fn main() -> anyhow::Result<()> {
+//! let wfs_builder = ShackHartmann::<Geometric>::builder().lenslet_array(48, 16, 25.5 / 48.);
+//! let mut gmt = Gmt::builder().build()?;
+//! let mut src = wfs_builder.guide_stars(None).build()?;
+//! let mut wfs = wfs_builder.build()?;
+//! src.through(&mut gmt).xpupil();
+//! let valid_lenslets =
+//!     wfs.set_valid_lenslets_with(&mut src, ValidLensletStrategy::MinPerSegment(0.5, 20));
+//! valid_lenslets.save("sh48_valid-lenslets.pkl")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{fs::File, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utilities::Mask;
+
+/// The number of GMT segments
+const N_SEGMENT: usize = 7;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ValidLensletsError {
+    #[error("cannot open or create valid lenslets file")]
+    Io(#[from] std::io::Error),
+    #[error("cannot (de)serialize valid lenslets")]
+    Pickle(#[from] serde_pickle::Error),
+}
+pub type Result<T> = std::result::Result<T, ValidLensletsError>;
+
+/// Valid lenslet selection strategies
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ValidLensletStrategy {
+    /// Lenslets with a flux larger than the given fraction of the largest lenslet flux of a guide star
+    FluxFraction(f64),
+    /// Lenslets with a fraction of their area within the segmented pupil larger than the given fraction
+    ///
+    /// The gaps between segments and the spiders are excluded from the segmented pupil
+    SegmentOverlap(f64),
+    /// Lenslets selected with [SegmentOverlap](ValidLensletStrategy::SegmentOverlap)(`.0`)
+    /// and completed such as each segment is seen by at least `.1` lenslets
+    ///
+    /// The lenslets added to a segment are the ones with the largest overlap with the segment
+    MinPerSegment(f64, usize),
+}
+
+// Lenslet statistics in the pupil
+#[derive(Debug, Default, Clone, Copy)]
+struct LensletPupil {
+    flux: f64,
+    overlap: f64,
+    segment: u8,
+    segment_overlap: f64,
+}
+
+/// Valid lenslets mask
+///
+/// The mask keeps the ID (1 to 7) of the segment seen by each lenslet,
+/// the ID is the one of the segment with the largest overlap with the lenslet
+/// and 0 if the lenslet does not see any segment
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidLenslets {
+    pub n_side_lenslet: usize,
+    pub n_sensor: usize,
+    pub mask: Vec<bool>,
+    pub segment: Vec<u8>,
+}
+impl ValidLenslets {
+    /// Selects the valid lenslets with the given `strategy`
+    ///
+    /// `amplitude` and `segment` are the wavefront amplitude and the segment map
+    /// of the `n_sensor` guide stars in the exit pupil,
+    /// each guide star pupil is sampled with `n_px_lenslet*n_side_lenslet+1` pixels across
+    pub fn new(
+        strategy: ValidLensletStrategy,
+        n_side_lenslet: usize,
+        n_sensor: usize,
+        amplitude: &[f32],
+        segment: &[i32],
+    ) -> Self {
+        let lenslets = Self::lenslet_pupil(n_side_lenslet, n_sensor, amplitude, segment);
+        let n_lenslet = n_side_lenslet * n_side_lenslet;
+        let mut mask = Vec::with_capacity(lenslets.len());
+        for lenslets in lenslets.chunks(n_lenslet) {
+            let mut gs_mask: Vec<bool> = match strategy {
+                ValidLensletStrategy::FluxFraction(fraction) => {
+                    let max_flux = lenslets.iter().map(|l| l.flux).fold(0f64, f64::max);
+                    lenslets
+                        .iter()
+                        .map(|l| l.flux > 0. && l.flux >= fraction * max_flux)
+                        .collect()
+                }
+                ValidLensletStrategy::SegmentOverlap(fraction)
+                | ValidLensletStrategy::MinPerSegment(fraction, _) => lenslets
+                    .iter()
+                    .map(|l| l.overlap > 0. && l.overlap >= fraction)
+                    .collect(),
+            };
+            if let ValidLensletStrategy::MinPerSegment(_, min) = strategy {
+                for sid in 1..=N_SEGMENT as u8 {
+                    let n_valid = gs_mask
+                        .iter()
+                        .zip(lenslets)
+                        .filter(|(m, l)| **m && l.segment == sid)
+                        .count();
+                    if n_valid >= min {
+                        continue;
+                    }
+                    let mut candidates: Vec<_> = lenslets
+                        .iter()
+                        .enumerate()
+                        .filter(|(k, l)| !gs_mask[*k] && l.segment == sid)
+                        .collect();
+                    candidates
+                        .sort_by(|(_, a), (_, b)| b.segment_overlap.total_cmp(&a.segment_overlap));
+                    candidates
+                        .into_iter()
+                        .take(min - n_valid)
+                        .for_each(|(k, _)| gs_mask[k] = true);
+                }
+            }
+            mask.extend(gs_mask);
+        }
+        Self {
+            n_side_lenslet,
+            n_sensor,
+            mask,
+            segment: lenslets.iter().map(|l| l.segment).collect(),
+        }
+    }
+    // Flux, pupil overlap and segment of each lenslet
+    fn lenslet_pupil(
+        n_side_lenslet: usize,
+        n_sensor: usize,
+        amplitude: &[f32],
+        segment: &[i32],
+    ) -> Vec<LensletPupil> {
+        let n_px = ((amplitude.len() / n_sensor) as f64).sqrt() as usize;
+        let n_px_lenslet = (n_px - 1) / n_side_lenslet;
+        let n_lenslet = n_side_lenslet * n_side_lenslet;
+        let lenslet = |i: usize| (i / n_px_lenslet).min(n_side_lenslet - 1);
+        amplitude
+            .chunks(n_px * n_px)
+            .zip(segment.chunks(n_px * n_px))
+            .flat_map(|(amplitude, segment)| {
+                let mut n_sample = vec![0usize; n_lenslet];
+                let mut flux = vec![0f64; n_lenslet];
+                let mut segment_count = vec![[0usize; N_SEGMENT]; n_lenslet];
+                for j in 0..n_px {
+                    for i in 0..n_px {
+                        let k = lenslet(i) + n_side_lenslet * lenslet(j);
+                        let a = amplitude[i + n_px * j] as f64;
+                        n_sample[k] += 1;
+                        flux[k] += a * a;
+                        let sid = segment[i + n_px * j];
+                        if a > 0. && sid > 0 && sid as usize <= N_SEGMENT {
+                            segment_count[k][sid as usize - 1] += 1;
+                        }
+                    }
+                }
+                n_sample
+                    .into_iter()
+                    .zip(flux)
+                    .zip(segment_count)
+                    .map(|((n, flux), count)| {
+                        let (sid, n_max) = count
+                            .iter()
+                            .enumerate()
+                            .max_by_key(|(_, c)| **c)
+                            .map(|(k, c)| (k as u8 + 1, *c))
+                            .unwrap();
+                        LensletPupil {
+                            flux,
+                            overlap: count.iter().sum::<usize>() as f64 / n as f64,
+                            segment: if n_max > 0 { sid } else { 0 },
+                            segment_overlap: n_max as f64 / n as f64,
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+    /// Returns the number of valid lenslets
+    pub fn n_valid_lenslet(&self) -> usize {
+        self.mask.iter().filter(|m| **m).count()
+    }
+    /// Returns the mask as an integer vector as expected by [ShackHartmann::set_valid_lenslet](crate::wavefrontsensor::ShackHartmann::set_valid_lenslet)
+    pub fn to_i32(&self) -> Vec<i32> {
+        self.mask.iter().map(|m| *m as i32).collect()
+    }
+    /// Returns the ID of the segment seen by each valid lenslet
+    pub fn valid_segments(&self) -> Vec<u8> {
+        self.mask
+            .iter()
+            .zip(&self.segment)
+            .filter_map(|(m, s)| m.then_some(*s))
+            .collect()
+    }
+    /// Returns the number of valid lenslets per segment for each guide star
+    pub fn segment_report(&self) -> Vec<[usize; N_SEGMENT]> {
+        let n_lenslet = self.n_side_lenslet * self.n_side_lenslet;
+        self.mask
+            .chunks(n_lenslet)
+            .zip(self.segment.chunks(n_lenslet))
+            .map(|(mask, segment)| {
+                let mut report = [0usize; N_SEGMENT];
+                mask.iter()
+                    .zip(segment)
+                    .filter(|(m, s)| **m && **s > 0)
+                    .for_each(|(_, s)| report[*s as usize - 1] += 1);
+                report
+            })
+            .collect()
+    }
+    /// Returns the indices of the lenslets where the valid lenslets differ from `mask`
+    pub fn mismatch(&self, mask: &[bool]) -> Vec<usize> {
+        self.mask
+            .iter()
+            .zip(mask)
+            .enumerate()
+            .filter_map(|(k, (m, mo))| (m != mo).then_some(k))
+            .collect()
+    }
+    /// Saves the valid lenslets into a pickle file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = File::create(path)?;
+        serde_pickle::to_writer(&mut file, self, Default::default())?;
+        Ok(())
+    }
+    /// Loads the valid lenslets from a pickle file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_pickle::from_reader(file, Default::default())?)
+    }
+}
+impl PartialEq<Mask> for ValidLenslets {
+    fn eq(&self, other: &Mask) -> bool {
+        self.mask.len() == other.nel() && self.mismatch(&other.to_vec()).is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2 guide stars on a 4x4 lenslet array with 4x4 pixels per lenslet:
+    // segment #1 on the left half of the pupil and segment #2 on the right half
+    // of the pupil with a 3 pixels gap in the middle and the top pixel row vignetted
+    fn pupil() -> (Vec<f32>, Vec<i32>) {
+        let n = 17;
+        let (amplitude, segment): (Vec<f32>, Vec<i32>) = (0..n * n)
+            .map(|k| {
+                let (i, j) = (k % n, k / n);
+                if (7..=9).contains(&i) || j > 15 {
+                    (0., 0)
+                } else if i < 7 {
+                    (1., 1)
+                } else {
+                    (1., 2)
+                }
+            })
+            .unzip();
+        (amplitude.repeat(2), segment.repeat(2))
+    }
+
+    #[test]
+    fn strategies() {
+        let (amplitude, segment) = pupil();
+        let valid = ValidLenslets::new(
+            ValidLensletStrategy::SegmentOverlap(0.9),
+            4,
+            2,
+            &amplitude,
+            &segment,
+        );
+        assert_eq!(valid.n_valid_lenslet(), 2 * 6);
+        assert_eq!(valid.segment_report(), vec![[3, 3, 0, 0, 0, 0, 0]; 2]);
+        assert_eq!(valid.valid_segments()[..4], [1, 2, 1, 2]);
+        let valid = ValidLenslets::new(
+            ValidLensletStrategy::FluxFraction(0.5),
+            4,
+            2,
+            &amplitude,
+            &segment,
+        );
+        assert_eq!(valid.n_valid_lenslet(), 2 * 12);
+        let valid = ValidLenslets::new(
+            ValidLensletStrategy::MinPerSegment(0.9, 5),
+            4,
+            2,
+            &amplitude,
+            &segment,
+        );
+        assert_eq!(valid.segment_report(), vec![[5, 5, 0, 0, 0, 0, 0]; 2]);
+        assert!([1, 2, 12, 15].iter().all(|k| valid.mask[*k]));
+    }
+
+    #[test]
+    fn save_load() {
+        let (amplitude, segment) = pupil();
+        let valid = ValidLenslets::new(
+            ValidLensletStrategy::SegmentOverlap(0.5),
+            4,
+            2,
+            &amplitude,
+            &segment,
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("valid-lenslets.pkl");
+        valid.save(&path).unwrap();
+        let loaded = ValidLenslets::load(&path).unwrap();
+        assert_eq!(valid, loaded);
+        assert!(valid.mismatch(&loaded.mask).is_empty());
+        let mut mask = loaded.mask.clone();
+        mask[5] = !mask[5];
+        assert_eq!(valid.mismatch(&mask), vec![5]);
+    }
+
+    // The lenslet ordering k=i+n*j must match CEO own valid lenslets
+    // (the GMT pupil is not symmetric with respect to the diagonal)
+    #[test]
+    fn ceo_ordering() {
+        use crate::{
+            wavefrontsensor::Geometric, Builder, FromBuilder, Gmt, ShackHartmann, WavefrontSensor,
+            WavefrontSensorBuilder,
+        };
+        let wfs_builder = ShackHartmann::<Geometric>::builder().lenslet_array(48, 16, 25.5 / 48.);
+        let mut gmt = Gmt::builder().build().unwrap();
+        let mut src = wfs_builder.guide_stars(None).build().unwrap();
+        let mut wfs = wfs_builder.build().unwrap();
+        src.through(&mut gmt).xpupil();
+        wfs.calibrate(&mut src, 0.5);
+        let valid = wfs.valid_lenslets(&mut src, ValidLensletStrategy::FluxFraction(0.5));
+        let mask = wfs.valid_lenslet_mask();
+        assert_eq!(valid.mask.len(), mask.nel());
+        let mismatch = valid.mismatch(&mask.to_vec());
+        assert!(
+            mismatch.len() * 100 <= valid.n_valid_lenslet(),
+            "{} mismatched lenslets: {:?}",
+            mismatch.len(),
+            mismatch
+        );
+    }
+}